@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TABLE uploads(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    length BIGINT NOT NULL,
+    received BIGINT NOT NULL DEFAULT 0,
+    owned_by UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- Lease of the request appending to the upload, on whichever instance it runs.
+    writer UUID,
+    writing_until TIMESTAMPTZ,
+    CHECK (received >= 0 AND received <= length),
+    FOREIGN KEY (owned_by) REFERENCES users(id)
+);
+
+CREATE INDEX uploads_expires_at ON uploads(expires_at);
\ No newline at end of file
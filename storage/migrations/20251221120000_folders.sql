@@ -0,0 +1,108 @@
+-- Folders become real nodes: every row points at its parent instead of
+-- spelling out its location as a slash separated name.
+ALTER TABLE files ADD COLUMN parent_id UUID;
+ALTER TABLE files ADD FOREIGN KEY (parent_id) REFERENCES files(id);
+ALTER TABLE uploads ADD COLUMN parent_id UUID;
+ALTER TABLE uploads ADD FOREIGN KEY (parent_id) REFERENCES files(id);
+
+UPDATE files SET name = trim(both '/' from name);
+UPDATE uploads SET name = trim(both '/' from name);
+
+-- Every directory mentioned in a name becomes a folder, unless it already is one.
+WITH names AS (
+    SELECT owned_by, name, created_at FROM files
+    UNION ALL
+    SELECT owned_by, name, created_at FROM uploads
+),
+prefixes AS (
+    SELECT owned_by,
+           array_to_string((string_to_array(name, '/'))[1:n], '/') AS prefix,
+           min(created_at) AS created_at
+    FROM names,
+         generate_series(1, cardinality(string_to_array(name, '/')) - 1) AS n
+    GROUP BY owned_by, prefix
+)
+INSERT INTO files (name, path, owned_by, created_at)
+SELECT prefix, NULL, owned_by, created_at
+FROM prefixes p
+WHERE NOT EXISTS (
+    SELECT 1 FROM files f
+    WHERE f.owned_by = p.owned_by AND f.path IS NULL AND f.name = p.prefix
+);
+
+UPDATE files child
+SET parent_id = (
+    SELECT parent.id FROM files parent
+    WHERE parent.owned_by = child.owned_by
+      AND parent.path IS NULL
+      AND parent.name = regexp_replace(child.name, '/[^/]*$', '')
+    ORDER BY parent.created_at, parent.id
+    LIMIT 1
+)
+WHERE position('/' in child.name) > 0;
+
+UPDATE uploads child
+SET parent_id = (
+    SELECT parent.id FROM files parent
+    WHERE parent.owned_by = child.owned_by
+      AND parent.path IS NULL
+      AND parent.name = regexp_replace(child.name, '/[^/]*$', '')
+    ORDER BY parent.created_at, parent.id
+    LIMIT 1
+)
+WHERE position('/' in child.name) > 0;
+
+UPDATE files SET name = regexp_replace(name, '^.*/', '');
+UPDATE uploads SET name = regexp_replace(name, '^.*/', '');
+
+-- Names used to be free to repeat; number the duplicates like "name (1).ext",
+-- skipping any number a sibling already goes by.
+DO $$
+DECLARE
+    duplicate RECORD;
+    n INTEGER;
+    candidate TEXT;
+BEGIN
+    FOR duplicate IN
+        SELECT id, parent_id, owned_by, name
+        FROM (
+            SELECT id, parent_id, owned_by, name, created_at,
+                   row_number() OVER (
+                       PARTITION BY COALESCE(parent_id, owned_by), name
+                       ORDER BY created_at, id
+                   ) AS rank
+            FROM files
+            WHERE deleted_at IS NULL
+        ) ranked
+        WHERE rank > 1
+        ORDER BY created_at, id
+    LOOP
+        n := 1;
+        LOOP
+            candidate := CASE
+                WHEN duplicate.name ~ '.\.[^.]+$'
+                    THEN regexp_replace(duplicate.name, '(\.[^.]+)$', ' (' || n || ')\1')
+                ELSE duplicate.name || ' (' || n || ')'
+            END;
+            EXIT WHEN NOT EXISTS (
+                SELECT 1 FROM files sibling
+                WHERE sibling.deleted_at IS NULL
+                  AND sibling.name = candidate
+                  AND (sibling.parent_id = duplicate.parent_id
+                       OR (sibling.parent_id IS NULL AND duplicate.parent_id IS NULL
+                           AND sibling.owned_by = duplicate.owned_by))
+            );
+            n := n + 1;
+        END LOOP;
+        UPDATE files SET name = candidate WHERE id = duplicate.id;
+    END LOOP;
+END
+$$;
+
+ALTER TABLE files ADD CONSTRAINT files_name_segment
+    CHECK (name <> '' AND position('/' in name) = 0);
+CREATE UNIQUE INDEX files_parent_name ON files (parent_id, name)
+    WHERE parent_id IS NOT NULL AND deleted_at IS NULL;
+CREATE UNIQUE INDEX files_root_name ON files (owned_by, name)
+    WHERE parent_id IS NULL AND deleted_at IS NULL;
+CREATE INDEX files_parent_id ON files (parent_id);
\ No newline at end of file
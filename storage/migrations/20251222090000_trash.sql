@@ -0,0 +1,10 @@
+-- Rows deleted together with a folder point at it, so that restoring
+-- the folder brings back exactly what was deleted with it.
+ALTER TABLE files ADD COLUMN trashed_with UUID;
+ALTER TABLE files ADD FOREIGN KEY (trashed_with) REFERENCES files(id);
+CREATE INDEX files_trashed_with ON files (trashed_with);
+CREATE INDEX files_deleted_at ON files (deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Purging a folder should not be blocked by an upload heading into it.
+ALTER TABLE uploads DROP CONSTRAINT uploads_parent_id_fkey;
+ALTER TABLE uploads ADD FOREIGN KEY (parent_id) REFERENCES files(id) ON DELETE SET NULL;
\ No newline at end of file
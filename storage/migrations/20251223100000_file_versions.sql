@@ -0,0 +1,19 @@
+-- Every blob a file ever had; the newest one is also `files.path`.
+CREATE TABLE file_versions(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    file_id UUID NOT NULL,
+    path TEXT UNIQUE NOT NULL,
+    size BIGINT,
+    created_by UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (file_id) REFERENCES files(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id)
+);
+
+CREATE INDEX file_versions_file_id ON file_versions (file_id, created_at DESC);
+
+-- Sizes of blobs stored before versioning are not known to the database.
+INSERT INTO file_versions (file_id, path, size, created_by, created_at)
+SELECT id, path, NULL, COALESCE(edited_by, owned_by), COALESCE(edited_at, created_at)
+FROM files
+WHERE path IS NOT NULL;
\ No newline at end of file
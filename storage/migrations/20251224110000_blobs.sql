@@ -0,0 +1,34 @@
+-- Blobs are stored once per distinct content, under `blobs/` and named by their SHA-256.
+CREATE TABLE blobs(
+    hash TEXT PRIMARY KEY,
+    size BIGINT NOT NULL,
+    refs BIGINT NOT NULL DEFAULT 0,
+    -- Set, and committed, before the contents are deleted from the blob store.
+    collecting BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Versions without a hash still point at a blob stored the old way, one per upload.
+ALTER TABLE file_versions ADD COLUMN hash TEXT;
+ALTER TABLE file_versions ADD FOREIGN KEY (hash) REFERENCES blobs(hash);
+CREATE INDEX file_versions_hash ON file_versions (hash);
+
+-- Identical contents now share a path.
+ALTER TABLE files DROP CONSTRAINT files_path_key;
+ALTER TABLE file_versions DROP CONSTRAINT file_versions_path_key;
+
+CREATE FUNCTION count_blob_refs() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP IN ('INSERT', 'UPDATE') AND NEW.hash IS NOT NULL THEN
+        UPDATE blobs SET refs = refs + 1 WHERE hash = NEW.hash;
+    END IF;
+    IF TG_OP IN ('DELETE', 'UPDATE') AND OLD.hash IS NOT NULL THEN
+        UPDATE blobs SET refs = refs - 1 WHERE hash = OLD.hash;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER file_versions_blob_refs
+AFTER INSERT OR DELETE OR UPDATE OF hash ON file_versions
+FOR EACH ROW EXECUTE FUNCTION count_blob_refs();
\ No newline at end of file
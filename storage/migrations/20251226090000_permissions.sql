@@ -0,0 +1,18 @@
+-- Roles are declared weakest first, so that they compare and aggregate by strength.
+CREATE TYPE file_role AS ENUM ('viewer', 'commenter', 'editor', 'owner');
+
+-- Access to a file or folder granted to someone other than its owner.
+-- Grants on a folder extend to everything under it.
+CREATE TABLE permissions(
+    file_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    role file_role NOT NULL,
+    granted_by UUID NOT NULL,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (file_id, user_id),
+    FOREIGN KEY (file_id) REFERENCES files(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (granted_by) REFERENCES users(id)
+);
+
+CREATE INDEX permissions_user_id ON permissions (user_id);
\ No newline at end of file
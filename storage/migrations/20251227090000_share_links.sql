@@ -0,0 +1,20 @@
+CREATE TYPE link_mode AS ENUM ('read_only', 'upload_drop');
+
+-- Anonymous access to a file or folder through an unguessable token, of which only a hash is stored.
+-- Links act on behalf of their creator and stop working once the creator loses access.
+CREATE TABLE share_links(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    token_hash BYTEA NOT NULL UNIQUE,
+    file_id UUID NOT NULL,
+    created_by UUID NOT NULL,
+    mode link_mode NOT NULL DEFAULT 'read_only',
+    phc TEXT, -- is null for links without a password
+    expires_at TIMESTAMPTZ,
+    max_downloads INT CHECK (max_downloads > 0),
+    downloads INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (file_id) REFERENCES files(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX share_links_created_by ON share_links (created_by);
\ No newline at end of file
@@ -0,0 +1,68 @@
+CREATE TYPE change_kind AS ENUM ('create', 'edit', 'move', 'rename', 'delete', 'restore');
+
+-- Every change to a user's drive, in the order the changes were committed,
+-- with the state of the file right after it so that clients need not look it up.
+CREATE TABLE changes(
+    seq BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL,
+    file_id UUID NOT NULL, -- not a foreign key, files are journaled past their purge
+    kind change_kind NOT NULL,
+    name TEXT NOT NULL,
+    parent_id UUID,
+    folder BOOLEAN NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    xact BIGINT NOT NULL DEFAULT txid_current(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX changes_user_id_seq ON changes (user_id, seq);
+CREATE INDEX changes_file_id_seq ON changes (file_id, seq);
+
+-- The last change dropped from each user's journal; older cursors cannot be served.
+CREATE TABLE change_horizons(
+    user_id UUID PRIMARY KEY,
+    seq BIGINT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE FUNCTION journal_change() RETURNS TRIGGER AS $$
+DECLARE
+    change change_kind;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        change := 'create';
+    ELSIF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+        change := 'delete';
+    ELSIF OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN
+        change := 'restore';
+    ELSIF OLD.parent_id IS DISTINCT FROM NEW.parent_id THEN
+        change := 'move';
+    ELSIF OLD.name IS DISTINCT FROM NEW.name THEN
+        change := 'rename';
+    ELSIF OLD.path IS DISTINCT FROM NEW.path OR OLD.edited_at IS DISTINCT FROM NEW.edited_at THEN
+        change := 'edit';
+    ELSE
+        RETURN NULL;
+    END IF;
+    -- Holding the owner's tree lock until commit makes sequence numbers follow commit order,
+    -- so a client never moves its cursor past a change that has yet to become visible.
+    PERFORM pg_advisory_xact_lock(hashtextextended(NEW.owned_by::TEXT, 0));
+    -- A file created or changed the same way twice in one transaction is journaled once,
+    -- as an upload creates the file before pointing it at its contents.
+    UPDATE changes c
+    SET name = NEW.name, parent_id = NEW.parent_id, folder = NEW.path IS NULL, changed_at = now()
+    WHERE c.seq = (SELECT max(seq) FROM changes WHERE file_id = NEW.id)
+        AND c.xact = txid_current()
+        AND (c.kind = 'create' OR c.kind = change);
+    IF FOUND THEN
+        RETURN NULL;
+    END IF;
+    INSERT INTO changes (user_id, file_id, kind, name, parent_id, folder)
+    VALUES (NEW.owned_by, NEW.id, change, NEW.name, NEW.parent_id, NEW.path IS NULL);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER files_journal
+AFTER INSERT OR UPDATE ON files
+FOR EACH ROW EXECUTE FUNCTION journal_change();
\ No newline at end of file
@@ -0,0 +1,54 @@
+-- Changes are announced on the `drive_events` channel once committed, so that every server
+-- instance can push them to the sessions of the user concerned.
+CREATE FUNCTION notify_change() RETURNS TRIGGER AS $$
+BEGIN
+    -- Deferred until commit, so announce the change as it ended up after being coalesced.
+    -- Identical notifications within a transaction are only delivered once.
+    PERFORM pg_notify('drive_events', json_build_object(
+        'userId', c.user_id,
+        'kind', c.kind,
+        'fileId', c.file_id,
+        'name', c.name,
+        'parentId', c.parent_id,
+        'folder', c.folder,
+        'seq', c.seq
+    )::TEXT)
+    FROM changes c
+    WHERE c.seq = NEW.seq;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER changes_notify
+AFTER INSERT OR UPDATE ON changes
+DEFERRABLE INITIALLY DEFERRED
+FOR EACH ROW EXECUTE FUNCTION notify_change();
+
+-- Users are told when something is shared with them, or stops being.
+CREATE FUNCTION notify_permission() RETURNS TRIGGER AS $$
+DECLARE
+    grant_row permissions;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        grant_row := OLD;
+    ELSE
+        grant_row := NEW;
+    END IF;
+    PERFORM pg_notify('drive_events', json_build_object(
+        'userId', grant_row.user_id,
+        'kind', CASE WHEN TG_OP = 'DELETE' THEN 'unshare' ELSE 'share' END,
+        'fileId', f.id,
+        'name', f.name,
+        'parentId', f.parent_id,
+        'folder', f.path IS NULL,
+        'role', CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE grant_row.role END
+    )::TEXT)
+    FROM files f
+    WHERE f.id = grant_row.file_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER permissions_notify
+AFTER INSERT OR UPDATE OR DELETE ON permissions
+FOR EACH ROW EXECUTE FUNCTION notify_permission();
\ No newline at end of file
@@ -0,0 +1,26 @@
+-- Long-lived tokens traded for new access tokens, stored as SHA-256 hashes.
+-- Every refresh replaces the token with a new one of the same family, which is what a login started;
+-- presenting a replaced token again means it leaked, and ends the whole family.
+CREATE TABLE refresh_tokens(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    family UUID NOT NULL,
+    user_id UUID NOT NULL,
+    token_hash BYTEA NOT NULL UNIQUE,
+    access_jti UUID NOT NULL, -- the access token issued along with this one
+    access_expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ, -- set once traded for a new token
+    revoked_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
+CREATE INDEX refresh_tokens_user_id ON refresh_tokens (user_id);
+CREATE INDEX refresh_tokens_access_jti ON refresh_tokens (access_jti);
+
+-- Access tokens that stop working before they expire, kept until they would have.
+CREATE TABLE revoked_tokens(
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
\ No newline at end of file
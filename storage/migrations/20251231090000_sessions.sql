@@ -0,0 +1,28 @@
+-- A login, from which the refresh tokens descend; access tokens name it in their `sid` claim.
+CREATE TABLE sessions(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    device_name TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL, -- when the latest refresh token expires
+    revoked_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_user_id ON sessions (user_id);
+
+-- Families of refresh tokens become the sessions they stood for.
+INSERT INTO sessions (id, user_id, created_at, last_seen_at, expires_at, revoked_at)
+SELECT family, user_id, min(created_at), max(created_at), max(expires_at),
+    CASE WHEN bool_and(revoked_at IS NOT NULL) THEN max(revoked_at) END
+FROM refresh_tokens
+GROUP BY family, user_id;
+
+ALTER TABLE refresh_tokens RENAME COLUMN family TO session_id;
+ALTER INDEX refresh_tokens_family RENAME TO refresh_tokens_session_id;
+ALTER TABLE refresh_tokens
+    ADD FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE;
+DROP INDEX refresh_tokens_access_jti;
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Long-lived tokens users make for scripts, limited to the scopes they were given.
+CREATE TABLE personal_tokens(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    token_hash BYTEA NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL CHECK (
+        scopes <@ ARRAY['files:read', 'files:write', 'config:write', 'admin']
+    ),
+    expires_at TIMESTAMPTZ, -- is null for tokens that never expire
+    last_used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX personal_tokens_user_id ON personal_tokens (user_id);
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Keys access tokens are signed with. New keys are published ahead of signing anything,
+-- and old ones are kept until the tokens they signed have expired.
+CREATE TABLE signing_keys(
+    kid TEXT PRIMARY KEY,
+    algorithm TEXT NOT NULL CHECK (algorithm IN ('EdDSA', 'RS256')),
+    private_key TEXT NOT NULL, -- PKCS#8 PEM
+    not_before TIMESTAMPTZ NOT NULL, -- when the key starts signing
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
\ No newline at end of file
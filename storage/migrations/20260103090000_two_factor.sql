@@ -0,0 +1,20 @@
+-- Authenticator apps users enrolled, which logins then ask a code of.
+CREATE TABLE two_factor(
+    user_id UUID PRIMARY KEY,
+    secret BYTEA NOT NULL,
+    enabled_at TIMESTAMPTZ, -- is null until the first code is checked
+    last_step BIGINT NOT NULL DEFAULT 0, -- of the last code used, which cannot be used again
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Single-use codes standing in for the authenticator app.
+CREATE TABLE recovery_codes(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    code_hash BYTEA NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    UNIQUE (user_id, code_hash)
+);
\ No newline at end of file
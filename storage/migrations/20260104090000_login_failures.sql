@@ -0,0 +1,10 @@
+-- Failed logins, counted per login name and per client address, which slow down and then lock out guessing.
+-- Login names are counted whether or not such a user exists, so that lockouts give nothing away.
+CREATE TABLE login_failures(
+    kind TEXT NOT NULL CHECK (kind IN ('login', 'ip')),
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    PRIMARY KEY (kind, subject)
+);
\ No newline at end of file
@@ -0,0 +1,28 @@
+-- Users signing in through single sign-on need no password of their own.
+ALTER TABLE users ALTER COLUMN phc DROP NOT NULL;
+
+-- Accounts at identity providers, linked to users.
+CREATE TABLE identities(
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id UUID NOT NULL,
+    email TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_login_at TIMESTAMPTZ,
+    PRIMARY KEY (issuer, subject),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX identities_user_id ON identities (user_id);
+
+-- Logins sent off to an identity provider, waiting for it to send the user back.
+CREATE TABLE oidc_logins(
+    state_hash BYTEA PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    user_id UUID, -- of the user linking an identity, or null when logging in
+    device_name TEXT,
+    cookies BOOLEAN NOT NULL DEFAULT false, -- whether the session is kept in cookies
+    expires_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TYPE user_role AS ENUM ('user', 'admin');
+
+-- What users may do across the server, and whether they may still log in.
+ALTER TABLE users
+    ADD COLUMN role user_role NOT NULL DEFAULT 'user',
+    ADD COLUMN disabled_at TIMESTAMPTZ;
+
+-- Set when the identity provider stopped knowing the account, which disabled its user.
+ALTER TABLE identities ADD COLUMN disabled_at TIMESTAMPTZ;
\ No newline at end of file
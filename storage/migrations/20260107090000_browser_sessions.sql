@@ -0,0 +1,2 @@
+-- The CSRF token of sessions kept in browser cookies, which their unsafe requests must carry.
+ALTER TABLE sessions ADD COLUMN csrf_hash BYTEA;
\ No newline at end of file
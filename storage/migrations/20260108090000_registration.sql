@@ -0,0 +1,32 @@
+-- Verified addresses are unique whatever their case, so that registering with someone else's
+-- address cannot keep them from registering with it. Users made before registration asked for one
+-- have none.
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMPTZ;
+CREATE UNIQUE INDEX users_email ON users (lower(email)) WHERE email_verified_at IS NOT NULL;
+CREATE INDEX users_email_any ON users (lower(email));
+
+-- Single-use codes administrators hand out while registration is by invitation only.
+CREATE TABLE invites(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    code_hash BYTEA NOT NULL UNIQUE,
+    email TEXT, -- is null for invites anyone may use
+    created_by UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ, -- is null for invites that never expire
+    used_by UUID,
+    used_at TIMESTAMPTZ,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (used_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- Tokens mailed to new users to prove the address they registered with is theirs.
+CREATE TABLE email_verifications(
+    token_hash BYTEA PRIMARY KEY,
+    user_id UUID NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX email_verifications_user_id ON email_verifications (user_id);
\ No newline at end of file
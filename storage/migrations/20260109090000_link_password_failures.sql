@@ -0,0 +1,4 @@
+-- Wrong share link passwords are counted per link, by its id, alongside failed logins.
+ALTER TABLE login_failures DROP CONSTRAINT login_failures_kind_check;
+ALTER TABLE login_failures ADD CONSTRAINT login_failures_kind_check
+    CHECK (kind IN ('login', 'ip', 'link'));
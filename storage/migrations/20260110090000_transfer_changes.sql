@@ -0,0 +1,52 @@
+-- Files handed over to another user are journaled as deleted from the drive of their previous owner
+-- and created in that of the new one. Files in the trash are in neither drive, so nothing is told.
+CREATE OR REPLACE FUNCTION journal_change() RETURNS TRIGGER AS $$
+DECLARE
+    change change_kind;
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.owned_by IS DISTINCT FROM NEW.owned_by THEN
+        PERFORM pg_advisory_xact_lock(hashtextextended(OLD.owned_by::TEXT, 0));
+        PERFORM pg_advisory_xact_lock(hashtextextended(NEW.owned_by::TEXT, 0));
+        IF OLD.deleted_at IS NULL THEN
+            INSERT INTO changes (user_id, file_id, kind, name, parent_id, folder)
+            VALUES (OLD.owned_by, OLD.id, 'delete', OLD.name, OLD.parent_id, OLD.path IS NULL);
+        END IF;
+        IF NEW.deleted_at IS NULL THEN
+            INSERT INTO changes (user_id, file_id, kind, name, parent_id, folder)
+            VALUES (NEW.owned_by, NEW.id, 'create', NEW.name, NEW.parent_id, NEW.path IS NULL);
+        END IF;
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'INSERT' THEN
+        change := 'create';
+    ELSIF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+        change := 'delete';
+    ELSIF OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN
+        change := 'restore';
+    ELSIF OLD.parent_id IS DISTINCT FROM NEW.parent_id THEN
+        change := 'move';
+    ELSIF OLD.name IS DISTINCT FROM NEW.name THEN
+        change := 'rename';
+    ELSIF OLD.path IS DISTINCT FROM NEW.path OR OLD.edited_at IS DISTINCT FROM NEW.edited_at THEN
+        change := 'edit';
+    ELSE
+        RETURN NULL;
+    END IF;
+    -- Holding the owner's tree lock until commit makes sequence numbers follow commit order,
+    -- so a client never moves its cursor past a change that has yet to become visible.
+    PERFORM pg_advisory_xact_lock(hashtextextended(NEW.owned_by::TEXT, 0));
+    -- A file created or changed the same way twice in one transaction is journaled once,
+    -- as an upload creates the file before pointing it at its contents.
+    UPDATE changes c
+    SET name = NEW.name, parent_id = NEW.parent_id, folder = NEW.path IS NULL, changed_at = now()
+    WHERE c.seq = (SELECT max(seq) FROM changes WHERE file_id = NEW.id)
+        AND c.xact = txid_current()
+        AND (c.kind = 'create' OR c.kind = change);
+    IF FOUND THEN
+        RETURN NULL;
+    END IF;
+    INSERT INTO changes (user_id, file_id, kind, name, parent_id, folder)
+    VALUES (NEW.owned_by, NEW.id, change, NEW.name, NEW.parent_id, NEW.path IS NULL);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -1,5 +1,7 @@
+use std::collections::HashSet;
 use std::io::Write;
-use std::path::{Component, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 
 use axum::body::Body;
@@ -7,84 +9,500 @@ use axum::extract::Query;
 use axum::response::{IntoResponse, Response};
 use axum::{
     Json,
-    extract::{FromRequestParts, Multipart, State},
-    http::{StatusCode, request::Parts},
+    extract::{FromRequestParts, Multipart, State, multipart::Field},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header, request::Parts},
 };
 use chrono::Duration;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::db::Config;
-use crate::{auth, db};
+use crate::blob::{self, BlobStore};
+use crate::db::{Config, Role};
+use crate::{auth, db, mail};
+
+pub mod admin;
+pub mod browser;
+pub mod changes;
+pub mod events;
+pub mod files;
+pub mod keys;
+pub mod links;
+pub mod oidc;
+pub mod range;
+pub mod registration;
+pub mod sessions;
+pub mod sharing;
+pub mod tokens;
+pub mod trash;
+pub mod tus;
+pub mod two_factor;
+pub mod versions;
 
 #[derive(Deserialize)]
-pub struct Credentials {
+#[serde(rename_all = "camelCase")]
+pub struct LoginRequest {
     pub login: String,
     pub password: String,
+    /// Shown in the list of sessions, such as "Work laptop".
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Whether to keep the tokens in cookies rather than hand them over, for browsers.
+    #[serde(default)]
+    pub cookies: bool,
 }
 
-pub type LoginRequest = Credentials;
-pub type RegisterRequest = Credentials;
-
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthOk {
     pub access_token: String,
+    /// Traded at `/auth/refresh` for a new pair of tokens once the access token expires.
+    pub refresh_token: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+    #[serde(skip)]
+    pub session_id: uuid::Uuid,
 }
 
-// POST /auth/register
-pub async fn register(
-    State(shared): State<Shared>,
-    Json(req): Json<RegisterRequest>,
-) -> Result<StatusCode, Error> {
-    let _user_id = auth::register_user(&shared.pool, &req.login, &req.password).await?;
-    Ok(StatusCode::CREATED)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// What logging in gives: tokens, or a token to trade for them along with a code of the user's authenticator.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginOk {
+    Done(AuthOk),
+    Pending(two_factor::MfaPending),
 }
 
 // POST /auth/login
 pub async fn login(
     State(shared): State<Shared>,
+    origin: sessions::Origin,
     Json(req): Json<LoginRequest>,
-) -> Result<Json<AuthOk>, Error> {
-    let user_id = auth::login_user(&shared.pool, &req.login, &req.password).await?;
-    let token = auth::jwt::issue(user_id, &shared.jwt_secret, Duration::minutes(30))?;
-    tracing::debug!(
-        "Response: {}",
-        serde_json::json!(AuthOk {
-            access_token: token.clone()
+) -> Result<Response, Error> {
+    let user_id = auth::throttle::guard(
+        &shared.pool,
+        &shared.settings,
+        &req.login,
+        origin.ip.as_deref(),
+        auth::login_user(&shared.pool, &shared.settings, &req.login, &req.password),
+    )
+    .await?;
+    if let Some(pending) = two_factor::challenge(&shared, user_id).await? {
+        tracing::debug!("Asked {} for a second factor", user_id);
+        return Ok(Json(LoginOk::Pending(pending)).into_response());
+    }
+    let mut tx = shared.pool.begin().await?;
+    let ok = sessions::start(&mut tx, &shared, user_id, req.device_name, origin).await?;
+    let response = if req.cookies {
+        browser::respond(&mut tx, &shared, ok).await?
+    } else {
+        Json(LoginOk::Done(ok)).into_response()
+    };
+    tx.commit().await?;
+    auth::throttle::succeeded(&shared.pool, &req.login).await?;
+    tracing::debug!("Logged in {}", user_id);
+    Ok(response)
+}
+
+// POST /auth/password
+pub async fn change_password(
+    State(shared): State<Shared>,
+    origin: sessions::Origin,
+    claims: auth::jwt::Claims,
+    Json(req): Json<ChangePassword>,
+) -> Result<StatusCode, Error> {
+    let user = db::user::find_by_id(&shared.pool, &claims.sub)
+        .await?
+        .ok_or(Error::NotFound(String::from("No such user")))?;
+    let current = user.phc.as_deref().ok_or(Error::Forbidden(String::from(
+        "The account signs in through single sign-on and has no password",
+    )))?;
+    auth::throttle::guard(
+        &shared.pool,
+        &shared.settings,
+        &user.login,
+        origin.ip.as_deref(),
+        async {
+            match auth::verify_password(&req.current_password, current)? {
+                true => Ok(()),
+                false => Err(Error::Unauthorized(String::from("Invalid credentials"))),
+            }
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        Error::Unauthorized(_) => Error::Forbidden(String::from("The current password is wrong")),
+        e => e,
+    })?;
+    auth::password::check(&shared.settings, &user.login, &req.new_password)?;
+    let phc = auth::hash_password(&req.new_password, &shared.settings.argon2)?;
+    let mut tx = shared.pool.begin().await?;
+    db::user::update_password(&mut *tx, &user.id, &phc).await?;
+    db::session::revoke_all(&mut *tx, &user.id, claims.sid.as_ref()).await?;
+    tx.commit().await?;
+    tracing::info!("{} changed their password", user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How long an unfinished resumable upload is kept before being discarded.
+    pub upload_ttl: Duration,
+    /// How long deleted files stay in the trash before being purged.
+    pub trash_retention: Duration,
+    /// How many of the newest versions of every file are always kept.
+    pub versions_keep_last: i64,
+    /// For how many days the last version of each day is kept on top of that.
+    pub versions_keep_daily: i32,
+    /// How long changes stay in the journal before clients behind them have to resync.
+    pub change_retention: Duration,
+    /// How long an access token works for.
+    pub access_token_ttl: Duration,
+    /// How long a refresh token can be traded for new tokens; every refresh starts it over.
+    pub refresh_token_ttl: Duration,
+    /// What new access tokens are signed with, either `EdDSA` or `RS256`.
+    pub jwt_algorithm: jsonwebtoken::Algorithm,
+    /// How long a signing key is used for before a new one replaces it.
+    pub key_rotation: Duration,
+    /// Who access tokens say issued them, and who they say they are for.
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    /// How long a login waits for the code of an authenticator once the password was checked.
+    pub mfa_token_ttl: Duration,
+    /// The name authenticator apps show next to the codes of this server.
+    pub totp_issuer: String,
+    /// How many logins in a row may fail for a login name before it is locked out for a while.
+    pub login_attempts: i32,
+    /// The same for a client address, which many users may share.
+    pub ip_login_attempts: i32,
+    /// How long the first lockout lasts; every further failure doubles it.
+    pub login_backoff: Duration,
+    /// How long a lockout lasts at most, and how long failures are remembered for.
+    pub login_lockout: Duration,
+    /// How many characters passwords need at least.
+    pub password_min_length: usize,
+    /// Passwords known from breaches, which users may not choose.
+    pub breached_passwords: Arc<HashSet<String>>,
+    /// How costly password hashes are to make; older hashes are made anew as users log in.
+    pub argon2: argon2::Params,
+    /// The OpenID Connect provider users may sign in through, if any.
+    pub oidc: Option<auth::oidc::Provider>,
+    /// The LDAP directory users may log in with the password of, if any.
+    pub ldap: Option<auth::ldap::Directory>,
+    /// Whether browsers send the session cookies along with requests started by other sites.
+    pub cookie_same_site: browser::SameSite,
+    /// The origins browsers may call the API from besides its own, such as `https://drive.example.com`.
+    pub cors_origins: Vec<String>,
+    pub registration: registration::Mode,
+    /// Domains the email addresses of new users must be at; any domain if empty.
+    /// Needs `email_verification`, as nothing else proves an address is at one.
+    pub registration_domains: Vec<String>,
+    /// Whether users who registered with an email address must verify it before logging in.
+    pub email_verification: bool,
+    pub email_verification_ttl: Duration,
+    /// Where the links of verification mails lead, with the token added as `token`.
+    /// Without one, mails carry the bare token.
+    pub email_verification_url: Option<String>,
+    pub mailer: Option<Arc<dyn mail::Mailer>>,
+    /// Whether to take client addresses from `X-Forwarded-For`, which only a reverse proxy should set.
+    pub trust_proxy: bool,
+    /// How often the background tasks look for expired data.
+    pub sweep_interval: std::time::Duration,
+    /// How often the token an event stream was opened with is checked for having been revoked.
+    pub events_recheck: std::time::Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            upload_ttl: Duration::hours(24),
+            trash_retention: Duration::days(30),
+            versions_keep_last: 10,
+            versions_keep_daily: 30,
+            change_retention: Duration::days(30),
+            access_token_ttl: Duration::minutes(30),
+            refresh_token_ttl: Duration::days(30),
+            jwt_algorithm: jsonwebtoken::Algorithm::EdDSA,
+            key_rotation: Duration::days(30),
+            jwt_issuer: String::from("proto-drive"),
+            jwt_audience: String::from("proto-drive"),
+            mfa_token_ttl: Duration::minutes(5),
+            totp_issuer: String::from("proto-drive"),
+            login_attempts: 5,
+            ip_login_attempts: 20,
+            login_backoff: Duration::seconds(30),
+            login_lockout: Duration::hours(1),
+            password_min_length: 8,
+            breached_passwords: Default::default(),
+            argon2: Default::default(),
+            oidc: None,
+            ldap: None,
+            cookie_same_site: Default::default(),
+            cors_origins: Vec::new(),
+            registration: Default::default(),
+            registration_domains: Vec::new(),
+            email_verification: false,
+            email_verification_ttl: Duration::hours(24),
+            email_verification_url: None,
+            mailer: None,
+            trust_proxy: false,
+            sweep_interval: std::time::Duration::from_secs(10 * 60),
+            events_recheck: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+pub(crate) fn var_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T, Error> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| Error::Configuration(format!("{} has an invalid value", name))),
+        Err(std::env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Settings {
+    pub fn from_env() -> Result<Settings, Error> {
+        let default = Settings::default();
+        let jwt_algorithm = var_or("JWT_ALGORITHM", default.jwt_algorithm)?;
+        if !auth::jwt::ALGORITHMS.contains(&jwt_algorithm) {
+            return Err(Error::Configuration(String::from(
+                "JWT_ALGORITHM must be EdDSA or RS256",
+            )));
+        }
+        let cors_origins: Vec<String> = var_or("CORS_ORIGINS", String::new())?
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        if let Some(origin) = cors_origins
+            .iter()
+            .find(|origin| HeaderValue::from_str(origin).is_err())
+        {
+            return Err(Error::Configuration(format!(
+                "CORS_ORIGINS has an invalid origin: {}",
+                origin
+            )));
+        }
+        let registration_domains: Vec<String> = var_or("REGISTRATION_DOMAINS", String::new())?
+            .split(',')
+            .map(|domain| domain.trim().trim_start_matches('@').to_ascii_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        let email_verification = var_or("EMAIL_VERIFICATION", default.email_verification)?;
+        if !registration_domains.is_empty() && !email_verification {
+            return Err(Error::Configuration(String::from(
+                "REGISTRATION_DOMAINS needs EMAIL_VERIFICATION, or anyone could claim an address at them",
+            )));
+        }
+        let mailer = mail::from_env()?;
+        if email_verification && mailer.is_none() {
+            return Err(Error::Configuration(String::from(
+                "EMAIL_VERIFICATION needs SMTP_URL to send mail through",
+            )));
+        }
+        Ok(Settings {
+            upload_ttl: Duration::hours(var_or(
+                "UPLOAD_TTL_HOURS",
+                default.upload_ttl.num_hours(),
+            )?),
+            trash_retention: Duration::days(var_or(
+                "TRASH_RETENTION_DAYS",
+                default.trash_retention.num_days(),
+            )?),
+            versions_keep_last: var_or("VERSIONS_KEEP_LAST", default.versions_keep_last)?,
+            versions_keep_daily: var_or("VERSIONS_KEEP_DAILY_DAYS", default.versions_keep_daily)?,
+            change_retention: Duration::days(var_or(
+                "CHANGE_RETENTION_DAYS",
+                default.change_retention.num_days(),
+            )?),
+            access_token_ttl: Duration::minutes(var_or(
+                "ACCESS_TOKEN_TTL_MINUTES",
+                default.access_token_ttl.num_minutes(),
+            )?),
+            refresh_token_ttl: Duration::days(var_or(
+                "REFRESH_TOKEN_TTL_DAYS",
+                default.refresh_token_ttl.num_days(),
+            )?),
+            jwt_algorithm,
+            key_rotation: Duration::days(var_or(
+                "JWT_KEY_ROTATION_DAYS",
+                default.key_rotation.num_days(),
+            )?),
+            jwt_issuer: var_or("JWT_ISSUER", default.jwt_issuer)?,
+            jwt_audience: var_or("JWT_AUDIENCE", default.jwt_audience)?,
+            mfa_token_ttl: Duration::minutes(var_or(
+                "MFA_TOKEN_TTL_MINUTES",
+                default.mfa_token_ttl.num_minutes(),
+            )?),
+            totp_issuer: var_or("TOTP_ISSUER", default.totp_issuer)?,
+            login_attempts: var_or("LOGIN_ATTEMPTS", default.login_attempts)?,
+            ip_login_attempts: var_or("IP_LOGIN_ATTEMPTS", default.ip_login_attempts)?,
+            login_backoff: Duration::seconds(var_or(
+                "LOGIN_BACKOFF_SECS",
+                default.login_backoff.num_seconds(),
+            )?),
+            login_lockout: Duration::minutes(var_or(
+                "LOGIN_LOCKOUT_MINUTES",
+                default.login_lockout.num_minutes(),
+            )?),
+            password_min_length: var_or("PASSWORD_MIN_LENGTH", default.password_min_length)?,
+            breached_passwords: match std::env::var_os("BREACHED_PASSWORDS") {
+                Some(path) => Arc::new(auth::password::load_breached(Path::new(&path))?),
+                None => default.breached_passwords,
+            },
+            argon2: argon2::Params::new(
+                var_or("ARGON2_MEMORY_KIB", default.argon2.m_cost())?,
+                var_or("ARGON2_ITERATIONS", default.argon2.t_cost())?,
+                var_or("ARGON2_PARALLELISM", default.argon2.p_cost())?,
+                None,
+            )
+            .map_err(|e| Error::Configuration(format!("Invalid Argon2 parameters: {}", e)))?,
+            oidc: auth::oidc::Provider::from_env()?,
+            ldap: auth::ldap::Directory::from_env()?,
+            cookie_same_site: var_or("COOKIE_SAME_SITE", default.cookie_same_site)?,
+            cors_origins,
+            registration: var_or("REGISTRATION", default.registration)?,
+            registration_domains,
+            email_verification,
+            email_verification_ttl: Duration::hours(var_or(
+                "EMAIL_VERIFICATION_TTL_HOURS",
+                default.email_verification_ttl.num_hours(),
+            )?),
+            email_verification_url: std::env::var("EMAIL_VERIFICATION_URL").ok(),
+            mailer,
+            trust_proxy: var_or("TRUST_PROXY", default.trust_proxy)?,
+            sweep_interval: std::time::Duration::from_secs(var_or(
+                "SWEEP_INTERVAL_SECS",
+                default.sweep_interval.as_secs(),
+            )?),
+            events_recheck: std::time::Duration::from_secs(var_or(
+                "EVENTS_RECHECK_SECS",
+                default.events_recheck.as_secs(),
+            )?),
         })
-    );
-    Ok(Json(AuthOk {
-        access_token: token,
-    }))
+    }
 }
 
 #[derive(Clone)]
 pub struct Shared {
     pub pool: PgPool,
-    pub jwt_secret: Arc<[u8]>,
+    /// What access tokens are signed and validated with, reloaded from the database on every rotation.
+    pub keys: auth::jwt::Keys,
+    /// Where uploads are received before being handed to `blobs`.
     pub root: PathBuf,
+    pub blobs: Arc<dyn BlobStore>,
+    pub settings: Settings,
+    /// Where changes heard from the database are handed to connected sessions.
+    pub events: events::Hub,
 }
 
 impl Shared {
     pub async fn from_env() -> Result<Shared, Error> {
         let db_connection_string = std::env::var("DATABASE_URL")?;
-        let jwt_secret = Arc::from(std::env::var("JWT_SECRET")?.as_bytes());
         let root = std::path::PathBuf::from(&std::env::var("ROOT")?);
+        let blobs = blob::from_env(&root)?;
+        let settings = Settings::from_env()?;
         let pool = sqlx::postgres::PgPoolOptions::new()
             .max_connections(5)
             .acquire_timeout(std::time::Duration::from_secs(5))
             .connect(&db_connection_string)
             .await?;
-        Ok(Shared {
+        let keys = auth::jwt::Keys::new(&settings.jwt_issuer, &settings.jwt_audience);
+        let shared = Shared {
             pool,
-            jwt_secret,
+            keys,
             root,
+            blobs,
+            settings,
+            events: Default::default(),
+        };
+        keys::rotate(&shared).await?;
+        Ok(shared)
+    }
+}
+
+/// Checks an access token, including whether it was revoked before expiring.
+pub(crate) async fn verify(shared: &Shared, token: &str) -> Result<auth::jwt::Claims, Error> {
+    let claims = auth::jwt::validate(token, &shared.keys)
+        .map_err(|_| Error::Unauthorized(String::from("Invalid access token")))?;
+    if db::token::is_revoked(&shared.pool, &claims.jti).await? {
+        return Err(Error::Unauthorized(String::from(
+            "The access token was revoked",
+        )));
+    }
+    if let Some(session_id) = &claims.sid
+        && !db::session::touch(&shared.pool, session_id).await?
+    {
+        return Err(Error::Unauthorized(String::from("The session has ended")));
+    }
+    Ok(claims)
+}
+
+fn bearer(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+}
+
+/// Finds who a token belongs to: a JWT issued at login, which may be used anywhere,
+/// or a personal access token, which may only be used where `scope` is required and granted.
+pub(crate) async fn authenticate(
+    shared: &Shared,
+    token: &str,
+    scope: Option<auth::Scope>,
+) -> Result<auth::User, Error> {
+    if !token.starts_with(tokens::PREFIX) {
+        let claims = verify(shared, token).await?;
+        return Ok(auth::User { id: claims.sub });
+    }
+    let token = db::personal_token::find_by_hash(&shared.pool, &auth::token::hash(token))
+        .await?
+        .filter(|token| !token.expired())
+        .ok_or(Error::Unauthorized(String::from("Invalid access token")))?;
+    match scope {
+        Some(scope) if token.scopes.iter().any(|s| s == scope.as_str()) => {
+            Ok(auth::User { id: token.user_id })
+        }
+        Some(scope) => Err(Error::Forbidden(format!(
+            "The token lacks the \"{}\" scope",
+            scope
+        ))),
+        None => Err(Error::Forbidden(String::from(
+            "Personal access tokens cannot be used here",
+        ))),
+    }
+}
+
+impl FromRequestParts<Shared> for auth::jwt::Claims {
+    type Rejection = StatusCode;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        state: &Shared,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        Box::pin(async move {
+            let claims = match bearer(parts) {
+                Some(token) => verify(state, token).await,
+                None => browser::authenticate(state, parts).await,
+            };
+            claims.map_err(|e| e.into_response().status())
         })
     }
 }
 
+/// Takes a bearer token or the session cookies of a browser.
+/// Routes declare the scope personal access tokens need through a request extension.
 impl FromRequestParts<Shared> for auth::User {
     type Rejection = StatusCode;
 
@@ -93,21 +511,21 @@ impl FromRequestParts<Shared> for auth::User {
         state: &Shared,
     ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
         Box::pin(async move {
-            let jwt_secret = &state.jwt_secret;
-            let auth_header = parts
-                .headers
-                .get(axum::http::header::AUTHORIZATION)
-                .and_then(|h| h.to_str().ok())
-                .and_then(|s| s.strip_prefix("Bearer "))
-                .ok_or(StatusCode::UNAUTHORIZED)?;
-            let claims = auth::jwt::validate(auth_header, jwt_secret)
-                .map_err(|_| StatusCode::UNAUTHORIZED)?;
-            Ok(Self { id: claims.sub })
+            let user = match bearer(parts) {
+                Some(token) => {
+                    let scope = parts.extensions.get::<auth::Scope>().copied();
+                    authenticate(state, token, scope).await
+                }
+                None => browser::authenticate(state, parts)
+                    .await
+                    .map(|claims| auth::User { id: claims.sub }),
+            };
+            user.map_err(|e| e.into_response().status())
         })
     }
 }
 
-fn sanitize_destination(input: &str) -> String {
+pub(crate) fn sanitize_destination(input: &str) -> String {
     if !input.starts_with("/") {
         tracing::warn!("The path was not absolute");
     }
@@ -132,6 +550,92 @@ fn sanitize_destination(input: &str) -> String {
     }
 }
 
+/// Walks a sanitized destination such as `/docs/reports/` down from `start`,
+/// or from the root folder of `owner_id` when `start` is `None`.
+/// Missing folders are created when `create` is set and reported as not found otherwise.
+/// Returns `None` for the root folder itself.
+pub(crate) async fn resolve_folder(
+    conn: &mut PgConnection,
+    owner_id: &uuid::Uuid,
+    start: Option<uuid::Uuid>,
+    destination: &str,
+    create: bool,
+) -> Result<Option<uuid::Uuid>, Error> {
+    let mut parent = start;
+    for name in destination.split('/').filter(|s| !s.is_empty()) {
+        let folder_id =
+            match db::file::find_child(&mut *conn, owner_id, parent.as_ref(), name).await? {
+                Some(file) if file.path.is_none() => file.id,
+                Some(_) => {
+                    return Err(Error::Conflict(format!(
+                        "\"{}\" is a file, not a folder",
+                        name
+                    )));
+                }
+                None if create => {
+                    db::file::create(&mut *conn, name, None, owner_id, parent.as_ref()).await?
+                }
+                None => {
+                    return Err(Error::NotFound(format!("No folder named \"{}\"", name)));
+                }
+            };
+        parent = Some(folder_id);
+    }
+    Ok(parent)
+}
+
+/// Finds the folder an upload lands in, creating it if needed: `destination` under `parent_id`,
+/// which the user must be able to edit, or under the user's own root folder when that is unset.
+pub(crate) async fn upload_target(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    parent_id: Option<uuid::Uuid>,
+    destination: &str,
+) -> Result<Option<uuid::Uuid>, Error> {
+    let (owner_id, start) = match parent_id.filter(|id| !id.is_nil()) {
+        Some(parent_id) => {
+            let parent = sharing::authorize_folder(conn, user, &parent_id, Role::Editor).await?;
+            (parent.owned_by, Some(parent.id))
+        }
+        None => (user.id, None),
+    };
+    resolve_folder(conn, &owner_id, start, destination, true).await
+}
+
+/// Spools a multipart file field into a temp file of `user_id`, hashing it on the way.
+/// Returns the sanitized file name along with the temp path and the digest.
+pub(crate) async fn receive_file(
+    shared: &Shared,
+    user_id: &uuid::Uuid,
+    field: &mut Field<'_>,
+) -> Result<(String, PathBuf, blob::Digest), Error> {
+    let name = sanitize_filename::sanitize(field.file_name().unwrap_or_default());
+    if name.is_empty() {
+        tracing::error!(name: "filename_empty", "File name contained no characters once sanitized");
+        return Err(Error::BadRequest(String::from(
+            "File name contained no characters once sanitized",
+        )));
+    }
+    tracing::debug!("Sanitized name: \"{}\"", &name);
+    let temp_path = PathBuf::new()
+        .join("temp")
+        .join(user_id.to_string())
+        .join(uuid::Uuid::new_v4().to_string());
+    tracing::debug!("Temp path: \"{}\"", &temp_path.to_string_lossy());
+    std::fs::create_dir_all(shared.root.join(&temp_path).parent().unwrap())?;
+    tracing::trace!("Created intermediate directores");
+    let mut temp = std::fs::File::create(shared.root.join(&temp_path))?;
+    tracing::trace!("Opened the temp file");
+    let mut hasher = blob::Hasher::default();
+    while let Some(chunk) = field.chunk().await? {
+        hasher.update(&chunk);
+        temp.write_all(&chunk)?;
+    }
+    temp.sync_all()?;
+    tracing::trace!("Processed all chunks");
+    Ok((name, temp_path, hasher.finish()))
+}
+
 // POST /upload
 pub async fn upload_file(
     State(shared): State<Shared>,
@@ -139,74 +643,57 @@ pub async fn upload_file(
     mut multipart: Multipart,
 ) -> Result<StatusCode, Error> {
     let mut destination = None;
+    let mut parent_id = None;
     let mut file = None;
     while let Some(mut field) = multipart.next_field().await? {
         match field.name() {
             Some("file") if field.file_name().is_some() => {
                 tracing::info!("Matched a \"file\" field");
-                let name = sanitize_filename::sanitize(field.file_name().unwrap());
-                if name.is_empty() {
-                    tracing::error!(name: "filename_empty", "File name contained no characters once sanitized");
-                    return Err(Error::BadRequest(String::from(
-                        "File name contained no characters once sanitized",
-                    )));
-                }
-                tracing::debug!("Sanitized name: \"{}\"", &name);
-                let file_id = db::file::create(&shared.pool, &name, None, &user.id).await?;
-                tracing::trace!("Acquired file UUID");
-                let temp_path = std::path::PathBuf::new()
-                    .join("temp")
-                    .join(user.id.to_string())
-                    .join(file_id.to_string());
-                tracing::debug!("Temp path: \"{}\"", &temp_path.to_string_lossy());
-                db::file::r#move(&shared.pool, &file_id, &temp_path.to_string_lossy()).await?;
-                tracing::trace!("Updated DB path");
-                std::fs::create_dir_all(shared.root.join(&temp_path).parent().unwrap())?;
-                tracing::trace!("Created intermediate directores");
-                let mut temp = std::fs::File::create(shared.root.join(&temp_path))?;
-                tracing::trace!("Opened the temp file");
-                while let Some(chunk) = field.chunk().await? {
-                    temp.write_all(&chunk)?;
-                }
-                temp.sync_all()?;
-                tracing::trace!("Processed all chunks");
-                file = Some((name, file_id, temp_path));
+                file = Some(receive_file(&shared, &user.id, &mut field).await?);
             }
             Some("destination") => {
                 tracing::trace!("Matched a \"destination\" field");
                 destination = Some(sanitize_destination(&field.text().await?));
             }
+            Some("parentId") => {
+                tracing::trace!("Matched a \"parentId\" field");
+                parent_id = Some(field.text().await?.trim().parse().map_err(|_| {
+                    Error::BadRequest(String::from("\"parentId\" is not a valid UUID"))
+                })?);
+            }
             _ => {
                 tracing::trace!("Skipped a field");
             }
         }
     }
-    let destination = destination.ok_or(Error::BadRequest(String::from(
-        "Multipart missing \"destination\" field.",
-    )))?;
-    let (name, file_id, temp_path) = file.ok_or(Error::BadRequest(String::from(
+    // Without a folder to start from, the destination is the only way to say where the file goes.
+    let destination = match (destination, parent_id) {
+        (Some(destination), _) => destination,
+        (None, Some(_)) => String::from("/"),
+        (None, None) => {
+            return Err(Error::BadRequest(String::from(
+                "Multipart missing \"destination\" field.",
+            )));
+        }
+    };
+    let (name, temp_path, digest) = file.ok_or(Error::BadRequest(String::from(
         "Multipart missing \"file\" field.",
     )))?;
-    let new_name = destination + &name;
-    tracing::debug!("New name: {}", &new_name);
-    db::file::rename(&shared.pool, &file_id, &new_name).await?;
-    tracing::info!("Renamed DB file");
-    let new_path = std::path::PathBuf::new()
-        .join("storage")
-        .join(user.id.to_string())
-        .join(file_id.to_string());
-    tracing::debug!("New path: {}", &new_path.to_string_lossy());
-    db::file::r#move(
-        &shared.pool,
-        &file_id,
-        &shared.root.join(&new_path).to_string_lossy(),
+    let mut tx = shared.pool.begin().await?;
+    let parent_id = upload_target(&mut tx, &user, parent_id, &destination).await?;
+    tracing::debug!("Destination folder: {:?}", parent_id);
+    let file_id = versions::store(
+        &shared,
+        &mut tx,
+        &user.id,
+        parent_id.as_ref(),
+        &name,
+        &temp_path,
+        Some(digest),
     )
     .await?;
-    tracing::info!("Moved DB file");
-    std::fs::create_dir_all(shared.root.join(&new_path).parent().unwrap())?;
-    tracing::trace!("Created intermediate directores");
-    tokio::fs::rename(shared.root.join(temp_path), shared.root.join(new_path)).await?;
-    tracing::info!("Moved physical file");
+    tracing::info!("Stored {}", file_id);
+    tx.commit().await?;
     Ok(StatusCode::CREATED)
 }
 
@@ -215,29 +702,112 @@ pub async fn download_file(
     State(shared): State<Shared>,
     user: auth::User,
     axum::extract::Path(file_id): axum::extract::Path<uuid::Uuid>,
-) -> Result<impl IntoResponse, Error> {
-    let file = db::file::find_by_id(&shared.pool, &file_id)
-        .await?
-        .ok_or(Error::NotFound(String::from(
-            "A file with that UUID does not exist",
-        )))?;
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let mut conn = shared.pool.acquire().await?;
+    let file = sharing::authorize(&mut conn, &user, &file_id, Role::Viewer).await?;
+    drop(conn);
     tracing::trace!("Found the file");
-    if file.owned_by != user.id {
-        return Err(Error::Forbidden(String::from(
-            "You do not have access to that file",
-        )));
-    }
-    if file.path.is_none() {
+    let Some(path) = &file.path else {
         return Err(Error::BadRequest(String::from("Cannot download a folder")));
-    }
+    };
     tracing::trace!("File is not a folder");
-    let path = shared.root.join(file.path.unwrap());
-    tracing::debug!("Looking for file at {}", path.to_string_lossy());
-    let file = tokio::fs::File::open(&path).await?;
-    tracing::trace!("File opened");
-    let stream = tokio_util::io::ReaderStream::new(file);
+    let last_modified = file.edited_at.unwrap_or(file.created_at);
+    serve_blob(&*shared.blobs, path, last_modified, &method, &headers).await
+}
+
+/// Streams a blob, honouring `Range` and conditional request headers.
+pub(crate) async fn serve_blob(
+    blobs: &dyn BlobStore,
+    key: &str,
+    last_modified: chrono::DateTime<chrono::Utc>,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    tracing::debug!("Looking for blob {}", key);
+    let len = blobs.size(key).await?;
+    let validators = range::Validators::new(last_modified, len);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&validators.etag).unwrap(),
+    );
+    response_headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&range::http_date(validators.last_modified)).unwrap(),
+    );
+    if range::not_modified(headers, &validators) {
+        tracing::debug!("Client copy is current");
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+    let ranges = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) if range::range_applies(headers, &validators) => range::parse(value, len),
+        _ => range::Ranges::Full,
+    };
+    tracing::debug!("Serving {:?}", ranges);
+    let (status, content_length, reader): (_, _, Pin<Box<dyn AsyncRead + Send>>) = match ranges {
+        range::Ranges::Unsatisfiable => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response());
+        }
+        range::Ranges::Full => (StatusCode::OK, len, blobs.get(key, None).await?),
+        range::Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end - 1, len))
+                    .unwrap(),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                range.end - range.start,
+                blobs.get(key, Some(range.clone())).await?,
+            )
+        }
+        range::Ranges::Partial(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            response_headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                    .unwrap(),
+            );
+            let mut content_length = 0;
+            let mut reader: Pin<Box<dyn AsyncRead + Send>> = Box::pin(tokio::io::empty());
+            for range in &ranges {
+                let part = format!(
+                    "--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    range.start,
+                    range.end - 1,
+                    len
+                );
+                content_length += part.len() as u64 + (range.end - range.start) + 2;
+                reader = Box::pin(
+                    reader
+                        .chain(std::io::Cursor::new(part))
+                        .chain(blobs.get(key, Some(range.clone())).await?)
+                        .chain(&b"\r\n"[..]),
+                );
+            }
+            let end = format!("--{}--\r\n", boundary);
+            content_length += end.len() as u64;
+            reader = Box::pin(reader.chain(std::io::Cursor::new(end)));
+            (StatusCode::PARTIAL_CONTENT, content_length, reader)
+        }
+    };
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    if *method == Method::HEAD {
+        return Ok((status, response_headers).into_response());
+    }
+    tracing::trace!("Blob opened");
+    let stream = tokio_util::io::ReaderStream::new(reader);
     let body = Body::from_stream(stream);
-    Ok(body)
+    Ok((status, response_headers, body).into_response())
 }
 
 #[derive(Debug, Deserialize)]
@@ -245,20 +815,27 @@ pub struct SearchQuery {
     pub name: String,
 }
 
-// GET /folder/{query}
+// GET /folder?name={path}
 pub async fn find_files(
     State(shared): State<Shared>,
     user: auth::User,
     Query(SearchQuery { name }): Query<SearchQuery>,
 ) -> Result<Json<Vec<db::File>>, Error> {
-    let name = name.trim();
-    if name.is_empty() {
-        let files = db::file::root(&shared.pool, &user.id).await?;
-        Ok(Json(files))
-    } else {
-        let files = db::file::folder(&shared.pool, &user.id, name).await?;
-        Ok(Json(files))
-    }
+    let mut tx = shared.pool.begin().await?;
+    let folder_id = resolve_folder(
+        &mut tx,
+        &user.id,
+        None,
+        &sanitize_destination(name.trim()),
+        false,
+    )
+    .await?;
+    let files = match folder_id {
+        Some(folder_id) => db::file::children(&mut *tx, &folder_id).await?,
+        None => db::file::root(&mut *tx, &user.id).await?,
+    };
+    tx.commit().await?;
+    Ok(Json(files))
 }
 
 // GET /folder/{file_id}
@@ -273,15 +850,87 @@ pub async fn get_folder(
         Ok(Json(files))
     } else {
         let mut tx = shared.pool.begin().await?;
-        let file = db::file::find_by_id(&mut *tx, &file_id)
-            .await?
-            .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
-        let files = db::file::folder(&mut *tx, &user.id, &file.name).await?;
+        let folder = sharing::authorize_folder(&mut tx, &user, &file_id, Role::Viewer).await?;
+        let files = db::file::children(&mut *tx, &folder.id).await?;
         tx.commit().await?;
         Ok(Json(files))
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewFolder {
+    pub name: String,
+    pub parent_id: Option<uuid::Uuid>,
+}
+
+// POST /folder
+pub async fn create_folder(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Json(NewFolder { name, parent_id }): Json<NewFolder>,
+) -> Result<(StatusCode, Json<db::File>), Error> {
+    let name = sanitize_filename::sanitize(name.trim());
+    if name.is_empty() {
+        return Err(Error::BadRequest(String::from(
+            "Folder name contained no characters once sanitized",
+        )));
+    }
+    let parent_id = parent_id.filter(|id| !id.is_nil());
+    let mut tx = shared.pool.begin().await?;
+    // Folders made inside someone else's folder belong to them, like the rest of their tree.
+    let owner_id = match &parent_id {
+        Some(parent_id) => {
+            sharing::authorize_folder(&mut tx, &user, parent_id, Role::Editor)
+                .await?
+                .owned_by
+        }
+        None => user.id,
+    };
+    let folder_id = db::file::create(&mut *tx, &name, None, &owner_id, parent_id.as_ref()).await?;
+    let folder = db::file::find_by_id(&mut *tx, &folder_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(folder)))
+}
+
+// GET /folder/{file_id}/tree
+pub async fn get_tree(
+    State(shared): State<Shared>,
+    user: auth::User,
+    axum::extract::Path(file_id): axum::extract::Path<uuid::Uuid>,
+) -> Result<Json<Vec<db::File>>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let folder = sharing::authorize_folder(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let files = db::file::subtree(&mut *tx, &folder.id).await?;
+    tx.commit().await?;
+    Ok(Json(files))
+}
+
+// GET /files/{file_id}/ancestors
+pub async fn get_ancestors(
+    State(shared): State<Shared>,
+    user: auth::User,
+    axum::extract::Path(file_id): axum::extract::Path<uuid::Uuid>,
+) -> Result<Json<Vec<db::File>>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = sharing::authorize(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let mut files = db::file::ancestors(&mut *tx, &file.id).await?;
+    if file.owned_by != user.id {
+        // Folders above the one shared with the user stay hidden.
+        let ids: Vec<_> = files.iter().map(|f| f.id).collect();
+        let granted = db::permission::granted_among(&mut *tx, &user.id, &ids).await?;
+        let top = files
+            .iter()
+            .position(|f| granted.contains(&f.id))
+            .unwrap_or(files.len() - 1);
+        files.drain(..top);
+    }
+    tx.commit().await?;
+    Ok(Json(files))
+}
+
 // GET /config
 pub async fn get_config(
     State(shared): State<Shared>,
@@ -346,12 +995,26 @@ pub enum Error {
     InputOutput(#[from] std::io::Error),
     #[error("BAD_REQUEST generic error")]
     BadRequest(String),
+    #[error("BAD_GATEWAY generic error")]
+    BadGateway(String),
     #[error("NOT_FOUND generic error")]
     NotFound(String),
     #[error("FORBIDDEN generic error")]
     Forbidden(String),
+    #[error("CONFLICT generic error")]
+    Conflict(String),
+    #[error("GONE generic error")]
+    Gone(String),
+    #[error("LOCKED generic error")]
+    Locked(String),
+    #[error("UNSUPPORTED_MEDIA_TYPE generic error")]
+    UnsupportedMediaType(String),
+    #[error("Configuration error")]
+    Configuration(String),
     #[error("Invalid credentials")]
     Unauthorized(String),
+    #[error("TOO_MANY_REQUESTS generic error")]
+    TooManyRequests(String),
     #[error("JWT error")]
     JsonWebTokenError(#[from] jsonwebtoken::errors::Error),
 }
@@ -383,10 +1046,19 @@ impl IntoResponse for Error {
                 if let sqlx::Error::Database(db_err) = &e
                     && db_err.code().as_deref() == Some("23505")
                 {
-                    (
-                        StatusCode::CONFLICT,
-                        String::from("A user with such login already exists."),
-                    )
+                    let message = match db_err.constraint() {
+                        Some("files_parent_name" | "files_root_name") => {
+                            "A file with that name already exists in this folder."
+                        }
+                        Some("users_login_key") => "A user with such login already exists.",
+                        Some("users_email") => "A user with such email address already exists.",
+                        Some("identities_pkey") => "That account is already linked to a user.",
+                        Some("permissions_pkey") => {
+                            "That user already has access to this file; change their role instead."
+                        }
+                        _ => "Such a record already exists.",
+                    };
+                    (StatusCode::CONFLICT, String::from(message))
                 } else {
                     (
                         StatusCode::INTERNAL_SERVER_ERROR,
@@ -415,11 +1087,24 @@ impl IntoResponse for Error {
                     String::from("Something went wrong."),
                 )
             }
+            Error::Configuration(message) => {
+                tracing::error!(name: "configuration_error", "{}", message);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    String::from("Something went wrong."),
+                )
+            }
             Error::Multipart(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             Error::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            Error::BadGateway(message) => (StatusCode::BAD_GATEWAY, message),
             Error::NotFound(message) => (StatusCode::NOT_FOUND, message),
             Error::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            Error::Conflict(message) => (StatusCode::CONFLICT, message),
+            Error::Gone(message) => (StatusCode::GONE, message),
+            Error::Locked(message) => (StatusCode::LOCKED, message),
+            Error::UnsupportedMediaType(message) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, message),
             Error::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            Error::TooManyRequests(message) => (StatusCode::TOO_MANY_REQUESTS, message),
         };
 
         // Create the JSON response body
@@ -0,0 +1,392 @@
+//! Managing users, for users with the `admin` role.
+//!
+//! Every route here also takes personal access tokens with the `admin` scope, which only
+//! administrators may give their tokens. Administrators cannot disable or demote themselves,
+//! so that the server never loses its last one by accident.
+
+use axum::Json;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::{StatusCode, request::Parts};
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::files::{ConflictPolicy, settle_name};
+use crate::api::{Error, Shared, registration};
+use crate::db::login_failure::Kind;
+use crate::db::user::Role;
+use crate::{auth, db};
+
+/// The most users listed at once.
+const MAX_PAGE: i64 = 200;
+
+/// A user holding the `admin` role.
+#[derive(Clone, Debug)]
+pub struct Admin {
+    pub id: Uuid,
+}
+
+impl FromRequestParts<Shared> for Admin {
+    type Rejection = StatusCode;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        state: &Shared,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        Box::pin(async move {
+            let user = auth::User::from_request_parts(parts, state).await?;
+            match db::user::find_by_id(&state.pool, &user.id).await {
+                Ok(Some(found)) if found.role == Role::Admin && found.disabled_at.is_none() => {
+                    Ok(Admin { id: user.id })
+                }
+                Ok(_) => Err(StatusCode::FORBIDDEN),
+                Err(e) => Err(Error::from(e).into_response().status()),
+            }
+        })
+    }
+}
+
+impl Admin {
+    fn user(&self) -> auth::User {
+        auth::User { id: self.id }
+    }
+}
+
+async fn account(shared: &Shared, user_id: &Uuid) -> Result<db::user::Account, Error> {
+    db::user::account(&shared.pool, user_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No such user")))
+}
+
+#[derive(Deserialize)]
+pub struct UsersQuery {
+    /// Part of the login of the users to list.
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// GET /admin/users?q={query}&limit={limit}&offset={offset}
+pub async fn list_users(
+    State(shared): State<Shared>,
+    _admin: Admin,
+    Query(UsersQuery { q, limit, offset }): Query<UsersQuery>,
+) -> Result<Json<Vec<db::user::Account>>, Error> {
+    let query = q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let limit = limit.unwrap_or(MAX_PAGE).clamp(1, MAX_PAGE);
+    let offset = offset.unwrap_or(0).max(0);
+    Ok(Json(
+        db::user::search(&shared.pool, query, limit, offset).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewUser {
+    pub login: String,
+    pub password: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Taken as verified, whatever domains registration is limited to.
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+// POST /admin/users
+pub async fn create_user(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Json(req): Json<NewUser>,
+) -> Result<(StatusCode, Json<db::user::Account>), Error> {
+    let login = req.login.trim();
+    if login.is_empty() {
+        return Err(Error::BadRequest(String::from("A user needs a login")));
+    }
+    let email = req
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty());
+    if email.is_some_and(|email| !registration::valid_email(email)) {
+        return Err(Error::BadRequest(String::from(
+            "The email address is invalid",
+        )));
+    }
+    auth::password::check(&shared.settings, login, &req.password)?;
+    let phc = auth::hash_password(&req.password, &shared.settings.argon2)?;
+    let mut tx = shared.pool.begin().await?;
+    let user_id = db::user::create(&mut *tx, login, Some(&phc)).await?;
+    db::config::init(&mut *tx, &user_id).await?;
+    db::user::set_role(&mut *tx, &user_id, req.role).await?;
+    if let Some(email) = email {
+        db::user::set_email(&mut *tx, &user_id, email, true).await?;
+    }
+    let account = db::user::account(&mut *tx, &user_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No such user")))?;
+    tx.commit().await?;
+    tracing::info!("{} made user {} as {:?}", admin.id, user_id, req.role);
+    Ok((StatusCode::CREATED, Json(account)))
+}
+
+// GET /admin/users/{user_id}
+pub async fn get_user(
+    State(shared): State<Shared>,
+    _admin: Admin,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<db::user::Account>, Error> {
+    Ok(Json(account(&shared, &user_id).await?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdate {
+    pub role: Role,
+}
+
+// PATCH /admin/users/{user_id}
+pub async fn update_user(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<UserUpdate>,
+) -> Result<Json<db::user::Account>, Error> {
+    if user_id == admin.id && req.role != Role::Admin {
+        return Err(Error::BadRequest(String::from(
+            "Administrators cannot demote themselves",
+        )));
+    }
+    account(&shared, &user_id).await?;
+    db::user::set_role(&shared.pool, &user_id, req.role).await?;
+    tracing::info!("{} made {} {:?}", admin.id, user_id, req.role);
+    Ok(Json(account(&shared, &user_id).await?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordReset {
+    pub password: String,
+}
+
+/// Sets a new password, logging the user out everywhere and lifting any lockout of their login.
+// POST /admin/users/{user_id}/password
+pub async fn reset_password(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<PasswordReset>,
+) -> Result<StatusCode, Error> {
+    let user = account(&shared, &user_id).await?;
+    auth::password::check(&shared.settings, &user.login, &req.password)?;
+    let phc = auth::hash_password(&req.password, &shared.settings.argon2)?;
+    let mut tx = shared.pool.begin().await?;
+    db::user::update_password(&mut *tx, &user.id, &phc).await?;
+    db::session::revoke_all(&mut *tx, &user.id, None).await?;
+    db::login_failure::clear(&mut *tx, Kind::Login, &user.login).await?;
+    tx.commit().await?;
+    tracing::info!("{} reset the password of {}", admin.id, user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stops the user from logging in or using their tokens, and ends their sessions.
+// POST /admin/users/{user_id}/disable
+pub async fn disable_user(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<db::user::Account>, Error> {
+    if user_id == admin.id {
+        return Err(Error::BadRequest(String::from(
+            "Administrators cannot disable themselves",
+        )));
+    }
+    account(&shared, &user_id).await?;
+    let mut tx = shared.pool.begin().await?;
+    if db::user::disable(&mut *tx, &user_id).await? {
+        db::session::revoke_all(&mut *tx, &user_id, None).await?;
+        tracing::info!("{} disabled {}", admin.id, user_id);
+    }
+    tx.commit().await?;
+    Ok(Json(account(&shared, &user_id).await?))
+}
+
+// POST /admin/users/{user_id}/enable
+pub async fn enable_user(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<db::user::Account>, Error> {
+    account(&shared, &user_id).await?;
+    if db::user::enable(&shared.pool, &user_id).await? {
+        tracing::info!("{} enabled {}", admin.id, user_id);
+    }
+    Ok(Json(account(&shared, &user_id).await?))
+}
+
+/// Ends every session of the user; their personal access tokens keep working.
+// POST /admin/users/{user_id}/logout
+pub async fn logout_user(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    account(&shared, &user_id).await?;
+    db::session::revoke_all(&shared.pool, &user_id, None).await?;
+    tracing::info!("{} logged {} out everywhere", admin.id, user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /admin/users/{user_id}/usage
+pub async fn get_usage(
+    State(shared): State<Shared>,
+    _admin: Admin,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<db::user::Usage>, Error> {
+    account(&shared, &user_id).await?;
+    Ok(Json(db::user::usage(&shared.pool, &user_id).await?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transfer {
+    /// Who receives the files.
+    pub to: Uuid,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transferred {
+    /// The folder in the recipient's root holding what was in the root of the previous owner.
+    pub folder: db::File,
+    pub files: u64,
+}
+
+/// Hands every file of a user over to another, into a folder named after the previous owner.
+/// Shares and links the previous owner made stay as they are; links stop working once the
+/// files are no longer theirs. The change feeds of both see the files go and arrive.
+// POST /admin/users/{user_id}/transfer
+pub async fn transfer_files(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<Transfer>,
+) -> Result<Json<Transferred>, Error> {
+    if user_id == req.to {
+        return Err(Error::BadRequest(String::from(
+            "The files already belong to that user",
+        )));
+    }
+    let from = account(&shared, &user_id).await?;
+    let to = account(&shared, &req.to).await?;
+    let mut tx = shared.pool.begin().await?;
+    // Both trees change shape. Locked in the same order whichever way files go,
+    // so that transfers between two users cannot deadlock.
+    let (first, second) = if from.id < to.id {
+        (&from.id, &to.id)
+    } else {
+        (&to.id, &from.id)
+    };
+    db::file::lock_tree(&mut *tx, first).await?;
+    db::file::lock_tree(&mut *tx, second).await?;
+    let name = settle_name(
+        &mut tx,
+        &admin.user(),
+        &to.id,
+        None,
+        &from.login,
+        &Uuid::nil(),
+        ConflictPolicy::Rename,
+    )
+    .await?;
+    let folder_id = db::file::create(&mut *tx, &name, None, &to.id, None).await?;
+    let files = db::file::transfer(&mut *tx, &from.id, &to.id, &folder_id).await?;
+    db::permission::drop_owned(&mut *tx, &to.id).await?;
+    let folder = db::file::find_by_id(&mut *tx, &folder_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    tracing::info!(
+        "{} handed {} files of {} over to {}",
+        admin.id,
+        files,
+        from.id,
+        to.id
+    );
+    Ok(Json(Transferred { folder, files }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewInvite {
+    /// The only address the invite may be used with, if any.
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedInvite {
+    /// Only ever shown here.
+    pub code: String,
+    #[serde(flatten)]
+    pub invite: db::Invite,
+}
+
+// POST /admin/invites
+pub async fn create_invite(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Json(req): Json<NewInvite>,
+) -> Result<(StatusCode, Json<CreatedInvite>), Error> {
+    let email = req
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty());
+    if email.is_some_and(|email| !registration::valid_email(email)) {
+        return Err(Error::BadRequest(String::from(
+            "The email address is invalid",
+        )));
+    }
+    if req.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(Error::BadRequest(String::from(
+            "The expiry must be in the future",
+        )));
+    }
+    let code = auth::token::generate();
+    let invite = db::invite::create(
+        &shared.pool,
+        &auth::token::hash(&code),
+        email,
+        &admin.id,
+        req.expires_at,
+    )
+    .await?;
+    tracing::info!("{} made invite {}", admin.id, invite.id);
+    Ok((StatusCode::CREATED, Json(CreatedInvite { code, invite })))
+}
+
+// GET /admin/invites
+pub async fn list_invites(
+    State(shared): State<Shared>,
+    _admin: Admin,
+) -> Result<Json<Vec<db::Invite>>, Error> {
+    Ok(Json(db::invite::list(&shared.pool).await?))
+}
+
+// DELETE /admin/invites/{invite_id}
+pub async fn withdraw_invite(
+    State(shared): State<Shared>,
+    admin: Admin,
+    Path(invite_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    if db::invite::delete(&shared.pool, &invite_id).await? {
+        tracing::info!("{} withdrew invite {}", admin.id, invite_id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::NotFound(String::from("No such unused invite")))
+    }
+}
@@ -0,0 +1,269 @@
+//! Serving browsers, which have nowhere to keep tokens out of reach of scripts on the page.
+//!
+//! Logging in with `cookies` set keeps the tokens of the session in `HttpOnly` cookies rather than
+//! handing them over. Requests made with those cookies that change anything must also carry the
+//! session's CSRF token in `X-CSRF-Token`, which pages of other sites cannot read from the
+//! `csrf_token` cookie. Which other origins may call the API at all is up to `CORS_ORIGINS`.
+
+use std::str::FromStr;
+
+use axum::Json;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header, request::Parts};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use serde::Serialize;
+use sqlx::{Executor, PgConnection, Postgres};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use uuid::Uuid;
+
+use crate::api::{AuthOk, Error, Settings, Shared, links, tus, verify};
+use crate::auth::{jwt, token};
+use crate::db;
+
+pub const ACCESS_COOKIE: &str = "access_token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Holds the `state` of a single sign-on under way, tying it to the browser it started in.
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// Whether browsers send the cookies along with requests started by other sites.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SameSite {
+    #[default]
+    Strict,
+    Lax,
+    /// Needed when the client is served from another site; CSRF tokens still guard changes.
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl FromStr for SameSite {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CookieOk {
+    /// Sent back in `X-CSRF-Token` with every request that changes anything.
+    pub csrf_token: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+}
+
+/// Finds the value of a cookie a request came with.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn set_cookie(
+    settings: &Settings,
+    name: &str,
+    value: &str,
+    path: &str,
+    max_age: i64,
+    http_only: bool,
+) -> (HeaderName, String) {
+    let mut cookie = format!(
+        "{}={}; Path={}; Max-Age={}; Secure; SameSite={}",
+        name,
+        value,
+        path,
+        max_age,
+        settings.cookie_same_site.as_str()
+    );
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    (header::SET_COOKIE, cookie)
+}
+
+/// Sets, or with an empty `state` clears, the cookie a single sign-on has to come back with.
+/// The provider sends the browser back from another site, which `Strict` cookies are kept from.
+pub(crate) fn oidc_state(settings: &Settings, state: &str, max_age: i64) -> (HeaderName, String) {
+    let same_site = match settings.cookie_same_site {
+        SameSite::None => SameSite::None,
+        _ => SameSite::Lax,
+    };
+    let cookie = format!(
+        "{}={}; Path=/auth/oidc; Max-Age={}; Secure; SameSite={}; HttpOnly",
+        OIDC_STATE_COOKIE,
+        state,
+        max_age,
+        same_site.as_str()
+    );
+    (header::SET_COOKIE, cookie)
+}
+
+/// Hands the tokens of a session over in cookies, along with a new CSRF token for it.
+pub(crate) async fn respond(
+    conn: &mut PgConnection,
+    shared: &Shared,
+    ok: AuthOk,
+) -> Result<Response, Error> {
+    let csrf_token = token::generate();
+    db::session::set_csrf(&mut *conn, &ok.session_id, &token::hash(&csrf_token)).await?;
+    let settings = &shared.settings;
+    let refresh_ttl = settings.refresh_token_ttl.num_seconds();
+    let cookies = [
+        set_cookie(
+            settings,
+            ACCESS_COOKIE,
+            &ok.access_token,
+            "/",
+            ok.expires_in,
+            true,
+        ),
+        set_cookie(
+            settings,
+            REFRESH_COOKIE,
+            &ok.refresh_token,
+            "/auth",
+            refresh_ttl,
+            true,
+        ),
+        set_cookie(settings, CSRF_COOKIE, &csrf_token, "/", refresh_ttl, false),
+    ];
+    let body = CookieOk {
+        csrf_token,
+        expires_in: ok.expires_in,
+    };
+    Ok((AppendHeaders(cookies), Json(body)).into_response())
+}
+
+/// Answers a logout, telling the browser to drop the cookies of the session if it sent them.
+pub(crate) fn forget(settings: &Settings, headers: &HeaderMap) -> Response {
+    if cookie(headers, ACCESS_COOKIE).is_none() && cookie(headers, REFRESH_COOKIE).is_none() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    let cookies = [
+        set_cookie(settings, ACCESS_COOKIE, "", "/", 0, true),
+        set_cookie(settings, REFRESH_COOKIE, "", "/auth", 0, true),
+        set_cookie(settings, CSRF_COOKIE, "", "/", 0, false),
+    ];
+    (StatusCode::NO_CONTENT, AppendHeaders(cookies)).into_response()
+}
+
+/// Checks that a request carries the CSRF token of the session its cookies belong to.
+pub(crate) async fn check_csrf<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    headers: &HeaderMap,
+    session_id: &Uuid,
+) -> Result<(), Error> {
+    let invalid = || Error::Forbidden(String::from("Missing or invalid CSRF token"));
+    let csrf_token = headers
+        .get(CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(invalid)?;
+    if !db::session::csrf_matches(e, session_id, &token::hash(csrf_token)).await? {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Checks the access token in the cookies of a request, and its CSRF token unless the request
+/// only reads.
+pub(crate) async fn authenticate(shared: &Shared, parts: &Parts) -> Result<jwt::Claims, Error> {
+    let access_token = cookie(&parts.headers, ACCESS_COOKIE)
+        .ok_or(Error::Unauthorized(String::from("Not logged in")))?;
+    let claims = verify(shared, access_token).await?;
+    if !parts.method.is_safe() {
+        let session_id = claims.sid.ok_or(Error::Forbidden(String::from(
+            "Missing or invalid CSRF token",
+        )))?;
+        check_csrf(&shared.pool, &parts.headers, &session_id).await?;
+    }
+    Ok(claims)
+}
+
+/// Whether a request comes from a page of the API's own origin or one of `CORS_ORIGINS`, for
+/// requests no CORS check stands in front of, such as WebSocket upgrades. Requests without an
+/// `Origin` were not made by a page at all.
+pub(crate) fn allowed_origin(settings: &Settings, headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let origin = origin.trim_end_matches('/');
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+    let same = origin
+        .split_once("://")
+        .is_some_and(|(_, authority)| Some(authority) == host);
+    same || settings
+        .cors_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+}
+
+/// Lets browsers call the API from the allowed origins, cookies included.
+pub fn cors(settings: &Settings) -> Option<CorsLayer> {
+    if settings.cors_origins.is_empty() {
+        return None;
+    }
+    let origins = settings
+        .cors_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok());
+    Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true)
+            .allow_methods([
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::RANGE,
+                header::IF_RANGE,
+                header::IF_NONE_MATCH,
+                header::IF_MODIFIED_SINCE,
+                HeaderName::from_static(CSRF_HEADER),
+                HeaderName::from_static(links::PASSWORD_HEADER),
+                tus::TUS_RESUMABLE,
+                tus::UPLOAD_LENGTH,
+                tus::UPLOAD_OFFSET,
+                tus::UPLOAD_METADATA,
+            ])
+            .expose_headers([
+                header::CONTENT_RANGE,
+                header::ETAG,
+                header::LAST_MODIFIED,
+                header::LOCATION,
+                tus::TUS_RESUMABLE,
+                tus::TUS_VERSION,
+                tus::TUS_EXTENSION,
+                tus::UPLOAD_LENGTH,
+                tus::UPLOAD_OFFSET,
+                tus::UPLOAD_EXPIRES,
+            ]),
+    )
+}
@@ -0,0 +1,66 @@
+//! The change journal, for clients keeping a copy of the drive in sync.
+//!
+//! A client starts by asking for a cursor without passing one, lists the drive,
+//! then polls with the cursor it was last given to get everything that changed since.
+
+use axum::Json;
+use axum::extract::{Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Error, Shared};
+use crate::{auth, db};
+
+/// How many changes are returned at once unless the client asks for fewer.
+pub const MAX_BATCH: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    pub cursor: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delta {
+    pub changes: Vec<db::Change>,
+    /// Where to continue from next time.
+    pub cursor: i64,
+    /// Whether more changes can be fetched right away.
+    pub has_more: bool,
+}
+
+// GET /changes?cursor={cursor}
+pub async fn list_changes(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Query(ChangesQuery { cursor, limit }): Query<ChangesQuery>,
+) -> Result<Json<Delta>, Error> {
+    let limit = limit.unwrap_or(MAX_BATCH).clamp(1, MAX_BATCH);
+    let mut tx = shared.pool.begin().await?;
+    let latest = db::change::latest(&mut *tx, &user.id).await?;
+    let Some(cursor) = cursor else {
+        return Ok(Json(Delta {
+            changes: Vec::new(),
+            cursor: latest,
+            has_more: false,
+        }));
+    };
+    if cursor < db::change::horizon(&mut *tx, &user.id).await? {
+        return Err(Error::Gone(String::from(
+            "The cursor is too old, a full resync is required",
+        )));
+    }
+    if cursor > latest {
+        return Err(Error::BadRequest(String::from("Unknown cursor")));
+    }
+    let mut changes = db::change::since(&mut *tx, &user.id, cursor, limit + 1).await?;
+    tx.commit().await?;
+    let has_more = changes.len() as i64 > limit;
+    changes.truncate(limit as usize);
+    let cursor = changes.last().map_or(cursor, |change| change.seq);
+    Ok(Json(Delta {
+        changes,
+        cursor,
+        has_more,
+    }))
+}
@@ -0,0 +1,271 @@
+//! Live notifications of changes, pushed over WebSocket or Server-Sent Events.
+//!
+//! Changes are announced by Postgres on the [`CHANNEL`] channel when they are committed.
+//! Every server instance listens to it and hands what it hears to the sessions connected to it,
+//! so that a change made through one instance reaches the user's sessions on all of them.
+//!
+//! A stream lasts as long as the token it was opened with: it ends when an access token expires,
+//! and when the token, or the session it belongs to, is found revoked on one of the checks made
+//! every `events_recheck`.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade, close_code};
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::{header, request::Parts};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use uuid::Uuid;
+
+use crate::api::{Error, Shared, browser, tokens};
+use crate::auth;
+use crate::db::Role;
+
+pub const CHANNEL: &str = "drive_events";
+
+/// How many events a slow session may fall behind before missing some and being told to resync.
+const BACKLOG: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    Create,
+    Edit,
+    Move,
+    Rename,
+    Delete,
+    Restore,
+    Share,
+    Unshare,
+}
+
+/// Something that happened to a file, as announced by the database.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    /// Whose sessions are told.
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub kind: EventKind,
+    pub file_id: Uuid,
+    pub name: String,
+    pub parent_id: Option<Uuid>,
+    pub folder: bool,
+    /// Where the change sits in the change feed, for changes to the user's own drive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    /// The role granted, for files shared with the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+/// What a session is told.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Notice {
+    /// Events were missed, so what the client knows has to be caught up through the change feed.
+    Resync,
+    #[serde(untagged)]
+    Event(Event),
+}
+
+/// Hands the events heard by this instance to the sessions connected to it.
+/// Every user with sessions connected has a channel of their own, so that the events of a busy
+/// user hold up no one else.
+#[derive(Clone, Default)]
+pub struct Hub {
+    senders: Arc<Mutex<HashMap<Uuid, broadcast::Sender<Event>>>>,
+}
+
+impl Hub {
+    pub fn publish(&self, event: Event) {
+        let mut senders = self.senders.lock().unwrap();
+        if let Some(sender) = senders.get(&event.user_id) {
+            let user_id = event.user_id;
+            // Failing means every session of the user went away.
+            if sender.send(event).is_err() {
+                senders.remove(&user_id);
+            }
+        }
+    }
+
+    /// The events meant for `user_id` from now on.
+    pub fn subscribe(&self, user_id: Uuid) -> impl Stream<Item = Notice> + Send + 'static {
+        let receiver = self
+            .senders
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(BACKLOG).0)
+            .subscribe();
+        BroadcastStream::new(receiver).map(move |event| match event {
+            Ok(event) => Notice::Event(event),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                tracing::warn!("A session of {} missed {} events", user_id, missed);
+                Notice::Resync
+            }
+        })
+    }
+}
+
+/// Forwards what is announced on [`CHANNEL`] to the hub until the connection fails.
+pub async fn relay(shared: &Shared) -> Result<(), Error> {
+    let mut listener = PgListener::connect_with(&shared.pool).await?;
+    listener.listen(CHANNEL).await?;
+    tracing::info!("Listening for events");
+    loop {
+        let notification = listener.recv().await?;
+        match serde_json::from_str::<Event>(notification.payload()) {
+            Ok(event) => shared.events.publish(event),
+            Err(e) => tracing::warn!("Ignored a malformed event: {}", e),
+        }
+    }
+}
+
+/// What a stream was opened with, so that it can be checked again while it lasts.
+struct Grant {
+    user_id: Uuid,
+    token: String,
+    scope: Option<auth::Scope>,
+    /// When the token expires, for access tokens; personal access tokens are left to the checks.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Grant {
+    /// Whether the token still stands for the same user.
+    async fn holds(&self, shared: &Shared) -> bool {
+        super::authenticate(shared, &self.token, self.scope)
+            .await
+            .is_ok_and(|user| user.id == self.user_id)
+    }
+
+    /// Returns once the token expires or stops holding.
+    async fn lapse(self, shared: Shared) {
+        let expiry = async {
+            match self.expires_at {
+                Some(at) => {
+                    tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let revocation = async {
+            loop {
+                tokio::time::sleep(shared.settings.events_recheck).await;
+                if !self.holds(&shared).await {
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            _ = expiry => tracing::debug!("The events of {} stopped as the token expired", self.user_id),
+            _ = revocation => tracing::debug!("The events of {} stopped as the token was revoked", self.user_id),
+        }
+    }
+}
+
+/// Takes the token from the `Authorization` header or the session cookies, or, as browsers cannot
+/// set headers on WebSocket and `EventSource` requests, from the `accessToken` query parameter.
+async fn authenticate(parts: &Parts, shared: &Shared) -> Result<Grant, Error> {
+    let scope = parts.extensions.get::<auth::Scope>().copied();
+    let query = parts.uri.query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("accessToken="))
+    });
+    let (token, scope) = match super::bearer(parts).or(query) {
+        Some(token) => (token, scope),
+        // Cookies only ever carry access tokens. Browsers send them along with WebSocket
+        // upgrades from any site, so pages of other origins are turned away.
+        None => match browser::cookie(&parts.headers, browser::ACCESS_COOKIE) {
+            Some(_) if !browser::allowed_origin(&shared.settings, &parts.headers) => {
+                return Err(Error::Forbidden(String::from(
+                    "Events cannot be streamed to pages of that origin",
+                )));
+            }
+            Some(token) => (token, None),
+            None => return Err(Error::Unauthorized(String::from("Missing access token"))),
+        },
+    };
+    let (user_id, expires_at) = if token.starts_with(tokens::PREFIX) {
+        (super::authenticate(shared, token, scope).await?.id, None)
+    } else {
+        let claims = super::verify(shared, token).await?;
+        (claims.sub, Some(claims.expires_at()))
+    };
+    Ok(Grant {
+        user_id,
+        token: token.to_string(),
+        scope,
+        expires_at,
+    })
+}
+
+// GET /events
+pub async fn subscribe(State(shared): State<Shared>, request: Request) -> Response {
+    let (mut parts, _) = request.into_parts();
+    let grant = match authenticate(&parts, &shared).await {
+        Ok(grant) => grant,
+        Err(e) => return e.into_response(),
+    };
+    let user_id = grant.user_id;
+    let notices = shared
+        .events
+        .subscribe(user_id)
+        .take_until(grant.lapse(shared.clone()));
+    let upgrade = parts
+        .headers
+        .get(header::UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    if upgrade {
+        match WebSocketUpgrade::from_request_parts(&mut parts, &shared).await {
+            Ok(ws) => ws.on_upgrade(move |socket| push(socket, notices)),
+            Err(rejection) => rejection.into_response(),
+        }
+    } else {
+        tracing::debug!("Streaming events to {} over SSE", user_id);
+        let stream = notices
+            .map(|notice| Ok::<_, Infallible>(sse::Event::default().json_data(&notice).unwrap()));
+        Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    }
+}
+
+/// Sends every notice as a JSON text message until either side goes away,
+/// closing the socket once there are no more.
+async fn push(mut socket: WebSocket, notices: impl Stream<Item = Notice> + Send + 'static) {
+    let mut notices = std::pin::pin!(notices);
+    loop {
+        tokio::select! {
+            notice = notices.next() => {
+                let Some(notice) = notice else {
+                    let frame = CloseFrame {
+                        code: close_code::POLICY,
+                        reason: Utf8Bytes::from_static("The access token expired or was revoked"),
+                    };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                    break;
+                };
+                let text = serde_json::to_string(&notice).unwrap();
+                if socket.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    tracing::debug!("WebSocket closed");
+}
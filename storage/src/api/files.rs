@@ -0,0 +1,301 @@
+//! Renaming, moving and copying files and whole folders.
+
+use std::collections::HashMap;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::sharing::{authorize, authorize_folder, role};
+use crate::api::{Error, Shared, versions};
+use crate::db::Role;
+use crate::{auth, db};
+
+/// What to do when the destination folder already has an entry with the same name.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+    #[default]
+    Fail,
+    Overwrite,
+    Rename,
+}
+
+/// Inserts a counter before the extension, turning `report.pdf` into `report (1).pdf`.
+pub fn numbered(name: &str, n: usize) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], n, &name[dot..]),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Picks the name `file_id` will have in `parent_id`, a folder of `owner_id`,
+/// applying `policy` to whatever is in the way.
+pub(crate) async fn settle_name(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    owner_id: &Uuid,
+    parent_id: Option<&Uuid>,
+    name: &str,
+    file_id: &Uuid,
+    policy: ConflictPolicy,
+) -> Result<String, Error> {
+    let existing = match db::file::find_child(&mut *conn, owner_id, parent_id, name).await? {
+        Some(existing) if existing.id != *file_id => existing,
+        _ => return Ok(name.to_string()),
+    };
+    match policy {
+        ConflictPolicy::Fail => Err(Error::Conflict(String::from(
+            "A file with that name already exists in this folder.",
+        ))),
+        ConflictPolicy::Overwrite => {
+            tracing::info!("Overwriting {}", existing.id);
+            db::file::delete(&mut *conn, &existing.id, &user.id).await?;
+            Ok(name.to_string())
+        }
+        ConflictPolicy::Rename => {
+            let mut n = 1;
+            loop {
+                let candidate = numbered(name, n);
+                if db::file::find_child(&mut *conn, owner_id, parent_id, &candidate)
+                    .await?
+                    .is_none()
+                {
+                    return Ok(candidate);
+                }
+                n += 1;
+            }
+        }
+    }
+}
+
+/// Resolves a destination folder the user may add to, the nil UUID standing for their root folder.
+/// Returns the owner of the folder along with it.
+async fn destination(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    parent_id: Uuid,
+) -> Result<(Uuid, Option<Uuid>), Error> {
+    if parent_id.is_nil() {
+        Ok((user.id, None))
+    } else {
+        let folder = authorize_folder(&mut *conn, user, &parent_id, Role::Editor).await?;
+        Ok((folder.owned_by, Some(folder.id)))
+    }
+}
+
+fn sanitize_name(name: &str) -> Result<String, Error> {
+    let name = sanitize_filename::sanitize(name.trim());
+    if name.is_empty() {
+        return Err(Error::BadRequest(String::from(
+            "File name contained no characters once sanitized",
+        )));
+    }
+    Ok(name)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileUpdate {
+    pub name: Option<String>,
+    /// The folder to move into, the nil UUID being the root folder.
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+// PATCH /files/{file_id}
+pub async fn update_file(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+    Json(update): Json<FileUpdate>,
+) -> Result<Json<db::File>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Editor).await?;
+    db::file::lock_tree(&mut *tx, &file.owned_by).await?;
+    let parent_id = match update.parent_id {
+        Some(parent_id) => match destination(&mut tx, &user, parent_id).await? {
+            (owner_id, _) if owner_id != file.owned_by => {
+                return Err(Error::BadRequest(String::from(
+                    "Files cannot be moved out of their owner's drive",
+                )));
+            }
+            (_, parent_id) => parent_id,
+        },
+        None => file.parent_id,
+    };
+    if let Some(parent_id) = &parent_id
+        && file.path.is_none()
+        && db::file::ancestors(&mut *tx, parent_id)
+            .await?
+            .iter()
+            .any(|f| f.id == file.id)
+    {
+        return Err(Error::BadRequest(String::from(
+            "Cannot move a folder into itself",
+        )));
+    }
+    let name = match &update.name {
+        Some(name) => sanitize_name(name)?,
+        None => file.name.clone(),
+    };
+    let name = settle_name(
+        &mut tx,
+        &user,
+        &file.owned_by,
+        parent_id.as_ref(),
+        &name,
+        &file.id,
+        update.on_conflict,
+    )
+    .await?;
+    if parent_id != file.parent_id {
+        tracing::debug!("Moving {} into {:?}", file.id, parent_id);
+        db::file::reparent(&mut *tx, &file.id, parent_id.as_ref()).await?;
+    }
+    if name != file.name {
+        tracing::debug!("Renaming {} to \"{}\"", file.id, name);
+        db::file::rename(&mut *tx, &file.id, &name).await?;
+    }
+    let file = db::file::find_by_id(&mut *tx, &file.id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    Ok(Json(file))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyRequest {
+    pub name: Option<String>,
+    /// The folder to copy into, the nil UUID being the root folder.
+    /// Copies land next to the original when omitted.
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+// POST /files/{file_id}/copy
+pub async fn copy_file(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+    Json(request): Json<CopyRequest>,
+) -> Result<(StatusCode, Json<db::File>), Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let (owner_id, parent_id) = match request.parent_id {
+        Some(parent_id) => destination(&mut tx, &user, parent_id).await?,
+        None => {
+            let parent = match &file.parent_id {
+                Some(parent_id) => db::file::find_by_id(&mut *tx, parent_id).await?,
+                None => None,
+            };
+            match parent {
+                Some(parent) if role(&mut tx, &user, &parent).await? >= Some(Role::Editor) => {
+                    (parent.owned_by, Some(parent.id))
+                }
+                // Copies the user cannot put next to the original land in their root folder.
+                _ => (user.id, None),
+            }
+        }
+    };
+    db::file::lock_tree(&mut *tx, &owner_id).await?;
+    let name = match &request.name {
+        Some(name) => sanitize_name(name)?,
+        None => file.name.clone(),
+    };
+    let policy = match request.on_conflict {
+        // Copying next to the original without a new name is always a duplicate.
+        ConflictPolicy::Fail if request.parent_id.is_none() && request.name.is_none() => {
+            ConflictPolicy::Rename
+        }
+        policy => policy,
+    };
+    if policy == ConflictPolicy::Overwrite
+        && db::file::find_child(&mut *tx, &owner_id, parent_id.as_ref(), &name)
+            .await?
+            .is_some_and(|existing| existing.id == file.id)
+    {
+        return Err(Error::Conflict(String::from(
+            "Cannot overwrite a file with its own copy",
+        )));
+    }
+    let name = settle_name(
+        &mut tx,
+        &user,
+        &owner_id,
+        parent_id.as_ref(),
+        &name,
+        &Uuid::nil(),
+        policy,
+    )
+    .await?;
+    let copy_id = copy_tree(
+        &shared,
+        &mut tx,
+        &owner_id,
+        &user,
+        &file,
+        parent_id.as_ref(),
+        &name,
+    )
+    .await?;
+    let copy = db::file::find_by_id(&mut *tx, &copy_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(copy)))
+}
+
+/// Duplicates `file` and, for folders, everything under it, into the drive of `owner_id`.
+/// Copies share the blobs of their originals.
+async fn copy_tree(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    owner_id: &Uuid,
+    user: &auth::User,
+    file: &db::File,
+    parent_id: Option<&Uuid>,
+    name: &str,
+) -> Result<Uuid, Error> {
+    let tree = db::file::subtree(&mut *conn, &file.id).await?;
+    let mut copies: HashMap<Uuid, Uuid> = HashMap::new();
+    for node in &tree {
+        let (node_parent, node_name) = if node.id == file.id {
+            (parent_id.copied(), name)
+        } else {
+            (
+                node.parent_id.and_then(|p| copies.get(&p).copied()),
+                node.name.as_str(),
+            )
+        };
+        let copy_id =
+            db::file::create(&mut *conn, node_name, None, owner_id, node_parent.as_ref()).await?;
+        if let Some(path) = &node.path {
+            let digest = db::version::current(&mut *conn, &node.id)
+                .await?
+                .filter(|version| &version.path == path)
+                .and_then(|version| version.digest());
+            versions::reuse(shared, &mut *conn, &copy_id, path, digest, &user.id).await?;
+        }
+        copies.insert(node.id, copy_id);
+    }
+    tracing::info!("Copied {} entries from {}", tree.len(), file.id);
+    Ok(copies[&file.id])
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn numbered_names() {
+        assert_eq!(super::numbered("report.pdf", 1), "report (1).pdf");
+        assert_eq!(super::numbered("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(super::numbered("README", 1), "README (1)");
+        assert_eq!(super::numbered(".env", 3), ".env (3)");
+    }
+}
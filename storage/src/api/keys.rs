@@ -0,0 +1,75 @@
+//! The keys access tokens are signed with.
+//!
+//! Keys are kept in the database so that every instance signs with the same one. A new key is made
+//! once the newest is older than the rotation period, and only starts signing one sweep later, by
+//! when every instance has loaded it. Replaced keys keep validating tokens until those expire.
+
+use axum::Json;
+use axum::extract::State;
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use uuid::Uuid;
+
+use crate::api::{Error, Shared};
+use crate::auth::jwt;
+use crate::db;
+
+/// Makes a new key if it is time to, drops the keys no token can be signed with anymore
+/// and loads the rest. Returns whether a key was made.
+pub async fn rotate(shared: &Shared) -> Result<bool, Error> {
+    let settings = &shared.settings;
+    let algorithm = format!("{:?}", settings.jwt_algorithm);
+    let now = Utc::now();
+    let sweep = chrono::Duration::from_std(settings.sweep_interval).unwrap_or_default();
+    let mut tx = shared.pool.begin().await?;
+    db::signing_key::lock(&mut *tx).await?;
+    let keys = db::signing_key::all(&mut *tx).await?;
+    let due = keys
+        .iter()
+        .max_by_key(|key| key.created_at)
+        .is_none_or(|key| {
+            key.created_at <= now - settings.key_rotation || key.algorithm != algorithm
+        });
+    if due {
+        let kid = Uuid::new_v4().simple().to_string();
+        let pem = jwt::generate(settings.jwt_algorithm)?;
+        let not_before = if keys.is_empty() { now } else { now + sweep };
+        db::signing_key::create(&mut *tx, &kid, &algorithm, &pem, not_before).await?;
+        tracing::info!("Made signing key {}, signing from {}", kid, not_before);
+    }
+    // An instance may go on signing with a replaced key until its next sweep.
+    let retired =
+        db::signing_key::retire(&mut *tx, now - settings.access_token_ttl - sweep).await?;
+    if retired > 0 {
+        tracing::info!("Retired {} signing keys", retired);
+    }
+    tx.commit().await?;
+    load(shared).await?;
+    Ok(due)
+}
+
+/// Loads the keys from the database, signing with the newest one that may sign already.
+pub async fn load(shared: &Shared) -> Result<(), Error> {
+    let keys = db::signing_key::all(&shared.pool).await?;
+    let now = Utc::now();
+    let mut ring = Vec::with_capacity(keys.len());
+    for key in &keys {
+        let algorithm = key
+            .algorithm
+            .parse()
+            .map_err(|_| Error::Configuration(format!("Key {} has no algorithm", key.kid)))?;
+        ring.push((key.kid.as_str(), algorithm, key.private_key.as_str()));
+    }
+    let signing = keys
+        .iter()
+        .rev()
+        .find(|key| key.not_before <= now)
+        .map(|key| key.kid.as_str());
+    shared.keys.replace(&ring, signing)?;
+    Ok(())
+}
+
+// GET /.well-known/jwks.json
+pub async fn jwks(State(shared): State<Shared>) -> Json<JwkSet> {
+    Json(shared.keys.jwks())
+}
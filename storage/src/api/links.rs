@@ -0,0 +1,341 @@
+//! Public share links: anyone holding a link's token may reach what it points at without logging in.
+//!
+//! A link acts on behalf of whoever created it, so it stops working as soon as they lose access.
+//! Links can expire, require a password, which is sent in the `X-Link-Password` header,
+//! and cap how many times they are downloaded from.
+
+use axum::Json;
+use axum::extract::{Multipart, Path, State};
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::files::{self, ConflictPolicy};
+use crate::api::sessions::Origin;
+use crate::api::{Error, Shared, receive_file, serve_blob, sharing, versions};
+use crate::db::Role;
+use crate::db::link::{LinkMode, Terms};
+use crate::{auth, db};
+
+pub const PASSWORD_HEADER: &str = "x-link-password";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkRequest {
+    #[serde(default)]
+    pub mode: LinkMode,
+    pub password: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedLink {
+    /// Only ever shown here.
+    pub token: String,
+    #[serde(flatten)]
+    pub info: db::Link,
+}
+
+// POST /files/{file_id}/links
+pub async fn create_link(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+    Json(req): Json<LinkRequest>,
+) -> Result<(StatusCode, Json<CreatedLink>), Error> {
+    if req.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(Error::BadRequest(String::from(
+            "The expiry time has already passed",
+        )));
+    }
+    if req.max_downloads.is_some_and(|max| max <= 0) {
+        return Err(Error::BadRequest(String::from(
+            "The download limit must be positive",
+        )));
+    }
+    let phc = match req.password.as_deref() {
+        Some("") => {
+            return Err(Error::BadRequest(String::from(
+                "The password cannot be empty",
+            )));
+        }
+        Some(password) => Some(auth::hash_password(password, &shared.settings.argon2)?),
+        None => None,
+    };
+    let mut tx = shared.pool.begin().await?;
+    let file = sharing::authorize(&mut tx, &user, &file_id, Role::Owner).await?;
+    if req.mode == LinkMode::UploadDrop && file.path.is_some() {
+        return Err(Error::BadRequest(String::from(
+            "Only folders can take uploads",
+        )));
+    }
+    let terms = Terms {
+        mode: req.mode,
+        phc,
+        expires_at: req.expires_at,
+        max_downloads: req.max_downloads,
+    };
+    let token = auth::token::generate();
+    let info = db::link::create(
+        &mut *tx,
+        &auth::token::hash(&token),
+        &file.id,
+        &user.id,
+        &terms,
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("Created link {} to {}", info.id, file.id);
+    Ok((StatusCode::CREATED, Json(CreatedLink { token, info })))
+}
+
+// GET /links
+pub async fn list_links(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<Vec<db::Link>>, Error> {
+    let links = db::link::active(&shared.pool, &user.id).await?;
+    Ok(Json(links))
+}
+
+// DELETE /links/{link_id}
+pub async fn revoke_link(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(link_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    if !db::link::delete(&shared.pool, &link_id, &user.id).await? {
+        return Err(Error::NotFound(String::from("No link with such UUID")));
+    }
+    tracing::info!("Revoked link {}", link_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checks that a link can be used right now in `mode`, returning it with the file it points at.
+async fn open(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    token: &str,
+    origin: &Origin,
+    headers: &HeaderMap,
+    mode: LinkMode,
+) -> Result<(db::Link, db::File), Error> {
+    let link = db::link::find_by_hash(&mut *conn, &auth::token::hash(token))
+        .await?
+        .ok_or(Error::NotFound(String::from("No such link")))?;
+    if link.expired() {
+        return Err(Error::Gone(String::from("This link has expired")));
+    }
+    if link.exhausted() {
+        return Err(Error::Gone(String::from(
+            "This link has reached its download limit",
+        )));
+    }
+    if let Some(phc) = &link.phc {
+        let password = headers
+            .get(PASSWORD_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Error::Unauthorized(String::from(
+                "This link is protected by a password",
+            )))?;
+        auth::throttle::guard_link(
+            &shared.pool,
+            &shared.settings,
+            &link.id.to_string(),
+            origin.ip.as_deref(),
+            async {
+                match auth::verify_password(password, phc)? {
+                    true => Ok(()),
+                    false => Err(Error::Unauthorized(String::from("Wrong password"))),
+                }
+            },
+        )
+        .await?;
+    }
+    if link.mode != mode {
+        return Err(Error::Forbidden(String::from(match link.mode {
+            LinkMode::ReadOnly => "This link does not take uploads",
+            LinkMode::UploadDrop => "This link only takes uploads",
+        })));
+    }
+    let needed = match mode {
+        LinkMode::ReadOnly => Role::Viewer,
+        LinkMode::UploadDrop => Role::Editor,
+    };
+    let creator = auth::User {
+        id: link.created_by,
+    };
+    let file = match sharing::authorize(conn, &creator, &link.file_id, needed).await {
+        Ok(file) => file,
+        Err(Error::Forbidden(_) | Error::NotFound(_)) => {
+            return Err(Error::NotFound(String::from("No such link")));
+        }
+        Err(e) => return Err(e),
+    };
+    Ok((link, file))
+}
+
+/// Serves a file reached through a link, counting the download if it gets the file from its start.
+/// On a link without a download limit, requests for a later range, such as resumed downloads
+/// and seeking players, are not counted; on a limited one every range served counts,
+/// since a client could otherwise fetch the whole file in pieces, or in one multipart response.
+async fn download(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    link: &db::Link,
+    file: &db::File,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    let Some(path) = &file.path else {
+        return Err(Error::BadRequest(String::from("Cannot download a folder")));
+    };
+    let last_modified = file.edited_at.unwrap_or(file.created_at);
+    let response = serve_blob(&*shared.blobs, path, last_modified, method, headers).await?;
+    let counted = match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT if link.max_downloads.is_some() => true,
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|range| range.starts_with("bytes 0-")),
+        _ => false,
+    };
+    if *method != Method::HEAD && counted && !db::link::count_download(&mut *conn, &link.id).await?
+    {
+        return Err(Error::Gone(String::from(
+            "This link has reached its download limit",
+        )));
+    }
+    Ok(response)
+}
+
+// GET /s/{token}
+pub async fn open_link(
+    State(shared): State<Shared>,
+    Path(token): Path<String>,
+    origin: Origin,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let mut conn = shared.pool.acquire().await?;
+    let (link, file) = open(
+        &shared,
+        &mut conn,
+        &token,
+        &origin,
+        &headers,
+        LinkMode::ReadOnly,
+    )
+    .await?;
+    if file.path.is_none() {
+        let files = db::file::children(&mut *conn, &file.id).await?;
+        return Ok(Json(files).into_response());
+    }
+    download(&shared, &mut conn, &link, &file, &method, &headers).await
+}
+
+// GET /s/{token}/files/{file_id}
+pub async fn open_link_file(
+    State(shared): State<Shared>,
+    Path((token, file_id)): Path<(String, Uuid)>,
+    origin: Origin,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let mut conn = shared.pool.acquire().await?;
+    let (link, folder) = open(
+        &shared,
+        &mut conn,
+        &token,
+        &origin,
+        &headers,
+        LinkMode::ReadOnly,
+    )
+    .await?;
+    let ancestors = db::file::ancestors(&mut *conn, &file_id).await?;
+    // Trashed files are not served, even when the link reaches them.
+    let file = match db::file::find_by_id(&mut *conn, &file_id).await? {
+        Some(file) if ancestors.iter().any(|a| a.id == folder.id) => file,
+        _ => {
+            return Err(Error::NotFound(String::from(
+                "No file with such UUID behind this link",
+            )));
+        }
+    };
+    if file.path.is_none() {
+        let files = db::file::children(&mut *conn, &file.id).await?;
+        return Ok(Json(files).into_response());
+    }
+    download(&shared, &mut conn, &link, &file, &method, &headers).await
+}
+
+// POST /s/{token}
+pub async fn drop_upload(
+    State(shared): State<Shared>,
+    Path(token): Path<String>,
+    origin: Origin,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<StatusCode, Error> {
+    let (link, folder) = {
+        let mut conn = shared.pool.acquire().await?;
+        open(
+            &shared,
+            &mut conn,
+            &token,
+            &origin,
+            &headers,
+            LinkMode::UploadDrop,
+        )
+        .await?
+    };
+    let creator = auth::User {
+        id: link.created_by,
+    };
+    let mut received = Vec::new();
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() == Some("file") && field.file_name().is_some() {
+            received.push(receive_file(&shared, &creator.id, &mut field).await?);
+        }
+    }
+    if received.is_empty() {
+        return Err(Error::BadRequest(String::from(
+            "Multipart missing \"file\" field.",
+        )));
+    }
+    let mut tx = shared.pool.begin().await?;
+    db::file::lock_tree(&mut *tx, &folder.owned_by).await?;
+    for (name, temp_path, digest) in received {
+        // Visitors cannot see what is in the folder, so they never get to replace anything.
+        let name = files::settle_name(
+            &mut tx,
+            &creator,
+            &folder.owned_by,
+            Some(&folder.id),
+            &name,
+            &Uuid::nil(),
+            ConflictPolicy::Rename,
+        )
+        .await?;
+        let file_id = versions::store(
+            &shared,
+            &mut tx,
+            &creator.id,
+            Some(&folder.id),
+            &name,
+            &temp_path,
+            Some(digest),
+        )
+        .await?;
+        tracing::info!("Dropped {} through link {}", file_id, link.id);
+    }
+    tx.commit().await?;
+    Ok(StatusCode::CREATED)
+}
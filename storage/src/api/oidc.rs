@@ -0,0 +1,201 @@
+//! Logging in through the OpenID Connect provider.
+//!
+//! `/auth/oidc/login` sends users off to the provider, which sends them back to `/auth/oidc/callback`
+//! to be logged in as the user their account is linked to, one being made for them on the way if
+//! need be. Users who have a password can link an account to themselves from `/auth/oidc/link`.
+//!
+//! Both set a cookie holding the `state` sent to the provider, and the callback is only taken from
+//! the browser that has it, so that nobody can finish a login or link they started in someone
+//! else's browser. Users with a second factor are asked for it as after a password.
+
+use axum::Json;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse, Redirect, Response};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::{Error, LoginOk, Shared, browser, sessions, two_factor};
+use crate::auth::{self, oidc};
+use crate::db;
+
+/// How long users have to log in at the provider.
+const LOGIN_TTL: Duration = Duration::minutes(10);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Start {
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Whether to keep the session in cookies, as when logging in with a password.
+    #[serde(default)]
+    pub cookies: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Authorization {
+    pub authorization_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct Callback {
+    pub state: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+fn provider(shared: &Shared) -> Result<&oidc::Provider, Error> {
+    shared
+        .settings
+        .oidc
+        .as_ref()
+        .ok_or(Error::NotFound(String::from(
+            "Single sign-on is not configured",
+        )))
+}
+
+/// Remembers a login being sent off to the provider, returning where to send it
+/// along with the cookie it has to come back with.
+async fn begin(
+    shared: &Shared,
+    user_id: Option<Uuid>,
+    device_name: Option<String>,
+    cookies: bool,
+) -> Result<(String, AppendHeaders<[(HeaderName, String); 1]>), Error> {
+    let provider = provider(shared)?;
+    let state = auth::token::generate();
+    let pkce = oidc::Pkce::new();
+    let login = db::oidc_login::OidcLogin {
+        nonce: auth::token::generate(),
+        code_verifier: pkce.verifier,
+        user_id,
+        device_name,
+        cookies,
+    };
+    let url = provider
+        .authorization_url(&state, &login.nonce, &pkce.challenge)
+        .await?;
+    let expires_at = Utc::now() + LOGIN_TTL;
+    db::oidc_login::create(&shared.pool, &auth::token::hash(&state), &login, expires_at).await?;
+    let cookie = browser::oidc_state(&shared.settings, &state, LOGIN_TTL.num_seconds());
+    Ok((url, AppendHeaders([cookie])))
+}
+
+// GET /auth/oidc/login
+pub async fn login(
+    State(shared): State<Shared>,
+    Query(start): Query<Start>,
+) -> Result<Response, Error> {
+    let (url, cookie) = begin(&shared, None, start.device_name, start.cookies).await?;
+    Ok((cookie, Redirect::to(&url)).into_response())
+}
+
+// POST /auth/oidc/link
+pub async fn link(State(shared): State<Shared>, user: auth::User) -> Result<Response, Error> {
+    let (authorization_url, cookie) = begin(&shared, Some(user.id), None, false).await?;
+    Ok((cookie, Json(Authorization { authorization_url })).into_response())
+}
+
+/// Makes a user for an account at the provider, named after it.
+async fn provision(shared: &Shared, issuer: &str, claims: &oidc::IdClaims) -> Result<Uuid, Error> {
+    let login = claims
+        .preferred_username
+        .as_deref()
+        .or(claims.email.as_deref())
+        .unwrap_or(&claims.sub);
+    let mut tx = shared.pool.begin().await?;
+    if db::user::login_exists(&mut *tx, login).await? {
+        return Err(Error::Conflict(format!(
+            "A user named {} already exists; log in as them and link the account instead",
+            login
+        )));
+    }
+    let user_id = db::user::create(&mut *tx, login, None).await?;
+    db::config::init(&mut *tx, &user_id).await?;
+    db::identity::create(
+        &mut *tx,
+        issuer,
+        &claims.sub,
+        &user_id,
+        claims.email.as_deref(),
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("Made user {} for {} at {}", user_id, claims.sub, issuer);
+    Ok(user_id)
+}
+
+// GET /auth/oidc/callback
+pub async fn callback(
+    State(shared): State<Shared>,
+    origin: sessions::Origin,
+    headers: HeaderMap,
+    Query(callback): Query<Callback>,
+) -> Result<Response, Error> {
+    let provider = provider(&shared)?;
+    if browser::cookie(&headers, browser::OIDC_STATE_COOKIE) != Some(callback.state.as_str()) {
+        return Err(Error::BadRequest(String::from(
+            "The login was started in another browser",
+        )));
+    }
+    let login = db::oidc_login::take(&shared.pool, &auth::token::hash(&callback.state))
+        .await?
+        .ok_or(Error::BadRequest(String::from(
+            "The login expired or was already finished",
+        )))?;
+    let code = match (&callback.code, &callback.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(Error::Unauthorized(format!(
+                "The identity provider refused the login: {}",
+                error.as_deref().unwrap_or("no code")
+            )));
+        }
+    };
+    let claims = provider
+        .exchange(code, &login.code_verifier, &login.nonce)
+        .await?;
+    let issuer = &provider.config.issuer;
+    let finished = AppendHeaders([browser::oidc_state(&shared.settings, "", 0)]);
+
+    if let Some(user_id) = login.user_id {
+        db::identity::create(
+            &shared.pool,
+            issuer,
+            &claims.sub,
+            &user_id,
+            claims.email.as_deref(),
+        )
+        .await?;
+        tracing::info!("Linked {} at {} to {}", claims.sub, issuer, user_id);
+        return Ok((StatusCode::NO_CONTENT, finished).into_response());
+    }
+
+    let user_id = match db::identity::login(&shared.pool, issuer, &claims.sub).await? {
+        Some(user_id) => user_id,
+        None if provider.config.provision => provision(&shared, issuer, &claims).await?,
+        None => {
+            return Err(Error::Forbidden(String::from(
+                "No user is linked to that account",
+            )));
+        }
+    };
+    if let Some(pending) = two_factor::challenge(&shared, user_id).await? {
+        tracing::debug!("Asked {} for a second factor", user_id);
+        return Ok((finished, Json(LoginOk::Pending(pending))).into_response());
+    }
+    let mut tx = shared.pool.begin().await?;
+    let ok = sessions::start(&mut tx, &shared, user_id, login.device_name, origin).await?;
+    let response = if login.cookies {
+        browser::respond(&mut tx, &shared, ok).await?
+    } else {
+        Json(LoginOk::Done(ok)).into_response()
+    };
+    tx.commit().await?;
+    tracing::debug!("Logged in {} through single sign-on", user_id);
+    Ok((finished, response).into_response())
+}
@@ -0,0 +1,159 @@
+//! Byte ranges and conditional requests for downloads, after RFC 9110 sections 13 and 14.
+
+use std::ops::Range;
+
+use axum::http::{HeaderMap, header};
+use chrono::{DateTime, Utc};
+
+/// Requests asking for more ranges than this are served in full.
+pub const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// What a client can use to tell whether its copy of a file is current.
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    pub fn new(last_modified: DateTime<Utc>, len: u64) -> Validators {
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", last_modified.timestamp_micros(), len),
+            last_modified,
+        }
+    }
+}
+
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Parses a `Range` header against a representation of `len` bytes.
+///
+/// Headers that cannot be parsed are ignored, as the RFC allows,
+/// and result in the full representation being served.
+pub fn parse(value: &str, len: u64) -> Ranges {
+    let Some(specs) = value.trim().strip_prefix("bytes=") else {
+        return Ranges::Full;
+    };
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Full;
+        };
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some(len.saturating_sub(suffix)..len),
+                Err(_) => return Ranges::Full,
+            },
+            (first, "") => match first.parse::<u64>() {
+                Ok(first) => Some(first..len),
+                Err(_) => return Ranges::Full,
+            },
+            (first, last) => match (first.parse::<u64>(), last.parse::<u64>()) {
+                (Ok(first), Ok(last)) if first <= last => {
+                    Some(first..len.min(last.saturating_add(1)))
+                }
+                _ => return Ranges::Full,
+            },
+        };
+        if let Some(range) = range.filter(|r| r.start < r.end) {
+            ranges.push(range);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        Ranges::Full
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+fn strip_weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// Checks an entity tag list such as `If-None-Match` against `etag`.
+fn etag_listed(list: &str, etag: &str, weak: bool) -> bool {
+    list.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || if weak {
+                strip_weak(tag) == strip_weak(etag)
+            } else {
+                !tag.starts_with("W/") && tag == etag
+            }
+    })
+}
+
+/// Whether a GET or HEAD should be answered with `304 Not Modified`.
+pub fn not_modified(headers: &HeaderMap, validators: &Validators) -> bool {
+    if let Some(list) = headers.get(header::IF_NONE_MATCH) {
+        return list
+            .to_str()
+            .is_ok_and(|list| etag_listed(list, &validators.etag, true));
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date)
+        .is_some_and(|since| validators.last_modified.timestamp() <= since.timestamp())
+}
+
+/// Whether the `Range` header should be honoured given `If-Range`.
+pub fn range_applies(headers: &HeaderMap, validators: &Validators) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str().map(str::trim) else {
+        return false;
+    };
+    if value.starts_with('"') || value.starts_with("W/") {
+        etag_listed(value, &validators.etag, false)
+    } else {
+        parse_http_date(value)
+            .is_some_and(|date| date.timestamp() == validators.last_modified.timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ranges;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn range_parsing() {
+        assert_eq!(super::parse("bytes=0-4", 10), Ranges::Partial(vec![0..5]));
+        assert_eq!(super::parse("bytes=5-", 10), Ranges::Partial(vec![5..10]));
+        assert_eq!(super::parse("bytes=-3", 10), Ranges::Partial(vec![7..10]));
+        assert_eq!(super::parse("bytes=8-20", 10), Ranges::Partial(vec![8..10]));
+        assert_eq!(
+            super::parse("bytes=0-0, -1", 10),
+            Ranges::Partial(vec![0..1, 9..10])
+        );
+        assert_eq!(super::parse("bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(super::parse("bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(super::parse("bytes=4-2", 10), Ranges::Full);
+        assert_eq!(super::parse("items=0-4", 10), Ranges::Full);
+    }
+
+    #[test]
+    fn etag_comparison() {
+        assert!(super::etag_listed("\"a\", W/\"b\"", "\"b\"", true));
+        assert!(!super::etag_listed("W/\"b\"", "\"b\"", false));
+        assert!(super::etag_listed("*", "\"b\"", true));
+    }
+}
@@ -0,0 +1,267 @@
+//! Who may register at `/auth/register`, and proving the email addresses they register with.
+//!
+//! `REGISTRATION` is `open` to anyone, `invite` for those holding an unused invite code from an
+//! administrator, or `disabled`. `REGISTRATION_DOMAINS` limits new users to addresses at those
+//! domains, and with `EMAIL_VERIFICATION` they cannot log in until they follow the link mailed
+//! to them.
+
+use std::str::FromStr;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::{Error, Settings, Shared};
+use crate::auth::token;
+use crate::mail::Mail;
+use crate::{auth, db};
+
+/// Who may register.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Open,
+    /// Only with an invite code.
+    Invite,
+    Disabled,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "open" => Ok(Mode::Open),
+            "invite" => Ok(Mode::Invite),
+            "disabled" => Ok(Mode::Disabled),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterRequest {
+    pub login: String,
+    pub password: String,
+    /// Needed when registration is limited to some domains or addresses are verified.
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+/// Roughly checks the shape of an address; only mailing it proves anything.
+pub fn valid_email(email: &str) -> bool {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    }
+}
+
+/// Turns away addresses that are malformed or outside the domains registration is limited to.
+fn check_email(settings: &Settings, email: &str) -> Result<(), Error> {
+    if !valid_email(email) {
+        return Err(Error::BadRequest(String::from(
+            "The email address is invalid",
+        )));
+    }
+    let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+    let allowed = settings.registration_domains.is_empty()
+        || settings
+            .registration_domains
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(domain));
+    if !allowed {
+        return Err(Error::Forbidden(String::from(
+            "Registration is not open to addresses at that domain",
+        )));
+    }
+    Ok(())
+}
+
+/// Mails a new verification token to a user.
+async fn send_verification(
+    conn: &mut PgConnection,
+    shared: &Shared,
+    user_id: &Uuid,
+    login: &str,
+    email: &str,
+) -> Result<(), Error> {
+    let settings = &shared.settings;
+    let mailer = settings
+        .mailer
+        .as_ref()
+        .ok_or(Error::Configuration(String::from(
+            "Email verification is on, but no mailer is set up",
+        )))?;
+    let verification = token::generate();
+    let expires_at = Utc::now() + settings.email_verification_ttl;
+    db::email_verification::create(&mut *conn, &token::hash(&verification), user_id, expires_at)
+        .await?;
+    let instructions = match &settings.email_verification_url {
+        Some(url) => {
+            let link = reqwest::Url::parse_with_params(url, [("token", &verification)])
+                .map_err(|e| Error::Configuration(format!("{} is not a URL: {}", url, e)))?;
+            format!("To confirm it, open {}", link)
+        }
+        None => format!("To confirm it, verify this token: {}", verification),
+    };
+    let mail = Mail {
+        to: email.to_string(),
+        subject: String::from("Confirm your email address"),
+        body: format!(
+            "Hello {},\n\nyou registered with this address. {}\n\nThe token expires in {} hours; \
+             if you did not register, ignore this mail.\n",
+            login,
+            instructions,
+            settings.email_verification_ttl.num_hours()
+        ),
+    };
+    mailer.send(&mail).await?;
+    tracing::info!("Sent {} a verification token", user_id);
+    Ok(())
+}
+
+// POST /auth/register
+pub async fn register(
+    State(shared): State<Shared>,
+    Json(req): Json<RegisterRequest>,
+) -> Result<StatusCode, Error> {
+    let settings = &shared.settings;
+    let invite_code =
+        match settings.registration {
+            Mode::Open => None,
+            Mode::Invite => Some(req.invite_code.as_deref().ok_or(Error::Forbidden(
+                String::from("Registration needs an invite code"),
+            ))?),
+            Mode::Disabled => {
+                return Err(Error::Forbidden(String::from("Registration is closed")));
+            }
+        };
+    let email = req
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty());
+    match email {
+        Some(email) => check_email(settings, email)?,
+        None if settings.email_verification || !settings.registration_domains.is_empty() => {
+            return Err(Error::BadRequest(String::from(
+                "An email address is needed to register",
+            )));
+        }
+        None => {}
+    }
+    auth::password::check(settings, &req.login, &req.password)?;
+    let phc = auth::hash_password(&req.password, &settings.argon2)?;
+    let mut tx = shared.pool.begin().await?;
+    // Until verified, an address proves nothing, so it keeps nobody else from registering with it.
+    if let Some(email) = email
+        && db::user::email_taken(&mut *tx, email, settings.email_verification).await?
+    {
+        return Err(Error::Conflict(String::from(
+            "A user with such email address already exists.",
+        )));
+    }
+    let user_id = db::user::create(&mut *tx, &req.login, Some(&phc)).await?;
+    db::config::init(&mut *tx, &user_id).await?;
+    if let Some(email) = email {
+        db::user::set_email(&mut *tx, &user_id, email, false).await?;
+    }
+    if let Some(code) = invite_code
+        && !db::invite::redeem(&mut *tx, &token::hash(code), &user_id, email).await?
+    {
+        return Err(Error::Forbidden(String::from(
+            "The invite code is invalid, used or expired",
+        )));
+    }
+    if settings.email_verification
+        && let Some(email) = email
+    {
+        send_verification(&mut tx, &shared, &user_id, &req.login, email).await?;
+    }
+    tx.commit().await?;
+    tracing::info!("Registered {}", user_id);
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+// POST /auth/verify
+pub async fn verify_email(
+    State(shared): State<Shared>,
+    Json(req): Json<VerifyEmail>,
+) -> Result<StatusCode, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let user_id = db::email_verification::take(&mut *tx, &token::hash(&req.token))
+        .await?
+        .ok_or(Error::BadRequest(String::from(
+            "The verification token is invalid or expired",
+        )))?;
+    db::user::verify_email(&mut *tx, &user_id).await?;
+    tx.commit().await?;
+    tracing::info!("{} verified their email address", user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+/// Answers the same whether or not anyone registered with the address, and mails a user at most
+/// once a minute. Everyone still to verify the address is mailed, as several may have given it.
+// POST /auth/verify/resend
+pub async fn resend_verification(
+    State(shared): State<Shared>,
+    Json(req): Json<ResendVerification>,
+) -> Result<StatusCode, Error> {
+    if !shared.settings.email_verification {
+        return Ok(StatusCode::ACCEPTED);
+    }
+    let users = db::user::unverified_by_email(&shared.pool, req.email.trim()).await?;
+    let since = Utc::now() - chrono::Duration::minutes(1);
+    for user in users {
+        let Some(email) = &user.email else {
+            continue;
+        };
+        let mut tx = shared.pool.begin().await?;
+        if !db::email_verification::sent_since(&mut *tx, &user.id, since).await? {
+            send_verification(&mut tx, &shared, &user.id, &user.login, email).await?;
+        }
+        tx.commit().await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, valid_email};
+
+    #[test]
+    fn email_shapes() {
+        assert!(valid_email("carol@example.com"));
+        assert!(valid_email("carol+drive@mail.example.com"));
+        assert!(!valid_email("carol"));
+        assert!(!valid_email("@example.com"));
+        assert!(!valid_email("carol@localhost"));
+        assert!(!valid_email("carol@example.com."));
+        assert!(!valid_email("carol smith@example.com"));
+        assert_eq!("Invite".parse(), Ok(Mode::Invite));
+        assert_eq!("closed".parse::<Mode>(), Err(()));
+    }
+}
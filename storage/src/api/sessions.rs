@@ -0,0 +1,254 @@
+//! Keeping users logged in past the life of an access token, and logging them out.
+//!
+//! A login starts a session, and with it a family of refresh tokens. Every refresh trades the presented
+//! token for a new one of the same session, so presenting a token that was already traded means it leaked:
+//! the whole session is ended, which the access tokens issued to it name in their `sid` claim.
+
+use std::net::SocketAddr;
+
+use axum::Json;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, State};
+use axum::http::{HeaderMap, StatusCode, header, request::Parts};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::{AuthOk, Error, Shared, browser};
+use crate::auth::{self, jwt, token};
+use crate::db;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshRequest {
+    /// Taken from the cookie of a browser session when missing.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+/// Where a request comes from, as far as it tells.
+pub struct Origin {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl FromRequestParts<Shared> for Origin {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Shared,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let forwarded = parts
+            .headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|ip| ip.trim().to_string())
+            .filter(|_| state.settings.trust_proxy);
+        let ip = forwarded.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip().to_string())
+        });
+        Ok(Origin { user_agent, ip })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: db::Session,
+    /// Whether this is the session the request was made from.
+    pub current: bool,
+}
+
+/// Issues an access token along with a refresh token of a session.
+async fn grant(
+    conn: &mut PgConnection,
+    shared: &Shared,
+    user_id: Uuid,
+    session_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<AuthOk, Error> {
+    let ttl = shared.settings.access_token_ttl;
+    let mut claims = jwt::Claims::new(&shared.keys, user_id, ttl);
+    claims.sid = Some(session_id);
+    let access_token = jwt::sign(&claims, &shared.keys)?;
+    let refresh_token = token::generate();
+    let grant = db::token::Grant {
+        session_id,
+        token_hash: token::hash(&refresh_token),
+        access_jti: claims.jti,
+        access_expires_at: claims.expires_at(),
+        expires_at,
+    };
+    db::token::create(&mut *conn, &user_id, &grant).await?;
+    Ok(AuthOk {
+        access_token,
+        refresh_token,
+        expires_in: ttl.num_seconds(),
+        session_id,
+    })
+}
+
+/// Starts a session for a user who just logged in, unless they were disabled.
+pub(crate) async fn start(
+    conn: &mut PgConnection,
+    shared: &Shared,
+    user_id: Uuid,
+    device_name: Option<String>,
+    origin: Origin,
+) -> Result<AuthOk, Error> {
+    let user = db::user::find_by_id(&mut *conn, &user_id)
+        .await?
+        .filter(|user| user.disabled_at.is_none())
+        .ok_or(Error::Forbidden(String::from("The account is disabled")))?;
+    if shared.settings.email_verification
+        && user.email.is_some()
+        && user.email_verified_at.is_none()
+    {
+        return Err(Error::Forbidden(String::from(
+            "The email address has not been verified yet",
+        )));
+    }
+    let expires_at = Utc::now() + shared.settings.refresh_token_ttl;
+    let device = db::session::Device {
+        name: device_name.filter(|name| !name.trim().is_empty()),
+        user_agent: origin.user_agent,
+        ip: origin.ip,
+    };
+    let session_id = db::session::create(&mut *conn, &user_id, &device, expires_at).await?;
+    grant(conn, shared, user_id, session_id, expires_at).await
+}
+
+fn invalid() -> Error {
+    Error::Unauthorized(String::from("Invalid refresh token"))
+}
+
+// POST /auth/refresh
+pub async fn refresh(
+    State(shared): State<Shared>,
+    origin: Origin,
+    headers: HeaderMap,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Response, Error> {
+    let cookie = browser::cookie(&headers, browser::REFRESH_COOKIE);
+    let presented = req
+        .refresh_token
+        .as_deref()
+        .or(cookie)
+        .ok_or_else(invalid)?;
+    let from_cookie = req.refresh_token.is_none();
+    let mut tx = shared.pool.begin().await?;
+    let token = db::token::lock_by_hash(&mut *tx, &token::hash(presented))
+        .await?
+        .ok_or_else(invalid)?;
+    if from_cookie {
+        browser::check_csrf(&mut *tx, &headers, &token.session_id).await?;
+    }
+    if token.revoked_at.is_some() || token.expired() {
+        return Err(invalid());
+    }
+    if token.used_at.is_some() {
+        tracing::warn!(
+            "A refresh token of {} was reused, ending session {}",
+            token.user_id,
+            token.session_id
+        );
+        db::session::revoke(&mut *tx, &token.session_id, &token.user_id).await?;
+        tx.commit().await?;
+        return Err(invalid());
+    }
+    db::token::mark_used(&mut *tx, &token.id).await?;
+    let expires_at = Utc::now() + shared.settings.refresh_token_ttl;
+    db::session::extend(
+        &mut *tx,
+        &token.session_id,
+        origin.ip.as_deref(),
+        expires_at,
+    )
+    .await?;
+    let ok = grant(
+        &mut tx,
+        &shared,
+        token.user_id,
+        token.session_id,
+        expires_at,
+    )
+    .await?;
+    let response = if from_cookie {
+        browser::respond(&mut tx, &shared, ok).await?
+    } else {
+        Json(ok).into_response()
+    };
+    tx.commit().await?;
+    Ok(response)
+}
+
+// POST /auth/logout
+pub async fn logout(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    claims: jwt::Claims,
+) -> Result<Response, Error> {
+    let mut tx = shared.pool.begin().await?;
+    if let Some(session_id) = &claims.sid {
+        db::session::revoke(&mut *tx, session_id, &claims.sub).await?;
+    }
+    db::token::revoke_access(&mut *tx, &claims.jti, claims.expires_at()).await?;
+    tx.commit().await?;
+    Ok(browser::forget(&shared.settings, &headers))
+}
+
+// POST /auth/logout/all
+pub async fn logout_everywhere(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    claims: jwt::Claims,
+) -> Result<Response, Error> {
+    let mut tx = shared.pool.begin().await?;
+    db::session::revoke_all(&mut *tx, &claims.sub, None).await?;
+    db::token::revoke_access(&mut *tx, &claims.jti, claims.expires_at()).await?;
+    tx.commit().await?;
+    tracing::info!("Logged {} out of every session", claims.sub);
+    Ok(browser::forget(&shared.settings, &headers))
+}
+
+// GET /auth/sessions
+pub async fn list_sessions(
+    State(shared): State<Shared>,
+    claims: jwt::Claims,
+) -> Result<Json<Vec<SessionInfo>>, Error> {
+    let sessions = db::session::active(&shared.pool, &claims.sub).await?;
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: claims.sid == Some(session.id),
+                session,
+            })
+            .collect(),
+    ))
+}
+
+// DELETE /auth/sessions/{session_id}
+pub async fn end_session(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(session_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    if db::session::revoke(&shared.pool, &session_id, &user.id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::NotFound(String::from("No such session")))
+    }
+}
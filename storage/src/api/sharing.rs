@@ -0,0 +1,177 @@
+//! Access control: who may do what with a file, and sharing files with other users.
+//!
+//! The owner of a file holds the owner role on it. Anyone else holds the strongest role
+//! granted to them on the file or on any folder above it, if any.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::{Error, Shared};
+use crate::db::Role;
+use crate::{auth, db};
+
+/// The role the user holds on `file`, if any.
+pub(crate) async fn role(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    file: &db::File,
+) -> Result<Option<Role>, Error> {
+    if file.owned_by == user.id {
+        return Ok(Some(Role::Owner));
+    }
+    Ok(db::permission::role_of(&mut *conn, &file.id, &user.id).await?)
+}
+
+/// Fetches a file or folder on which the user holds at least `needed`.
+/// Every route touching existing files goes through here.
+pub(crate) async fn authorize(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    file_id: &Uuid,
+    needed: Role,
+) -> Result<db::File, Error> {
+    let file = db::file::find_by_id(&mut *conn, file_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    match role(conn, user, &file).await? {
+        Some(role) if role >= needed => Ok(file),
+        Some(_) => Err(Error::Forbidden(format!(
+            "You need to be {} of that file",
+            match needed {
+                Role::Viewer => "a viewer",
+                Role::Commenter => "a commenter",
+                Role::Editor => "an editor",
+                Role::Owner => "an owner",
+            }
+        ))),
+        None => Err(Error::Forbidden(String::from(
+            "You do not have access to that file",
+        ))),
+    }
+}
+
+/// Like [`authorize`], rejecting anything but folders.
+pub(crate) async fn authorize_folder(
+    conn: &mut PgConnection,
+    user: &auth::User,
+    folder_id: &Uuid,
+    needed: Role,
+) -> Result<db::File, Error> {
+    let folder = authorize(conn, user, folder_id, needed).await?;
+    if folder.path.is_some() {
+        return Err(Error::BadRequest(String::from("That file is not a folder")));
+    }
+    Ok(folder)
+}
+
+// GET /shared
+pub async fn shared_with_me(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<Vec<db::permission::Shared>>, Error> {
+    let files = db::permission::shared_with(&shared.pool, &user.id).await?;
+    Ok(Json(files))
+}
+
+// GET /files/{file_id}/permissions
+pub async fn list_permissions(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+) -> Result<Json<Vec<db::Permission>>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let permissions = db::permission::list(&mut *tx, &file.id).await?;
+    tx.commit().await?;
+    Ok(Json(permissions))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Grant {
+    pub login: String,
+    pub role: Role,
+}
+
+// POST /files/{file_id}/permissions
+pub async fn grant_permission(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+    Json(Grant { login, role }): Json<Grant>,
+) -> Result<(StatusCode, Json<db::Permission>), Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Owner).await?;
+    let grantee = db::user::find_by_login(&mut *tx, &login)
+        .await?
+        .ok_or(Error::NotFound(String::from("No user with such login")))?;
+    if grantee.id == file.owned_by {
+        return Err(Error::BadRequest(String::from(
+            "The owner already has full access",
+        )));
+    }
+    db::permission::grant(&mut *tx, &file.id, &grantee.id, role, &user.id).await?;
+    let permission = db::permission::find(&mut *tx, &file.id, &grantee.id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No permission for that user")))?;
+    tx.commit().await?;
+    tracing::info!("Shared {} with {} as {:?}", file.id, grantee.id, role);
+    Ok((StatusCode::CREATED, Json(permission)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleChange {
+    pub role: Role,
+}
+
+// PATCH /files/{file_id}/permissions/{user_id}
+pub async fn change_permission(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path((file_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(RoleChange { role }): Json<RoleChange>,
+) -> Result<Json<db::Permission>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Owner).await?;
+    if !db::permission::change(&mut *tx, &file.id, &user_id, role, &user.id).await? {
+        return Err(Error::NotFound(String::from("No permission for that user")));
+    }
+    let permission = db::permission::find(&mut *tx, &file.id, &user_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No permission for that user")))?;
+    tx.commit().await?;
+    tracing::info!(
+        "Changed the role of {} on {} to {:?}",
+        user_id,
+        file.id,
+        role
+    );
+    Ok(Json(permission))
+}
+
+// DELETE /files/{file_id}/permissions/{user_id}
+pub async fn revoke_permission(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path((file_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, Error> {
+    let mut tx = shared.pool.begin().await?;
+    // Anyone may give up access shared with them; only owners may take it from others.
+    let needed = if user_id == user.id {
+        Role::Viewer
+    } else {
+        Role::Owner
+    };
+    let file = authorize(&mut tx, &user, &file_id, needed).await?;
+    if !db::permission::revoke(&mut *tx, &file.id, &user_id).await? {
+        return Err(Error::NotFound(String::from("No permission for that user")));
+    }
+    tx.commit().await?;
+    tracing::info!("Revoked the access of {} to {}", user_id, file.id);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,105 @@
+//! Personal access tokens, for scripts that should not hold a password.
+//!
+//! A token is only accepted by routes requiring one of the scopes it was given,
+//! and never by the routes managing sessions and tokens themselves.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::{Error, Shared};
+use crate::auth::{self, Scope};
+use crate::db;
+
+/// Tells personal access tokens apart from JWTs.
+pub const PREFIX: &str = "pat_";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedToken {
+    /// Only ever shown here.
+    pub token: String,
+    #[serde(flatten)]
+    pub info: db::PersonalToken,
+}
+
+// POST /auth/tokens
+pub async fn create_token(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Json(req): Json<CreateToken>,
+) -> Result<(StatusCode, Json<CreatedToken>), Error> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(Error::BadRequest(String::from("A token needs a name")));
+    }
+    if req.scopes.is_empty() {
+        return Err(Error::BadRequest(String::from(
+            "A token needs at least one scope",
+        )));
+    }
+    if req.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(Error::BadRequest(String::from(
+            "The expiry must be in the future",
+        )));
+    }
+    if req.scopes.contains(&Scope::Admin)
+        && db::user::find_by_id(&shared.pool, &user.id)
+            .await?
+            .is_none_or(|user| user.role != db::user::Role::Admin)
+    {
+        return Err(Error::Forbidden(String::from(
+            "Only administrators may give tokens the \"admin\" scope",
+        )));
+    }
+    let mut scopes: Vec<String> = req.scopes.iter().map(|s| s.as_str().to_string()).collect();
+    scopes.sort();
+    scopes.dedup();
+    let token = format!("{}{}", PREFIX, auth::token::generate());
+    let info = db::personal_token::create(
+        &shared.pool,
+        &user.id,
+        name,
+        &auth::token::hash(&token),
+        &scopes,
+        req.expires_at,
+    )
+    .await?;
+    tracing::info!("{} made token {}", user.id, info.id);
+    Ok((StatusCode::CREATED, Json(CreatedToken { token, info })))
+}
+
+// GET /auth/tokens
+pub async fn list_tokens(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<Vec<db::PersonalToken>>, Error> {
+    Ok(Json(
+        db::personal_token::list(&shared.pool, &user.id).await?,
+    ))
+}
+
+// DELETE /auth/tokens/{token_id}
+pub async fn revoke_token(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(token_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    if db::personal_token::delete(&shared.pool, &token_id, &user.id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::NotFound(String::from("No such token")))
+    }
+}
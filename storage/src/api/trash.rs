@@ -0,0 +1,134 @@
+//! Soft deletion, the trash listing, restoring and permanent removal.
+
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::api::files::{ConflictPolicy, settle_name};
+use crate::api::sharing::authorize;
+use crate::api::{Error, Shared};
+use crate::db::Role;
+use crate::{auth, blob, db};
+
+// DELETE /files/{file_id}
+pub async fn delete_file(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Editor).await?;
+    db::file::lock_tree(&mut *tx, &file.owned_by).await?;
+    db::file::delete(&mut *tx, &file.id, &user.id).await?;
+    tx.commit().await?;
+    tracing::info!("Moved {} to the trash", file.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /trash
+pub async fn list_trash(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<Vec<db::file::Trashed>>, Error> {
+    let files = db::file::trash(&shared.pool, &user.id).await?;
+    Ok(Json(files))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreQuery {
+    #[serde(default = "rename")]
+    pub on_conflict: ConflictPolicy,
+}
+
+fn rename() -> ConflictPolicy {
+    ConflictPolicy::Rename
+}
+
+// POST /trash/{file_id}/restore
+pub async fn restore_file(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+    Query(RestoreQuery { on_conflict }): Query<RestoreQuery>,
+) -> Result<Json<db::File>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    db::file::lock_tree(&mut *tx, &user.id).await?;
+    let trashed = db::file::find_trashed(&mut *tx, &file_id)
+        .await?
+        .ok_or(Error::NotFound(String::from(
+            "No trashed file with such UUID",
+        )))?;
+    if trashed.owned_by != user.id {
+        return Err(Error::Forbidden(String::from(
+            "You do not have access to that file",
+        )));
+    }
+    // The original folder may be gone by now, in which case the file lands in the root folder.
+    let parent_id = match &trashed.parent_id {
+        Some(parent_id) => db::file::find_by_id(&mut *tx, parent_id)
+            .await?
+            .filter(|parent| parent.owned_by == user.id)
+            .map(|parent| parent.id),
+        None => None,
+    };
+    let name = settle_name(
+        &mut tx,
+        &user,
+        &user.id,
+        parent_id.as_ref(),
+        &trashed.name,
+        &trashed.id,
+        on_conflict,
+    )
+    .await?;
+    db::file::reparent(&mut *tx, &trashed.id, parent_id.as_ref()).await?;
+    db::file::rename(&mut *tx, &trashed.id, &name).await?;
+    db::file::restore(&mut *tx, &trashed.id).await?;
+    let file = db::file::find_by_id(&mut *tx, &trashed.id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    tracing::info!("Restored {} from the trash", file.id);
+    Ok(Json(file))
+}
+
+// DELETE /trash
+pub async fn empty_trash(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<StatusCode, Error> {
+    let trashed = db::file::trash(&shared.pool, &user.id).await?;
+    for file in &trashed {
+        purge(&shared, &file.id).await?;
+    }
+    if !trashed.is_empty() {
+        blob::collect_garbage(&shared).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Permanently removes a trashed file, its rows and the blobs they pointed at.
+/// Does nothing if the file was restored in the meantime.
+///
+/// Blobs of its versions are only collected by the next `blob::collect_garbage`,
+/// which callers run once after purging however many files.
+pub async fn purge(shared: &Shared, file_id: &Uuid) -> Result<(), Error> {
+    let mut tx = shared.pool.begin().await?;
+    let Some(trashed) = db::file::find_trashed(&mut *tx, file_id).await? else {
+        return Ok(());
+    };
+    db::file::lock_tree(&mut *tx, &trashed.owned_by).await?;
+    if db::file::find_trashed(&mut *tx, file_id).await?.is_none() {
+        return Ok(());
+    }
+    let paths = db::file::purge(&mut *tx, file_id).await?;
+    tx.commit().await?;
+    for path in &paths {
+        shared.blobs.delete(path).await?;
+    }
+    tracing::info!("Purged {} and {} blobs", file_id, paths.len());
+    Ok(())
+}
@@ -0,0 +1,459 @@
+//! Resumable uploads following the core tus 1.0.0 protocol
+//! with the `creation`, `expiration` and `termination` extensions.
+//!
+//! An upload is created with `POST /uploads`, filled with `PATCH /uploads/{upload_id}`
+//! at the offset reported by `HEAD /uploads/{upload_id}`, and turned into a regular
+//! file once the last byte arrives.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use chrono::Utc;
+use http_body_util::BodyExt;
+use sqlx::PgConnection;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::api::range::http_date;
+use crate::api::sharing::authorize_folder;
+use crate::api::{Error, Shared, sanitize_destination, upload_target, versions};
+use crate::db::Role;
+use crate::{auth, db};
+
+pub const VERSION: &str = "1.0.0";
+pub const EXTENSIONS: &str = "creation,expiration,termination";
+
+pub(crate) const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+pub(crate) const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+pub(crate) const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+pub(crate) const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+pub(crate) const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+pub(crate) const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+pub(crate) const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+/// Rejects requests speaking another protocol version and stamps every response
+/// with `Tus-Resumable`, as the protocol requires.
+pub async fn protocol(request: Request, next: Next) -> Response {
+    if request.method() != Method::OPTIONS
+        && request
+            .headers()
+            .get(TUS_RESUMABLE)
+            .map(HeaderValue::as_bytes)
+            != Some(VERSION.as_bytes())
+    {
+        tracing::warn!("Unsupported or missing Tus-Resumable header");
+        return (StatusCode::PRECONDITION_FAILED, [(TUS_VERSION, VERSION)]).into_response();
+    }
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(VERSION));
+    response
+}
+
+/// Location of the partial data, relative to `Shared::root`.
+pub fn temp_path(upload: &db::Upload) -> PathBuf {
+    PathBuf::new()
+        .join("temp")
+        .join(upload.owned_by.to_string())
+        .join(upload.id.to_string())
+}
+
+fn parse_offset(headers: &HeaderMap, name: &HeaderName) -> Result<i64, Error> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+        .ok_or(Error::BadRequest(format!(
+            "Missing or invalid {} header",
+            name
+        )))
+}
+
+/// Parses `Upload-Metadata`: comma separated pairs of a key and a base64 encoded value.
+fn parse_metadata(value: &str) -> Result<HashMap<String, String>, Error> {
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(value.trim())
+                    .map_err(|_| {
+                        Error::BadRequest(format!("Metadata value for \"{}\" is not base64", key))
+                    })?;
+                let value = String::from_utf8(bytes).map_err(|_| {
+                    Error::BadRequest(format!("Metadata value for \"{}\" is not UTF-8", key))
+                })?;
+                (key, value)
+            }
+            None => (pair, String::new()),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+fn locked(e: sqlx::Error) -> Error {
+    if let sqlx::Error::Database(db_err) = &e
+        && db_err.code().as_deref() == Some("55P03")
+    {
+        Error::Locked(String::from(
+            "The upload is being written to by another request",
+        ))
+    } else {
+        Error::Database(e)
+    }
+}
+
+/// How long a writer's claim on an upload lasts without being renewed.
+const LEASE: Duration = Duration::from_secs(30);
+
+/// Exclusive right to append to an upload, leased in its row so that it holds
+/// across every instance sharing the database.
+///
+/// Held instead of a row lock while the body streams in, so a slow client
+/// does not keep a pooled connection busy. The lease is renewed as chunks arrive;
+/// a client stalling past it, or a request dying without giving it up,
+/// loses the upload to the next request.
+struct Writer {
+    id: Uuid,
+    upload_id: Uuid,
+    renewed: Instant,
+}
+
+impl Writer {
+    async fn claim(shared: &Shared, upload_id: &Uuid) -> Result<(Writer, db::Upload), Error> {
+        let id = Uuid::new_v4();
+        let upload = db::upload::claim(&shared.pool, upload_id, &id, LEASE.as_secs_f64())
+            .await?
+            .ok_or(Error::Locked(String::from(
+                "The upload is being written to by another request",
+            )))?;
+        let writer = Writer {
+            id,
+            upload_id: *upload_id,
+            renewed: Instant::now(),
+        };
+        Ok((writer, upload))
+    }
+
+    /// Renews the lease once half of it has run out, so it never lapses between two chunks
+    /// arriving in time, and fails if another request took the upload over meanwhile.
+    async fn renew(&mut self, shared: &Shared) -> Result<(), Error> {
+        if self.renewed.elapsed() < LEASE / 2 {
+            return Ok(());
+        }
+        if !db::upload::renew(&shared.pool, &self.upload_id, &self.id, LEASE.as_secs_f64()).await? {
+            return Err(Error::Locked(String::from(
+                "The upload was taken over by another request",
+            )));
+        }
+        self.renewed = Instant::now();
+        Ok(())
+    }
+}
+
+/// Turns a fully received upload into a regular file, or a new version of one.
+async fn complete(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    upload: &db::Upload,
+) -> Result<Uuid, Error> {
+    // Access to the destination may have been revoked while the upload was under way.
+    if let Some(parent_id) = &upload.parent_id {
+        let uploader = auth::User {
+            id: upload.owned_by,
+        };
+        authorize_folder(&mut *conn, &uploader, parent_id, Role::Editor).await?;
+    }
+    let file_id = versions::store(
+        shared,
+        &mut *conn,
+        &upload.owned_by,
+        upload.parent_id.as_ref(),
+        &upload.name,
+        &temp_path(upload),
+        None,
+    )
+    .await?;
+    db::upload::delete(&mut *conn, &upload.id).await?;
+    tracing::info!("Completed upload {} as file {}", upload.id, file_id);
+    Ok(file_id)
+}
+
+// OPTIONS /uploads
+pub async fn upload_options() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_RESUMABLE, VERSION),
+            (TUS_VERSION, VERSION),
+            (TUS_EXTENSION, EXTENSIONS),
+        ],
+    )
+}
+
+// POST /uploads
+pub async fn create_upload(
+    State(shared): State<Shared>,
+    user: auth::User,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let length = parse_offset(&headers, &UPLOAD_LENGTH)?;
+    let metadata = match headers.get(UPLOAD_METADATA) {
+        Some(value) => parse_metadata(
+            value
+                .to_str()
+                .map_err(|_| Error::BadRequest(String::from("Invalid Upload-Metadata header")))?,
+        )?,
+        None => HashMap::new(),
+    };
+    let name = sanitize_filename::sanitize(metadata.get("filename").ok_or(Error::BadRequest(
+        String::from("Upload-Metadata missing \"filename\" key."),
+    ))?);
+    if name.is_empty() {
+        return Err(Error::BadRequest(String::from(
+            "File name contained no characters once sanitized",
+        )));
+    }
+    let destination = sanitize_destination(
+        metadata
+            .get("destination")
+            .map(String::as_str)
+            .unwrap_or("/"),
+    );
+    let expires_at = Utc::now() + shared.settings.upload_ttl;
+    let parent_id =
+        match metadata.get("parentId") {
+            Some(parent_id) => Some(parent_id.trim().parse().map_err(|_| {
+                Error::BadRequest(String::from("\"parentId\" is not a valid UUID"))
+            })?),
+            None => None,
+        };
+    let mut tx = shared.pool.begin().await?;
+    let parent_id = upload_target(&mut tx, &user, parent_id, &destination).await?;
+    let upload_id = db::upload::create(
+        &mut *tx,
+        &name,
+        parent_id.as_ref(),
+        length,
+        &user.id,
+        expires_at,
+    )
+    .await?;
+    let upload = db::upload::find_by_id(&mut *tx, &upload_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No upload with such UUID")))?;
+    let temp = shared.root.join(temp_path(&upload));
+    std::fs::create_dir_all(temp.parent().unwrap())?;
+    tokio::fs::File::create(&temp).await?;
+    tracing::debug!("Temp path: \"{}\"", &temp.to_string_lossy());
+    if length == 0 {
+        complete(&shared, &mut tx, &upload).await?;
+    }
+    tx.commit().await?;
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("/uploads/{}", upload_id)),
+            (UPLOAD_EXPIRES, http_date(expires_at)),
+        ],
+    )
+        .into_response())
+}
+
+// HEAD /uploads/{upload_id}
+pub async fn upload_offset(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(upload_id): Path<Uuid>,
+) -> Result<Response, Error> {
+    let upload = db::upload::find_by_id(&shared.pool, &upload_id)
+        .await?
+        .filter(|u| u.expires_at > Utc::now())
+        .ok_or(Error::NotFound(String::from("No upload with such UUID")))?;
+    if upload.owned_by != user.id {
+        return Err(Error::Forbidden(String::from(
+            "You do not have access to that upload",
+        )));
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, upload.received.to_string()),
+            (UPLOAD_LENGTH, upload.length.to_string()),
+            (UPLOAD_EXPIRES, http_date(upload.expires_at)),
+            (header::CACHE_CONTROL, String::from("no-store")),
+        ],
+    )
+        .into_response())
+}
+
+// PATCH /uploads/{upload_id}
+pub async fn append_upload(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(upload_id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    if headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes)
+        != Some(OFFSET_OCTET_STREAM.as_bytes())
+    {
+        return Err(Error::UnsupportedMediaType(format!(
+            "Content-Type must be {}",
+            OFFSET_OCTET_STREAM
+        )));
+    }
+    let offset = parse_offset(&headers, &UPLOAD_OFFSET)?;
+    let upload = db::upload::find_by_id(&shared.pool, &upload_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No upload with such UUID")))?;
+    if upload.owned_by != user.id {
+        return Err(Error::Forbidden(String::from(
+            "You do not have access to that upload",
+        )));
+    }
+    let (mut writer, upload) = Writer::claim(&shared, &upload_id).await?;
+    let result = append(&shared, &mut writer, &upload, offset, body).await;
+    // A successful request gave the lease up along with the new offset.
+    if result.is_err()
+        && let Err(e) = db::upload::release(&shared.pool, &upload.id, &writer.id).await
+    {
+        tracing::warn!("Could not release upload {}: {}", upload.id, e);
+    }
+    result
+}
+
+/// Appends the body to an upload leased by `writer`, and completes it with the last byte.
+async fn append(
+    shared: &Shared,
+    writer: &mut Writer,
+    upload: &db::Upload,
+    offset: i64,
+    body: Body,
+) -> Result<Response, Error> {
+    if upload.expires_at <= Utc::now() {
+        return Err(Error::NotFound(String::from("No upload with such UUID")));
+    }
+    if offset != upload.received {
+        return Err(Error::Conflict(format!(
+            "Upload-Offset {} does not match the current offset {}",
+            offset, upload.received
+        )));
+    }
+    // The body arrives at the client's pace, so no connection is held while it does.
+    let path = shared.root.join(temp_path(upload));
+    let mut temp = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await?;
+    // Drop whatever an interrupted request left past the recorded offset.
+    temp.set_len(upload.received as u64).await?;
+    temp.seek(std::io::SeekFrom::Start(upload.received as u64))
+        .await?;
+    tracing::trace!("Opened the temp file at offset {}", upload.received);
+    let mut received = upload.received;
+    let mut body = body;
+    let mut interrupted = None;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::warn!("Upload body interrupted: {}", e);
+                interrupted = Some(e);
+                break;
+            }
+        };
+        if let Ok(chunk) = frame.into_data() {
+            if received + chunk.len() as i64 > upload.length {
+                return Err(Error::BadRequest(String::from(
+                    "Request body exceeds Upload-Length",
+                )));
+            }
+            writer.renew(shared).await?;
+            temp.write_all(&chunk).await?;
+            received += chunk.len() as i64;
+        }
+    }
+    temp.sync_all().await?;
+    tracing::trace!("Processed all chunks, offset is now {}", received);
+    let mut tx = shared.pool.begin().await?;
+    // The upload may have been terminated meanwhile.
+    db::upload::lock(&mut *tx, &upload.id)
+        .await
+        .map_err(locked)?
+        .ok_or(Error::NotFound(String::from("No upload with such UUID")))?;
+    if !db::upload::advance(&mut *tx, &upload.id, &writer.id, received).await? {
+        return Err(Error::Locked(String::from(
+            "The upload was taken over by another request",
+        )));
+    }
+    if received == upload.length {
+        complete(shared, &mut tx, upload).await?;
+    }
+    tx.commit().await?;
+    if let Some(e) = interrupted {
+        return Err(Error::BadRequest(format!(
+            "Request body interrupted at offset {}: {}",
+            received, e
+        )));
+    }
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET, received.to_string())],
+    )
+        .into_response())
+}
+
+// DELETE /uploads/{upload_id}
+pub async fn terminate_upload(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(upload_id): Path<Uuid>,
+) -> Result<StatusCode, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let upload = db::upload::lock(&mut *tx, &upload_id)
+        .await
+        .map_err(locked)?
+        .ok_or(Error::NotFound(String::from("No upload with such UUID")))?;
+    if upload.owned_by != user.id {
+        return Err(Error::Forbidden(String::from(
+            "You do not have access to that upload",
+        )));
+    }
+    db::upload::delete(&mut *tx, &upload.id).await?;
+    remove_temp(&shared, &upload).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_temp(shared: &Shared, upload: &db::Upload) -> Result<(), Error> {
+    match tokio::fs::remove_file(shared.root.join(temp_path(upload))).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn metadata_parsing() {
+        let metadata =
+            super::parse_metadata("filename dGVzdC50eHQ=,destination L2RvY3M=, is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "test.txt");
+        assert_eq!(metadata["destination"], "/docs");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(super::parse_metadata("filename !!!").is_err());
+    }
+}
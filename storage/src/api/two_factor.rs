@@ -0,0 +1,285 @@
+//! Two-factor authentication with the codes of an authenticator app.
+//!
+//! Users enroll by adding a secret to their app and sending back its first code. From then on,
+//! a login that got the password right only gets a short-lived token, traded at `/auth/login/2fa`
+//! along with a code, or one of the recovery codes handed out when enrolling, for the real tokens.
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::{Error, Shared, browser, sessions};
+use crate::auth::{self, jwt, totp};
+use crate::db;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MfaPending {
+    /// Traded at `/auth/login/2fa` along with a code.
+    pub mfa_token: String,
+    /// Seconds until the token expires.
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecondFactor {
+    pub mfa_token: String,
+    /// A code of the authenticator, or a recovery code.
+    pub code: String,
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Whether to keep the tokens in cookies rather than hand them over, for browsers.
+    #[serde(default)]
+    pub cookies: bool,
+}
+
+#[derive(Deserialize)]
+pub struct Code {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    /// The secret, for typing into an authenticator app.
+    pub secret: String,
+    /// The secret as an `otpauth://` URI, for showing as a QR code.
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodes {
+    /// Only ever shown here.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Asks for a second factor if the user enabled one.
+pub(crate) async fn challenge(shared: &Shared, user_id: Uuid) -> Result<Option<MfaPending>, Error> {
+    let enabled = db::two_factor::find(&shared.pool, &user_id)
+        .await?
+        .is_some_and(|factor| factor.enabled());
+    if !enabled {
+        return Ok(None);
+    }
+    let ttl = shared.settings.mfa_token_ttl;
+    Ok(Some(MfaPending {
+        mfa_token: jwt::issue_pending(user_id, &shared.keys, ttl)?,
+        expires_in: ttl.num_seconds(),
+    }))
+}
+
+/// Checks a code of the user's authenticator, using it up.
+async fn check_code(
+    conn: &mut PgConnection,
+    factor: &db::TwoFactor,
+    code: &str,
+) -> Result<bool, Error> {
+    match totp::step(&factor.secret, code, totp::now()) {
+        Some(step) => Ok(db::two_factor::use_step(conn, &factor.user_id, step).await?),
+        None => Ok(false),
+    }
+}
+
+/// Makes new recovery codes for a user, replacing any left.
+async fn renew_recovery_codes(
+    conn: &mut PgConnection,
+    user_id: &Uuid,
+) -> Result<RecoveryCodes, Error> {
+    let recovery_codes = totp::recovery_codes();
+    let hashes: Vec<Vec<u8>> = recovery_codes
+        .iter()
+        .map(|code| auth::token::hash(&totp::normalize(code)))
+        .collect();
+    db::two_factor::set_recovery_codes(conn, user_id, &hashes).await?;
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Finds the enabled factor of a user and checks a fresh code of it.
+/// Wrong codes count as failed logins, so that a stolen session cannot guess its way through.
+async fn require_code(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    user_id: &Uuid,
+    origin: &sessions::Origin,
+    code: &str,
+) -> Result<db::TwoFactor, Error> {
+    let user = db::user::find_by_id(&mut *conn, user_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No such user")))?;
+    let factor = auth::throttle::guard(
+        &shared.pool,
+        &shared.settings,
+        &user.login,
+        origin.ip.as_deref(),
+        async {
+            let factor = db::two_factor::find(&mut *conn, user_id)
+                .await?
+                .filter(|factor| factor.enabled())
+                .ok_or(Error::NotFound(String::from(
+                    "Two-factor authentication is not enabled",
+                )))?;
+            if !check_code(&mut *conn, &factor, code).await? {
+                return Err(Error::Unauthorized(String::from("Invalid code")));
+            }
+            Ok(factor)
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        Error::Unauthorized(message) => Error::Forbidden(message),
+        e => e,
+    })?;
+    auth::throttle::succeeded(&shared.pool, &user.login).await?;
+    Ok(factor)
+}
+
+// POST /auth/login/2fa
+pub async fn login_second_factor(
+    State(shared): State<Shared>,
+    origin: sessions::Origin,
+    Json(req): Json<SecondFactor>,
+) -> Result<Response, Error> {
+    let invalid = || Error::Unauthorized(String::from("Invalid code"));
+    let claims = jwt::validate_pending(&req.mfa_token, &shared.keys)
+        .map_err(|_| Error::Unauthorized(String::from("Invalid MFA token")))?;
+    let user = db::user::find_by_id(&shared.pool, &claims.sub)
+        .await?
+        .ok_or_else(invalid)?;
+    let ip = origin.ip.clone();
+    let response = auth::throttle::guard(
+        &shared.pool,
+        &shared.settings,
+        &user.login,
+        ip.as_deref(),
+        async {
+            let mut tx = shared.pool.begin().await?;
+            let factor = db::two_factor::find(&mut *tx, &user.id)
+                .await?
+                .filter(|factor| factor.enabled())
+                .ok_or_else(invalid)?;
+            if !check_code(&mut tx, &factor, &req.code).await? {
+                let hash = auth::token::hash(&totp::normalize(&req.code));
+                if !db::two_factor::use_recovery_code(&mut *tx, &user.id, &hash).await? {
+                    return Err(invalid());
+                }
+                tracing::info!("{} logged in with a recovery code", user.id);
+            }
+            let ok = sessions::start(&mut tx, &shared, user.id, req.device_name, origin).await?;
+            let response = if req.cookies {
+                browser::respond(&mut tx, &shared, ok).await?
+            } else {
+                Json(ok).into_response()
+            };
+            tx.commit().await?;
+            Ok(response)
+        },
+    )
+    .await?;
+    auth::throttle::succeeded(&shared.pool, &user.login).await?;
+    tracing::debug!("Logged in {} with a second factor", user.id);
+    Ok(response)
+}
+
+// GET /auth/2fa
+pub async fn status(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<TwoFactorStatus>, Error> {
+    let enabled = db::two_factor::find(&shared.pool, &user.id)
+        .await?
+        .is_some_and(|factor| factor.enabled());
+    let recovery_codes_left = db::two_factor::recovery_codes_left(&shared.pool, &user.id).await?;
+    Ok(Json(TwoFactorStatus {
+        enabled,
+        recovery_codes_left,
+    }))
+}
+
+// POST /auth/2fa
+pub async fn enroll(
+    State(shared): State<Shared>,
+    user: auth::User,
+) -> Result<Json<Enrollment>, Error> {
+    let account = db::user::find_by_id(&shared.pool, &user.id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No such user")))?;
+    let secret = totp::generate_secret();
+    if !db::two_factor::enroll(&shared.pool, &user.id, &secret).await? {
+        return Err(Error::Conflict(String::from(
+            "Two-factor authentication is already enabled",
+        )));
+    }
+    Ok(Json(Enrollment {
+        secret: totp::encode(&secret),
+        otpauth_uri: totp::uri(&secret, &shared.settings.totp_issuer, &account.login),
+    }))
+}
+
+// POST /auth/2fa/activate
+pub async fn activate(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Json(req): Json<Code>,
+) -> Result<Json<RecoveryCodes>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let factor = db::two_factor::find(&mut *tx, &user.id)
+        .await?
+        .ok_or(Error::NotFound(String::from(
+            "Enroll an authenticator first",
+        )))?;
+    if factor.enabled() {
+        return Err(Error::Conflict(String::from(
+            "Two-factor authentication is already enabled",
+        )));
+    }
+    if !check_code(&mut tx, &factor, &req.code).await? {
+        return Err(Error::Forbidden(String::from("Invalid code")));
+    }
+    db::two_factor::enable(&mut *tx, &user.id).await?;
+    let codes = renew_recovery_codes(&mut tx, &user.id).await?;
+    tx.commit().await?;
+    tracing::info!("{} enabled two-factor authentication", user.id);
+    Ok(Json(codes))
+}
+
+// POST /auth/2fa/recovery-codes
+pub async fn regenerate_recovery_codes(
+    State(shared): State<Shared>,
+    user: auth::User,
+    origin: sessions::Origin,
+    Json(req): Json<Code>,
+) -> Result<Json<RecoveryCodes>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    require_code(&shared, &mut tx, &user.id, &origin, &req.code).await?;
+    let codes = renew_recovery_codes(&mut tx, &user.id).await?;
+    tx.commit().await?;
+    Ok(Json(codes))
+}
+
+// DELETE /auth/2fa
+pub async fn disable(
+    State(shared): State<Shared>,
+    user: auth::User,
+    origin: sessions::Origin,
+    Json(req): Json<Code>,
+) -> Result<StatusCode, Error> {
+    let mut tx = shared.pool.begin().await?;
+    require_code(&shared, &mut tx, &user.id, &origin, &req.code).await?;
+    db::two_factor::disable(&mut *tx, &user.id).await?;
+    tx.commit().await?;
+    tracing::info!("{} disabled two-factor authentication", user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
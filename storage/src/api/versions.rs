@@ -0,0 +1,179 @@
+//! Version history: every upload onto an existing file keeps the previous blob around.
+
+use std::path::Path as FsPath;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, Method};
+use axum::response::Response;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::sharing::authorize;
+use crate::api::{Error, Shared, serve_blob};
+use crate::db::Role;
+use crate::{auth, blob, db};
+
+/// Files a finished upload found at `temp` under `name` in `parent_id`, or the user's root folder.
+/// Creates the file, or a new version of it if the folder already has a file with that name.
+/// `digest` can be left out when the contents were not hashed while receiving them.
+pub(crate) async fn store(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    user_id: &Uuid,
+    parent_id: Option<&Uuid>,
+    name: &str,
+    temp: &FsPath,
+    digest: Option<blob::Digest>,
+) -> Result<Uuid, Error> {
+    // Files uploaded into someone else's folder belong to them, like the rest of their tree.
+    let owner_id = match parent_id {
+        Some(parent_id) => {
+            db::file::find_by_id(&mut *conn, parent_id)
+                .await?
+                .ok_or(Error::NotFound(String::from("No folder with such UUID")))?
+                .owned_by
+        }
+        None => *user_id,
+    };
+    let file_id = match db::file::find_child(&mut *conn, &owner_id, parent_id, name).await? {
+        Some(existing) if existing.path.is_none() => {
+            return Err(Error::Conflict(String::from(
+                "A folder with that name already exists in this folder.",
+            )));
+        }
+        Some(existing) => {
+            db::file::edit(&mut *conn, &existing.id, user_id).await?;
+            tracing::info!("Storing a new version of {}", existing.id);
+            existing.id
+        }
+        None => {
+            let file_id = db::file::create(&mut *conn, name, None, &owner_id, parent_id).await?;
+            tracing::info!("Storing a new file {}", file_id);
+            file_id
+        }
+    };
+    let (path, digest) = blob::ingest(shared, conn, temp, digest).await?;
+    let path = path.to_string_lossy();
+    db::file::r#move(&mut *conn, &file_id, &path).await?;
+    db::version::create(
+        &mut *conn,
+        &file_id,
+        &path,
+        digest.size,
+        Some(&digest.hash),
+        user_id,
+    )
+    .await?;
+    tracing::debug!("New path: {}", &path);
+    Ok(file_id)
+}
+
+/// Points `file_id` at the blob at `path` as a new version.
+/// Blobs stored before deduplication, which have no `digest`, are copied into the blob store first.
+pub(crate) async fn reuse(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    file_id: &Uuid,
+    path: &str,
+    digest: Option<blob::Digest>,
+    user_id: &Uuid,
+) -> Result<(), Error> {
+    let (path, digest) = match digest {
+        Some(digest) => (path.to_string(), digest),
+        None => blob::adopt(&*shared.blobs, conn, path).await?,
+    };
+    db::version::create(
+        &mut *conn,
+        file_id,
+        &path,
+        digest.size,
+        Some(&digest.hash),
+        user_id,
+    )
+    .await?;
+    db::file::r#move(&mut *conn, file_id, &path).await?;
+    Ok(())
+}
+
+// GET /files/{file_id}/versions
+pub async fn list_versions(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path(file_id): Path<Uuid>,
+) -> Result<Json<Vec<db::Version>>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let versions = db::version::list(&mut *tx, &file.id).await?;
+    tx.commit().await?;
+    Ok(Json(versions))
+}
+
+// GET /files/{file_id}/versions/{version_id}
+pub async fn download_version(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path((file_id, version_id)): Path<(Uuid, Uuid)>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Viewer).await?;
+    let version = db::version::find_by_id(&mut *tx, &file.id, &version_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No version with such UUID")))?;
+    tx.commit().await?;
+    serve_blob(
+        &*shared.blobs,
+        &version.path,
+        version.created_at,
+        &method,
+        &headers,
+    )
+    .await
+}
+
+// POST /files/{file_id}/versions/{version_id}/restore
+pub async fn restore_version(
+    State(shared): State<Shared>,
+    user: auth::User,
+    Path((file_id, version_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<db::File>, Error> {
+    let mut tx = shared.pool.begin().await?;
+    let file = authorize(&mut tx, &user, &file_id, Role::Editor).await?;
+    let version = db::version::find_by_id(&mut *tx, &file.id, &version_id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No version with such UUID")))?;
+    // Restoring adds the old contents as the newest version, keeping the history intact.
+    reuse(
+        &shared,
+        &mut tx,
+        &file.id,
+        &version.path,
+        version.digest(),
+        &user.id,
+    )
+    .await?;
+    db::file::edit(&mut *tx, &file.id, &user.id).await?;
+    let restored = db::file::find_by_id(&mut *tx, &file.id)
+        .await?
+        .ok_or(Error::NotFound(String::from("No file with such UUID")))?;
+    tx.commit().await?;
+    tracing::info!("Restored version {} of {}", version.id, file.id);
+    Ok(Json(restored))
+}
+
+/// Applies the version retention policy to every file, removing the blobs no longer needed.
+pub async fn prune(shared: &Shared) -> Result<usize, Error> {
+    let pruned = db::version::prune(
+        &shared.pool,
+        shared.settings.versions_keep_last,
+        shared.settings.versions_keep_daily,
+    )
+    .await?;
+    for version in pruned.iter().filter(|v| v.hash.is_none()) {
+        shared.blobs.delete(&version.path).await?;
+    }
+    blob::collect_garbage(shared).await?;
+    Ok(pruned.len())
+}
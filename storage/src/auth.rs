@@ -1,4 +1,7 @@
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use std::sync::{Arc, Mutex};
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version};
+use async_trait::async_trait;
 use password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
 use sqlx::PgPool;
 use uuid::Uuid;
@@ -6,11 +9,23 @@ use uuid::Uuid;
 use crate::api;
 
 pub mod jwt;
+pub mod ldap;
+pub mod oidc;
+pub mod password;
+pub mod scope;
+pub mod throttle;
+pub mod token;
+pub mod totp;
+
+pub use scope::Scope;
 
-pub fn hash_password(password: &str) -> password_hash::Result<String> {
+fn argon(params: &Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+}
+
+pub fn hash_password(password: &str, params: &Params) -> password_hash::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon = Argon2::default();
-    let hash = argon.hash_password(password.as_bytes(), &salt)?;
+    let hash = argon(params).hash_password(password.as_bytes(), &salt)?;
 
     Ok(hash.to_string())
 }
@@ -22,27 +37,120 @@ pub fn verify_password(password: &str, phc: &str) -> password_hash::Result<bool>
         .is_ok())
 }
 
-pub async fn register_user(pool: &PgPool, login: &str, password: &str) -> Result<Uuid, api::Error> {
-    let phc = hash_password(password)?;
+/// Whether a hash was made otherwise than `hash_password` would make it now.
+pub fn outdated(phc: &str, params: &Params) -> bool {
+    let Ok(hash) = PasswordHash::new(phc) else {
+        return true;
+    };
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&hash).map_or(true, |used| {
+            (used.m_cost(), used.t_cost(), used.p_cost())
+                != (params.m_cost(), params.t_cost(), params.p_cost())
+        })
+}
+
+pub async fn register_user(
+    pool: &PgPool,
+    params: &Params,
+    login: &str,
+    password: &str,
+) -> Result<Uuid, api::Error> {
+    let phc = hash_password(password, params)?;
     let mut tx = pool.begin().await?;
-    let user_id = crate::db::user::create(&mut *tx, login, &phc).await?;
+    let user_id = crate::db::user::create(&mut *tx, login, Some(&phc)).await?;
     crate::db::config::init(&mut *tx, &user_id).await?;
     tx.commit().await?;
     Ok(user_id)
 }
 
-pub async fn login_user(pool: &PgPool, login: &str, password: &str) -> Result<Uuid, api::Error> {
-    let user = crate::db::user::find_by_login(pool, login)
-        .await?
-        .ok_or(api::Error::Unauthorized(String::from("Invalid login")))?;
-    let ok = verify_password(password, &user.phc)?;
-    if ok {
-        Ok(user.id)
-    } else {
-        Err(api::Error::Unauthorized(String::from(
-            "Invalid credentials",
-        )))
+/// Stands in for the hash of users that do not exist, so that logging in as one takes as long as
+/// logging in as anyone else. Kept for the parameters it was made with, which seldom change.
+static DUMMY_PHC: Mutex<Option<(Params, Arc<str>)>> = Mutex::new(None);
+
+fn dummy_phc(params: &Params) -> password_hash::Result<Arc<str>> {
+    let mut dummy = DUMMY_PHC.lock().unwrap();
+    if let Some((made_with, phc)) = &*dummy
+        && made_with == params
+    {
+        return Ok(phc.clone());
+    }
+    let phc: Arc<str> = hash_password("not anyone's password", params)?.into();
+    *dummy = Some((params.clone(), phc.clone()));
+    Ok(phc)
+}
+
+/// Somewhere login names and passwords are checked against.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Finds the user a login name and password belong to, or `None` if they are not right here.
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, api::Error>;
+}
+
+/// The passwords users set themselves, hashed with Argon2 into `users`.
+pub struct Passwords {
+    pub params: Params,
+}
+
+#[async_trait]
+impl Authenticator for Passwords {
+    /// Unknown users are checked against a dummy hash, taking as long as wrong passwords.
+    /// Hashes made with outdated parameters are made anew while the password is at hand.
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, api::Error> {
+        let params = &self.params;
+        let user = crate::db::user::find_by_login(pool, login).await?;
+        let phc = match user.as_ref().and_then(|user| user.phc.as_deref()) {
+            Some(phc) => Arc::from(phc),
+            None => dummy_phc(params)?,
+        };
+        let ok = verify_password(password, &phc)?;
+        match user {
+            Some(user) if ok && user.phc.is_some() => {
+                if outdated(&phc, params) {
+                    let phc = hash_password(password, params)?;
+                    crate::db::user::update_password(pool, &user.id, &phc).await?;
+                    tracing::info!("Rehashed the password of {}", user.id);
+                }
+                Ok(Some(user.id))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Checks a login name and password with the users' own passwords, then with the directory if
+/// there is one, turning away unknown users and wrong passwords alike.
+pub async fn login_user(
+    pool: &PgPool,
+    settings: &api::Settings,
+    login: &str,
+    password: &str,
+) -> Result<Uuid, api::Error> {
+    let passwords = Passwords {
+        params: settings.argon2.clone(),
+    };
+    let mut authenticators: Vec<&dyn Authenticator> = vec![&passwords];
+    if let Some(directory) = &settings.ldap {
+        authenticators.push(directory);
+    }
+    for authenticator in authenticators {
+        if let Some(user_id) = authenticator.authenticate(pool, login, password).await? {
+            return Ok(user_id);
+        }
     }
+    Err(api::Error::Unauthorized(String::from(
+        "Invalid credentials",
+    )))
 }
 
 #[derive(Clone, Debug)]
@@ -55,9 +163,13 @@ mod tests {
     #[test]
     fn password_roundtrip() {
         let password = "avada kedavra";
-        let phc = super::hash_password(password).unwrap();
+        let params = argon2::Params::default();
+        let phc = super::hash_password(password, &params).unwrap();
 
         assert!(super::verify_password(password, &phc).unwrap());
         assert!(!super::verify_password("wrong", &phc).unwrap());
+        assert!(!super::outdated(&phc, &params));
+        let stronger = argon2::Params::new(params.m_cost() * 2, 3, 1, None).unwrap();
+        assert!(super::outdated(&phc, &stronger));
     }
 }
@@ -1,50 +1,302 @@
-use chrono::{Duration, Utc};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode, errors::Result};
+//! Access tokens, signed with asymmetric keys that can be published and rotated.
+//!
+//! Every key has an id, sent as the `kid` header of the tokens it signs, so that tokens signed
+//! with a key being rotated out keep validating until they expire.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use jsonwebtoken::errors::{Error, ErrorKind, Result};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+};
+use password_hash::rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// The algorithms keys can be made for.
+pub const ALGORITHMS: [Algorithm; 2] = [Algorithm::EdDSA, Algorithm::RS256];
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Claims {
     pub sub: Uuid,
+    pub iss: String,
+    pub aud: String,
     pub iat: i64,
     pub exp: i64,
+    /// Identifies the token, so that it can be revoked before it expires.
+    pub jti: Uuid,
+    /// The session the token was issued to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<Uuid>,
+}
+
+impl Claims {
+    pub fn new(keys: &Keys, user_id: Uuid, ttl: Duration) -> Claims {
+        let now = Utc::now();
+        Claims {
+            sub: user_id,
+            iss: keys.issuer.clone(),
+            aud: keys.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + ttl).timestamp(),
+            jti: Uuid::new_v4(),
+            sid: None,
+        }
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.exp, 0).unwrap_or_default()
+    }
+}
+
+struct Key {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
 }
 
-pub fn issue(user_id: Uuid, secret: &[u8], ttl: Duration) -> Result<String> {
-    let now = Utc::now();
-    let claims = Claims {
-        sub: user_id,
-        iat: now.timestamp(),
-        exp: (now + ttl).timestamp(),
+impl Key {
+    /// Reads a PKCS#8 private key.
+    fn from_pem(kid: &str, algorithm: Algorithm, pem: &str) -> Result<Key> {
+        let (encoding, parameters) = match algorithm {
+            Algorithm::EdDSA => {
+                let key = ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+                    .map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+                let parameters = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes()),
+                });
+                (EncodingKey::from_ed_pem(pem.as_bytes())?, parameters)
+            }
+            Algorithm::RS256 => {
+                let encoding = EncodingKey::from_rsa_pem(pem.as_bytes())?;
+                let parameters = Jwk::from_encoding_key(&encoding, algorithm)?.algorithm;
+                (encoding, parameters)
+            }
+            _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+        };
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(match algorithm {
+                    Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+                    _ => KeyAlgorithm::RS256,
+                }),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        };
+        Ok(Key {
+            algorithm,
+            encoding,
+            decoding: DecodingKey::from_jwk(&jwk)?,
+            jwk,
+        })
+    }
+}
+
+/// Makes a new private key, as PKCS#8 PEM.
+pub fn generate(algorithm: Algorithm) -> Result<String> {
+    let invalid = |_| Error::from(ErrorKind::InvalidKeyFormat);
+    let pem = match algorithm {
+        Algorithm::EdDSA => {
+            let mut secret = [0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            ed25519_dalek::SigningKey::from_bytes(&secret)
+                .to_pkcs8_pem(Default::default())
+                .map_err(invalid)?
+        }
+        Algorithm::RS256 => rsa::RsaPrivateKey::new(&mut OsRng, 2048)
+            .map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?
+            .to_pkcs8_pem(Default::default())
+            .map_err(invalid)?,
+        _ => return Err(ErrorKind::InvalidAlgorithm.into()),
     };
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret),
-    )
-}
-
-pub fn validate(token: &str, secret: &[u8]) -> Result<Claims> {
-    let data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret),
-        &Validation::default(),
-    )?;
+    Ok(pem.to_string())
+}
+
+#[derive(Default)]
+struct Ring {
+    keys: HashMap<String, Key>,
+    /// The key new tokens are signed with.
+    signing: Option<String>,
+}
+
+/// The keys tokens are signed and validated with, and who tokens are issued by and for.
+#[derive(Clone)]
+pub struct Keys {
+    issuer: String,
+    audience: String,
+    ring: Arc<RwLock<Ring>>,
+}
+
+impl Keys {
+    pub fn new(issuer: &str, audience: &str) -> Keys {
+        Keys {
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            ring: Default::default(),
+        }
+    }
+
+    /// A single fresh key, kept nowhere, for tests and tools.
+    pub fn ephemeral() -> Keys {
+        let keys = Keys::new("proto-drive", "proto-drive");
+        let pem = generate(Algorithm::EdDSA).unwrap();
+        keys.replace(&[("ephemeral", Algorithm::EdDSA, &pem)], Some("ephemeral"))
+            .unwrap();
+        keys
+    }
+
+    /// Swaps in a new set of keys given as id, algorithm and private key.
+    pub fn replace(&self, keys: &[(&str, Algorithm, &str)], signing: Option<&str>) -> Result<()> {
+        let mut ring = Ring {
+            keys: HashMap::new(),
+            signing: signing.map(String::from),
+        };
+        for (kid, algorithm, pem) in keys {
+            ring.keys
+                .insert(kid.to_string(), Key::from_pem(kid, *algorithm, pem)?);
+        }
+        *self.ring.write().unwrap() = ring;
+        Ok(())
+    }
+
+    /// Who tokens waiting on a second factor are for, so that nothing taking access tokens takes them.
+    fn pending_audience(&self) -> String {
+        format!("{}#mfa", self.audience)
+    }
+
+    /// The public halves of the keys, for others to validate tokens with.
+    pub fn jwks(&self) -> JwkSet {
+        let ring = self.ring.read().unwrap();
+        let mut keys: Vec<Jwk> = ring.keys.values().map(|key| key.jwk.clone()).collect();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+        JwkSet { keys }
+    }
+}
+
+pub fn sign(claims: &Claims, keys: &Keys) -> Result<String> {
+    let ring = keys.ring.read().unwrap();
+    let (kid, key) = ring
+        .signing
+        .as_ref()
+        .and_then(|kid| ring.keys.get(kid).map(|key| (kid, key)))
+        .ok_or(Error::from(ErrorKind::InvalidKeyFormat))?;
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(kid.clone());
+    encode(&header, claims, &key.encoding)
+}
+
+pub fn issue(user_id: Uuid, keys: &Keys, ttl: Duration) -> Result<String> {
+    sign(&Claims::new(keys, user_id, ttl), keys)
+}
+
+pub fn validate(token: &str, keys: &Keys) -> Result<Claims> {
+    decode_for(token, keys, &keys.audience)
+}
+
+/// Issues a token standing for a password that was checked, which is only good for
+/// trading along with a second factor for an access token.
+pub fn issue_pending(user_id: Uuid, keys: &Keys, ttl: Duration) -> Result<String> {
+    let mut claims = Claims::new(keys, user_id, ttl);
+    claims.aud = keys.pending_audience();
+    sign(&claims, keys)
+}
+
+pub fn validate_pending(token: &str, keys: &Keys) -> Result<Claims> {
+    decode_for(token, keys, &keys.pending_audience())
+}
+
+fn decode_for(token: &str, keys: &Keys, audience: &str) -> Result<Claims> {
+    let kid = decode_header(token)?
+        .kid
+        .ok_or(Error::from(ErrorKind::InvalidToken))?;
+    let ring = keys.ring.read().unwrap();
+    let key = ring
+        .keys
+        .get(&kid)
+        .ok_or(Error::from(ErrorKind::InvalidToken))?;
+    let mut validation = Validation::new(key.algorithm);
+    validation.set_issuer(&[&keys.issuer]);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+    let data = decode::<Claims>(token, &key.decoding, &validation)?;
     Ok(data.claims)
 }
 
 #[cfg(test)]
 mod tests {
     use chrono::Duration;
+    use jsonwebtoken::Algorithm;
     use uuid::Uuid;
 
+    use super::Keys;
+
     #[test]
     fn jwt_roundtrip() {
-        let secret = "secret".as_bytes();
+        let keys = Keys::ephemeral();
         let id = Uuid::new_v4();
-        let token = super::issue(id, secret, Duration::minutes(30)).unwrap();
-        let claims = super::validate(&token, secret).unwrap();
+        let token = super::issue(id, &keys, Duration::minutes(30)).unwrap();
+        let claims = super::validate(&token, &keys).unwrap();
         assert_eq!(claims.sub, id);
+        let other = super::issue(id, &keys, Duration::minutes(30)).unwrap();
+        assert_ne!(super::validate(&other, &keys).unwrap().jti, claims.jti);
+
+        let pending = super::issue_pending(id, &keys, Duration::minutes(5)).unwrap();
+        assert!(super::validate(&pending, &keys).is_err());
+        assert_eq!(super::validate_pending(&pending, &keys).unwrap().sub, id);
+        assert!(super::validate_pending(&token, &keys).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let ed = super::generate(Algorithm::EdDSA).unwrap();
+        let rsa = super::generate(Algorithm::RS256).unwrap();
+        let keys = Keys::new("drive", "drive");
+        keys.replace(&[("old", Algorithm::EdDSA, &ed)], Some("old"))
+            .unwrap();
+        let old = super::issue(Uuid::new_v4(), &keys, Duration::minutes(30)).unwrap();
+        keys.replace(
+            &[
+                ("old", Algorithm::EdDSA, &ed),
+                ("new", Algorithm::RS256, &rsa),
+            ],
+            Some("new"),
+        )
+        .unwrap();
+        let new = super::issue(Uuid::new_v4(), &keys, Duration::minutes(30)).unwrap();
+        assert_eq!(
+            jsonwebtoken::decode_header(&new).unwrap().alg,
+            Algorithm::RS256
+        );
+        assert!(super::validate(&old, &keys).is_ok());
+        assert!(super::validate(&new, &keys).is_ok());
+        let jwks = keys.jwks();
+        assert_eq!(jwks.keys.len(), 2);
+        assert!(jwks.find("old").is_some() && jwks.find("new").is_some());
+
+        // Retired keys no longer validate, and nor do tokens meant for someone else.
+        keys.replace(&[("new", Algorithm::RS256, &rsa)], Some("new"))
+            .unwrap();
+        assert!(super::validate(&old, &keys).is_err());
+        let elsewhere = Keys::new("drive", "elsewhere");
+        elsewhere
+            .replace(&[("new", Algorithm::RS256, &rsa)], Some("new"))
+            .unwrap();
+        assert!(super::validate(&new, &elsewhere).is_err());
     }
 }
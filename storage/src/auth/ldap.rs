@@ -0,0 +1,255 @@
+//! Logging in with the password of an account in an LDAP directory.
+//!
+//! Accounts are looked up with a service account and then bound to with the password given. Users
+//! are made for accounts the first time they log in and take their role from the groups of their
+//! account, and [`Directory::sync`] disables the users of accounts the directory no longer finds.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, ldap_escape};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api::{Error, var_or};
+use crate::auth::Authenticator;
+use crate::db;
+use crate::db::user::Role;
+
+/// What accounts of the directory are linked to users as in `identities`.
+pub const ISSUER: &str = "ldap";
+
+/// The result code of a bind with the wrong password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct Config {
+    /// Such as `ldaps://ldap.example.com`.
+    pub url: String,
+    /// The service account accounts are looked up with.
+    pub bind_dn: String,
+    pub bind_password: String,
+    /// Where accounts are looked up under.
+    pub base_dn: String,
+    /// The attribute holding login names, such as `uid` or `sAMAccountName`.
+    pub login_attribute: String,
+    /// What else accounts must match to log in, such as `(objectClass=person)`. Accounts that stop
+    /// matching it, because they were disabled or removed, have their users disabled.
+    pub user_filter: String,
+    /// The attribute listing the groups of an account.
+    pub group_attribute: String,
+    /// The groups whose members are admins; everyone else is a user.
+    pub admin_groups: Vec<String>,
+    /// How often users are checked against the directory.
+    pub sync_interval: Duration,
+}
+
+/// An account found in the directory.
+struct Account {
+    dn: String,
+    login: String,
+    role: Role,
+}
+
+#[derive(Clone)]
+pub struct Directory {
+    pub config: Arc<Config>,
+}
+
+impl std::fmt::Debug for Directory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Directory")
+            .field("url", &self.config.url)
+            .field("base_dn", &self.config.base_dn)
+            .finish_non_exhaustive()
+    }
+}
+
+fn unreachable(e: ldap3::LdapError) -> Error {
+    tracing::error!(name: "ldap_error", "{}", e);
+    Error::BadGateway(String::from("The directory could not be reached"))
+}
+
+/// The values of an attribute, whose name the directory may spell in another case.
+fn values<'a>(attrs: &'a HashMap<String, Vec<String>>, name: &str) -> &'a [String] {
+    attrs
+        .iter()
+        .find(|(attr, _)| attr.eq_ignore_ascii_case(name))
+        .map_or(&[], |(_, values)| values.as_slice())
+}
+
+impl Directory {
+    pub fn new(config: Config) -> Directory {
+        Directory {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Reads the directory from `LDAP_*` variables, if `LDAP_URL` is set.
+    pub fn from_env() -> Result<Option<Directory>, Error> {
+        let url = match std::env::var("LDAP_URL") {
+            Ok(url) => url,
+            Err(std::env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let admin_groups = var_or("LDAP_ADMIN_GROUPS", String::new())?
+            .split(';')
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(Some(Directory::new(Config {
+            url,
+            bind_dn: std::env::var("LDAP_BIND_DN")?,
+            bind_password: std::env::var("LDAP_BIND_PASSWORD")?,
+            base_dn: std::env::var("LDAP_BASE_DN")?,
+            login_attribute: var_or("LDAP_LOGIN_ATTRIBUTE", String::from("uid"))?,
+            user_filter: var_or("LDAP_USER_FILTER", String::from("(objectClass=person)"))?,
+            group_attribute: var_or("LDAP_GROUP_ATTRIBUTE", String::from("memberOf"))?,
+            admin_groups,
+            sync_interval: Duration::from_secs(60 * var_or("LDAP_SYNC_MINUTES", 15)?),
+        })))
+    }
+
+    /// Connects as the service account.
+    async fn connect(&self) -> Result<Ldap, Error> {
+        let settings = LdapConnSettings::new().set_conn_timeout(Duration::from_secs(10));
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(unreachable)?;
+        ldap3::drive!(conn);
+        ldap.simple_bind(&self.config.bind_dn, &self.config.bind_password)
+            .await
+            .and_then(|result| result.success())
+            .map_err(unreachable)?;
+        Ok(ldap)
+    }
+
+    /// Looks up the account of a login name, if it may log in.
+    async fn find(&self, ldap: &mut Ldap, login: &str) -> Result<Option<Account>, Error> {
+        let config = &self.config;
+        let filter = format!(
+            "(&{}({}={}))",
+            config.user_filter,
+            config.login_attribute,
+            ldap_escape(login)
+        );
+        let attrs = [config.login_attribute.as_str(), &config.group_attribute];
+        let (entries, _) = ldap
+            .search(&config.base_dn, Scope::Subtree, &filter, attrs)
+            .await
+            .and_then(|result| result.success())
+            .map_err(unreachable)?;
+        let mut entries = entries.into_iter().map(SearchEntry::construct);
+        let (Some(entry), None) = (entries.next(), entries.next()) else {
+            return Ok(None);
+        };
+        let Some(login) = values(&entry.attrs, &config.login_attribute).first() else {
+            return Ok(None);
+        };
+        let admin = values(&entry.attrs, &config.group_attribute)
+            .iter()
+            .any(|group| {
+                config
+                    .admin_groups
+                    .iter()
+                    .any(|admin| admin.eq_ignore_ascii_case(group))
+            });
+        Ok(Some(Account {
+            dn: entry.dn,
+            login: login.clone(),
+            role: if admin { Role::Admin } else { Role::User },
+        }))
+    }
+
+    /// Finds the user of an account that just logged in, making one if it is new.
+    async fn user_of(&self, pool: &PgPool, account: &Account) -> Result<Option<Uuid>, Error> {
+        let mut tx = pool.begin().await?;
+        let user_id = match db::identity::login(&mut *tx, ISSUER, &account.login).await? {
+            Some(user_id) => {
+                if db::identity::restore(&mut *tx, ISSUER, &account.login).await? {
+                    tracing::info!("Enabled {} again as the directory knows them", user_id);
+                }
+                user_id
+            }
+            None if db::user::login_exists(&mut *tx, &account.login).await? => {
+                tracing::warn!(
+                    "{} logged in to the directory, but a user of that name is not from it",
+                    account.login
+                );
+                return Ok(None);
+            }
+            None => {
+                let user_id = db::user::create(&mut *tx, &account.login, None).await?;
+                db::config::init(&mut *tx, &user_id).await?;
+                db::identity::create(&mut *tx, ISSUER, &account.login, &user_id, None).await?;
+                tracing::info!("Made user {} for {} of the directory", user_id, account.dn);
+                user_id
+            }
+        };
+        db::user::set_role(&mut *tx, &user_id, account.role).await?;
+        tx.commit().await?;
+        Ok(Some(user_id))
+    }
+
+    /// Checks every user from the directory against it: users of accounts it no longer finds are
+    /// disabled and logged out, those of accounts it finds again are enabled, and everyone's role
+    /// follows their groups. Returns how many users were disabled and enabled.
+    pub async fn sync(&self, pool: &PgPool) -> Result<(usize, usize), Error> {
+        let identities = db::identity::list(pool, ISSUER).await?;
+        let mut ldap = self.connect().await?;
+        let (mut disabled, mut enabled) = (0, 0);
+        for identity in identities {
+            let mut tx = pool.begin().await?;
+            match self.find(&mut ldap, &identity.subject).await? {
+                Some(account) => {
+                    db::user::set_role(&mut *tx, &identity.user_id, account.role).await?;
+                    if db::identity::restore(&mut *tx, ISSUER, &identity.subject).await? {
+                        enabled += 1;
+                    }
+                }
+                None => {
+                    if let Some(user_id) =
+                        db::identity::suspend(&mut *tx, ISSUER, &identity.subject).await?
+                    {
+                        db::session::revoke_all(&mut *tx, &user_id, None).await?;
+                        disabled += 1;
+                    }
+                }
+            }
+            tx.commit().await?;
+        }
+        let _ = ldap.unbind().await;
+        Ok((disabled, enabled))
+    }
+}
+
+#[async_trait]
+impl Authenticator for Directory {
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, Error> {
+        // A bind without a password is anonymous, which directories let through.
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let mut ldap = self.connect().await?;
+        let Some(account) = self.find(&mut ldap, login).await? else {
+            return Ok(None);
+        };
+        let bound = ldap
+            .simple_bind(&account.dn, password)
+            .await
+            .map_err(unreachable)?;
+        let _ = ldap.unbind().await;
+        if bound.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bound.success().map_err(unreachable)?;
+        self.user_of(pool, &account).await
+    }
+}
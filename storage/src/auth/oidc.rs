@@ -0,0 +1,304 @@
+//! Single sign-on through an OpenID Connect provider, with the authorization code flow and PKCE.
+//!
+//! The provider is discovered from its issuer the first time it is needed. ID tokens are checked
+//! against the keys it publishes, which are fetched again whenever a token names a key not seen yet.
+
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::api::{Error, var_or};
+use crate::auth::token;
+
+/// Algorithms ID tokens may be signed with; ones taking a shared secret are not trusted.
+const ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+pub struct Config {
+    pub issuer: String,
+    pub client_id: String,
+    /// Is none for public clients.
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to, `/auth/oidc/callback` of this server.
+    pub redirect_uri: String,
+    pub scopes: String,
+    /// Whether to make users for accounts that are not linked to one yet.
+    pub provision: bool,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Discovered {
+    metadata: Arc<Metadata>,
+    jwks: JwkSet,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// What an ID token says about the user.
+#[derive(Deserialize)]
+pub struct IdClaims {
+    pub sub: String,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+}
+
+/// A secret proving that whoever redeems a code is who asked for it.
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Pkce {
+        let verifier = token::generate();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Pkce {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+impl Default for Pkce {
+    fn default() -> Self {
+        Pkce::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct Provider {
+    pub config: Arc<Config>,
+    http: reqwest::Client,
+    discovered: Arc<RwLock<Option<Arc<Discovered>>>>,
+}
+
+impl std::fmt::Debug for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Provider")
+            .field("issuer", &self.config.issuer)
+            .field("client_id", &self.config.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+fn unreachable(e: impl std::fmt::Display) -> Error {
+    tracing::error!(name: "oidc_error", "{}", e);
+    Error::BadGateway(String::from("The identity provider could not be reached"))
+}
+
+fn rejected() -> Error {
+    Error::Unauthorized(String::from("The identity provider's answer was invalid"))
+}
+
+impl Provider {
+    pub fn new(config: Config) -> Provider {
+        Provider {
+            config: Arc::new(config),
+            http: reqwest::Client::new(),
+            discovered: Default::default(),
+        }
+    }
+
+    /// Reads the provider from `OIDC_*` variables, if `OIDC_ISSUER` is set.
+    pub fn from_env() -> Result<Option<Provider>, Error> {
+        let issuer = match std::env::var("OIDC_ISSUER") {
+            Ok(issuer) => issuer,
+            Err(std::env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(Provider::new(Config {
+            issuer,
+            client_id: std::env::var("OIDC_CLIENT_ID")?,
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri: std::env::var("OIDC_REDIRECT_URI")?,
+            scopes: var_or("OIDC_SCOPES", String::from("openid profile email"))?,
+            provision: var_or("OIDC_PROVISION", true)?,
+        })))
+    }
+
+    async fn fetch_jwks(&self, uri: &str) -> Result<JwkSet, Error> {
+        self.http
+            .get(uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(unreachable)?
+            .json()
+            .await
+            .map_err(unreachable)
+    }
+
+    async fn fetch_metadata(&self) -> Result<Metadata, Error> {
+        let issuer = self.config.issuer.trim_end_matches('/');
+        let metadata: Metadata = self
+            .http
+            .get(format!("{}/.well-known/openid-configuration", issuer))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(unreachable)?
+            .json()
+            .await
+            .map_err(unreachable)?;
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(Error::Configuration(format!(
+                "The provider at {} calls itself {}",
+                issuer, metadata.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+
+    /// Discovers the provider, or fetches its keys again when `stale` are the ones known.
+    async fn discover(&self, stale: Option<&Arc<Discovered>>) -> Result<Arc<Discovered>, Error> {
+        if stale.is_none()
+            && let Some(current) = &*self.discovered.read().await
+        {
+            return Ok(current.clone());
+        }
+        let mut discovered = self.discovered.write().await;
+        if let Some(current) = &*discovered
+            && stale.is_none_or(|stale| !Arc::ptr_eq(stale, current))
+        {
+            return Ok(current.clone());
+        }
+        let metadata = match &*discovered {
+            Some(current) => current.metadata.clone(),
+            None => Arc::new(self.fetch_metadata().await?),
+        };
+        let jwks = self.fetch_jwks(&metadata.jwks_uri).await?;
+        let current = Arc::new(Discovered { metadata, jwks });
+        *discovered = Some(current.clone());
+        Ok(current)
+    }
+
+    /// Where to send a user to log in at the provider.
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        challenge: &str,
+    ) -> Result<String, Error> {
+        let discovered = self.discover(None).await?;
+        let config = &self.config;
+        let url = reqwest::Url::parse_with_params(
+            &discovered.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &config.client_id),
+                ("redirect_uri", &config.redirect_uri),
+                ("scope", &config.scopes),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(unreachable)?;
+        Ok(url.to_string())
+    }
+
+    /// Redeems a code the provider sent a user back with, returning who the user is.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        verifier: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, Error> {
+        let discovered = self.discover(None).await?;
+        let config = &self.config;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_uri),
+            ("client_id", &config.client_id),
+            ("code_verifier", verifier),
+        ];
+        if let Some(secret) = &config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = self
+            .http
+            .post(&discovered.metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        if !response.status().is_success() {
+            tracing::warn!(
+                "The identity provider refused a code: {}",
+                response.status()
+            );
+            return Err(Error::Unauthorized(String::from(
+                "The identity provider refused the login",
+            )));
+        }
+        let tokens: TokenResponse = response.json().await.map_err(|_| rejected())?;
+        let claims = self.validate(&tokens.id_token, discovered).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(rejected());
+        }
+        Ok(claims)
+    }
+
+    async fn validate(
+        &self,
+        id_token: &str,
+        mut discovered: Arc<Discovered>,
+    ) -> Result<IdClaims, Error> {
+        let header = decode_header(id_token).map_err(|_| rejected())?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(rejected());
+        }
+        let find = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        let jwk = match find(&discovered.jwks) {
+            Some(jwk) => jwk,
+            None => {
+                discovered = self.discover(Some(&discovered)).await?;
+                find(&discovered.jwks).ok_or_else(rejected)?
+            }
+        };
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| rejected())?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovered.metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let data = decode::<IdClaims>(id_token, &key, &validation).map_err(|e| {
+            tracing::warn!("Turned away an ID token: {}", e);
+            rejected()
+        })?;
+        Ok(data.claims)
+    }
+}
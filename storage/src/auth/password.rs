@@ -0,0 +1,71 @@
+//! What passwords users may choose.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::api::{Error, Settings};
+
+/// Longer passwords only make hashing slower.
+pub const MAX_LENGTH: usize = 1024;
+
+/// Reads a list of breached passwords, one per line.
+pub fn load_breached(path: &Path) -> std::io::Result<HashSet<String>> {
+    let list = std::fs::read_to_string(path)?;
+    Ok(list
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Turns away passwords that are too short or too long, that are the login itself,
+/// or that appear in the list of breached passwords.
+pub fn check(settings: &Settings, login: &str, password: &str) -> Result<(), Error> {
+    let length = password.chars().count();
+    if length < settings.password_min_length {
+        return Err(Error::BadRequest(format!(
+            "The password must be at least {} characters long",
+            settings.password_min_length
+        )));
+    }
+    if length > MAX_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "The password must be at most {} characters long",
+            MAX_LENGTH
+        )));
+    }
+    if password.eq_ignore_ascii_case(login) {
+        return Err(Error::BadRequest(String::from(
+            "The password cannot be the login",
+        )));
+    }
+    if settings.breached_passwords.contains(password) {
+        return Err(Error::BadRequest(String::from(
+            "The password is known from a breach, choose another one",
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::api::Settings;
+
+    #[test]
+    fn policy() {
+        let settings = Settings {
+            breached_passwords: Arc::new(["password123".to_string()].into()),
+            ..Default::default()
+        };
+        let check = |password: &str| super::check(&settings, "algernon", password).is_ok();
+        assert!(!check(""));
+        assert!(!check("flowers"));
+        assert!(!check("Algernon"));
+        assert!(!check("password123"));
+        assert!(!check(&"a".repeat(super::MAX_LENGTH + 1)));
+        assert!(check("forget-me-nots"));
+    }
+}
@@ -0,0 +1,62 @@
+//! What a personal access token may be used for.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Browsing and downloading files, and reading the view config.
+    #[serde(rename = "files:read")]
+    FilesRead,
+    /// Uploading, changing, deleting and sharing files.
+    #[serde(rename = "files:write")]
+    FilesWrite,
+    #[serde(rename = "config:write")]
+    ConfigWrite,
+    /// The administration API, for administrators only.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Scope::FilesRead,
+        Scope::FilesWrite,
+        Scope::ConfigWrite,
+        Scope::Admin,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::FilesRead => "files:read",
+            Scope::FilesWrite => "files:write",
+            Scope::ConfigWrite => "config:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scope;
+
+    #[test]
+    fn scope_names() {
+        for scope in Scope::ALL {
+            let json = serde_json::to_string(&scope).unwrap();
+            assert_eq!(json, format!("\"{}\"", scope));
+            assert_eq!(serde_json::from_str::<Scope>(&json).unwrap(), scope);
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(Scope::parse("files:delete"), None);
+    }
+}
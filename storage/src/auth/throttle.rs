@@ -0,0 +1,184 @@
+//! Slowing down password guessing.
+//!
+//! Failed logins are counted per login name and per client address, and wrong share link passwords
+//! per link and per client address. Past a few failures in a row,
+//! each one locks the name or address out for twice as long as the one before, up to a limit.
+//! Names are counted whether or not such a user exists, so that lockouts do not tell them apart.
+//! Attempts are counted as they start and taken back if they succeed, so a burst of them
+//! cannot get past the lockout by all being checked before any has failed.
+
+use std::future::Future;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+
+use crate::api::{Error, Settings};
+use crate::db::login_failure::{self, Kind};
+
+/// How long to lock out for after `failures` failures in a row, when `free` of them are let through.
+pub fn lockout(failures: i32, free: i32, settings: &Settings) -> Option<Duration> {
+    let over = failures.saturating_sub(free);
+    if over <= 0 {
+        return None;
+    }
+    let factor = 2i64.saturating_pow((over - 1) as u32);
+    let seconds = settings.login_backoff.num_seconds().saturating_mul(factor);
+    Some(Duration::seconds(
+        seconds.min(settings.login_lockout.num_seconds()),
+    ))
+}
+
+/// An attempt counted as failed until it turns out otherwise.
+struct Reservation<'a> {
+    kind: Kind,
+    subject: &'a str,
+    failures: i32,
+    /// The lockout the attempt brought about, lifted again unless it fails.
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Counts an attempt against `subject` before it is made, so that attempts made at the same time
+/// cannot all get in ahead of the lockout. Returns nothing if the subject is locked out.
+async fn reserve<'a>(
+    pool: &PgPool,
+    settings: &Settings,
+    kind: Kind,
+    subject: &'a str,
+    free: i32,
+) -> Result<Option<Reservation<'a>>, Error> {
+    let forget_before = Utc::now() - settings.login_lockout;
+    let mut tx = pool.begin().await?;
+    let Some(failures) = login_failure::attempt(&mut *tx, kind, subject, forget_before).await?
+    else {
+        return Ok(None);
+    };
+    let locked_until = match lockout(failures, free, settings) {
+        Some(lockout) => {
+            Some(login_failure::lock(&mut *tx, kind, subject, Utc::now() + lockout).await?)
+        }
+        None => None,
+    };
+    tx.commit().await?;
+    Ok(Some(Reservation {
+        kind,
+        subject,
+        failures,
+        locked_until,
+    }))
+}
+
+async fn withdraw(pool: &PgPool, reservations: &[Reservation<'_>]) -> Result<(), Error> {
+    for reservation in reservations {
+        login_failure::withdraw(
+            pool,
+            reservation.kind,
+            reservation.subject,
+            reservation.locked_until,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Makes an attempt to log in unless the login name or address is locked out,
+/// counting it as failed if it is turned away as unauthorized.
+pub async fn guard<T>(
+    pool: &PgPool,
+    settings: &Settings,
+    login: &str,
+    ip: Option<&str>,
+    attempt: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let subjects = [
+        Some((Kind::Login, login, settings.login_attempts)),
+        ip.map(|ip| (Kind::Ip, ip, settings.ip_login_attempts)),
+    ];
+    throttle(pool, settings, &subjects, "Too many failed logins", attempt).await
+}
+
+/// Makes an attempt at the password of a share link unless the link or address is locked out,
+/// counting it as failed if it is turned away as unauthorized.
+pub async fn guard_link<T>(
+    pool: &PgPool,
+    settings: &Settings,
+    link_id: &str,
+    ip: Option<&str>,
+    attempt: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let subjects = [
+        Some((Kind::Link, link_id, settings.login_attempts)),
+        ip.map(|ip| (Kind::Ip, ip, settings.ip_login_attempts)),
+    ];
+    throttle(
+        pool,
+        settings,
+        &subjects,
+        "Too many wrong passwords",
+        attempt,
+    )
+    .await
+}
+
+async fn throttle<T>(
+    pool: &PgPool,
+    settings: &Settings,
+    subjects: &[Option<(Kind, &str, i32)>],
+    what: &str,
+    attempt: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let mut reservations = Vec::new();
+    for &(kind, subject, free) in subjects.iter().flatten() {
+        match reserve(pool, settings, kind, subject, free).await? {
+            Some(reservation) => reservations.push(reservation),
+            None => {
+                withdraw(pool, &reservations).await?;
+                return Err(Error::TooManyRequests(format!("{}, try again later", what)));
+            }
+        }
+    }
+    match attempt.await {
+        Err(Error::Unauthorized(message)) => {
+            for reservation in &reservations {
+                if let Some(until) = reservation.locked_until {
+                    tracing::warn!(
+                        "Locked out {} {} until {} after {} failures",
+                        reservation.kind.as_str(),
+                        reservation.subject,
+                        until,
+                        reservation.failures
+                    );
+                }
+            }
+            Err(Error::Unauthorized(message))
+        }
+        result => {
+            withdraw(pool, &reservations).await?;
+            result
+        }
+    }
+}
+
+/// Forgets the failures of a login name once it logged in all the way, second factor included.
+pub async fn succeeded(pool: &PgPool, login: &str) -> Result<(), Error> {
+    login_failure::clear(pool, Kind::Login, login).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use crate::api::Settings;
+
+    #[test]
+    fn backoff() {
+        let settings = Settings::default();
+        let lockout = |failures| super::lockout(failures, 5, &settings);
+        assert_eq!(lockout(5), None);
+        assert_eq!(lockout(6), Some(Duration::seconds(30)));
+        assert_eq!(lockout(7), Some(Duration::minutes(1)));
+        assert_eq!(lockout(9), Some(Duration::minutes(4)));
+        assert_eq!(lockout(13), Some(Duration::hours(1)));
+        assert_eq!(lockout(i32::MAX), Some(Duration::hours(1)));
+    }
+}
@@ -0,0 +1,105 @@
+//! Time-based one-time passwords, as made by authenticator apps, and the recovery codes that stand in
+//! for them when the app is lost.
+
+use password_hash::rand_core::{OsRng, RngCore};
+use totp_rs::{Algorithm, TOTP};
+
+/// How many seconds a code lasts.
+const STEP: u64 = 30;
+/// How many steps a code may be off by, for clocks that drift.
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// Letters and digits that cannot be mistaken for one another.
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn totp(secret: &[u8], issuer: &str, login: &str) -> TOTP {
+    TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP,
+        secret.to_vec(),
+        Some(issuer.replace(':', "")),
+        login.replace(':', ""),
+    )
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// The secret as typed into an authenticator app.
+pub fn encode(secret: &[u8]) -> String {
+    totp(secret, "", "").get_secret_base32()
+}
+
+/// An `otpauth://` URI, as scanned by authenticator apps.
+pub fn uri(secret: &[u8], issuer: &str, login: &str) -> String {
+    totp(secret, issuer, login).get_url()
+}
+
+/// The code for the step a time falls in.
+pub fn code(secret: &[u8], time: u64) -> String {
+    totp(secret, "", "").generate(time)
+}
+
+/// Finds the step a code was made for, close enough to `time`.
+/// Steps count up, so remembering the last one used keeps a code from being used twice.
+pub fn step(secret: &[u8], code: &str, time: u64) -> Option<i64> {
+    let totp = totp(secret, "", "");
+    let now = time / STEP;
+    (now.saturating_sub(SKEW)..=now + SKEW)
+        .find(|step| totp.check(code.trim(), step * STEP))
+        .map(|step| step as i64)
+}
+
+pub fn now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+/// Makes a batch of single-use recovery codes, such as `k7hm2-x9qpt`.
+pub fn recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            OsRng.fill_bytes(&mut bytes);
+            let chars: String = bytes
+                .iter()
+                .map(|b| ALPHABET[*b as usize % ALPHABET.len()] as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// The form recovery codes are hashed in, forgiving case and dashes.
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn codes() {
+        let secret = super::generate_secret();
+        let time = 1_700_000_000;
+        let code = super::code(&secret, time);
+        assert_eq!(code.len(), 6);
+        let step = super::step(&secret, &code, time).unwrap();
+        assert_eq!(super::step(&secret, &code, time + 30), Some(step));
+        assert_eq!(super::step(&secret, &code, time + 90), None);
+
+        let uri = super::uri(&secret, "proto-drive", "algernon");
+        assert!(uri.starts_with("otpauth://totp/proto-drive:algernon?"));
+        assert!(uri.contains(&super::encode(&secret)));
+
+        let codes = super::recovery_codes();
+        assert_eq!(codes.len(), 10);
+        assert_eq!(super::normalize(&codes[0].to_uppercase()).len(), 10);
+    }
+}
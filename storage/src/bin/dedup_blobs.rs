@@ -0,0 +1,24 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Moves the blobs stored one per upload into the content-addressed layout.
+/// The blob store is configured the same way as for the server.
+#[tokio::main]
+async fn main() {
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| {
+        eprintln!("usage: DATABASE_URL=... ROOT=... dedup_blobs");
+        std::process::exit(1);
+    });
+    let root = env::var("ROOT").map(PathBuf::from).unwrap_or_else(|_| {
+        eprintln!("usage: DATABASE_URL=... ROOT=... dedup_blobs");
+        std::process::exit(1);
+    });
+    let pool = sqlx::PgPool::connect(&database_url)
+        .await
+        .expect("connecting to the database failed");
+    let blobs = storage::blob::from_env(&root).expect("configuring the blob store failed");
+    let rehashed = storage::blob::rehash_legacy(&pool, &*blobs)
+        .await
+        .expect("rehashing failed");
+    println!("Rehashed {} versions", rehashed);
+}
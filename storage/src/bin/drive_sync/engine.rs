@@ -0,0 +1,332 @@
+//! Looking at both sides and carrying out the plan.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+use crate::Error;
+use crate::filter::Filter;
+use crate::plan::{self, Action, Local, Remote, Synced};
+use crate::remote::{self, Client};
+use crate::state::State;
+
+pub struct Engine {
+    root: PathBuf,
+    client: Client,
+    state: State,
+    filter: Filter,
+}
+
+fn name(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+fn stat(path: &Path) -> Result<Local, Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as i64);
+    Ok(Local {
+        folder: metadata.is_dir(),
+        mtime,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+    })
+}
+
+/// Lists the local folder by path, leaving out what is not synced.
+fn scan(root: &Path, filter: &Filter) -> Result<BTreeMap<String, Local>, Error> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                tracing::warn!("Skipped {:?}, its name is not valid UTF-8", entry.path());
+                continue;
+            };
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if entry.file_type()?.is_symlink() || !filter.includes(&path) {
+                continue;
+            }
+            let local = stat(&entry.path())?;
+            if local.folder {
+                pending.push((entry.path(), path.clone()));
+            }
+            entries.insert(path, local);
+        }
+    }
+    Ok(entries)
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool, Error> {
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (std::fs::File::open(a)?, std::fs::File::open(b)?);
+    let (mut left, mut right) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = a.read(&mut left)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut right[..n])?;
+        if left[..n] != right[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+impl Engine {
+    pub fn new(root: PathBuf, client: Client, state: State, filter: Filter) -> Engine {
+        Engine {
+            root,
+            client,
+            state,
+            filter,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Whether a local change at `path` matters to syncing.
+    pub fn concerns(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root)
+            .ok()
+            .and_then(Path::to_str)
+            .is_some_and(|path| !path.is_empty() && self.filter.includes(path))
+    }
+
+    /// Reads the change feed, telling whether anything changed on the server since last time.
+    pub async fn remote_changed(&mut self) -> Result<bool, Error> {
+        let cursor = self.state.cursor()?;
+        let (latest, changed) = match self.client.changes(cursor).await? {
+            Some(delta) => (delta.cursor, cursor.is_none() || !delta.changes.is_empty()),
+            None => {
+                tracing::info!("Fell too far behind the change feed, resyncing");
+                let latest = self.client.changes(None).await?.map_or(0, |d| d.cursor);
+                (latest, true)
+            }
+        };
+        self.state.set_cursor(latest)?;
+        Ok(changed)
+    }
+
+    /// Brings both sides in line. Returns whether another pass is needed right away,
+    /// such as to upload a conflicted copy.
+    pub async fn pass(&mut self) -> Result<bool, Error> {
+        let remote: BTreeMap<String, Remote> = self
+            .client
+            .tree()
+            .await?
+            .into_iter()
+            .filter(|(path, _)| self.filter.includes(path))
+            .collect();
+        let local = scan(&self.root, &self.filter)?;
+        let mut synced = self.state.entries()?;
+        // Entries that stopped being synced are forgotten, and left where they are.
+        for path in synced.keys() {
+            if !self.filter.includes(path) {
+                self.state.remove(path)?;
+            }
+        }
+        synced.retain(|path, _| self.filter.includes(path));
+        let mut folders: HashMap<String, Uuid> = remote
+            .iter()
+            .filter(|(_, remote)| remote.folder)
+            .map(|(path, remote)| (path.clone(), remote.id))
+            .collect();
+        let mut again = false;
+        for (path, action) in plan::plan(&remote, &local, &synced) {
+            tracing::info!("{:?} {}", action, path);
+            match self
+                .apply(&path, action, &remote, &synced, &mut folders)
+                .await
+            {
+                Ok(more) => again |= more,
+                Err(e @ Error::Status(StatusCode::UNAUTHORIZED, _)) => return Err(e),
+                Err(e) => tracing::error!("Could not sync {}: {}", path, e),
+            }
+        }
+        Ok(again)
+    }
+
+    /// The folder `path` goes in on the server, the nil UUID being the root folder.
+    fn parent_id(path: &str, folders: &HashMap<String, Uuid>) -> Result<Uuid, Error> {
+        match path.rsplit_once('/') {
+            None => Ok(Uuid::nil()),
+            Some((parent, _)) => folders
+                .get(parent)
+                .copied()
+                .ok_or(Error::Missing(parent.to_string())),
+        }
+    }
+
+    fn record_folder(&self, path: &str, remote: &Remote) -> Result<(), Error> {
+        self.state.put(
+            path,
+            &Synced {
+                file_id: remote.id,
+                folder: true,
+                modified: remote.modified.clone(),
+                mtime: 0,
+                size: 0,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn record_file(&self, path: &str, remote: &Remote) -> Result<(), Error> {
+        let local = stat(&self.root.join(path))?;
+        self.state.put(
+            path,
+            &Synced {
+                file_id: remote.id,
+                folder: false,
+                modified: remote.modified.clone(),
+                mtime: local.mtime,
+                size: local.size,
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn fetch(&self, path: &str, remote: &Remote) -> Result<(), Error> {
+        let target = self.root.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.client.download(&remote.id, &target).await?;
+        self.record_file(path, remote)
+    }
+
+    async fn send(&self, path: &str, folders: &HashMap<String, Uuid>) -> Result<(), Error> {
+        let parent_id = Self::parent_id(path, folders)?;
+        let source = self.root.join(path);
+        // Taken first, so that changes made during the upload are picked up next time.
+        let local = stat(&source)?;
+        self.client.upload(&parent_id, name(path), &source).await?;
+        let remote = self
+            .client
+            .find(&parent_id, name(path))
+            .await?
+            .ok_or(Error::Missing(path.to_string()))?;
+        self.state.put(
+            path,
+            &Synced {
+                file_id: remote.id,
+                folder: false,
+                modified: remote.modified,
+                mtime: local.mtime,
+                size: local.size,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Keeps the remote side of an entry both sides changed, setting the local one aside
+    /// unless they turn out to be the same.
+    async fn merge(&self, path: &str, remote: &Remote) -> Result<bool, Error> {
+        let target = self.root.join(path);
+        let local = stat(&target)?;
+        if remote.folder && local.folder {
+            self.record_folder(path, remote)?;
+            return Ok(false);
+        }
+        let part = remote::part(&target);
+        if !remote.folder && !local.folder {
+            self.client.download(&remote.id, &part).await?;
+            if same_contents(&part, &target)? {
+                std::fs::remove_file(&part)?;
+                self.record_file(path, remote)?;
+                return Ok(false);
+            }
+        }
+        let stamp = chrono::Local::now().format("%Y-%m-%d %H%M%S").to_string();
+        let conflicted = plan::conflicted_copy(path, &stamp);
+        std::fs::rename(&target, self.root.join(&conflicted))?;
+        tracing::warn!(
+            "{} changed on both sides, kept ours as {}",
+            path,
+            conflicted
+        );
+        if remote.folder {
+            std::fs::create_dir(&target)?;
+            self.record_folder(path, remote)?;
+        } else {
+            std::fs::rename(&part, &target)?;
+            self.record_file(path, remote)?;
+        }
+        // The conflicted copy is new, and is uploaded by the next pass.
+        Ok(true)
+    }
+
+    async fn apply(
+        &self,
+        path: &str,
+        action: Action,
+        remote: &BTreeMap<String, Remote>,
+        synced: &BTreeMap<String, Synced>,
+        folders: &mut HashMap<String, Uuid>,
+    ) -> Result<bool, Error> {
+        let target = self.root.join(path);
+        match action {
+            Action::Download => self.fetch(path, &remote[path]).await?,
+            Action::CreateLocalFolder => {
+                std::fs::create_dir_all(&target)?;
+                self.record_folder(path, &remote[path])?;
+            }
+            Action::Upload => self.send(path, folders).await?,
+            Action::CreateRemoteFolder => {
+                let parent_id = Self::parent_id(path, folders)?;
+                let folder = self.client.create_folder(&parent_id, name(path)).await?;
+                folders.insert(path.to_string(), folder.id);
+                let remote = Remote {
+                    id: folder.id,
+                    folder: true,
+                    modified: folder.edited_at.unwrap_or(folder.created_at),
+                };
+                self.record_folder(path, &remote)?;
+            }
+            Action::DeleteLocal => {
+                let result = if synced[path].folder {
+                    std::fs::remove_dir(&target)
+                } else {
+                    std::fs::remove_file(&target)
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    // Whatever is left in the folder is not synced, so it stays.
+                    Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => {
+                        tracing::info!("Kept {}, it still holds files that are not synced", path);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                self.state.remove(path)?;
+            }
+            Action::DeleteRemote => {
+                self.client.delete(&synced[path].file_id).await?;
+                self.state.remove(path)?;
+            }
+            Action::Merge => return self.merge(path, &remote[path]).await,
+            Action::Record => self.record_folder(path, &remote[path])?,
+            Action::Forget => self.state.remove(path)?,
+        }
+        Ok(false)
+    }
+}
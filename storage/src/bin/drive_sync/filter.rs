@@ -0,0 +1,116 @@
+//! Which paths take part in syncing: ignore patterns and selective sync.
+
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Where ignore patterns are read from, one per line, relative to the local folder.
+pub const IGNORE_FILE: &str = ".syncignore";
+
+/// Never synced: the client's own files and the usual clutter.
+const ALWAYS_IGNORED: &[&str] = &[
+    IGNORE_FILE,
+    ".sync-state.db*",
+    "*.sync-part",
+    ".DS_Store",
+    "Thumbs.db",
+];
+
+pub struct Filter {
+    ignored: GlobSet,
+    /// The folders to sync, all of them when empty.
+    folders: Vec<String>,
+}
+
+impl Filter {
+    pub fn new(patterns: &[String], folders: &[String]) -> Result<Filter, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in ALWAYS_IGNORED
+            .iter()
+            .copied()
+            .chain(patterns.iter().map(String::as_str))
+        {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(Filter {
+            ignored: builder.build()?,
+            folders: folders
+                .iter()
+                .map(|f| f.trim_matches('/').to_string())
+                .filter(|f| !f.is_empty())
+                .collect(),
+        })
+    }
+
+    /// Reads the ignore patterns kept in the local folder, skipping blank lines and `#` comments.
+    pub fn load(root: &Path, folders: &[String]) -> Result<Filter, crate::Error> {
+        let patterns = match std::fs::read_to_string(root.join(IGNORE_FILE)) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Filter::new(&patterns, folders)?)
+    }
+
+    /// Whether `path` or a folder above it matches an ignore pattern,
+    /// either by name or by its path from the top of the drive.
+    pub fn ignored(&self, path: &str) -> bool {
+        let mut prefix = String::new();
+        for name in path.split('/') {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(name);
+            if self.ignored.is_match(name) || self.ignored.is_match(&prefix) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether `path` is in a selected folder, or is a folder leading to one.
+    pub fn selected(&self, path: &str) -> bool {
+        self.folders.is_empty()
+            || self.folders.iter().any(|folder| {
+                path == folder
+                    || path.starts_with(&format!("{}/", folder))
+                    || folder.starts_with(&format!("{}/", path))
+            })
+    }
+
+    pub fn includes(&self, path: &str) -> bool {
+        !self.ignored(path) && self.selected(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+
+    #[test]
+    fn filtering() {
+        let filter = Filter::new(
+            &[
+                "*.tmp".to_string(),
+                "build".to_string(),
+                "docs/private/*".to_string(),
+            ],
+            &["/docs/".to_string(), "photos/2025".to_string()],
+        )
+        .unwrap();
+        assert!(filter.includes("docs/report.pdf"));
+        assert!(!filter.includes("docs/draft.tmp"));
+        assert!(!filter.includes("docs/build/out.bin"));
+        assert!(!filter.includes("docs/private/diary.txt"));
+        assert!(!filter.includes("docs/.sync-state.db"));
+        assert!(filter.includes("photos"));
+        assert!(filter.includes("photos/2025/beach.jpg"));
+        assert!(!filter.includes("photos/2024/beach.jpg"));
+        assert!(!filter.includes("music"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Mirrors a drive into a local folder, keeping both in sync.
+//!
+//! Configured from the environment: `SYNC_SERVER`, `SYNC_LOGIN`, `SYNC_PASSWORD`, `SYNC_ROOT`,
+//! `SYNC_FOLDERS` (comma separated, everything when unset) and `SYNC_POLL_SECS`.
+
+mod engine;
+mod filter;
+mod plan;
+mod remote;
+mod state;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use reqwest::StatusCode;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::engine::Engine;
+use crate::filter::Filter;
+use crate::remote::Client;
+use crate::state::{STATE_FILE, State};
+
+/// How many passes in a row one sync may take before waiting for the next change.
+const MAX_PASSES: usize = 5;
+
+/// How long local changes are let settle before syncing them.
+const SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    State(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Pattern(#[from] globset::Error),
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+    #[error("{0}: {1}")]
+    Environment(&'static str, std::env::VarError),
+    #[error("{1} ({0})")]
+    Status(StatusCode, String),
+    #[error("\"{0}\" is missing from the server")]
+    Missing(String),
+}
+
+fn var(name: &'static str) -> Result<String, Error> {
+    std::env::var(name).map_err(|e| Error::Environment(name, e))
+}
+
+fn var_or(name: &'static str, default: &str) -> Result<String, Error> {
+    match std::env::var(name) {
+        Err(std::env::VarError::NotPresent) => Ok(default.to_string()),
+        value => value.map_err(|e| Error::Environment(name, e)),
+    }
+}
+
+/// Runs passes until one leaves nothing to do, logging in again if the token expired.
+async fn sync(engine: &mut Engine) -> Result<(), Error> {
+    for _ in 0..MAX_PASSES {
+        match engine.pass().await {
+            Ok(true) => continue,
+            Ok(false) => return Ok(()),
+            Err(Error::Status(StatusCode::UNAUTHORIZED, _)) => {
+                engine.client_mut().relogin().await?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Syncs whenever something changes locally, and whenever the change feed says so.
+async fn run(mut engine: Engine, poll: Duration) -> Result<(), Error> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let _ = sender.send(event);
+    })?;
+    watcher.watch(engine.root(), RecursiveMode::Recursive)?;
+    sync(&mut engine).await?;
+    engine.remote_changed().await?;
+    let mut interval = tokio::time::interval(poll);
+    loop {
+        tokio::select! {
+            Some(event) = receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        tracing::error!("Watching failed: {}", e);
+                        continue;
+                    }
+                };
+                if !event.paths.iter().any(|path| engine.concerns(path)) {
+                    continue;
+                }
+                tokio::time::sleep(SETTLE).await;
+                while receiver.try_recv().is_ok() {}
+                if let Err(e) = sync(&mut engine).await {
+                    tracing::error!("Sync failed: {}", e);
+                }
+                // What was just uploaded shows up in the feed, and is already in sync.
+                while receiver.try_recv().is_ok() {}
+                if let Err(e) = engine.remote_changed().await {
+                    tracing::error!("Reading the change feed failed: {}", e);
+                }
+            }
+            _ = interval.tick() => {
+                let changed = match engine.remote_changed().await {
+                    Ok(changed) => changed,
+                    Err(Error::Status(StatusCode::UNAUTHORIZED, _)) => {
+                        engine.client_mut().relogin().await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::error!("Reading the change feed failed: {}", e);
+                        continue;
+                    }
+                };
+                if changed {
+                    if let Err(e) = sync(&mut engine).await {
+                        tracing::error!("Sync failed: {}", e);
+                    }
+                    while receiver.try_recv().is_ok() {}
+                }
+            }
+        }
+    }
+}
+
+async fn start() -> Result<(), Error> {
+    let server = var_or("SYNC_SERVER", "http://localhost:3001")?;
+    let login = var("SYNC_LOGIN")?;
+    let password = var("SYNC_PASSWORD")?;
+    let root = PathBuf::from(var("SYNC_ROOT")?);
+    let folders: Vec<String> = var_or("SYNC_FOLDERS", "")?
+        .split(',')
+        .map(str::trim)
+        .filter(|folder| !folder.is_empty())
+        .map(String::from)
+        .collect();
+    let poll = var_or("SYNC_POLL_SECS", "30")?
+        .parse()
+        .map_err(|_| Error::Environment("SYNC_POLL_SECS", std::env::VarError::NotPresent))?;
+    std::fs::create_dir_all(&root)?;
+    let client = Client::login(&server, &login, &password).await?;
+    let state = State::open(&root.join(STATE_FILE))?;
+    let filter = Filter::load(&root, &folders)?;
+    run(
+        Engine::new(root, client, state, filter),
+        Duration::from_secs(poll),
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("{}=info", env!("CARGO_CRATE_NAME")).into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    if let Err(e) = start().await {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::PgPool;
+    use storage::api::Shared;
+
+    use super::{Engine, Filter, STATE_FILE, State};
+    use crate::remote::{self, Client};
+
+    async fn serve(pool: PgPool, root: &std::path::Path) -> String {
+        let shared = Shared {
+            pool,
+            keys: storage::auth::jwt::Keys::ephemeral(),
+            root: root.to_path_buf(),
+            blobs: Arc::new(storage::blob::local::LocalStore::new(root)),
+            settings: Default::default(),
+            events: Default::default(),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, storage::app(shared)).await });
+        format!("http://{}", address)
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn syncing(pool: PgPool) {
+        storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+            .await
+            .unwrap();
+        let server_root = tempfile::tempdir().unwrap();
+        let server = serve(pool, server_root.path()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().to_path_buf();
+        std::fs::create_dir(local.join("docs")).unwrap();
+        std::fs::write(local.join("docs/notes.txt"), "mine").unwrap();
+        std::fs::write(local.join("docs/scratch.tmp"), "ignored").unwrap();
+        let client = Client::login(&server, "algernon", "flowers").await.unwrap();
+        let state = State::open(&local.join(STATE_FILE)).unwrap();
+        let filter = Filter::new(&["*.tmp".to_string()], &[]).unwrap();
+        let mut engine = Engine::new(local.clone(), client, state, filter);
+        let other = Client::login(&server, "algernon", "flowers").await.unwrap();
+
+        // Local files are uploaded, ignored ones are not.
+        assert!(!engine.pass().await.unwrap());
+        let tree = other.tree().await.unwrap();
+        assert_eq!(tree.keys().collect::<Vec<_>>(), ["docs", "docs/notes.txt"]);
+        let docs = tree["docs"].id;
+
+        // Remote files are downloaded.
+        let upload = server_root.path().join("upload");
+        std::fs::write(&upload, "theirs").unwrap();
+        other.upload(&docs, "report.txt", &upload).await.unwrap();
+        assert!(!engine.pass().await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(local.join("docs/report.txt")).unwrap(),
+            "theirs"
+        );
+        assert!(!engine.pass().await.unwrap());
+
+        // Changes on both sides keep the remote contents, and set the local ones aside.
+        std::fs::write(&upload, "theirs, edited").unwrap();
+        other.upload(&docs, "notes.txt", &upload).await.unwrap();
+        std::fs::write(local.join("docs/notes.txt"), "mine, edited").unwrap();
+        assert!(engine.pass().await.unwrap());
+        assert!(!engine.pass().await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(local.join("docs/notes.txt")).unwrap(),
+            "theirs, edited"
+        );
+        let copies: Vec<String> = std::fs::read_dir(local.join("docs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("notes (conflicted copy "))
+            .collect();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(local.join("docs").join(&copies[0])).unwrap(),
+            "mine, edited"
+        );
+        let tree = other.tree().await.unwrap();
+        assert!(tree.contains_key(&format!("docs/{}", copies[0])));
+
+        // Files too large to be sent in one request body are uploaded all the same.
+        let large: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(local.join("docs/large.bin"), &large).unwrap();
+        assert!(!engine.pass().await.unwrap());
+        let tree = other.tree().await.unwrap();
+        let fetched = server_root.path().join("fetched");
+        other
+            .download(&tree["docs/large.bin"].id, &fetched)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&fetched).unwrap(), large);
+        assert!(!remote::part(&fetched).exists());
+
+        // Local deletions are carried over.
+        std::fs::remove_file(local.join("docs/report.txt")).unwrap();
+        assert!(!engine.pass().await.unwrap());
+        let tree = other.tree().await.unwrap();
+        assert!(!tree.contains_key("docs/report.txt"));
+        assert!(tree.contains_key("docs/notes.txt"));
+    }
+}
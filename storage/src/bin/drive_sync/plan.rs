@@ -0,0 +1,301 @@
+//! Deciding what to do about every path, given both sides and what they looked like when last in sync.
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+/// An entry of the drive.
+#[derive(Clone, Debug)]
+pub struct Remote {
+    pub id: Uuid,
+    pub folder: bool,
+    /// When the contents last changed, as reported by the server.
+    pub modified: String,
+}
+
+/// An entry of the local folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Local {
+    pub folder: bool,
+    pub mtime: i64,
+    pub size: u64,
+}
+
+/// Both sides of an entry as they were when last in sync.
+#[derive(Clone, Debug)]
+pub struct Synced {
+    pub file_id: Uuid,
+    pub folder: bool,
+    pub modified: String,
+    pub mtime: i64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Download,
+    Upload,
+    CreateLocalFolder,
+    CreateRemoteFolder,
+    DeleteLocal,
+    DeleteRemote,
+    /// Both sides changed: keep the remote contents and set the local ones aside as a conflicted copy.
+    Merge,
+    /// Both sides agree, remember them as in sync.
+    Record,
+    /// Gone from both sides.
+    Forget,
+}
+
+impl Action {
+    fn deletes(self) -> bool {
+        matches!(self, Action::DeleteLocal | Action::DeleteRemote)
+    }
+}
+
+fn remote_changed(remote: &Remote, synced: &Synced) -> bool {
+    remote.folder != synced.folder || (!remote.folder && remote.modified != synced.modified)
+}
+
+fn local_changed(local: &Local, synced: &Synced) -> bool {
+    local.folder != synced.folder
+        || (!local.folder && (local.mtime, local.size) != (synced.mtime, synced.size))
+}
+
+fn decide(
+    remote: Option<&Remote>,
+    local: Option<&Local>,
+    synced: Option<&Synced>,
+) -> Option<Action> {
+    let fetch = |remote: &Remote| {
+        if remote.folder {
+            Action::CreateLocalFolder
+        } else {
+            Action::Download
+        }
+    };
+    let send = |local: &Local| {
+        if local.folder {
+            Action::CreateRemoteFolder
+        } else {
+            Action::Upload
+        }
+    };
+    match (remote, local, synced) {
+        (None, None, None) => None,
+        (None, None, Some(_)) => Some(Action::Forget),
+        (Some(remote), None, None) => Some(fetch(remote)),
+        (None, Some(local), None) => Some(send(local)),
+        (Some(remote), Some(local), None) if remote.folder && local.folder => Some(Action::Record),
+        (Some(_), Some(_), None) => Some(Action::Merge),
+        (Some(remote), None, Some(synced)) if remote_changed(remote, synced) => Some(fetch(remote)),
+        (Some(_), None, Some(_)) => Some(Action::DeleteRemote),
+        (None, Some(local), Some(synced)) if local_changed(local, synced) => Some(send(local)),
+        (None, Some(_), Some(_)) => Some(Action::DeleteLocal),
+        (Some(remote), Some(local), Some(synced)) => {
+            match (remote_changed(remote, synced), local_changed(local, synced)) {
+                (false, false) => None,
+                (true, false) => Some(fetch(remote)),
+                (false, true) => Some(send(local)),
+                (true, true) if remote.folder && local.folder => Some(Action::Record),
+                (true, true) => Some(Action::Merge),
+            }
+        }
+    }
+}
+
+/// Lists what to do, in the order to do it: folders are created before what goes in them
+/// and deleted after it.
+pub fn plan(
+    remote: &BTreeMap<String, Remote>,
+    local: &BTreeMap<String, Local>,
+    synced: &BTreeMap<String, Synced>,
+) -> Vec<(String, Action)> {
+    let mut paths: Vec<&String> = remote
+        .keys()
+        .chain(local.keys())
+        .chain(synced.keys())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    let mut actions: Vec<(String, Action)> = paths
+        .into_iter()
+        .filter_map(|path| {
+            decide(remote.get(path), local.get(path), synced.get(path))
+                .map(|action| (path.clone(), action))
+        })
+        .collect();
+    // A folder deleted on one side survives if something in it changed on the other.
+    let kept: Vec<(String, Action)> = actions
+        .iter()
+        .filter(|(_, action)| {
+            matches!(
+                action,
+                Action::Download
+                    | Action::CreateLocalFolder
+                    | Action::Merge
+                    | Action::Upload
+                    | Action::CreateRemoteFolder
+            )
+        })
+        .cloned()
+        .collect();
+    for (path, action) in actions.iter_mut() {
+        let prefix = format!("{}/", path);
+        let inside = |wanted: &[Action]| {
+            kept.iter()
+                .any(|(other, action)| other.starts_with(&prefix) && wanted.contains(action))
+        };
+        if *action == Action::DeleteRemote
+            && inside(&[Action::Download, Action::CreateLocalFolder, Action::Merge])
+        {
+            *action = Action::CreateLocalFolder;
+        } else if *action == Action::DeleteLocal
+            && inside(&[Action::Upload, Action::CreateRemoteFolder])
+        {
+            *action = Action::CreateRemoteFolder;
+        }
+    }
+    let (mut deletions, mut rest): (Vec<_>, Vec<_>) = actions
+        .into_iter()
+        .partition(|(_, action)| action.deletes());
+    deletions.reverse();
+    rest.append(&mut deletions);
+    rest
+}
+
+/// Where local contents that clashed with remote ones are set aside.
+pub fn conflicted_copy(path: &str, stamp: &str) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(slash) => (&path[..=slash], &path[slash + 1..]),
+        None => ("", path),
+    };
+    let name = match name.rfind('.') {
+        Some(dot) if dot > 0 => format!(
+            "{} (conflicted copy {}){}",
+            &name[..dot],
+            stamp,
+            &name[dot..]
+        ),
+        _ => format!("{} (conflicted copy {})", name, stamp),
+    };
+    format!("{}{}", dir, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Action, Local, Remote, Synced};
+    use uuid::Uuid;
+
+    fn remote(folder: bool, modified: &str) -> Remote {
+        Remote {
+            id: Uuid::nil(),
+            folder,
+            modified: modified.to_string(),
+        }
+    }
+
+    fn local(folder: bool, mtime: i64) -> Local {
+        Local {
+            folder,
+            mtime,
+            size: 1,
+        }
+    }
+
+    fn synced(folder: bool, modified: &str, mtime: i64) -> Synced {
+        Synced {
+            file_id: Uuid::nil(),
+            folder,
+            modified: modified.to_string(),
+            mtime,
+            size: 1,
+        }
+    }
+
+    #[test]
+    fn planning() {
+        let remote = BTreeMap::from([
+            ("docs".to_string(), remote(true, "t0")),
+            ("docs/new.txt".to_string(), remote(false, "t1")),
+            ("docs/same.txt".to_string(), remote(false, "t0")),
+            ("docs/remote.txt".to_string(), remote(false, "t2")),
+            ("docs/both.txt".to_string(), remote(false, "t2")),
+            ("docs/kept.txt".to_string(), remote(false, "t0")),
+            ("old".to_string(), remote(true, "t0")),
+            ("old/a.txt".to_string(), remote(false, "t0")),
+        ]);
+        let local = BTreeMap::from([
+            ("docs".to_string(), local(true, 0)),
+            ("docs/same.txt".to_string(), local(false, 0)),
+            ("docs/remote.txt".to_string(), local(false, 0)),
+            ("docs/both.txt".to_string(), local(false, 5)),
+            ("docs/mine.txt".to_string(), local(false, 0)),
+            ("gone".to_string(), local(true, 0)),
+            ("gone/b.txt".to_string(), local(false, 0)),
+        ]);
+        let synced = BTreeMap::from([
+            ("docs".to_string(), synced(true, "t0", 0)),
+            ("docs/same.txt".to_string(), synced(false, "t0", 0)),
+            ("docs/remote.txt".to_string(), synced(false, "t0", 0)),
+            ("docs/both.txt".to_string(), synced(false, "t0", 0)),
+            ("docs/kept.txt".to_string(), synced(false, "t0", 0)),
+            ("docs/vanished.txt".to_string(), synced(false, "t0", 0)),
+            ("old".to_string(), synced(true, "t0", 0)),
+            ("old/a.txt".to_string(), synced(false, "t0", 0)),
+            ("gone".to_string(), synced(true, "t0", 0)),
+            ("gone/b.txt".to_string(), synced(false, "t0", 0)),
+        ]);
+        let actions = super::plan(&remote, &local, &synced);
+        let expected = [
+            ("docs/both.txt", Action::Merge),
+            ("docs/mine.txt", Action::Upload),
+            ("docs/new.txt", Action::Download),
+            ("docs/remote.txt", Action::Download),
+            ("docs/vanished.txt", Action::Forget),
+            ("old/a.txt", Action::DeleteRemote),
+            ("old", Action::DeleteRemote),
+            ("gone/b.txt", Action::DeleteLocal),
+            ("gone", Action::DeleteLocal),
+            ("docs/kept.txt", Action::DeleteRemote),
+        ];
+        assert_eq!(
+            actions,
+            expected.map(|(path, action)| (path.to_string(), action))
+        );
+    }
+
+    #[test]
+    fn deleted_folders_survive_changes_inside() {
+        let remote = BTreeMap::from([
+            ("shared".to_string(), remote(true, "t0")),
+            ("shared/edited.txt".to_string(), remote(false, "t1")),
+        ]);
+        let synced = BTreeMap::from([
+            ("shared".to_string(), synced(true, "t0", 0)),
+            ("shared/edited.txt".to_string(), synced(false, "t0", 0)),
+        ]);
+        let actions = super::plan(&remote, &BTreeMap::new(), &synced);
+        assert_eq!(
+            actions,
+            [
+                ("shared".to_string(), Action::CreateLocalFolder),
+                ("shared/edited.txt".to_string(), Action::Download),
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicted_copies() {
+        assert_eq!(
+            super::conflicted_copy("docs/report.pdf", "2025-12-30 101500"),
+            "docs/report (conflicted copy 2025-12-30 101500).pdf"
+        );
+        assert_eq!(
+            super::conflicted_copy("README", "2025-12-30 101500"),
+            "README (conflicted copy 2025-12-30 101500)"
+        );
+    }
+}
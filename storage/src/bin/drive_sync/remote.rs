@@ -0,0 +1,331 @@
+//! The server's side of syncing, spoken over the same routes as any other client.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::StreamExt;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, header};
+use serde::Deserialize;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::Error;
+use crate::plan::Remote;
+
+/// The version of the tus protocol uploads are made with.
+const TUS_VERSION: &str = "1.0.0";
+
+/// How many times an upload is resumed after failing before it is given up.
+const UPLOAD_RETRIES: usize = 3;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct File {
+    pub id: Uuid,
+    pub name: String,
+    pub path: Option<String>,
+    pub parent_id: Option<Uuid>,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delta {
+    pub changes: Vec<serde_json::Value>,
+    pub cursor: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthOk {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    server: String,
+    login: String,
+    password: String,
+    token: String,
+}
+
+/// Fails on anything but a success, with the message the server gave.
+async fn check(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .json::<ErrorResponse>()
+        .await
+        .map(|e| e.message)
+        .unwrap_or_default();
+    Err(Error::Status(status, message))
+}
+
+/// Where the contents of `destination` are received before taking its place.
+/// Ignored by the filter, so that half a file is never uploaded.
+pub fn part(destination: &Path) -> PathBuf {
+    let name = destination
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    destination.with_file_name(format!(".{}.sync-part", name))
+}
+
+fn upload_offset(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get("Upload-Offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+impl Client {
+    pub async fn login(server: &str, login: &str, password: &str) -> Result<Client, Error> {
+        let mut client = Client {
+            http: reqwest::Client::new(),
+            server: server.trim_end_matches('/').to_string(),
+            login: login.to_string(),
+            password: password.to_string(),
+            token: String::new(),
+        };
+        client.relogin().await?;
+        Ok(client)
+    }
+
+    /// Gets a fresh access token, the previous one having expired.
+    pub async fn relogin(&mut self) -> Result<(), Error> {
+        let response = self
+            .http
+            .post(format!("{}/auth/login", self.server))
+            .json(&serde_json::json!({ "login": self.login, "password": self.password }))
+            .send()
+            .await?;
+        self.token = check(response).await?.json::<AuthOk>().await?.access_token;
+        tracing::info!("Logged in as {}", self.login);
+        Ok(())
+    }
+
+    fn request(&self, method: Method, route: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.server, route))
+            .bearer_auth(&self.token)
+    }
+
+    async fn list(&self, route: &str) -> Result<Vec<File>, Error> {
+        let response = self.request(Method::GET, route).send().await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Lists the whole drive by path, such as `docs/report.pdf`.
+    pub async fn tree(&self) -> Result<BTreeMap<String, Remote>, Error> {
+        let mut files = self.list(&format!("/folder/{}", Uuid::nil())).await?;
+        let folders: Vec<Uuid> = files
+            .iter()
+            .filter(|f| f.path.is_none())
+            .map(|f| f.id)
+            .collect();
+        for folder in folders {
+            let subtree = self.list(&format!("/folder/{}/tree", folder)).await?;
+            files.extend(subtree.into_iter().filter(|f| f.id != folder));
+        }
+        let by_id: HashMap<Uuid, &File> = files.iter().map(|f| (f.id, f)).collect();
+        let mut tree = BTreeMap::new();
+        for file in &files {
+            let mut names = vec![file.name.as_str()];
+            let mut parent = file.parent_id;
+            while let Some(parent_id) = parent {
+                let Some(folder) = by_id.get(&parent_id) else {
+                    break;
+                };
+                names.push(&folder.name);
+                parent = folder.parent_id;
+            }
+            if parent.is_some() {
+                tracing::warn!("Could not place {} in the drive", file.id);
+                continue;
+            }
+            names.reverse();
+            let remote = Remote {
+                id: file.id,
+                folder: file.path.is_none(),
+                modified: file
+                    .edited_at
+                    .clone()
+                    .unwrap_or_else(|| file.created_at.clone()),
+            };
+            tree.insert(names.join("/"), remote);
+        }
+        Ok(tree)
+    }
+
+    /// Looks up an entry of a folder by name, the nil UUID being the root folder.
+    pub async fn find(&self, parent_id: &Uuid, name: &str) -> Result<Option<Remote>, Error> {
+        let files = self.list(&format!("/folder/{}", parent_id)).await?;
+        Ok(files
+            .into_iter()
+            .find(|f| f.name == name)
+            .map(|file| Remote {
+                id: file.id,
+                folder: file.path.is_none(),
+                modified: file.edited_at.unwrap_or(file.created_at),
+            }))
+    }
+
+    /// Saves the contents of a file to `destination`, which is only replaced once all of them
+    /// made it to disk.
+    pub async fn download(&self, file_id: &Uuid, destination: &Path) -> Result<(), Error> {
+        let part = part(destination);
+        let received = self.receive(file_id, &part).await;
+        if received.is_err() {
+            let _ = tokio::fs::remove_file(&part).await;
+        }
+        received?;
+        tokio::fs::rename(&part, destination).await?;
+        Ok(())
+    }
+
+    async fn receive(&self, file_id: &Uuid, part: &Path) -> Result<(), Error> {
+        let response = self
+            .request(Method::GET, &format!("/download/{}", file_id))
+            .send()
+            .await?;
+        let mut stream = check(response).await?.bytes_stream();
+        let mut file = tokio::fs::File::create(part).await?;
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    fn tus(&self, method: Method, route: &str) -> RequestBuilder {
+        self.request(method, route)
+            .header("Tus-Resumable", TUS_VERSION)
+    }
+
+    /// Uploads `source` as `name` into a folder, the nil UUID being the root folder.
+    /// Goes through resumable uploads, so that files of any size make it, picking up where
+    /// an interrupted request left off.
+    pub async fn upload(&self, parent_id: &Uuid, name: &str, source: &Path) -> Result<(), Error> {
+        let length = tokio::fs::metadata(source).await?.len();
+        let metadata = format!(
+            "filename {},parentId {}",
+            STANDARD.encode(name),
+            STANDARD.encode(parent_id.to_string())
+        );
+        let response = self
+            .tus(Method::POST, "/uploads")
+            .header("Upload-Length", length)
+            .header("Upload-Metadata", metadata)
+            .send()
+            .await?;
+        let response = check(response).await?;
+        let route = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Error::Status(
+                response.status(),
+                String::from("The upload was made without a location"),
+            ))?
+            .to_string();
+        let mut offset = 0;
+        let mut retries = 0;
+        while offset < length {
+            match self.append(&route, source, offset, length).await {
+                Ok(appended) => offset = appended,
+                Err(e) if retries < UPLOAD_RETRIES => {
+                    retries += 1;
+                    tracing::warn!("Resuming the upload of {}: {}", name, e);
+                    offset = self.offset(&route).await?;
+                }
+                Err(e) => {
+                    let _ = self.tus(Method::DELETE, &route).send().await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the rest of `source` from `offset` on, returning the offset the server got to.
+    async fn append(
+        &self,
+        route: &str,
+        source: &Path,
+        offset: u64,
+        length: u64,
+    ) -> Result<u64, Error> {
+        let mut file = tokio::fs::File::open(source).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+        let response = self
+            .tus(Method::PATCH, route)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header(header::CONTENT_LENGTH, length - offset)
+            .header("Upload-Offset", offset)
+            .body(body)
+            .send()
+            .await?;
+        let response = check(response).await?;
+        upload_offset(&response).ok_or(Error::Status(
+            response.status(),
+            String::from("The upload offset is missing"),
+        ))
+    }
+
+    /// Asks how much of an upload the server has.
+    async fn offset(&self, route: &str) -> Result<u64, Error> {
+        let response = check(self.tus(Method::HEAD, route).send().await?).await?;
+        upload_offset(&response).ok_or(Error::Status(
+            response.status(),
+            String::from("The upload offset is missing"),
+        ))
+    }
+
+    pub async fn create_folder(&self, parent_id: &Uuid, name: &str) -> Result<File, Error> {
+        let response = self
+            .request(Method::POST, "/folder")
+            .json(&serde_json::json!({ "name": name, "parentId": parent_id }))
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Moves a file to the trash. Files already gone are not an error.
+    pub async fn delete(&self, file_id: &Uuid) -> Result<(), Error> {
+        let response = self
+            .request(Method::DELETE, &format!("/files/{}", file_id))
+            .send()
+            .await?;
+        match check(response).await {
+            Ok(_) | Err(Error::Status(StatusCode::NOT_FOUND, _)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the change feed after `cursor`, or just gets the latest cursor.
+    /// Returns `None` when the cursor is too old to be served.
+    pub async fn changes(&self, cursor: Option<i64>) -> Result<Option<Delta>, Error> {
+        let route = match cursor {
+            Some(cursor) => format!("/changes?cursor={}", cursor),
+            None => String::from("/changes"),
+        };
+        let response = self.request(Method::GET, &route).send().await?;
+        match check(response).await {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(Error::Status(StatusCode::GONE, _)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
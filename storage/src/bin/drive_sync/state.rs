@@ -0,0 +1,107 @@
+//! The local state database, remembering what both sides looked like when last in sync.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, params};
+use uuid::Uuid;
+
+use crate::plan::Synced;
+
+/// Kept in the local folder, and never synced.
+pub const STATE_FILE: &str = ".sync-state.db";
+
+pub struct State {
+    conn: Connection,
+}
+
+impl State {
+    pub fn open(path: &Path) -> rusqlite::Result<State> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS entries(
+                path TEXT PRIMARY KEY,
+                file_id TEXT NOT NULL,
+                folder INTEGER NOT NULL,
+                modified TEXT NOT NULL,
+                mtime INTEGER NOT NULL,
+                size INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS meta(
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            "#,
+        )?;
+        Ok(State { conn })
+    }
+
+    pub fn entries(&self) -> rusqlite::Result<BTreeMap<String, Synced>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT path, file_id, folder, modified, mtime, size FROM entries;")?;
+        let rows = statement.query_map([], |row| {
+            let file_id: String = row.get(1)?;
+            Ok((
+                row.get::<_, String>(0)?,
+                Synced {
+                    file_id: file_id.parse().unwrap_or(Uuid::nil()),
+                    folder: row.get(2)?,
+                    modified: row.get(3)?,
+                    mtime: row.get(4)?,
+                    size: row.get::<_, i64>(5)? as u64,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+
+    pub fn put(&self, path: &str, synced: &Synced) -> rusqlite::Result<()> {
+        self.conn.execute(
+            r#"
+            INSERT INTO entries (path, file_id, folder, modified, mtime, size)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (path) DO UPDATE
+            SET file_id = ?2, folder = ?3, modified = ?4, mtime = ?5, size = ?6;
+            "#,
+            params![
+                path,
+                synced.file_id.to_string(),
+                synced.folder,
+                synced.modified,
+                synced.mtime,
+                synced.size as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &str) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM entries WHERE path = ?1;", params![path])?;
+        Ok(())
+    }
+
+    /// Where the change feed was last read up to.
+    pub fn cursor(&self) -> rusqlite::Result<Option<i64>> {
+        let value: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = 'cursor';", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value.and_then(|v| v.parse().ok()))
+    }
+
+    pub fn set_cursor(&self, cursor: i64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            r#"
+            INSERT INTO meta (key, value) VALUES ('cursor', ?1)
+            ON CONFLICT (key) DO UPDATE SET value = ?1;
+            "#,
+            params![cursor.to_string()],
+        )?;
+        Ok(())
+    }
+}
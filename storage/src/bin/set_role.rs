@@ -0,0 +1,41 @@
+use std::env;
+
+use storage::db::user::{self, Role};
+
+/// Gives users a role, such as making the first administrator of a server.
+#[tokio::main]
+async fn main() {
+    let usage = || -> ! {
+        eprintln!("usage: DATABASE_URL=... set_role <admin|user> <login>...");
+        std::process::exit(1);
+    };
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| usage());
+    let mut args = env::args().skip(1);
+    let name = args.next().unwrap_or_else(|| usage());
+    let role = match name.as_str() {
+        "admin" => Role::Admin,
+        "user" => Role::User,
+        _ => usage(),
+    };
+    let logins: Vec<String> = args.collect();
+    if logins.is_empty() {
+        usage();
+    }
+    let pool = sqlx::PgPool::connect(&database_url)
+        .await
+        .expect("connecting to the database failed");
+    for login in logins {
+        match user::find_by_login(&pool, &login)
+            .await
+            .expect("looking up the user failed")
+        {
+            Some(found) => {
+                user::set_role(&pool, &found.id, role)
+                    .await
+                    .expect("setting the role failed");
+                println!("{} is now {}", login, name);
+            }
+            None => println!("No user {}", login),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::env;
+
+use storage::db::login_failure::{self, Kind};
+
+/// Lifts the lockout of login names, of client addresses given after `--ip`
+/// or of share link ids given after `--link`, forgetting the attempts that failed for them.
+#[tokio::main]
+async fn main() {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: DATABASE_URL=... unlock_login [--ip | --link] <login, address or link id>..."
+        );
+        std::process::exit(1);
+    };
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| usage());
+    let mut kind = Kind::Login;
+    let mut subjects = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--ip" => kind = Kind::Ip,
+            "--link" => kind = Kind::Link,
+            _ => subjects.push((kind, arg)),
+        }
+    }
+    if subjects.is_empty() {
+        usage();
+    }
+    let pool = sqlx::PgPool::connect(&database_url)
+        .await
+        .expect("connecting to the database failed");
+    for (kind, subject) in subjects {
+        let cleared = login_failure::clear(&pool, kind, &subject)
+            .await
+            .expect("unlocking failed");
+        if cleared {
+            println!("Unlocked {} {}", kind.as_str(), subject);
+        } else {
+            println!("No failed logins for {} {}", kind.as_str(), subject);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Content-addressed storage: every distinct content is kept once under `blobs/`, named by its SHA-256.
+//!
+//! Where the contents actually live is up to a [`BlobStore`], picked with the `BLOB_STORE` variable.
+
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sha2::{Digest as _, Sha256};
+use sqlx::{PgConnection, PgPool};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::api::{Error, Shared, var_or};
+use crate::db;
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Somewhere to keep blob contents, addressed by keys such as `blobs/ab/cd/abcd...`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Writes everything `contents` yields under `key`, replacing what was there.
+    /// Returns the number of bytes written.
+    async fn put(&self, key: &str, contents: Reader) -> io::Result<u64>;
+
+    /// Reads the blob under `key`, or just `range` of it.
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader>;
+
+    /// Removes the blob under `key`. Removing a blob that is not there is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    async fn size(&self, key: &str) -> io::Result<u64>;
+
+    /// The keys of every blob whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Moves the local file at `path` into the store under `key`.
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<u64> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = self.put(key, Box::pin(file)).await?;
+        tokio::fs::remove_file(path).await?;
+        Ok(len)
+    }
+}
+
+/// Builds the blob store selected by `BLOB_STORE`: `local` (the default) keeps blobs under `root`,
+/// `memory` keeps them in memory until shutdown and `s3` uses an S3-compatible service.
+pub fn from_env(root: &Path) -> Result<Arc<dyn BlobStore>, Error> {
+    match var_or("BLOB_STORE", String::from("local"))?.as_str() {
+        "local" => Ok(Arc::new(local::LocalStore::new(root))),
+        "memory" => Ok(Arc::new(memory::MemoryStore::default())),
+        "s3" => Ok(Arc::new(s3::S3Store::new(&s3::Config::from_env()?)?)),
+        other => Err(Error::Configuration(format!(
+            "Unknown blob store \"{}\"",
+            other
+        ))),
+    }
+}
+
+/// The hash and length of some contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digest {
+    pub hash: String,
+    pub size: i64,
+}
+
+/// Computes a [`Digest`] from chunks as they come in.
+#[derive(Default)]
+pub struct Hasher {
+    sha: Sha256,
+    size: i64,
+}
+
+impl Hasher {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.sha.update(chunk);
+        self.size += chunk.len() as i64;
+    }
+
+    pub fn finish(self) -> Digest {
+        Digest {
+            hash: format!("{:x}", self.sha.finalize()),
+            size: self.size,
+        }
+    }
+}
+
+/// Where the blob with `hash` is stored, relative to `Shared::root`.
+/// Blobs are fanned out over two levels of directories to keep them small.
+pub fn path(hash: &str) -> PathBuf {
+    PathBuf::new()
+        .join("blobs")
+        .join(&hash[..2])
+        .join(&hash[2..4])
+        .join(hash)
+}
+
+pub async fn hash(mut contents: Reader) -> io::Result<Digest> {
+    let mut hasher = Hasher::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = contents.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Moves the local file at `temp` into the blob store, unless the same contents are already there.
+/// The blob is only referenced once a version pointing at the returned path is created.
+/// Should that never be committed, the contents are left for [`sweep_orphans`].
+pub(crate) async fn ingest(
+    shared: &Shared,
+    conn: &mut PgConnection,
+    temp: &Path,
+    digest: Option<Digest>,
+) -> Result<(PathBuf, Digest), Error> {
+    let temp = shared.root.join(temp);
+    let digest = match digest {
+        Some(digest) => digest,
+        None => hash(Box::pin(tokio::fs::File::open(&temp).await?)).await?,
+    };
+    let path = path(&digest.hash);
+    let key = path.to_string_lossy();
+    let inserted = db::blob::claim(&mut *conn, &digest.hash, digest.size).await?;
+    if inserted || !shared.blobs.exists(&key).await? {
+        tracing::debug!("Storing blob {}", digest.hash);
+        shared.blobs.put_file(&key, &temp).await?;
+    } else {
+        tracing::debug!("Blob {} is already stored", digest.hash);
+        tokio::fs::remove_file(&temp).await?;
+    }
+    Ok((path, digest))
+}
+
+/// Removes the blobs no version refers to any more.
+///
+/// Blobs are marked as being collected before their contents go, each under a lock on its row,
+/// so that a blob claimed meanwhile is either spared or written again.
+pub async fn collect_garbage(shared: &Shared) -> Result<usize, Error> {
+    let hashes = db::blob::mark_unreferenced(&shared.pool).await?;
+    let mut collected = 0;
+    for hash in &hashes {
+        let mut tx = shared.pool.begin().await?;
+        if db::blob::release(&mut *tx, hash).await? {
+            shared.blobs.delete(&path(hash).to_string_lossy()).await?;
+            collected += 1;
+        }
+        tx.commit().await?;
+    }
+    Ok(collected)
+}
+
+/// Removes the contents stored without a blob recorded for them,
+/// as left behind by a transaction that rolled back after [`ingest`].
+pub async fn sweep_orphans(shared: &Shared) -> Result<usize, Error> {
+    let hashes: Vec<String> = shared
+        .blobs
+        .list("blobs/")
+        .await?
+        .into_iter()
+        .filter_map(|key| {
+            let hash = key.rsplit('/').next()?;
+            (hash.len() == 64
+                && hash.bytes().all(|b| b.is_ascii_hexdigit())
+                && path(hash) == Path::new(&key))
+            .then(|| hash.to_string())
+        })
+        .collect();
+    let mut swept = 0;
+    for hash in db::blob::unrecorded(&shared.pool, &hashes).await? {
+        let mut tx = shared.pool.begin().await?;
+        // An ingest still under way has recorded the blob, and is waited for.
+        if db::blob::hold(&mut *tx, &hash).await? {
+            shared.blobs.delete(&path(&hash).to_string_lossy()).await?;
+            db::blob::release(&mut *tx, &hash).await?;
+            swept += 1;
+        }
+        tx.commit().await?;
+    }
+    Ok(swept)
+}
+
+/// Copies a blob stored before deduplication to where its contents belong, leaving the original.
+/// Returns the new key, which is only referenced once a version pointing at it is created.
+pub(crate) async fn adopt(
+    blobs: &dyn BlobStore,
+    conn: &mut PgConnection,
+    key: &str,
+) -> Result<(String, Digest), Error> {
+    let digest = hash(blobs.get(key, None).await?).await?;
+    let path = path(&digest.hash).to_string_lossy().into_owned();
+    let inserted = db::blob::claim(&mut *conn, &digest.hash, digest.size).await?;
+    if inserted || !blobs.exists(&path).await? {
+        blobs.put(&path, blobs.get(key, None).await?).await?;
+    }
+    Ok((path, digest))
+}
+
+/// Moves the blobs stored one per upload into the content-addressed layout,
+/// deduplicating them on the way. Returns how many versions were rehashed.
+pub async fn rehash_legacy(pool: &PgPool, blobs: &dyn BlobStore) -> Result<usize, Error> {
+    db::version::adopt_unversioned(pool).await?;
+    let versions = db::version::unhashed(pool).await?;
+    let mut rehashed = 0;
+    for version in &versions {
+        if !blobs.exists(&version.path).await? {
+            tracing::warn!("Blob of version {} is missing", version.id);
+            continue;
+        }
+        let mut tx = pool.begin().await?;
+        let (path, digest) = adopt(blobs, &mut tx, &version.path).await?;
+        db::version::rehash(&mut *tx, &version.id, &path, digest.size, &digest.hash).await?;
+        db::file::relocate(&mut *tx, &version.path, &path).await?;
+        tx.commit().await?;
+        blobs.delete(&version.path).await?;
+        tracing::info!("Rehashed version {} as {}", version.id, digest.hash);
+        rehashed += 1;
+    }
+    Ok(rehashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hasher;
+
+    #[test]
+    fn hashing() {
+        let mut hasher = Hasher::default();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let digest = hasher.finish();
+        assert_eq!(
+            digest.hash,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(digest.size, 11);
+        assert_eq!(
+            super::path(&digest.hash),
+            std::path::Path::new("blobs/b9/4d").join(&digest.hash)
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Blobs kept as plain files under a root directory, each key being a relative path.
+
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{BlobStore, Reader};
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> LocalStore {
+        LocalStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    async fn create_parent(path: &Path) -> io::Result<()> {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, mut contents: Reader) -> io::Result<u64> {
+        let path = self.path(key);
+        Self::create_parent(&path).await?;
+        // Readers never see a half-written blob: it only takes its place once complete.
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(".{}.part", uuid::Uuid::new_v4().simple()));
+        let result = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let len = tokio::io::copy(&mut contents, &mut file).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(len)
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        result
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+        match range {
+            Some(range) => {
+                file.seek(io::SeekFrom::Start(range.start)).await?;
+                Ok(Box::pin(file.take(range.end - range.start)))
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)).await
+    }
+
+    async fn size(&self, key: &str) -> io::Result<u64> {
+        Ok(tokio::fs::metadata(self.path(key)).await?.len())
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![match prefix.rfind('/') {
+            Some(end) => self.path(&prefix[..end]),
+            None => self.root.clone(),
+        }];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                entries => entries?,
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let Some(key) = path
+                    .strip_prefix(&self.root)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .map(|key| key.replace(std::path::MAIN_SEPARATOR, "/"))
+                else {
+                    continue;
+                };
+                // Blobs still being written are not blobs yet.
+                if key.starts_with(prefix) && !key.ends_with(".part") {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<u64> {
+        let target = self.path(key);
+        Self::create_parent(&target).await?;
+        let len = tokio::fs::metadata(path).await?.len();
+        tokio::fs::rename(path, &target).await?;
+        Ok(len)
+    }
+}
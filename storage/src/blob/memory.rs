@@ -0,0 +1,78 @@
+//! Blobs kept in memory, mostly useful for tests.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+
+use super::{BlobStore, Reader};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryStore {
+    fn find(&self, key: &str) -> io::Result<Bytes> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryStore {
+    async fn put(&self, key: &str, mut contents: Reader) -> io::Result<u64> {
+        let mut buf = Vec::new();
+        contents.read_to_end(&mut buf).await?;
+        let len = buf.len() as u64;
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), Bytes::from(buf));
+        Ok(len)
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader> {
+        let blob = self.find(key)?;
+        let blob = match range {
+            Some(range) => {
+                let len = blob.len() as u64;
+                blob.slice(range.start.min(len) as usize..range.end.min(len) as usize)
+            }
+            None => blob,
+        };
+        Ok(Box::pin(io::Cursor::new(blob)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.blobs.lock().unwrap().contains_key(key))
+    }
+
+    async fn size(&self, key: &str) -> io::Result<u64> {
+        Ok(self.find(key)?.len() as u64)
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .blobs
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,132 @@
+//! Blobs kept as objects in a bucket of an S3-compatible service.
+
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path;
+use object_store::{GetOptions, GetRange, ObjectStore};
+use tokio::io::AsyncWriteExt;
+
+use super::{BlobStore, Reader};
+use crate::api::{Error, var_or};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bucket: String,
+    /// The service to talk to, AWS itself when unset.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, Error> {
+        Ok(Config {
+            bucket: std::env::var("S3_BUCKET")?,
+            endpoint: std::env::var("S3_ENDPOINT").ok(),
+            region: var_or("S3_REGION", String::from("us-east-1"))?,
+            access_key_id: std::env::var("S3_ACCESS_KEY_ID")?,
+            secret_access_key: std::env::var("S3_SECRET_ACCESS_KEY")?,
+        })
+    }
+}
+
+pub struct S3Store {
+    client: Arc<dyn ObjectStore>,
+}
+
+fn io_error(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+impl S3Store {
+    pub fn new(config: &Config) -> io::Result<S3Store> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(&config.bucket)
+            .with_region(&config.region)
+            .with_access_key_id(&config.access_key_id)
+            .with_secret_access_key(&config.secret_access_key);
+        if let Some(endpoint) = &config.endpoint {
+            // Self-hosted services such as MinIO are usually reached by path and over plain HTTP.
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(true)
+                .with_virtual_hosted_style_request(false);
+        }
+        Ok(S3Store {
+            client: Arc::new(builder.build().map_err(io_error)?),
+        })
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, mut contents: Reader) -> io::Result<u64> {
+        // Small blobs go up in a single request, bigger ones as a multipart upload.
+        let mut writer = BufWriter::new(self.client.clone(), Path::from(key));
+        let len = match tokio::io::copy(&mut contents, &mut writer).await {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(e);
+            }
+        };
+        writer.shutdown().await?;
+        Ok(len)
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader> {
+        let options = GetOptions {
+            range: range.map(GetRange::Bounded),
+            ..Default::default()
+        };
+        let result = self
+            .client
+            .get_opts(&Path::from(key), options)
+            .await
+            .map_err(io_error)?;
+        let stream = result.into_stream().map_err(io_error);
+        Ok(Box::pin(tokio_util::io::StreamReader::new(stream)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.client.delete(&Path::from(key)).await {
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            result => result.map_err(io_error),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.client.head(&Path::from(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn size(&self, key: &str) -> io::Result<u64> {
+        let meta = self.client.head(&Path::from(key)).await.map_err(io_error)?;
+        Ok(meta.size)
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // Object store paths are listed by whole segments, so a partial last one is filtered here.
+        let (dir, _) = prefix.rsplit_once('/').unwrap_or_default();
+        self.client
+            .list(Some(&Path::from(dir)))
+            .map_ok(|meta| meta.location.to_string())
+            .try_filter(|key| std::future::ready(key.starts_with(prefix)))
+            .try_collect()
+            .await
+            .map_err(io_error)
+    }
+}
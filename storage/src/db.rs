@@ -1,7 +1,33 @@
+pub mod blob;
+pub mod change;
 pub mod config;
+pub mod email_verification;
 pub mod file;
+pub mod identity;
+pub mod invite;
+pub mod link;
+pub mod login_failure;
+pub mod oidc_login;
+pub mod permission;
+pub mod personal_token;
+pub mod session;
+pub mod signing_key;
+pub mod token;
+pub mod two_factor;
+pub mod upload;
 pub mod user;
+pub mod version;
 
+pub use change::Change;
 pub use config::Config;
 pub use file::File;
+pub use invite::Invite;
+pub use link::Link;
+pub use permission::{Permission, Role};
+pub use personal_token::PersonalToken;
+pub use session::Session;
+pub use signing_key::SigningKey;
+pub use two_factor::TwoFactor;
+pub use upload::Upload;
 pub use user::User;
+pub use version::Version;
@@ -0,0 +1,92 @@
+use sqlx::{Executor, Postgres, Result};
+
+/// Records a blob, or finds the one already stored with the same hash,
+/// taking it back if it is being collected.
+/// Returns whether the blob is new, in which case its contents still have to be written.
+pub async fn claim<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    hash: &str,
+    size: i64,
+) -> Result<bool> {
+    let rec = sqlx::query!(
+        r#"
+        INSERT INTO blobs (hash, size)
+        VALUES ($1, $2)
+        ON CONFLICT (hash) DO UPDATE SET size = EXCLUDED.size, collecting = false
+        RETURNING (xmax = 0) AS "inserted!";
+        "#,
+        hash,
+        size
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.inserted)
+}
+
+/// Marks every blob no version refers to any more as being collected, returning their hashes.
+pub async fn mark_unreferenced<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+) -> Result<Vec<String>> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE blobs
+        SET collecting = true
+        WHERE refs <= 0
+        RETURNING hash;
+        "#
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Forgets a blob marked as being collected, unless it has been claimed since.
+/// The row stays locked until the end of the transaction, so nothing can claim it meanwhile.
+/// Returns whether the blob was forgotten.
+pub async fn release<'e, E: Executor<'e, Database = Postgres>>(e: E, hash: &str) -> Result<bool> {
+    let rec = sqlx::query_scalar!(
+        r#"
+        DELETE FROM blobs
+        WHERE hash = $1 AND collecting AND refs <= 0
+        RETURNING hash;
+        "#,
+        hash
+    )
+    .fetch_optional(e)
+    .await?;
+    Ok(rec.is_some())
+}
+
+/// Of `hashes`, the ones without a blob recorded.
+pub async fn unrecorded<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    hashes: &[String],
+) -> Result<Vec<String>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT hash AS "hash!"
+        FROM unnest($1::TEXT[]) AS listed(hash)
+        WHERE NOT EXISTS (SELECT 1 FROM blobs WHERE blobs.hash = listed.hash);
+        "#,
+        hashes
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Records a blob that is about to be collected, unless it is recorded already.
+/// Waits for a transaction recording the same blob to end first.
+/// Returns whether the blob was recorded, to be forgotten with [`release`].
+pub async fn hold<'e, E: Executor<'e, Database = Postgres>>(e: E, hash: &str) -> Result<bool> {
+    let rec = sqlx::query_scalar!(
+        r#"
+        INSERT INTO blobs (hash, size, collecting)
+        VALUES ($1, 0, true)
+        ON CONFLICT (hash) DO NOTHING
+        RETURNING hash;
+        "#,
+        hash
+    )
+    .fetch_optional(e)
+    .await?;
+    Ok(rec.is_some())
+}
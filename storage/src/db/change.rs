@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+/// What happened to a file. Changes are journaled by a trigger on `files`.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "change_kind", rename_all = "lowercase")]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Create,
+    Edit,
+    Move,
+    Rename,
+    Delete,
+    Restore,
+}
+
+/// An entry of the change journal, along with where the file stood right after it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub seq: i64,
+    pub file_id: Uuid,
+    pub kind: ChangeKind,
+    pub name: String,
+    pub parent_id: Option<Uuid>,
+    pub folder: bool,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Lists up to `limit` changes to the user's drive made after `cursor`, oldest first.
+pub async fn since<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    cursor: i64,
+    limit: i64,
+) -> Result<Vec<Change>> {
+    sqlx::query_as!(
+        Change,
+        r#"
+        SELECT seq, file_id, kind AS "kind: ChangeKind", name, parent_id, folder, changed_at
+        FROM changes
+        WHERE user_id = $1 AND seq > $2
+        ORDER BY seq
+        LIMIT $3;
+        "#,
+        user_id,
+        cursor,
+        limit
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// The cursor pointing past the newest change to the user's drive.
+pub async fn latest<'e, E: Executor<'e, Database = Postgres>>(e: E, user_id: &Uuid) -> Result<i64> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT greatest(
+            (SELECT max(seq) FROM changes WHERE user_id = $1),
+            (SELECT seq FROM change_horizons WHERE user_id = $1),
+            0
+        ) AS "seq!";
+        "#,
+        user_id
+    )
+    .fetch_one(e)
+    .await
+}
+
+/// The oldest cursor that can still be served for the user.
+pub async fn horizon<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<i64> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT coalesce((SELECT seq FROM change_horizons WHERE user_id = $1), 0) AS "seq!";
+        "#,
+        user_id
+    )
+    .fetch_one(e)
+    .await
+}
+
+/// Drops the changes made before `before`, moving the horizon of every user concerned past them.
+/// Returns how many changes were dropped.
+pub async fn compact<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    before: DateTime<Utc>,
+) -> Result<i64> {
+    sqlx::query_scalar!(
+        r#"
+        WITH dropped AS (
+            DELETE FROM changes
+            WHERE changed_at < $1
+            RETURNING user_id, seq
+        ), horizons AS (
+            INSERT INTO change_horizons (user_id, seq)
+            SELECT user_id, max(seq)
+            FROM dropped
+            GROUP BY user_id
+            ON CONFLICT (user_id) DO UPDATE
+            SET seq = greatest(change_horizons.seq, excluded.seq)
+        )
+        SELECT count(*) AS "count!"
+        FROM dropped;
+        "#,
+        before
+    )
+    .fetch_one(e)
+    .await
+}
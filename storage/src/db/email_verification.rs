@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+    user_id: &Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_verifications (token_hash, user_id, expires_at)
+        VALUES ($1, $2, $3);
+        "#,
+        token_hash,
+        user_id,
+        expires_at
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Takes the user a token was mailed to, unless it expired, dropping every other token they had.
+pub async fn take<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+) -> Result<Option<Uuid>> {
+    let rec = sqlx::query!(
+        r#"
+        WITH verified AS (
+            SELECT user_id
+            FROM email_verifications
+            WHERE token_hash = $1 AND expires_at > now()
+        ), taken AS (
+            DELETE FROM email_verifications
+            WHERE user_id IN (SELECT user_id FROM verified)
+        )
+        SELECT user_id FROM verified;
+        "#,
+        token_hash
+    )
+    .fetch_optional(e)
+    .await?;
+    Ok(rec.map(|rec| rec.user_id))
+}
+
+/// Whether a token was mailed to a user since `since`.
+pub async fn sent_since<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    since: DateTime<Utc>,
+) -> Result<bool> {
+    let sent = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM email_verifications
+            WHERE user_id = $1 AND created_at > $2
+        );
+        "#,
+        user_id,
+        since
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(sent.unwrap_or(false))
+}
+
+/// Drops tokens that expired before being used, returning how many there were.
+pub async fn sweep<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM email_verifications
+        WHERE expires_at <= now();
+        "#
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
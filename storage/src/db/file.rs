@@ -3,12 +3,25 @@ use serde::Serialize;
 use sqlx::{Executor, Postgres, Result};
 use uuid::Uuid;
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Trashed {
+    pub id: Uuid,
+    pub name: String,
+    pub path: Option<String>,
+    pub parent_id: Option<Uuid>,
+    pub owned_by: Uuid,
+    pub deleted_by: Uuid,
+    pub deleted_at: DateTime<Utc>,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct File {
     pub id: Uuid,
     pub name: String,
-    pub path: Option<String>, // is null for folders
+    pub path: Option<String>,    // is null for folders
+    pub parent_id: Option<Uuid>, // is null for the root folder
     pub owned_by: Uuid,
     pub edited_by: Option<Uuid>,
     pub created_at: DateTime<Utc>,
@@ -20,22 +33,25 @@ pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
     name: &str,
     path: Option<&str>,
     owner_id: &Uuid,
+    parent_id: Option<&Uuid>,
 ) -> Result<Uuid> {
     let rec = sqlx::query!(
         r#"
-        INSERT INTO files (name, path, owned_by)
-        VALUES ($1, $2, $3)
+        INSERT INTO files (name, path, owned_by, parent_id)
+        VALUES ($1, $2, $3, $4)
         RETURNING id
         "#,
         name,
         path,
-        owner_id
+        owner_id,
+        parent_id
     )
     .fetch_one(e)
     .await?;
     Ok(rec.id)
 }
 
+/// Moves a file to the trash along with everything still alive under it.
 pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     file_id: &Uuid,
@@ -43,10 +59,19 @@ pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
 ) -> Result<()> {
     sqlx::query!(
         r#"
+        WITH RECURSIVE tree AS (
+            SELECT id FROM files WHERE id = $2
+            UNION ALL
+            SELECT f.id
+            FROM files f
+            JOIN tree t ON f.parent_id = t.id
+            WHERE f.deleted_at IS NULL
+        )
         UPDATE files
         SET deleted_by = $1,
-            deleted_at = now()
-        WHERE id = $2;
+            deleted_at = now(),
+            trashed_with = CASE WHEN id = $2 THEN NULL ELSE $2 END
+        WHERE id IN (SELECT id FROM tree);
         "#,
         user_id,
         file_id,
@@ -56,6 +81,61 @@ pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
     Ok(())
 }
 
+/// Brings a trashed file back together with whatever was deleted along with it.
+pub async fn restore<'e, E: Executor<'e, Database = Postgres>>(e: E, file_id: &Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE files
+        SET deleted_by = NULL,
+            deleted_at = NULL,
+            trashed_with = NULL
+        WHERE id = $1 OR trashed_with = $1;
+        "#,
+        file_id,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Permanently removes a file and everything under it.
+/// Returns the paths of the blobs stored before deduplication, which belonged to it alone;
+/// shared blobs are left to garbage collection.
+pub async fn purge<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Vec<String>> {
+    let paths = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE tree AS (
+            SELECT id FROM files WHERE id = $1
+            UNION ALL
+            SELECT f.id
+            FROM files f
+            JOIN tree t ON f.parent_id = t.id
+        ),
+        versions AS (
+            DELETE FROM file_versions
+            WHERE file_id IN (SELECT id FROM tree)
+            RETURNING path, hash
+        ),
+        removed AS (
+            DELETE FROM files
+            WHERE id IN (SELECT id FROM tree)
+            RETURNING path
+        )
+        SELECT path FROM versions WHERE hash IS NULL
+        UNION
+        SELECT path FROM removed
+        WHERE path NOT IN (SELECT path FROM versions WHERE hash IS NOT NULL);
+        "#,
+        file_id,
+    )
+    .fetch_all(e)
+    .await?;
+    Ok(paths.into_iter().flatten().collect())
+}
+
 pub async fn edit<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     file_id: &Uuid,
@@ -95,6 +175,26 @@ pub async fn r#move<'e, E: Executor<'e, Database = Postgres>>(
     Ok(())
 }
 
+/// Points every file stored at `from` to `to` instead.
+pub async fn relocate<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    from: &str,
+    to: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE files
+        SET path = $2
+        WHERE path = $1;
+        "#,
+        from,
+        to,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
 pub async fn rename<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     file_id: &Uuid,
@@ -121,9 +221,9 @@ pub async fn find_by_id<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         File,
         r#"
-        SELECT id, name, path, owned_by, edited_by, created_at, edited_at
+        SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at
         FROM files
-        WHERE id = $1;
+        WHERE id = $1 AND deleted_at IS NULL;
         "#,
         file_id
     )
@@ -142,9 +242,9 @@ pub async fn regex<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         File,
         r#"
-        SELECT id, name, path, owned_by, edited_by, created_at, edited_at
+        SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at
         FROM files
-        WHERE owned_by = $1 AND name ~ $2 AND deleted_by = NULL AND deleted_at = NULL
+        WHERE owned_by = $1 AND name ~ $2 AND deleted_by IS NULL AND deleted_at IS NULL
         ORDER BY $3
         LIMIT $4
         OFFSET $5;
@@ -159,26 +259,62 @@ pub async fn regex<'e, E: Executor<'e, Database = Postgres>>(
     .await
 }
 
-pub async fn folder<'e, E: Executor<'e, Database = Postgres>>(
+/// Serializes changes to the shape of a user's tree until the end of the transaction,
+/// so that concurrent moves cannot form a cycle.
+pub async fn lock_tree<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     owner_id: &Uuid,
-    name: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        SELECT FROM (SELECT pg_advisory_xact_lock(hashtextextended($1::UUID::TEXT, 0))) AS locked;
+        "#,
+        owner_id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn reparent<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    parent_id: Option<&Uuid>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE files
+        SET parent_id = $1
+        WHERE id = $2;
+        "#,
+        parent_id,
+        file_id,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Lists the contents of a folder.
+pub async fn children<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    folder_id: &Uuid,
 ) -> Result<Vec<File>> {
-    let pattern = format!("'{}{}'", name.replace("/", r"\/"), r"\/(\w|\.)+");
     sqlx::query_as!(
         File,
         r#"
-        SELECT id, name, path, owned_by, edited_by, created_at, edited_at
+        SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at
         FROM files
-        WHERE owned_by = $1 AND name ~ $2 AND deleted_by = NULL AND deleted_at = NULL;
+        WHERE parent_id = $1 AND deleted_by IS NULL AND deleted_at IS NULL
+        ORDER BY path IS NOT NULL, name;
         "#,
-        owner_id,
-        &pattern
+        folder_id
     )
     .fetch_all(e)
     .await
 }
 
+/// Lists the contents of a user's root folder.
 pub async fn root<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     owner_id: &Uuid,
@@ -186,12 +322,176 @@ pub async fn root<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         File,
         r#"
-        SELECT id, name, path, owned_by, edited_by, created_at, edited_at
+        SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at
+        FROM files
+        WHERE owned_by = $1 AND parent_id IS NULL AND deleted_by IS NULL AND deleted_at IS NULL
+        ORDER BY path IS NOT NULL, name;
+        "#,
+        owner_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Finds an entry by name in a folder, or in the owner's root folder when `parent_id` is `None`.
+pub async fn find_child<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    owner_id: &Uuid,
+    parent_id: Option<&Uuid>,
+    name: &str,
+) -> Result<Option<File>> {
+    sqlx::query_as!(
+        File,
+        r#"
+        SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at
+        FROM files
+        WHERE parent_id IS NOT DISTINCT FROM $2
+            AND ($2::UUID IS NOT NULL OR owned_by = $1)
+            AND name = $3
+            AND deleted_by IS NULL AND deleted_at IS NULL;
+        "#,
+        owner_id,
+        parent_id,
+        name
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Returns the chain of folders leading to a file, starting at the root and ending with the file itself.
+pub async fn ancestors<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Vec<File>> {
+    sqlx::query_as!(
+        File,
+        r#"
+        WITH RECURSIVE chain AS (
+            SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at, 0 AS depth
+            FROM files
+            WHERE id = $1
+            UNION ALL
+            SELECT f.id, f.name, f.path, f.parent_id, f.owned_by, f.edited_by, f.created_at, f.edited_at, c.depth + 1
+            FROM files f
+            JOIN chain c ON f.id = c.parent_id
+        )
+        SELECT id AS "id!", name AS "name!", path, parent_id, owned_by AS "owned_by!", edited_by,
+            created_at AS "created_at!", edited_at
+        FROM chain
+        ORDER BY depth DESC;
+        "#,
+        file_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Returns a file and everything nested under it, parents before their children.
+pub async fn subtree<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Vec<File>> {
+    sqlx::query_as!(
+        File,
+        r#"
+        WITH RECURSIVE tree AS (
+            SELECT id, name, path, parent_id, owned_by, edited_by, created_at, edited_at, 0 AS depth
+            FROM files
+            WHERE id = $1
+            UNION ALL
+            SELECT f.id, f.name, f.path, f.parent_id, f.owned_by, f.edited_by, f.created_at, f.edited_at, t.depth + 1
+            FROM files f
+            JOIN tree t ON f.parent_id = t.id
+            WHERE f.deleted_by IS NULL AND f.deleted_at IS NULL
+        )
+        SELECT id AS "id!", name AS "name!", path, parent_id, owned_by AS "owned_by!", edited_by,
+            created_at AS "created_at!", edited_at
+        FROM tree
+        ORDER BY depth, name;
+        "#,
+        file_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Lists what a user has put in the trash, leaving out entries deleted along with a folder.
+pub async fn trash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    owner_id: &Uuid,
+) -> Result<Vec<Trashed>> {
+    sqlx::query_as!(
+        Trashed,
+        r#"
+        SELECT id, name, path, parent_id, owned_by, deleted_by AS "deleted_by!", deleted_at AS "deleted_at!"
         FROM files
-        WHERE owned_by = $1 AND name ~ '(\w|\.)+' AND deleted_by = NULL AND deleted_at = NULL;
+        WHERE owned_by = $1 AND deleted_at IS NOT NULL AND trashed_with IS NULL
+        ORDER BY deleted_at DESC;
         "#,
         owner_id
     )
     .fetch_all(e)
     .await
 }
+
+pub async fn find_trashed<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Option<Trashed>> {
+    sqlx::query_as!(
+        Trashed,
+        r#"
+        SELECT id, name, path, parent_id, owned_by, deleted_by AS "deleted_by!", deleted_at AS "deleted_at!"
+        FROM files
+        WHERE id = $1 AND deleted_at IS NOT NULL AND trashed_with IS NULL;
+        "#,
+        file_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Finds trashed entries deleted before `before`, across all users.
+pub async fn expired_trash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    before: DateTime<Utc>,
+) -> Result<Vec<Trashed>> {
+    sqlx::query_as!(
+        Trashed,
+        r#"
+        SELECT id, name, path, parent_id, owned_by, deleted_by AS "deleted_by!", deleted_at AS "deleted_at!"
+        FROM files
+        WHERE deleted_at < $1 AND trashed_with IS NULL;
+        "#,
+        before
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Hands every file of `from` over to `to`, moving what was in the root folder of `from`
+/// into `folder_id`. Files in the trash stay there. Returns how many files changed hands.
+pub async fn transfer<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    from: &Uuid,
+    to: &Uuid,
+    folder_id: &Uuid,
+) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE files
+        SET owned_by = $2,
+            parent_id = CASE
+                WHEN parent_id IS NULL AND deleted_at IS NULL THEN $3
+                ELSE parent_id
+            END
+        WHERE owned_by = $1;
+        "#,
+        from,
+        to,
+        folder_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+/// An account at an identity provider, linked to a user.
+pub struct Identity {
+    pub subject: String,
+    pub user_id: Uuid,
+    /// When the provider stopped knowing the account.
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+/// Finds the user an account at an identity provider is linked to, marking it as logged in with.
+pub async fn login<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE identities
+        SET last_login_at = now()
+        WHERE issuer = $1 AND subject = $2
+        RETURNING user_id;
+        "#,
+        issuer,
+        subject
+    )
+    .fetch_optional(e)
+    .await
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    issuer: &str,
+    subject: &str,
+    user_id: &Uuid,
+    email: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO identities (issuer, subject, user_id, email, last_login_at)
+        VALUES ($1, $2, $3, $4, now());
+        "#,
+        issuer,
+        subject,
+        user_id,
+        email
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Lists the accounts linked at an identity provider.
+pub async fn list<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    issuer: &str,
+) -> Result<Vec<Identity>> {
+    sqlx::query_as!(
+        Identity,
+        r#"
+        SELECT subject, user_id, disabled_at
+        FROM identities
+        WHERE issuer = $1
+        ORDER BY subject;
+        "#,
+        issuer
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Disables the user of an account the identity provider no longer knows,
+/// returning who it was unless the account was already disabled.
+pub async fn suspend<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+        WITH identity AS (
+            UPDATE identities
+            SET disabled_at = now()
+            WHERE issuer = $1 AND subject = $2 AND disabled_at IS NULL
+            RETURNING user_id
+        )
+        UPDATE users u
+        SET disabled_at = coalesce(u.disabled_at, now())
+        FROM identity
+        WHERE u.id = identity.user_id
+        RETURNING u.id;
+        "#,
+        issuer,
+        subject
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Enables the user of an account that was suspended once the identity provider knows it again,
+/// returning whether they were enabled. A user disabled by other means, as by an administrator,
+/// stays disabled: only a `disabled_at` that `suspend` set is cleared.
+pub async fn restore<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    issuer: &str,
+    subject: &str,
+) -> Result<bool> {
+    let restored = sqlx::query_scalar!(
+        r#"
+        WITH suspended AS (
+            SELECT user_id, disabled_at
+            FROM identities
+            WHERE issuer = $1 AND subject = $2 AND disabled_at IS NOT NULL
+            FOR UPDATE
+        ), identity AS (
+            UPDATE identities i
+            SET disabled_at = NULL
+            FROM suspended
+            WHERE i.issuer = $1 AND i.subject = $2
+            RETURNING suspended.user_id, suspended.disabled_at
+        )
+        UPDATE users u
+        SET disabled_at = NULL
+        FROM identity
+        WHERE u.id = identity.user_id AND u.disabled_at = identity.disabled_at
+        RETURNING u.id;
+        "#,
+        issuer,
+        subject
+    )
+    .fetch_optional(e)
+    .await?;
+    Ok(restored.is_some())
+}
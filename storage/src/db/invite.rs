@@ -0,0 +1,94 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Invite {
+    pub id: Uuid,
+    /// Is none for invites anyone may use.
+    pub email: Option<String>,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub used_by: Option<Uuid>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    code_hash: &[u8],
+    email: Option<&str>,
+    created_by: &Uuid,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Invite> {
+    sqlx::query_as!(
+        Invite,
+        r#"
+        INSERT INTO invites (code_hash, email, created_by, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, email, created_by, created_at, expires_at, used_by, used_at;
+        "#,
+        code_hash,
+        email,
+        created_by,
+        expires_at
+    )
+    .fetch_one(e)
+    .await
+}
+
+/// Lists every invite, newest first.
+pub async fn list<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<Vec<Invite>> {
+    sqlx::query_as!(
+        Invite,
+        r#"
+        SELECT id, email, created_by, created_at, expires_at, used_by, used_at
+        FROM invites
+        ORDER BY created_at DESC, id;
+        "#
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Uses up the invite of a code for a new user, unless it was used, expired, or is meant for
+/// another address. Returns whether it could be used.
+pub async fn redeem<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    code_hash: &[u8],
+    user_id: &Uuid,
+    email: Option<&str>,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE invites
+        SET used_by = $2, used_at = now()
+        WHERE code_hash = $1
+            AND used_at IS NULL
+            AND (expires_at IS NULL OR expires_at > now())
+            AND (email IS NULL OR lower(email) = lower($3));
+        "#,
+        code_hash,
+        user_id,
+        email
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Withdraws an invite nobody used yet, returning whether there was one.
+pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(e: E, id: &Uuid) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM invites
+        WHERE id = $1 AND used_at IS NULL;
+        "#,
+        id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
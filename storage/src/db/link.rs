@@ -0,0 +1,160 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+/// What visitors of a link may do.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "link_mode", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum LinkMode {
+    /// Download the file, or browse the folder and download what is in it.
+    #[default]
+    ReadOnly,
+    /// Upload files into the folder without seeing what is already there.
+    UploadDrop,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    pub created_by: Uuid,
+    pub mode: LinkMode,
+    #[serde(rename = "protected", serialize_with = "is_some")]
+    pub phc: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Tells whether a link has a password without giving its hash away.
+fn is_some<S: serde::Serializer>(phc: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(phc.is_some())
+}
+
+impl Link {
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+}
+
+/// The conditions a link is usable under.
+pub struct Terms {
+    pub mode: LinkMode,
+    pub phc: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<i32>,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+    file_id: &Uuid,
+    user_id: &Uuid,
+    terms: &Terms,
+) -> Result<Link> {
+    sqlx::query_as!(
+        Link,
+        r#"
+        INSERT INTO share_links (token_hash, file_id, created_by, mode, phc, expires_at, max_downloads)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, file_id, created_by, mode AS "mode: LinkMode", phc, expires_at,
+            max_downloads, downloads, created_at;
+        "#,
+        token_hash,
+        file_id,
+        user_id,
+        terms.mode as LinkMode,
+        terms.phc,
+        terms.expires_at,
+        terms.max_downloads
+    )
+    .fetch_one(e)
+    .await
+}
+
+pub async fn find_by_hash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+) -> Result<Option<Link>> {
+    sqlx::query_as!(
+        Link,
+        r#"
+        SELECT id, file_id, created_by, mode AS "mode: LinkMode", phc, expires_at,
+            max_downloads, downloads, created_at
+        FROM share_links
+        WHERE token_hash = $1;
+        "#,
+        token_hash
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Lists the links a user made that still work, newest first.
+pub async fn active<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<Vec<Link>> {
+    sqlx::query_as!(
+        Link,
+        r#"
+        SELECT l.id, l.file_id, l.created_by, l.mode AS "mode: LinkMode", l.phc,
+            l.expires_at, l.max_downloads, l.downloads, l.created_at
+        FROM share_links l
+        JOIN files f ON f.id = l.file_id
+        WHERE l.created_by = $1
+            AND f.deleted_at IS NULL
+            AND (l.expires_at IS NULL OR l.expires_at > now())
+            AND (l.max_downloads IS NULL OR l.downloads < l.max_downloads)
+        ORDER BY l.created_at DESC;
+        "#,
+        user_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Revokes one of the user's links, returning whether there was one.
+pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    link_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM share_links
+        WHERE id = $1 AND created_by = $2;
+        "#,
+        link_id,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Counts a download against the link's limit, returning false once the limit is reached.
+pub async fn count_download<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    link_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE share_links
+        SET downloads = downloads + 1
+        WHERE id = $1 AND (max_downloads IS NULL OR downloads < max_downloads);
+        "#,
+        link_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
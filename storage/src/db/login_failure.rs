@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+
+/// What failed logins are counted against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Login,
+    Ip,
+    /// A share link, by its id, whose password is being guessed.
+    Link,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Login => "login",
+            Kind::Ip => "ip",
+            Kind::Link => "link",
+        }
+    }
+}
+
+/// Counts an attempt as failed ahead of making it, starting over if the last failure was before
+/// `forget_before`, unless the subject is locked out. Until the end of the transaction, the row
+/// stays locked and other attempts against the subject wait.
+/// Returns how many failures there are in a row, or nothing if the subject is locked out.
+pub async fn attempt<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    kind: Kind,
+    subject: &str,
+    forget_before: DateTime<Utc>,
+) -> Result<Option<i32>> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO login_failures (kind, subject, failures)
+        VALUES ($1, $2, 1)
+        ON CONFLICT (kind, subject) DO UPDATE
+        SET failures = CASE
+                WHEN login_failures.last_failed_at < $3 THEN 1
+                ELSE login_failures.failures + 1
+            END,
+            last_failed_at = now()
+        WHERE login_failures.locked_until IS NULL OR login_failures.locked_until <= now()
+        RETURNING failures;
+        "#,
+        kind.as_str(),
+        subject,
+        forget_before
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Locks a subject out until `until`, returning the time as stored.
+pub async fn lock<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    kind: Kind,
+    subject: &str,
+    until: DateTime<Utc>,
+) -> Result<DateTime<Utc>> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE login_failures
+        SET locked_until = $3
+        WHERE kind = $1 AND subject = $2
+        RETURNING locked_until AS "locked_until!";
+        "#,
+        kind.as_str(),
+        subject,
+        until
+    )
+    .fetch_one(e)
+    .await
+}
+
+/// Takes back an attempt counted by [`attempt`] that did not fail,
+/// along with the lockout it brought about, if it is still the one in place.
+pub async fn withdraw<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    kind: Kind,
+    subject: &str,
+    locked_until: Option<DateTime<Utc>>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE login_failures
+        SET failures = GREATEST(failures - 1, 0),
+            locked_until = CASE WHEN locked_until = $3 THEN NULL ELSE locked_until END
+        WHERE kind = $1 AND subject = $2;
+        "#,
+        kind.as_str(),
+        subject,
+        locked_until
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Forgets the failures of a subject, returning whether there were any.
+pub async fn clear<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    kind: Kind,
+    subject: &str,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM login_failures
+        WHERE kind = $1 AND subject = $2;
+        "#,
+        kind.as_str(),
+        subject
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Drops failures last seen before `before` that no longer lock anything out, returning how many there were.
+pub async fn sweep<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    before: DateTime<Utc>,
+) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM login_failures
+        WHERE last_failed_at < $1 AND (locked_until IS NULL OR locked_until <= now());
+        "#,
+        before
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,67 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+pub struct OidcLogin {
+    pub nonce: String,
+    pub code_verifier: String,
+    pub user_id: Option<Uuid>,
+    pub device_name: Option<String>,
+    /// Whether to hand the session over in cookies, as to a browser.
+    pub cookies: bool,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    state_hash: &[u8],
+    login: &OidcLogin,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO oidc_logins (state_hash, nonce, code_verifier, user_id, device_name, cookies, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
+        "#,
+        state_hash,
+        login.nonce,
+        login.code_verifier,
+        login.user_id,
+        login.device_name,
+        login.cookies,
+        expires_at
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Takes the login a state was handed out for, unless it expired, so that it can only come back once.
+pub async fn take<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    state_hash: &[u8],
+) -> Result<Option<OidcLogin>> {
+    sqlx::query_as!(
+        OidcLogin,
+        r#"
+        DELETE FROM oidc_logins
+        WHERE state_hash = $1 AND expires_at > now()
+        RETURNING nonce, code_verifier, user_id, device_name, cookies;
+        "#,
+        state_hash
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Drops logins that were never finished, returning how many there were.
+pub async fn sweep<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM oidc_logins
+        WHERE expires_at <= now();
+        "#
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
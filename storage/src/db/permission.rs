@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+/// What someone may do with a file, each role allowing everything the previous ones do.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, sqlx::Type,
+)]
+#[sqlx(type_name = "file_role", rename_all = "lowercase")]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    Viewer,
+    Commenter,
+    Editor,
+    Owner,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Permission {
+    pub file_id: Uuid,
+    pub user_id: Uuid,
+    pub login: String,
+    pub role: Role,
+    pub granted_by: Uuid,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// A file or folder someone else shared with the user.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Shared {
+    pub id: Uuid,
+    pub name: String,
+    pub path: Option<String>,
+    pub parent_id: Option<Uuid>,
+    pub owned_by: Uuid,
+    pub edited_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub role: Role,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// The strongest role granted to the user on a file or any folder above it.
+pub async fn role_of<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<Role>> {
+    sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE chain AS (
+            SELECT id, parent_id
+            FROM files
+            WHERE id = $1
+            UNION ALL
+            SELECT f.id, f.parent_id
+            FROM files f
+            JOIN chain c ON f.id = c.parent_id
+        )
+        SELECT max(p.role) AS "role: Role"
+        FROM permissions p
+        JOIN chain c ON p.file_id = c.id
+        WHERE p.user_id = $2;
+        "#,
+        file_id,
+        user_id
+    )
+    .fetch_one(e)
+    .await
+}
+
+pub async fn grant<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    user_id: &Uuid,
+    role: Role,
+    granted_by: &Uuid,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO permissions (file_id, user_id, role, granted_by)
+        VALUES ($1, $2, $3, $4);
+        "#,
+        file_id,
+        user_id,
+        role as Role,
+        granted_by
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Changes the role of an existing grant, returning whether there was one.
+pub async fn change<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    user_id: &Uuid,
+    role: Role,
+    granted_by: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE permissions
+        SET role = $3, granted_by = $4, granted_at = now()
+        WHERE file_id = $1 AND user_id = $2;
+        "#,
+        file_id,
+        user_id,
+        role as Role,
+        granted_by
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes a grant, returning whether there was one.
+pub async fn revoke<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM permissions
+        WHERE file_id = $1 AND user_id = $2;
+        "#,
+        file_id,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes the grants a user holds on files that became theirs, returning how many there were.
+pub async fn drop_owned<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM permissions p
+        USING files f
+        WHERE p.file_id = f.id AND p.user_id = $1 AND f.owned_by = $1;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn find<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<Permission>> {
+    sqlx::query_as!(
+        Permission,
+        r#"
+        SELECT p.file_id, p.user_id, u.login, p.role AS "role: Role", p.granted_by, p.granted_at
+        FROM permissions p
+        JOIN users u ON u.id = p.user_id
+        WHERE p.file_id = $1 AND p.user_id = $2;
+        "#,
+        file_id,
+        user_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Lists the grants made directly on a file, not those inherited from its folders.
+pub async fn list<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Vec<Permission>> {
+    sqlx::query_as!(
+        Permission,
+        r#"
+        SELECT p.file_id, p.user_id, u.login, p.role AS "role: Role", p.granted_by, p.granted_at
+        FROM permissions p
+        JOIN users u ON u.id = p.user_id
+        WHERE p.file_id = $1
+        ORDER BY p.granted_at, u.login;
+        "#,
+        file_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Lists what was shared with the user directly, newest grants first.
+pub async fn shared_with<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<Vec<Shared>> {
+    sqlx::query_as!(
+        Shared,
+        r#"
+        SELECT f.id, f.name, f.path, f.parent_id, f.owned_by, f.edited_by, f.created_at, f.edited_at,
+            p.role AS "role: Role", p.granted_at
+        FROM permissions p
+        JOIN files f ON f.id = p.file_id
+        WHERE p.user_id = $1 AND f.deleted_at IS NULL
+        ORDER BY p.granted_at DESC;
+        "#,
+        user_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Picks out the files among `file_ids` that were shared with the user directly.
+pub async fn granted_among<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    file_ids: &[Uuid],
+) -> Result<Vec<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT file_id
+        FROM permissions
+        WHERE user_id = $1 AND file_id = ANY($2);
+        "#,
+        user_id,
+        file_ids
+    )
+    .fetch_all(e)
+    .await
+}
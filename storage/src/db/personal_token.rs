@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonalToken {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PersonalToken {
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    name: &str,
+    token_hash: &[u8],
+    scopes: &[String],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<PersonalToken> {
+    sqlx::query_as!(
+        PersonalToken,
+        r#"
+        INSERT INTO personal_tokens (user_id, name, token_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, user_id, name, scopes, expires_at, last_used_at, created_at;
+        "#,
+        user_id,
+        name,
+        token_hash,
+        scopes,
+        expires_at
+    )
+    .fetch_one(e)
+    .await
+}
+
+/// Finds a token of a user who was not disabled by its hash, marking it as used. The time it was last used at is only written once a minute.
+pub async fn find_by_hash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+) -> Result<Option<PersonalToken>> {
+    sqlx::query_as!(
+        PersonalToken,
+        r#"
+        WITH token AS (
+            SELECT p.id, p.user_id, p.name, p.scopes, p.expires_at, p.last_used_at, p.created_at
+            FROM personal_tokens p
+            JOIN users u ON u.id = p.user_id
+            WHERE p.token_hash = $1 AND u.disabled_at IS NULL
+        ), used AS (
+            UPDATE personal_tokens p
+            SET last_used_at = now()
+            FROM token
+            WHERE p.id = token.id
+                AND (token.last_used_at IS NULL OR token.last_used_at < now() - INTERVAL '1 minute')
+        )
+        SELECT id AS "id!", user_id AS "user_id!", name AS "name!", scopes AS "scopes!",
+            expires_at, last_used_at, created_at AS "created_at!"
+        FROM token;
+        "#,
+        token_hash
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Lists the tokens of a user, newest first.
+pub async fn list<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<Vec<PersonalToken>> {
+    sqlx::query_as!(
+        PersonalToken,
+        r#"
+        SELECT id, user_id, name, scopes, expires_at, last_used_at, created_at
+        FROM personal_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC;
+        "#,
+        user_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Revokes one of the user's tokens, returning whether there was one.
+pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM personal_tokens
+        WHERE id = $1 AND user_id = $2;
+        "#,
+        token_id,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: Uuid,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What a session was started from.
+pub struct Device {
+    pub name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    device: &Device,
+    expires_at: DateTime<Utc>,
+) -> Result<Uuid> {
+    let rec = sqlx::query!(
+        r#"
+        INSERT INTO sessions (user_id, device_name, user_agent, ip, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id;
+        "#,
+        user_id,
+        device.name,
+        device.user_agent,
+        device.ip,
+        expires_at
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.id)
+}
+
+/// Keeps a session going after a refresh, from wherever it was made.
+pub async fn extend<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    id: &Uuid,
+    ip: Option<&str>,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET last_seen_at = now(), ip = coalesce($2, ip), expires_at = $3
+        WHERE id = $1;
+        "#,
+        id,
+        ip,
+        expires_at
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Marks a session as seen, returning whether it is still going.
+/// The time it was last seen at is only written once a minute.
+pub async fn touch<'e, E: Executor<'e, Database = Postgres>>(e: E, id: &Uuid) -> Result<bool> {
+    let active = sqlx::query_scalar!(
+        r#"
+        WITH session AS (
+            SELECT id, last_seen_at
+            FROM sessions
+            WHERE id = $1 AND revoked_at IS NULL AND expires_at > now()
+        ), seen AS (
+            UPDATE sessions s
+            SET last_seen_at = now()
+            FROM session
+            WHERE s.id = session.id AND session.last_seen_at < now() - INTERVAL '1 minute'
+        )
+        SELECT EXISTS (SELECT 1 FROM session);
+        "#,
+        id
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(active.unwrap_or(false))
+}
+
+/// Lists the sessions of a user that are still going, most recently seen first.
+pub async fn active<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<Vec<Session>> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        SELECT id, device_name, user_agent, ip, created_at, last_seen_at, expires_at
+        FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > now()
+        ORDER BY last_seen_at DESC;
+        "#,
+        user_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Ends one of the user's sessions along with its refresh tokens,
+/// returning whether there was one still going.
+pub async fn revoke<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    id: &Uuid,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let revoked = sqlx::query_scalar!(
+        r#"
+        WITH session AS (
+            UPDATE sessions
+            SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING id
+        ), tokens AS (
+            UPDATE refresh_tokens t
+            SET revoked_at = coalesce(t.revoked_at, now())
+            FROM session
+            WHERE t.session_id = session.id
+        )
+        SELECT count(*) AS "count!" FROM session;
+        "#,
+        id,
+        user_id
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(revoked > 0)
+}
+
+/// Ends every session of a user but `except`, along with their refresh tokens.
+pub async fn revoke_all<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    except: Option<&Uuid>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        WITH ended AS (
+            UPDATE sessions
+            SET revoked_at = coalesce(revoked_at, now())
+            WHERE user_id = $1 AND id IS DISTINCT FROM $2
+        )
+        UPDATE refresh_tokens
+        SET revoked_at = coalesce(revoked_at, now())
+        WHERE user_id = $1 AND session_id IS DISTINCT FROM $2;
+        "#,
+        user_id,
+        except
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Sets the CSRF token of a session kept in browser cookies, replacing the one it had.
+pub async fn set_csrf<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    id: &Uuid,
+    csrf_hash: &[u8],
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET csrf_hash = $2
+        WHERE id = $1;
+        "#,
+        id,
+        csrf_hash
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Whether a CSRF token is the one of a session.
+pub async fn csrf_matches<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    id: &Uuid,
+    csrf_hash: &[u8],
+) -> Result<bool> {
+    let matches = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM sessions
+            WHERE id = $1 AND csrf_hash = $2
+        );
+        "#,
+        id,
+        csrf_hash
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(matches.unwrap_or(false))
+}
+
+/// Drops sessions that ended, along with their refresh tokens, returning how many there were.
+pub async fn sweep<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM sessions
+        WHERE revoked_at IS NOT NULL OR expires_at <= now();
+        "#
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: String,
+    pub private_key: String,
+    pub not_before: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Serializes rotations until the end of the transaction, so that instances starting together
+/// do not each make a key.
+pub async fn lock<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<()> {
+    sqlx::query!(
+        r#"
+        SELECT FROM (SELECT pg_advisory_xact_lock(hashtextextended('signing_keys', 0))) AS locked;
+        "#
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Lists every key, oldest first.
+pub async fn all<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<Vec<SigningKey>> {
+    sqlx::query_as!(
+        SigningKey,
+        r#"
+        SELECT kid, algorithm, private_key, not_before, created_at
+        FROM signing_keys
+        ORDER BY not_before, created_at;
+        "#
+    )
+    .fetch_all(e)
+    .await
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    kid: &str,
+    algorithm: &str,
+    private_key: &str,
+    not_before: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO signing_keys (kid, algorithm, private_key, not_before)
+        VALUES ($1, $2, $3, $4);
+        "#,
+        kid,
+        algorithm,
+        private_key,
+        not_before
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Drops keys that were replaced by a newer key before `before`, returning how many there were.
+pub async fn retire<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    before: DateTime<Utc>,
+) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM signing_keys k
+        WHERE EXISTS (
+            SELECT 1
+            FROM signing_keys n
+            WHERE n.not_before > k.not_before AND n.not_before <= $1
+        );
+        "#,
+        before
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
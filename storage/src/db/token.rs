@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub access_jti: Uuid,
+    pub access_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl RefreshToken {
+    pub fn expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+/// A refresh token handed out along with an access token.
+pub struct Grant {
+    pub session_id: Uuid,
+    pub token_hash: Vec<u8>,
+    pub access_jti: Uuid,
+    pub access_expires_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    grant: &Grant,
+) -> Result<Uuid> {
+    let rec = sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens (session_id, user_id, token_hash, access_jti, access_expires_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id;
+        "#,
+        grant.session_id,
+        user_id,
+        grant.token_hash,
+        grant.access_jti,
+        grant.access_expires_at,
+        grant.expires_at
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.id)
+}
+
+/// Finds a refresh token by its hash, locking it until the transaction ends.
+pub async fn lock_by_hash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    token_hash: &[u8],
+) -> Result<Option<RefreshToken>> {
+    sqlx::query_as!(
+        RefreshToken,
+        r#"
+        SELECT id, session_id, user_id, access_jti, access_expires_at, created_at, expires_at,
+            used_at, revoked_at
+        FROM refresh_tokens
+        WHERE token_hash = $1
+        FOR UPDATE;
+        "#,
+        token_hash
+    )
+    .fetch_optional(e)
+    .await
+}
+
+pub async fn mark_used<'e, E: Executor<'e, Database = Postgres>>(e: E, id: &Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET used_at = now()
+        WHERE id = $1;
+        "#,
+        id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn revoke_access<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    jti: &Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO revoked_tokens (jti, expires_at)
+        VALUES ($1, $2)
+        ON CONFLICT (jti) DO NOTHING;
+        "#,
+        jti,
+        expires_at
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn is_revoked<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    jti: &Uuid,
+) -> Result<bool> {
+    let revoked = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM revoked_tokens
+            WHERE jti = $1
+        );
+        "#,
+        jti
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(revoked.unwrap_or(false))
+}
+
+/// Drops refresh tokens and revocations that expired, returning how many there were.
+pub async fn sweep<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<u64> {
+    let rec = sqlx::query!(
+        r#"
+        WITH refresh AS (
+            DELETE FROM refresh_tokens
+            WHERE expires_at <= now()
+            RETURNING 1
+        ), access AS (
+            DELETE FROM revoked_tokens
+            WHERE expires_at <= now()
+            RETURNING 1
+        )
+        SELECT (SELECT count(*) FROM refresh) + (SELECT count(*) FROM access) AS "swept!";
+        "#
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.swept as u64)
+}
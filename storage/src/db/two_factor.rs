@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+pub struct TwoFactor {
+    pub user_id: Uuid,
+    pub secret: Vec<u8>,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub last_step: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TwoFactor {
+    pub fn enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+}
+
+pub async fn find<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<Option<TwoFactor>> {
+    sqlx::query_as!(
+        TwoFactor,
+        r#"
+        SELECT user_id, secret, enabled_at, last_step, created_at
+        FROM two_factor
+        WHERE user_id = $1;
+        "#,
+        user_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Starts over an enrollment that was not finished, returning whether there was none enabled.
+pub async fn enroll<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    secret: &[u8],
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO two_factor (user_id, secret)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET secret = excluded.secret, last_step = 0, created_at = now()
+        WHERE two_factor.enabled_at IS NULL;
+        "#,
+        user_id,
+        secret
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Records the step of a code being used, returning false if it, or a later one, was used already.
+pub async fn use_step<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    step: i64,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE two_factor
+        SET last_step = $2
+        WHERE user_id = $1 AND last_step < $2;
+        "#,
+        user_id,
+        step
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn enable<'e, E: Executor<'e, Database = Postgres>>(e: E, user_id: &Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE two_factor
+        SET enabled_at = now()
+        WHERE user_id = $1;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Forgets the authenticator of a user along with their recovery codes.
+pub async fn disable<'e, E: Executor<'e, Database = Postgres>>(e: E, user_id: &Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"
+        WITH codes AS (
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+        )
+        DELETE FROM two_factor
+        WHERE user_id = $1;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Replaces the recovery codes of a user.
+pub async fn set_recovery_codes<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    code_hashes: &[Vec<u8>],
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        WITH old AS (
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+        )
+        INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, code_hash
+        FROM unnest($2::BYTEA[]) AS code_hash;
+        "#,
+        user_id,
+        code_hashes
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Uses up a recovery code, returning whether it was there to use.
+pub async fn use_recovery_code<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    code_hash: &[u8],
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE recovery_codes
+        SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL;
+        "#,
+        user_id,
+        code_hash
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn recovery_codes_left<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<i64> {
+    let left = sqlx::query_scalar!(
+        r#"
+        SELECT count(*) AS "left!"
+        FROM recovery_codes
+        WHERE user_id = $1 AND used_at IS NULL;
+        "#,
+        user_id
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(left)
+}
@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Upload {
+    pub id: Uuid,
+    pub name: String,
+    pub parent_id: Option<Uuid>,
+    pub length: i64,
+    pub received: i64,
+    pub owned_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    name: &str,
+    parent_id: Option<&Uuid>,
+    length: i64,
+    owner_id: &Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<Uuid> {
+    let rec = sqlx::query!(
+        r#"
+        INSERT INTO uploads (name, parent_id, length, owned_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id;
+        "#,
+        name,
+        parent_id,
+        length,
+        owner_id,
+        expires_at
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.id)
+}
+
+pub async fn find_by_id<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+) -> Result<Option<Upload>> {
+    sqlx::query_as!(
+        Upload,
+        r#"
+        SELECT id, name, parent_id, length, received, owned_by, created_at, expires_at
+        FROM uploads
+        WHERE id = $1;
+        "#,
+        upload_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Locks the upload row until the end of the transaction.
+/// Fails with `lock_not_available` instead of waiting if another request holds it.
+pub async fn lock<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+) -> Result<Option<Upload>> {
+    sqlx::query_as!(
+        Upload,
+        r#"
+        SELECT id, name, parent_id, length, received, owned_by, created_at, expires_at
+        FROM uploads
+        WHERE id = $1
+        FOR UPDATE NOWAIT;
+        "#,
+        upload_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Leases the upload to `writer` for `lease_secs`, unless another writer holds an unexpired lease.
+/// Returns `None` when the upload does not exist or is leased.
+pub async fn claim<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+    writer: &Uuid,
+    lease_secs: f64,
+) -> Result<Option<Upload>> {
+    sqlx::query_as!(
+        Upload,
+        r#"
+        UPDATE uploads
+        SET writer = $2, writing_until = now() + make_interval(secs => $3)
+        WHERE id = $1 AND (writing_until IS NULL OR writing_until < now())
+        RETURNING id, name, parent_id, length, received, owned_by, created_at, expires_at;
+        "#,
+        upload_id,
+        writer,
+        lease_secs
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Extends the lease of `writer`. Returns false if it was lost to another writer.
+pub async fn renew<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+    writer: &Uuid,
+    lease_secs: f64,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE uploads
+        SET writing_until = now() + make_interval(secs => $3)
+        WHERE id = $1 AND writer = $2;
+        "#,
+        upload_id,
+        writer,
+        lease_secs
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Gives up the lease of `writer`, if it still holds it.
+pub async fn release<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+    writer: &Uuid,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE uploads
+        SET writer = NULL, writing_until = NULL
+        WHERE id = $1 AND writer = $2;
+        "#,
+        upload_id,
+        writer
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Records the offset reached by `writer` and ends its lease.
+/// Returns false if the lease was lost to another writer.
+pub async fn advance<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+    writer: &Uuid,
+    received: i64,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE uploads
+        SET received = $1, writer = NULL, writing_until = NULL
+        WHERE id = $2 AND writer = $3;
+        "#,
+        received,
+        upload_id,
+        writer
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn delete<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    upload_id: &Uuid,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM uploads WHERE id = $1;
+        "#,
+        upload_id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn expired<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<Vec<Upload>> {
+    sqlx::query_as!(
+        Upload,
+        r#"
+        SELECT id, name, parent_id, length, received, owned_by, created_at, expires_at
+        FROM uploads
+        WHERE expires_at < now();
+        "#
+    )
+    .fetch_all(e)
+    .await
+}
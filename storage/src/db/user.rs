@@ -1,19 +1,37 @@
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::{Executor, Postgres, Result};
 use uuid::Uuid;
 
+/// What a user may do across the server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: Uuid,
     pub login: String,
-    pub phc: String,
+    /// Is none for users who only sign in through single sign-on or a directory.
+    pub phc: Option<String>,
+    pub role: Role,
+    /// When the user was stopped from logging in, if they were.
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub email: Option<String>,
+    /// When the user proved the address is theirs; is none until then.
+    pub email_verified_at: Option<DateTime<Utc>>,
 }
 
 pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     login: &str,
-    phc: &str,
+    phc: Option<&str>,
 ) -> Result<Uuid> {
     let rec = sqlx::query!(
         r#"
@@ -48,7 +66,7 @@ pub async fn find_by_id<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         User,
         r#"
-        SELECT id, login, phc
+        SELECT id, login, phc, role AS "role: Role", disabled_at, email, email_verified_at
         FROM users
         WHERE id = $1;
         "#,
@@ -65,7 +83,7 @@ pub async fn find_by_login<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         User,
         r#"
-        SELECT id, login, phc
+        SELECT id, login, phc, role AS "role: Role", disabled_at, email, email_verified_at
         FROM users
         WHERE login = $1;
         "#,
@@ -94,6 +112,87 @@ pub async fn login_exists<'e, E: Executor<'e, Database = Postgres>>(
     Ok(exists.unwrap_or(false))
 }
 
+/// Lists the users who registered with an address they have yet to verify.
+pub async fn unverified_by_email<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    email: &str,
+) -> Result<Vec<User>> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, login, phc, role AS "role: Role", disabled_at, email, email_verified_at
+        FROM users
+        WHERE lower(email) = lower($1) AND email_verified_at IS NULL;
+        "#,
+        email
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Whether a user has an address, or only whether one verified it if `verified_only`.
+pub async fn email_taken<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    email: &str,
+    verified_only: bool,
+) -> Result<bool> {
+    let taken = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM users
+            WHERE lower(email) = lower($1) AND (NOT $2 OR email_verified_at IS NOT NULL)
+        );
+        "#,
+        email,
+        verified_only
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(taken.unwrap_or(false))
+}
+
+/// Gives a user an email address, taken as theirs already if `verified`.
+pub async fn set_email<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    email: &str,
+    verified: bool,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET email = $2, email_verified_at = CASE WHEN $3 THEN now() END
+        WHERE id = $1;
+        "#,
+        user_id,
+        email,
+        verified
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Marks the email address of a user as theirs, returning whether it was not yet.
+/// Fails on `users_email` if someone else verified the address first.
+pub async fn verify_email<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET email_verified_at = now()
+        WHERE id = $1 AND email IS NOT NULL AND email_verified_at IS NULL;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 pub async fn update_password<'e, E: Executor<'e, Database = Postgres>>(
     e: E,
     user_id: &Uuid,
@@ -112,3 +211,161 @@ pub async fn update_password<'e, E: Executor<'e, Database = Postgres>>(
     .await?;
     Ok(())
 }
+
+pub async fn set_role<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+    role: Role,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET role = $1
+        WHERE id = $2;
+        "#,
+        role as Role,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Stops a user from logging in, returning whether they could until now.
+/// Sessions and tokens the user already has are ended separately.
+pub async fn disable<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET disabled_at = now()
+        WHERE id = $1 AND disabled_at IS NULL;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lets a disabled user log in again, returning whether they were disabled.
+pub async fn enable<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    user_id: &Uuid,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET disabled_at = NULL
+        WHERE id = $1 AND disabled_at IS NOT NULL;
+        "#,
+        user_id
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// A user as administrators see them, without their password hash.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Account {
+    pub id: Uuid,
+    pub login: String,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub email: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What a user's files take up, counting every version even where contents are shared.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub files: i64,
+    pub folders: i64,
+    /// The current contents of files outside the trash.
+    pub bytes: i64,
+    /// Older versions of files outside the trash.
+    pub version_bytes: i64,
+    /// Every version of files in the trash.
+    pub trash_bytes: i64,
+}
+
+/// Lists users whose login contains `query`, or everyone without one, by login.
+pub async fn search<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    query: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Account>> {
+    sqlx::query_as!(
+        Account,
+        r#"
+        SELECT id, login, role AS "role: Role", disabled_at, email, email_verified_at, created_at
+        FROM users
+        WHERE $1::TEXT IS NULL OR strpos(lower(login), lower($1)) > 0
+        ORDER BY login
+        LIMIT $2 OFFSET $3;
+        "#,
+        query,
+        limit,
+        offset
+    )
+    .fetch_all(e)
+    .await
+}
+
+pub async fn account<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    id: &Uuid,
+) -> Result<Option<Account>> {
+    sqlx::query_as!(
+        Account,
+        r#"
+        SELECT id, login, role AS "role: Role", disabled_at, email, email_verified_at, created_at
+        FROM users
+        WHERE id = $1;
+        "#,
+        id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+pub async fn usage<'e, E: Executor<'e, Database = Postgres>>(e: E, id: &Uuid) -> Result<Usage> {
+    sqlx::query_as!(
+        Usage,
+        r#"
+        WITH owned AS (
+            SELECT id, path, deleted_at
+            FROM files
+            WHERE owned_by = $1
+        ),
+        versions AS (
+            SELECT o.deleted_at,
+                coalesce(b.size, v.size, 0) AS size,
+                row_number() OVER (
+                    PARTITION BY v.file_id ORDER BY v.created_at DESC, v.id DESC
+                ) = 1 AS current
+            FROM owned o
+            JOIN file_versions v ON v.file_id = o.id
+            LEFT JOIN blobs b ON b.hash = v.hash
+        )
+        SELECT
+            (SELECT count(*) FROM owned WHERE deleted_at IS NULL AND path IS NOT NULL) AS "files!",
+            (SELECT count(*) FROM owned WHERE deleted_at IS NULL AND path IS NULL) AS "folders!",
+            coalesce(sum(size) FILTER (WHERE deleted_at IS NULL AND current), 0)::BIGINT AS "bytes!",
+            coalesce(sum(size) FILTER (WHERE deleted_at IS NULL AND NOT current), 0)::BIGINT
+                AS "version_bytes!",
+            coalesce(sum(size) FILTER (WHERE deleted_at IS NOT NULL), 0)::BIGINT AS "trash_bytes!"
+        FROM versions;
+        "#,
+        id
+    )
+    .fetch_one(e)
+    .await
+}
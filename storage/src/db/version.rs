@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Result};
+use uuid::Uuid;
+
+use crate::blob;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Version {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    #[serde(skip)]
+    pub path: String,
+    pub size: Option<i64>,    // is null for blobs stored before versioning
+    pub hash: Option<String>, // is null for blobs stored before deduplication
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Version {
+    pub fn digest(&self) -> Option<blob::Digest> {
+        Some(blob::Digest {
+            hash: self.hash.clone()?,
+            size: self.size?,
+        })
+    }
+}
+
+pub async fn create<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    path: &str,
+    size: i64,
+    hash: Option<&str>,
+    user_id: &Uuid,
+) -> Result<Uuid> {
+    let rec = sqlx::query!(
+        r#"
+        INSERT INTO file_versions (file_id, path, size, hash, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id;
+        "#,
+        file_id,
+        path,
+        size,
+        hash,
+        user_id
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(rec.id)
+}
+
+/// Lists the versions of a file, newest (the current one) first.
+pub async fn list<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Vec<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+        SELECT id, file_id, path, size, hash, created_by, created_at
+        FROM file_versions
+        WHERE file_id = $1
+        ORDER BY created_at DESC, id DESC;
+        "#,
+        file_id
+    )
+    .fetch_all(e)
+    .await
+}
+
+pub async fn find_by_id<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+    version_id: &Uuid,
+) -> Result<Option<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+        SELECT id, file_id, path, size, hash, created_by, created_at
+        FROM file_versions
+        WHERE id = $1 AND file_id = $2;
+        "#,
+        version_id,
+        file_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// The version a file's contents currently come from.
+pub async fn current<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    file_id: &Uuid,
+) -> Result<Option<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+        SELECT id, file_id, path, size, hash, created_by, created_at
+        FROM file_versions
+        WHERE file_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1;
+        "#,
+        file_id
+    )
+    .fetch_optional(e)
+    .await
+}
+
+/// Gives every file with contents but no history a first version.
+pub async fn adopt_unversioned<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO file_versions (file_id, path, size, created_by, created_at)
+        SELECT f.id, f.path, NULL, COALESCE(f.edited_by, f.owned_by), COALESCE(f.edited_at, f.created_at)
+        FROM files f
+        WHERE f.path IS NOT NULL
+            AND NOT EXISTS (SELECT FROM file_versions v WHERE v.file_id = f.id);
+        "#
+    )
+    .execute(e)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Lists the versions whose blobs were stored before deduplication.
+pub async fn unhashed<'e, E: Executor<'e, Database = Postgres>>(e: E) -> Result<Vec<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+        SELECT id, file_id, path, size, hash, created_by, created_at
+        FROM file_versions
+        WHERE hash IS NULL
+        ORDER BY created_at;
+        "#
+    )
+    .fetch_all(e)
+    .await
+}
+
+/// Points a version at a content-addressed blob.
+pub async fn rehash<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    version_id: &Uuid,
+    path: &str,
+    size: i64,
+    hash: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE file_versions
+        SET path = $2, size = $3, hash = $4
+        WHERE id = $1;
+        "#,
+        version_id,
+        path,
+        size,
+        hash
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the versions falling outside the retention policy and returns them.
+///
+/// A version survives if it is one of the `keep_last` newest versions of its file,
+/// or the last version of its day within the past `keep_daily` days.
+pub async fn prune<'e, E: Executor<'e, Database = Postgres>>(
+    e: E,
+    keep_last: i64,
+    keep_daily: i32,
+) -> Result<Vec<Version>> {
+    sqlx::query_as!(
+        Version,
+        r#"
+        WITH ranked AS (
+            SELECT id, created_at,
+                row_number() OVER (
+                    PARTITION BY file_id
+                    ORDER BY created_at DESC, id DESC
+                ) AS n,
+                row_number() OVER (
+                    PARTITION BY file_id, date_trunc('day', created_at)
+                    ORDER BY created_at DESC, id DESC
+                ) AS daily
+            FROM file_versions
+        )
+        DELETE FROM file_versions v
+        USING ranked r
+        WHERE v.id = r.id
+            AND r.n > GREATEST($1::BIGINT, 1)
+            AND NOT (r.daily = 1 AND r.created_at > now() - make_interval(days => $2))
+        RETURNING v.id, v.file_id, v.path, v.size, v.hash, v.created_by, v.created_at;
+        "#,
+        keep_last,
+        keep_daily
+    )
+    .fetch_all(e)
+    .await
+}
@@ -1,25 +1,200 @@
 pub mod api;
 pub mod auth;
+pub mod blob;
 pub mod db;
+pub mod mail;
+pub mod tasks;
 
 use axum::{
-    Router,
-    routing::{get, post, put},
+    Extension, Router,
+    routing::{MethodRouter, delete, get, head, patch, post, put},
 };
 
 use crate::api::Shared;
+use crate::auth::Scope;
+
+/// Lets personal access tokens holding `scope` reach a route; others turn them away.
+fn scoped(scope: Scope, route: MethodRouter<Shared>) -> MethodRouter<Shared> {
+    route.route_layer(Extension(scope))
+}
+
+fn read(route: MethodRouter<Shared>) -> MethodRouter<Shared> {
+    scoped(Scope::FilesRead, route)
+}
+
+fn write(route: MethodRouter<Shared>) -> MethodRouter<Shared> {
+    scoped(Scope::FilesWrite, route)
+}
+
+fn uploads() -> Router<Shared> {
+    Router::new()
+        .route(
+            "/uploads",
+            post(api::tus::create_upload).options(api::tus::upload_options),
+        )
+        .route(
+            "/uploads/{upload_id}",
+            head(api::tus::upload_offset)
+                .patch(api::tus::append_upload)
+                .delete(api::tus::terminate_upload),
+        )
+        .route_layer(Extension(Scope::FilesWrite))
+        .layer(axum::middleware::from_fn(api::tus::protocol))
+}
+
+fn admin() -> Router<Shared> {
+    Router::new()
+        .route(
+            "/admin/users",
+            get(api::admin::list_users).post(api::admin::create_user),
+        )
+        .route(
+            "/admin/users/{user_id}",
+            get(api::admin::get_user).patch(api::admin::update_user),
+        )
+        .route(
+            "/admin/users/{user_id}/password",
+            post(api::admin::reset_password),
+        )
+        .route(
+            "/admin/users/{user_id}/disable",
+            post(api::admin::disable_user),
+        )
+        .route(
+            "/admin/users/{user_id}/enable",
+            post(api::admin::enable_user),
+        )
+        .route(
+            "/admin/users/{user_id}/logout",
+            post(api::admin::logout_user),
+        )
+        .route("/admin/users/{user_id}/usage", get(api::admin::get_usage))
+        .route(
+            "/admin/users/{user_id}/transfer",
+            post(api::admin::transfer_files),
+        )
+        .route(
+            "/admin/invites",
+            get(api::admin::list_invites).post(api::admin::create_invite),
+        )
+        .route(
+            "/admin/invites/{invite_id}",
+            delete(api::admin::withdraw_invite),
+        )
+        .route_layer(Extension(Scope::Admin))
+}
 
 pub fn app(shared: Shared) -> Router {
+    let cors = api::browser::cors(&shared.settings);
     Router::new()
-        .route("/auth/register", post(api::register))
+        .route("/.well-known/jwks.json", get(api::keys::jwks))
+        .route("/auth/register", post(api::registration::register))
+        .route("/auth/verify", post(api::registration::verify_email))
+        .route(
+            "/auth/verify/resend",
+            post(api::registration::resend_verification),
+        )
         .route("/auth/login", post(api::login))
-        .route("/upload", post(api::upload_file))
-        .route("/download/{file_id}", get(api::download_file))
-        .route("/folder", get(api::find_files))
-        .route("/folder/{file_id}", get(api::get_folder))
-        .route("/config", get(api::get_config))
-        .route("/config", put(api::put_config))
+        .route(
+            "/auth/login/2fa",
+            post(api::two_factor::login_second_factor),
+        )
+        .route(
+            "/auth/2fa",
+            get(api::two_factor::status)
+                .post(api::two_factor::enroll)
+                .delete(api::two_factor::disable),
+        )
+        .route("/auth/2fa/activate", post(api::two_factor::activate))
+        .route(
+            "/auth/2fa/recovery-codes",
+            post(api::two_factor::regenerate_recovery_codes),
+        )
+        .route("/auth/oidc/login", get(api::oidc::login))
+        .route("/auth/oidc/link", post(api::oidc::link))
+        .route("/auth/oidc/callback", get(api::oidc::callback))
+        .route("/auth/password", post(api::change_password))
+        .route("/auth/refresh", post(api::sessions::refresh))
+        .route("/auth/logout", post(api::sessions::logout))
+        .route("/auth/logout/all", post(api::sessions::logout_everywhere))
+        .route("/auth/sessions", get(api::sessions::list_sessions))
+        .route(
+            "/auth/sessions/{session_id}",
+            delete(api::sessions::end_session),
+        )
+        .route(
+            "/auth/tokens",
+            get(api::tokens::list_tokens).post(api::tokens::create_token),
+        )
+        .route("/auth/tokens/{token_id}", delete(api::tokens::revoke_token))
+        .route("/upload", write(post(api::upload_file)))
+        .route("/download/{file_id}", read(get(api::download_file)))
+        .route(
+            "/folder",
+            read(get(api::find_files)).merge(write(post(api::create_folder))),
+        )
+        .route("/folder/{file_id}", read(get(api::get_folder)))
+        .route("/folder/{file_id}/tree", read(get(api::get_tree)))
+        .route(
+            "/files/{file_id}",
+            write(patch(api::files::update_file).delete(api::trash::delete_file)),
+        )
+        .route("/files/{file_id}/copy", write(post(api::files::copy_file)))
+        .route("/files/{file_id}/ancestors", read(get(api::get_ancestors)))
+        .route(
+            "/files/{file_id}/versions",
+            read(get(api::versions::list_versions)),
+        )
+        .route(
+            "/files/{file_id}/versions/{version_id}",
+            read(get(api::versions::download_version)),
+        )
+        .route(
+            "/files/{file_id}/versions/{version_id}/restore",
+            write(post(api::versions::restore_version)),
+        )
+        .route(
+            "/trash",
+            read(get(api::trash::list_trash)).merge(write(delete(api::trash::empty_trash))),
+        )
+        .route(
+            "/trash/{file_id}/restore",
+            write(post(api::trash::restore_file)),
+        )
+        .route(
+            "/files/{file_id}/permissions",
+            read(get(api::sharing::list_permissions))
+                .merge(write(post(api::sharing::grant_permission))),
+        )
+        .route(
+            "/files/{file_id}/permissions/{user_id}",
+            write(patch(api::sharing::change_permission).delete(api::sharing::revoke_permission)),
+        )
+        .route("/shared", read(get(api::sharing::shared_with_me)))
+        .route(
+            "/files/{file_id}/links",
+            write(post(api::links::create_link)),
+        )
+        .route("/links", read(get(api::links::list_links)))
+        .route("/links/{link_id}", write(delete(api::links::revoke_link)))
+        .route(
+            "/s/{token}",
+            get(api::links::open_link).post(api::links::drop_upload),
+        )
+        .route(
+            "/s/{token}/files/{file_id}",
+            get(api::links::open_link_file),
+        )
+        .route("/changes", read(get(api::changes::list_changes)))
+        .route("/events", read(get(api::events::subscribe)))
+        .route(
+            "/config",
+            read(get(api::get_config)).merge(scoped(Scope::ConfigWrite, put(api::put_config))),
+        )
+        .merge(uploads())
+        .merge(admin())
         .with_state(shared)
+        .layer(tower::util::option_layer(cors))
         .layer(
             tower::ServiceBuilder::new().layer(
                 tower_http::trace::TraceLayer::new_for_http()
@@ -27,7 +202,8 @@ pub fn app(shared: Shared) -> Router {
                         tracing::info_span!(
                             "http_request",
                             method = ?request.method(),
-                            uri = %request.uri(),
+                            // The query may carry an access token, so it is left out.
+                            uri = %request.uri().path(),
                         )
                     })
                     .on_request(|_request: &axum::extract::Request, _span: &tracing::Span| {
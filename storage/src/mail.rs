@@ -0,0 +1,30 @@
+//! Sending email, such as the links that verify the addresses of new users.
+//!
+//! How mail leaves the server is up to a [`Mailer`]; setting `SMTP_URL` hands it to an SMTP server.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::api::Error;
+
+pub mod smtp;
+
+/// A plain text email to one recipient.
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Somewhere to send mail through.
+#[async_trait]
+pub trait Mailer: Send + Sync + std::fmt::Debug {
+    /// Sends a mail, returning once the next hop has taken it.
+    async fn send(&self, mail: &Mail) -> Result<(), Error>;
+}
+
+/// Builds the mailer of `SMTP_URL`, if it is set.
+pub fn from_env() -> Result<Option<Arc<dyn Mailer>>, Error> {
+    Ok(smtp::Smtp::from_env()?.map(|smtp| Arc::new(smtp) as Arc<dyn Mailer>))
+}
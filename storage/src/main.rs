@@ -10,8 +10,23 @@ async fn main() {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
-    let app = storage::app(Shared::from_env().await.unwrap());
+    let shared = Shared::from_env().await.unwrap();
+    tokio::spawn(storage::tasks::expire_uploads(shared.clone()));
+    tokio::spawn(storage::tasks::purge_trash(shared.clone()));
+    tokio::spawn(storage::tasks::prune_versions(shared.clone()));
+    tokio::spawn(storage::tasks::collect_garbage(shared.clone()));
+    tokio::spawn(storage::tasks::compact_changes(shared.clone()));
+    tokio::spawn(storage::tasks::expire_tokens(shared.clone()));
+    tokio::spawn(storage::tasks::rotate_keys(shared.clone()));
+    tokio::spawn(storage::tasks::sync_directory(shared.clone()));
+    tokio::spawn(storage::tasks::relay_events(shared.clone()));
+    let app = storage::app(shared);
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
     tracing::debug!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -0,0 +1,165 @@
+//! Periodic background jobs spawned next to the HTTP server.
+
+use crate::api::{self, Shared};
+use crate::{blob, db};
+
+/// Discards resumable uploads that were abandoned past their expiry.
+pub async fn expire_uploads(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_uploads(&shared).await {
+            tracing::error!(name: "expire_uploads", "{}", e);
+        }
+    }
+}
+
+pub async fn sweep_uploads(shared: &Shared) -> Result<usize, api::Error> {
+    let expired = db::upload::expired(&shared.pool).await?;
+    for upload in &expired {
+        api::tus::remove_temp(shared, upload).await?;
+        db::upload::delete(&shared.pool, &upload.id).await?;
+        tracing::info!("Expired upload {}", upload.id);
+    }
+    Ok(expired.len())
+}
+
+/// Permanently removes files that sat in the trash for longer than the retention period.
+pub async fn purge_trash(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_trash(&shared).await {
+            tracing::error!(name: "purge_trash", "{}", e);
+        }
+    }
+}
+
+pub async fn sweep_trash(shared: &Shared) -> Result<usize, api::Error> {
+    let before = chrono::Utc::now() - shared.settings.trash_retention;
+    let expired = db::file::expired_trash(&shared.pool, before).await?;
+    for file in &expired {
+        api::trash::purge(shared, &file.id).await?;
+    }
+    if !expired.is_empty() {
+        blob::collect_garbage(shared).await?;
+    }
+    Ok(expired.len())
+}
+
+/// Thins out file histories according to the version retention settings.
+pub async fn prune_versions(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        match api::versions::prune(&shared).await {
+            Ok(pruned) if pruned > 0 => tracing::info!("Pruned {} versions", pruned),
+            Ok(_) => {}
+            Err(e) => tracing::error!(name: "prune_versions", "{}", e),
+        }
+    }
+}
+
+/// Removes blobs left behind by files and versions that were deleted,
+/// and contents stored for versions that never were.
+pub async fn collect_garbage(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        match blob::collect_garbage(&shared).await {
+            Ok(collected) if collected > 0 => tracing::info!("Collected {} blobs", collected),
+            Ok(_) => {}
+            Err(e) => tracing::error!(name: "collect_garbage", "{}", e),
+        }
+        match blob::sweep_orphans(&shared).await {
+            Ok(swept) if swept > 0 => tracing::info!("Swept {} orphaned blobs", swept),
+            Ok(_) => {}
+            Err(e) => tracing::error!(name: "sweep_orphans", "{}", e),
+        }
+    }
+}
+
+/// Drops journaled changes older than the retention period.
+pub async fn compact_changes(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        let before = chrono::Utc::now() - shared.settings.change_retention;
+        match db::change::compact(&shared.pool, before).await {
+            Ok(dropped) if dropped > 0 => tracing::info!("Compacted {} changes", dropped),
+            Ok(_) => {}
+            Err(e) => tracing::error!(name: "compact_changes", "{}", e),
+        }
+    }
+}
+
+/// Drops sessions that ended, refresh tokens and token revocations once they expire,
+/// and failed logins once they are forgotten.
+pub async fn expire_tokens(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_tokens(&shared).await {
+            tracing::error!(name: "expire_tokens", "{}", e);
+        }
+    }
+}
+
+pub async fn sweep_tokens(shared: &Shared) -> Result<u64, api::Error> {
+    let sessions = db::session::sweep(&shared.pool).await?;
+    let tokens = db::token::sweep(&shared.pool).await?;
+    let before = chrono::Utc::now() - shared.settings.login_lockout;
+    let failures = db::login_failure::sweep(&shared.pool, before).await?;
+    let tokens = tokens + db::oidc_login::sweep(&shared.pool).await?;
+    let tokens = tokens + db::email_verification::sweep(&shared.pool).await?;
+    if sessions + tokens + failures > 0 {
+        tracing::info!(
+            "Swept {} sessions, {} tokens and {} failed logins",
+            sessions,
+            tokens,
+            failures
+        );
+    }
+    Ok(sessions + tokens + failures)
+}
+
+/// Rotates the keys access tokens are signed with, and picks up keys other instances made.
+pub async fn rotate_keys(shared: Shared) {
+    let mut interval = tokio::time::interval(shared.settings.sweep_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = api::keys::rotate(&shared).await {
+            tracing::error!(name: "rotate_keys", "{}", e);
+        }
+    }
+}
+
+/// Checks the users from the LDAP directory against it, if there is one.
+pub async fn sync_directory(shared: Shared) {
+    let Some(directory) = &shared.settings.ldap else {
+        return;
+    };
+    let mut interval = tokio::time::interval(directory.config.sync_interval);
+    loop {
+        interval.tick().await;
+        match directory.sync(&shared.pool).await {
+            Ok((0, 0)) => {}
+            Ok((disabled, enabled)) => tracing::info!(
+                "Disabled {} and enabled {} users from the directory",
+                disabled,
+                enabled
+            ),
+            Err(e) => tracing::error!(name: "sync_directory", "{}", e),
+        }
+    }
+}
+
+/// Relays the events announced by the database to connected sessions, reconnecting as needed.
+pub async fn relay_events(shared: Shared) {
+    loop {
+        if let Err(e) = api::events::relay(&shared).await {
+            tracing::error!(name: "relay_events", "{}", e);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,452 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use storage::db::user::Role;
+
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+fn app(pool: &PgPool, dir: &tempfile::TempDir) -> axum::Router {
+    storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    })
+}
+
+async fn send(
+    app: &axum::Router,
+    request: axum::http::Request<axum::body::Body>,
+) -> (StatusCode, Value) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&bytes).unwrap_or_default())
+}
+
+async fn call(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value) {
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        builder = builder.header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        );
+    }
+    send(
+        app,
+        builder
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap(),
+    )
+    .await
+}
+
+async fn upload(app: &axum::Router, token: &str, destination: &str, name: &str) {
+    let body = axum::body::Body::from(format!(
+        concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+            "{}\r\n",
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "eleven byte\r\n",
+            "--BOUNDARY--\r\n"
+        ),
+        destination, name
+    ));
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/upload")
+        .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+        .header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        )
+        .body(body)
+        .unwrap();
+    let (status, _) = send(app, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+async fn login(app: &axum::Router, login: &str, password: &str) -> (StatusCode, Value) {
+    let credentials = json!({ "login": login, "password": password });
+    call(app, "POST", "/auth/login", None, credentials).await
+}
+
+async fn token(app: &axum::Router, login: &str, password: &str) -> String {
+    let (status, body) = self::login(app, login, password).await;
+    assert_eq!(status, StatusCode::OK);
+    body["accessToken"].as_str().unwrap().to_string()
+}
+
+/// Registers a user, returning their id.
+async fn user(pool: &PgPool, login: &str, password: &str, role: Role) -> String {
+    let user_id = storage::auth::register_user(pool, &Default::default(), login, password)
+        .await
+        .unwrap();
+    storage::db::user::set_role(pool, &user_id, role)
+        .await
+        .unwrap();
+    user_id.to_string()
+}
+
+#[sqlx::test]
+async fn managing_users(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(&pool, &dir);
+    let algernon_id = user(&pool, "algernon", "flowers for", Role::Admin).await;
+    let bob_id = user(&pool, "bob", "the builder", Role::User).await;
+    let algernon = token(&app, "algernon", "flowers for").await;
+    let bob = token(&app, "bob", "the builder").await;
+
+    // Only administrators reach the administration API, and only they may give tokens its scope.
+    let (status, _) = call(&app, "GET", "/admin/users", Some(&bob), Value::Null).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, "GET", "/admin/users", None, Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let admin_token = json!({ "name": "provisioning", "scopes": ["admin"] });
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/tokens",
+        Some(&bob),
+        admin_token.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = call(&app, "POST", "/auth/tokens", Some(&algernon), admin_token).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let script = body["token"].as_str().unwrap().to_string();
+    let read_token = json!({ "name": "backup", "scopes": ["files:read"] });
+    let (status, body) = call(&app, "POST", "/auth/tokens", Some(&algernon), read_token).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let reader = body["token"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, "GET", "/admin/users", Some(&reader), Value::Null).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Administrators make users with passwords the policy allows, and find them again.
+    let carol = json!({ "login": "carol", "password": "singing carols" });
+    let (status, body) = call(&app, "POST", "/admin/users", Some(&script), carol.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["role"], "user");
+    assert!(body.get("phc").is_none());
+    let carol_id = body["id"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, "POST", "/admin/users", Some(&script), carol).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let weak = json!({ "login": "dave", "password": "short" });
+    let (status, _) = call(&app, "POST", "/admin/users", Some(&script), weak).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = call(&app, "GET", "/admin/users", Some(&algernon), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let logins: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["login"].as_str().unwrap())
+        .collect();
+    assert_eq!(logins, ["algernon", "bob", "carol"]);
+    let (_, body) = call(
+        &app,
+        "GET",
+        "/admin/users?q=CAR",
+        Some(&algernon),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], carol_id);
+    let (_, body) = call(
+        &app,
+        "GET",
+        "/admin/users?limit=1&offset=1",
+        Some(&algernon),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(body[0]["login"], "bob");
+
+    // Resetting a password logs the user out everywhere.
+    let carol_user = format!("/admin/users/{}", carol_id);
+    let carol_token = token(&app, "carol", "singing carols").await;
+    let reset = json!({ "password": "a brand new song" });
+    let uri = format!("{}/password", carol_user);
+    let (status, _) = call(&app, "POST", &uri, Some(&algernon), reset).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/auth/sessions",
+        Some(&carol_token),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "carol", "singing carols").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let carol_token = token(&app, "carol", "a brand new song").await;
+
+    // Forcing a logout ends the sessions the user has, without stopping them from logging in.
+    let uri = format!("{}/logout", carol_user);
+    let (status, _) = call(&app, "POST", &uri, Some(&algernon), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/auth/sessions",
+        Some(&carol_token),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    token(&app, "carol", "a brand new song").await;
+
+    // Disabled users are logged out and cannot log in until they are enabled again.
+    let bob_user = format!("/admin/users/{}", bob_id);
+    let (status, body) = call(
+        &app,
+        "POST",
+        &format!("{}/disable", bob_user),
+        Some(&algernon),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["disabledAt"].is_string());
+    let (status, _) = call(&app, "GET", "/auth/sessions", Some(&bob), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "bob", "the builder").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = call(
+        &app,
+        "POST",
+        &format!("{}/enable", bob_user),
+        Some(&algernon),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["disabledAt"].is_null());
+    token(&app, "bob", "the builder").await;
+
+    // Roles change, but never an administrator's own.
+    let promote = json!({ "role": "admin" });
+    let (status, body) = call(&app, "PATCH", &carol_user, Some(&algernon), promote).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["role"], "admin");
+    let carol_token = token(&app, "carol", "a brand new song").await;
+    let (status, _) = call(&app, "GET", "/admin/users", Some(&carol_token), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let algernon_user = format!("/admin/users/{}", algernon_id);
+    let demote = json!({ "role": "user" });
+    let (status, _) = call(&app, "PATCH", &algernon_user, Some(&algernon), demote).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let uri = format!("{}/disable", algernon_user);
+    let (status, _) = call(&app, "POST", &uri, Some(&algernon), Value::Null).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let unknown = "/admin/users/00000000-0000-0000-0000-000000000000";
+    let (status, _) = call(&app, "GET", unknown, Some(&algernon), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn transferring_files(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(&pool, &dir);
+    user(&pool, "algernon", "flowers for", Role::Admin).await;
+    let bob_id = user(&pool, "bob", "the builder", Role::User).await;
+    let carol_id = user(&pool, "carol", "singing carols", Role::User).await;
+    let algernon = token(&app, "algernon", "flowers for").await;
+    let bob = token(&app, "bob", "the builder").await;
+    let carol = token(&app, "carol", "singing carols").await;
+
+    upload(&app, &bob, "/docs", "plan.txt").await;
+    upload(&app, &bob, "/docs", "notes.txt").await;
+    upload(&app, &bob, "/", "todo.txt").await;
+    upload(&app, &carol, "/bob", "about bob.txt").await;
+    let (_, root) = call(
+        &app,
+        "GET",
+        &format!("/folder/{}", uuid::Uuid::nil()),
+        Some(&bob),
+        Value::Null,
+    )
+    .await;
+    let id_of = |name: &str| {
+        root.as_array()
+            .unwrap()
+            .iter()
+            .find(|file| file["name"] == name)
+            .unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    let (docs, todo) = (id_of("docs"), id_of("todo.txt"));
+    let share = json!({ "login": "carol", "role": "viewer" });
+    let (status, _) = call(
+        &app,
+        "POST",
+        &format!("/files/{}/permissions", docs),
+        Some(&bob),
+        share,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = call(
+        &app,
+        "DELETE",
+        &format!("/files/{}", todo),
+        Some(&bob),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let bob_usage = format!("/admin/users/{}/usage", bob_id);
+    let (status, usage) = call(&app, "GET", &bob_usage, Some(&algernon), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        usage,
+        json!({ "files": 2, "folders": 1, "bytes": 22, "versionBytes": 0, "trashBytes": 11 })
+    );
+    let (status, _) = call(&app, "GET", &bob_usage, Some(&bob), Value::Null).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (_, changes) = call(&app, "GET", "/changes", Some(&bob), Value::Null).await;
+    let bob_cursor = changes["cursor"].clone();
+    let (_, changes) = call(&app, "GET", "/changes", Some(&carol), Value::Null).await;
+    let carol_cursor = changes["cursor"].clone();
+
+    // Everything bob had lands in a folder named after him, beside carol's own folder of that name.
+    let transfer = format!("/admin/users/{}/transfer", bob_id);
+    let (status, body) = call(
+        &app,
+        "POST",
+        &transfer,
+        Some(&algernon),
+        json!({ "to": carol_id }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["files"], 4);
+    assert_eq!(body["folder"]["name"], "bob (1)");
+    assert_eq!(body["folder"]["ownedBy"], carol_id);
+    let folder = body["folder"]["id"].as_str().unwrap().to_string();
+    let (status, tree) = call(
+        &app,
+        "GET",
+        &format!("/folder/{}/tree", folder),
+        Some(&carol),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let names: Vec<&str> = tree
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["bob (1)", "docs", "notes.txt", "plan.txt"]);
+
+    // The change feeds of both see the files go and arrive, except for those in the trash.
+    let journaled = |token: String, cursor: Value| {
+        let app = app.clone();
+        async move {
+            let (_, delta) = call(
+                &app,
+                "GET",
+                &format!("/changes?cursor={}", cursor),
+                Some(&token),
+                Value::Null,
+            )
+            .await;
+            let mut changes: Vec<(String, String)> = delta["changes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|change| {
+                    (
+                        change["kind"].as_str().unwrap().to_string(),
+                        change["name"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect();
+            changes.sort();
+            changes
+        }
+    };
+    let kinds = |kind: &str, names: &[&str]| -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|name| (kind.to_string(), name.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        journaled(bob.clone(), bob_cursor).await,
+        kinds("delete", &["docs", "notes.txt", "plan.txt"])
+    );
+    assert_eq!(
+        journaled(carol.clone(), carol_cursor).await,
+        kinds("create", &["bob (1)", "docs", "notes.txt", "plan.txt"])
+    );
+
+    // Carol owns what was shared with her, and bob has nothing left.
+    let (_, permissions) = call(
+        &app,
+        "GET",
+        &format!("/files/{}/permissions", docs),
+        Some(&carol),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(permissions, json!([]));
+    let (status, _) = call(
+        &app,
+        "GET",
+        &format!("/folder/{}", docs),
+        Some(&bob),
+        Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (_, usage) = call(&app, "GET", &bob_usage, Some(&algernon), Value::Null).await;
+    assert_eq!(
+        usage,
+        json!({ "files": 0, "folders": 0, "bytes": 0, "versionBytes": 0, "trashBytes": 0 })
+    );
+    let carol_usage = format!("/admin/users/{}/usage", carol_id);
+    let (_, usage) = call(&app, "GET", &carol_usage, Some(&algernon), Value::Null).await;
+    assert_eq!(
+        usage,
+        json!({ "files": 3, "folders": 3, "bytes": 33, "versionBytes": 0, "trashBytes": 11 })
+    );
+
+    let (status, _) = call(
+        &app,
+        "POST",
+        &transfer,
+        Some(&algernon),
+        json!({ "to": bob_id }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
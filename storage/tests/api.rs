@@ -5,6 +5,10 @@ use tower::ServiceExt;
 use tracing_subscriber::{EnvFilter, fmt};
 use uuid::uuid;
 
+/// Signs the tokens of every test, as a server would with the key it signs with.
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
 static TRACING: OnceCell<()> = OnceCell::new();
 
 pub fn init_tracing() {
@@ -20,15 +24,18 @@ async fn upload_success(pool: PgPool) {
     init_tracing();
     let token = storage::auth::jwt::issue(
         uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
-        "testing".as_bytes(),
+        &KEYS,
         chrono::Duration::minutes(30),
     )
     .unwrap();
     let dir = tempfile::tempdir().unwrap();
     let shared = Shared {
         pool,
-        jwt_secret: std::sync::Arc::from("testing".as_bytes()),
+        keys: KEYS.clone(),
         root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
     };
     let app = storage::app(shared);
     let body = axum::body::Body::from(concat!(
@@ -60,15 +67,18 @@ async fn download_success(pool: PgPool) {
     init_tracing();
     let token = storage::auth::jwt::issue(
         uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
-        "testing".as_bytes(),
+        &KEYS,
         chrono::Duration::minutes(30),
     )
     .unwrap();
     let dir = tempfile::tempdir().unwrap();
     let shared = Shared {
         pool,
-        jwt_secret: std::sync::Arc::from("testing".as_bytes()),
+        keys: KEYS.clone(),
         root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
     };
     tracing::debug!("root is {}", dir.path().to_string_lossy());
     let file_id = uuid!("7b798b53-5d49-404d-991f-ca92f74364e7");
@@ -98,3 +108,2087 @@ async fn download_success(pool: PgPool) {
     let bytes = response.into_body().collect().await.unwrap().to_bytes();
     assert_eq!(bytes, "Hello World!");
 }
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn resumable_upload_success(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let req = axum::http::Request::builder()
+        .method("POST")
+        .uri("/uploads")
+        .header("tus-resumable", "1.0.0")
+        .header("upload-length", "11")
+        // filename "test.txt", destination "/docs"
+        .header(
+            "upload-metadata",
+            "filename dGVzdC50eHQ=,destination L2RvY3M=",
+        )
+        .header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", &token),
+        )
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let location = response
+        .headers()
+        .get(axum::http::header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let patch = |offset: &str, chunk: &'static str| {
+        axum::http::Request::builder()
+            .method("PATCH")
+            .uri(&location)
+            .header("tus-resumable", "1.0.0")
+            .header("upload-offset", offset)
+            .header("content-type", "application/offset+octet-stream")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+            .body(axum::body::Body::from(chunk))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(patch("0", "hello ")).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "6");
+
+    let response = app.clone().oneshot(patch("0", "hello ")).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CONFLICT);
+
+    let req = axum::http::Request::builder()
+        .method("HEAD")
+        .uri(&location)
+        .header("tus-resumable", "1.0.0")
+        .header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", &token),
+        )
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "6");
+    assert_eq!(response.headers().get("upload-length").unwrap(), "11");
+
+    let response = app.clone().oneshot(patch("6", "world")).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "11");
+
+    let path = sqlx::query_scalar!("SELECT path FROM files WHERE name = 'test.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .unwrap();
+    let contents = std::fs::read_to_string(dir.path().join(path)).unwrap();
+    assert_eq!(contents, "hello world");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn resumable_upload_streams_without_a_connection(
+    pool_options: sqlx::postgres::PgPoolOptions,
+    connect_options: sqlx::postgres::PgConnectOptions,
+) {
+    init_tracing();
+    // A single connection: holding it while the body streams would starve the HEAD below.
+    let pool = pool_options
+        .max_connections(1)
+        .acquire_timeout(std::time::Duration::from_secs(2))
+        .connect_with(connect_options)
+        .await
+        .unwrap();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let request = |method: &str, uri: &str| {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("tus-resumable", "1.0.0")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+    };
+    let req = request("POST", "/uploads")
+        .header("upload-length", "11")
+        .header("upload-metadata", "filename dGVzdC50eHQ=")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let location = response
+        .headers()
+        .get(axum::http::header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let upload_id = location.strip_prefix("/uploads/").unwrap();
+    let temp = dir
+        .path()
+        .join("temp")
+        .join("331194d0-3c87-42ed-aab0-bac0fc637063")
+        .join(upload_id);
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<bytes::Bytes, std::io::Error>>(1);
+    let req = request("PATCH", &location)
+        .header("upload-offset", "0")
+        .header("content-type", "application/offset+octet-stream")
+        .body(axum::body::Body::from_stream(
+            tokio_stream::wrappers::ReceiverStream::new(receiver),
+        ))
+        .unwrap();
+    let slow = tokio::spawn(app.clone().oneshot(req));
+    sender.send(Ok(bytes::Bytes::from("hello "))).await.unwrap();
+    while std::fs::metadata(&temp).unwrap().len() < 6 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let req = request("HEAD", &location)
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "0");
+
+    let req = request("PATCH", &location)
+        .header("upload-offset", "0")
+        .header("content-type", "application/offset+octet-stream")
+        .body(axum::body::Body::from("hello world"))
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::LOCKED);
+
+    sender.send(Ok(bytes::Bytes::from("world"))).await.unwrap();
+    drop(sender);
+    let response = slow.await.unwrap().unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "11");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn resumable_upload_leases_across_instances(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let request = |method: &str, uri: &str| {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("tus-resumable", "1.0.0")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+    };
+    let req = request("POST", "/uploads")
+        .header("upload-length", "11")
+        .header("upload-metadata", "filename dGVzdC50eHQ=")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let location = response
+        .headers()
+        .get(axum::http::header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let patch = || {
+        request("PATCH", &location)
+            .header("upload-offset", "0")
+            .header("content-type", "application/offset+octet-stream")
+            .body(axum::body::Body::from("hello world"))
+            .unwrap()
+    };
+
+    // A request on another instance is writing to the upload.
+    sqlx::query!(
+        "UPDATE uploads SET writer = gen_random_uuid(), writing_until = now() + interval '1 minute'"
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = app.clone().oneshot(patch()).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::LOCKED);
+
+    // That request died without giving the upload up, and its lease ran out.
+    sqlx::query!("UPDATE uploads SET writing_until = now() - interval '1 second'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = app.clone().oneshot(patch()).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get("upload-offset").unwrap(), "11");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn resumable_upload_requires_version(pool: PgPool) {
+    init_tracing();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let req = axum::http::Request::builder()
+        .method("POST")
+        .uri("/uploads")
+        .header("upload-length", "11")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.oneshot(req).await.unwrap();
+    assert_eq!(
+        response.status(),
+        axum::http::StatusCode::PRECONDITION_FAILED
+    );
+    assert_eq!(response.headers().get("tus-version").unwrap(), "1.0.0");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "hello_world"))]
+async fn download_range_success(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let file_id = uuid!("7b798b53-5d49-404d-991f-ca92f74364e7");
+    let user_id = uuid!("331194d0-3c87-42ed-aab0-bac0fc637063");
+    let path = shared
+        .root
+        .join("storage")
+        .join(user_id.to_string())
+        .join(file_id.to_string());
+    std::fs::create_dir_all(path.parent().unwrap())
+        .expect("Failed to create the storage directory");
+    std::fs::write(&path, "Hello World!").expect("Failed to write to file");
+    let app = storage::app(shared);
+    let request = |method: &str, headers: &[(&str, &str)]| {
+        let mut builder = axum::http::Request::builder()
+            .method(method)
+            .uri(format!("/download/{}", file_id))
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(axum::body::Body::empty()).unwrap()
+    };
+    use http_body_util::BodyExt;
+
+    let response = app
+        .clone()
+        .oneshot(request("GET", &[("range", "bytes=6-")]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers().get("content-range").unwrap(),
+        "bytes 6-11/12"
+    );
+    let etag = response.headers().get("etag").unwrap().clone();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes, "World!");
+
+    let response = app
+        .clone()
+        .oneshot(request("GET", &[("range", "bytes=0-4,-1")]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::PARTIAL_CONTENT);
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let length: usize = response
+        .headers()
+        .get("content-length")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes.len(), length);
+    assert_eq!(
+        bytes,
+        format!(
+            concat!(
+                "--{0}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-4/12\r\n\r\nHello\r\n",
+                "--{0}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 11-11/12\r\n\r\n!\r\n",
+                "--{0}--\r\n"
+            ),
+            boundary
+        )
+    );
+
+    let response = app
+        .clone()
+        .oneshot(request("GET", &[("range", "bytes=12-")]))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status(),
+        axum::http::StatusCode::RANGE_NOT_SATISFIABLE
+    );
+    assert_eq!(
+        response.headers().get("content-range").unwrap(),
+        "bytes */12"
+    );
+
+    let response = app
+        .clone()
+        .oneshot(request("GET", &[("if-none-match", etag.to_str().unwrap())]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NOT_MODIFIED);
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &[("range", "bytes=6-"), ("if-range", "\"stale\"")],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes, "Hello World!");
+
+    let response = app.clone().oneshot(request("HEAD", &[])).await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    assert_eq!(response.headers().get("content-length").unwrap(), "12");
+    assert_eq!(response.headers().get("accept-ranges").unwrap(), "bytes");
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(bytes.is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "hello_world"))]
+async fn folder_hierarchy(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "docs" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let docs = json(response).await["id"].as_str().unwrap().to_string();
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "docs" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CONFLICT);
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "reports", "parentId": docs })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let reports = json(response).await["id"].as_str().unwrap().to_string();
+
+    let root = json(
+        app.clone()
+            .oneshot(request("GET", "/folder?name=", None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    let names: Vec<_> = root
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["docs", "hello_world.txt"]);
+
+    let children = json(
+        app.clone()
+            .oneshot(request("GET", &format!("/folder/{}", docs), None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(children[0]["id"], reports);
+    assert_eq!(children[0]["parentId"], docs);
+
+    let by_path = json(
+        app.clone()
+            .oneshot(request("GET", "/folder?name=/docs", None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(by_path, children);
+
+    let ancestors = json(
+        app.clone()
+            .oneshot(request(
+                "GET",
+                &format!("/files/{}/ancestors", reports),
+                None,
+            ))
+            .await
+            .unwrap(),
+    )
+    .await;
+    let names: Vec<_> = ancestors
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["docs", "reports"]);
+
+    let tree = json(
+        app.clone()
+            .oneshot(request("GET", &format!("/folder/{}/tree", docs), None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(tree.as_array().unwrap().len(), 2);
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/folder/7b798b53-5d49-404d-991f-ca92f74364e7",
+            None,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "hello_world"))]
+async fn move_rename_copy(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let file_id = uuid!("7b798b53-5d49-404d-991f-ca92f74364e7");
+    let user_id = uuid!("331194d0-3c87-42ed-aab0-bac0fc637063");
+    let path = shared
+        .root
+        .join("storage")
+        .join(user_id.to_string())
+        .join(file_id.to_string());
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "Hello World!").unwrap();
+    let app = storage::app(shared);
+    let request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "docs" })),
+        ))
+        .await
+        .unwrap();
+    let docs = json(response).await["id"].as_str().unwrap().to_string();
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "inner", "parentId": docs })),
+        ))
+        .await
+        .unwrap();
+    let inner = json(response).await["id"].as_str().unwrap().to_string();
+
+    // Move the file into the folder under a new name.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PATCH",
+            &format!("/files/{}", file_id),
+            Some(serde_json::json!({ "name": "greeting.txt", "parentId": docs })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    let moved = json(response).await;
+    assert_eq!(moved["name"], "greeting.txt");
+    assert_eq!(moved["parentId"], docs);
+
+    // A folder cannot be moved under itself.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PATCH",
+            &format!("/files/{}", docs),
+            Some(serde_json::json!({ "parentId": inner })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+
+    // Copying next to the original picks a numbered name.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/copy", file_id),
+            Some(serde_json::json!({})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    assert_eq!(json(response).await["name"], "greeting (1).txt");
+
+    // Renaming onto an existing name fails unless asked otherwise.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PATCH",
+            &format!("/files/{}", inner),
+            Some(serde_json::json!({ "name": "greeting.txt" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CONFLICT);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PATCH",
+            &format!("/files/{}", inner),
+            Some(serde_json::json!({ "name": "greeting.txt", "onConflict": "rename" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(json(response).await["name"], "greeting (2).txt");
+
+    // Copying a whole folder duplicates the rows and the blobs.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/copy", docs),
+            Some(serde_json::json!({ "parentId": uuid::Uuid::nil(), "name": "backup" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    let backup = json(response).await["id"].as_str().unwrap().to_string();
+    let tree = json(
+        app.clone()
+            .oneshot(request("GET", &format!("/folder/{}/tree", backup), None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    let tree = tree.as_array().unwrap();
+    assert_eq!(tree.len(), 4);
+    let copy = tree.iter().find(|f| f["name"] == "greeting.txt").unwrap();
+    assert_ne!(copy["id"], file_id.to_string());
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &format!("/download/{}", copy["id"].as_str().unwrap()),
+            None,
+        ))
+        .await
+        .unwrap();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes, "Hello World!");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "hello_world"))]
+async fn trash_and_restore(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let file_id = uuid!("7b798b53-5d49-404d-991f-ca92f74364e7");
+    let user_id = uuid!("331194d0-3c87-42ed-aab0-bac0fc637063");
+    let path = shared
+        .root
+        .join("storage")
+        .join(user_id.to_string())
+        .join(file_id.to_string());
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "Hello World!").unwrap();
+    let app = storage::app(shared.clone());
+    let request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "docs" })),
+        ))
+        .await
+        .unwrap();
+    let docs = json(response).await["id"].as_str().unwrap().to_string();
+    app.clone()
+        .oneshot(request(
+            "PATCH",
+            &format!("/files/{}", file_id),
+            Some(serde_json::json!({ "parentId": docs })),
+        ))
+        .await
+        .unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", &format!("/files/{}", docs), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    let trash = json(
+        app.clone()
+            .oneshot(request("GET", "/trash", None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(trash.as_array().unwrap().len(), 1);
+    assert_eq!(trash[0]["id"], docs);
+    let root = json(
+        app.clone()
+            .oneshot(request("GET", "/folder?name=", None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert!(root.as_array().unwrap().is_empty());
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/download/{}", file_id), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NOT_FOUND);
+
+    // A new folder took the name in the meantime.
+    app.clone()
+        .oneshot(request(
+            "POST",
+            "/folder",
+            Some(serde_json::json!({ "name": "docs" })),
+        ))
+        .await
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(request("POST", &format!("/trash/{}/restore", docs), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    assert_eq!(json(response).await["name"], "docs (1)");
+    let children = json(
+        app.clone()
+            .oneshot(request("GET", &format!("/folder/{}", docs), None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(children[0]["id"], file_id.to_string());
+
+    // Emptying the trash frees the blobs.
+    app.clone()
+        .oneshot(request("DELETE", &format!("/files/{}", file_id), None))
+        .await
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", "/trash", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert!(!path.exists());
+    let trash = json(
+        app.clone()
+            .oneshot(request("GET", "/trash", None))
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert!(trash.as_array().unwrap().is_empty());
+
+    // Anything older than the retention period is purged in the background.
+    app.clone()
+        .oneshot(request("DELETE", &format!("/files/{}", docs), None))
+        .await
+        .unwrap();
+    let mut shared = shared;
+    shared.settings.trash_retention = chrono::Duration::zero();
+    let purged = storage::tasks::sweep_trash(&shared).await.unwrap();
+    assert_eq!(purged, 1);
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn version_history(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared.clone());
+    let upload = |contents: &str| {
+        let body = axum::body::Body::from(format!(
+            concat!(
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+                "/docs\r\n",
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "{}\r\n",
+                "--BOUNDARY--\r\n"
+            ),
+            contents
+        ));
+        axum::http::Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+            .body(body)
+            .unwrap()
+    };
+    let request = |method: &str, uri: &str| {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    use http_body_util::BodyExt;
+    async fn body(response: axum::response::Response) -> axum::body::Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    for contents in ["first", "second"] {
+        let response = app.clone().oneshot(upload(contents)).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    }
+    let file_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'notes.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/download/{}", file_id)))
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, "second");
+
+    let versions: serde_json::Value = serde_json::from_slice(
+        &body(
+            app.clone()
+                .oneshot(request("GET", &format!("/files/{}/versions", file_id)))
+                .await
+                .unwrap(),
+        )
+        .await,
+    )
+    .unwrap();
+    let versions = versions.as_array().unwrap();
+    assert_eq!(versions.len(), 2);
+    let first = versions[1]["id"].as_str().unwrap();
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &format!("/files/{}/versions/{}", file_id, first),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, "first");
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/versions/{}/restore", file_id, first),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/download/{}", file_id)))
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, "first");
+
+    let mut shared = shared;
+    shared.settings.versions_keep_last = 1;
+    shared.settings.versions_keep_daily = 0;
+    let pruned = storage::api::versions::prune(&shared).await.unwrap();
+    assert_eq!(pruned, 2);
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/download/{}", file_id)))
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, "first");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "hello_world"))]
+async fn content_deduplication(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared.clone());
+    let upload = |name: &str| {
+        let body = axum::body::Body::from(format!(
+            concat!(
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+                "/docs\r\n",
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "installer\r\n",
+                "--BOUNDARY--\r\n"
+            ),
+            name
+        ));
+        axum::http::Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+            .body(body)
+            .unwrap()
+    };
+    let request = |method: &str, uri: &str| {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            )
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    use http_body_util::BodyExt;
+    async fn body(response: axum::response::Response) -> axum::body::Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    for name in ["a.txt", "b.txt"] {
+        let response = app.clone().oneshot(upload(name)).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+    }
+    let paths =
+        sqlx::query_scalar!("SELECT DISTINCT path FROM files WHERE name IN ('a.txt', 'b.txt')")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(paths.len(), 1);
+    let path = paths[0].clone().unwrap();
+    assert!(path.starts_with("blobs/"));
+    assert!(dir.path().join(&path).exists());
+    let refs = sqlx::query_scalar!("SELECT refs FROM blobs")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(refs, [2]);
+
+    // Nothing is collected while a file still refers to the blob.
+    let a = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'a.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", &format!("/files/{}", a)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", "/trash"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert!(dir.path().join(&path).exists());
+    let b = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'b.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", &format!("/files/{}", b)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", "/trash"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::NO_CONTENT);
+    assert!(!dir.path().join(&path).exists());
+    let blobs = sqlx::query_scalar!("SELECT count(*) FROM blobs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(blobs, Some(0));
+
+    // Blobs stored one per upload are moved into the blob store.
+    let legacy = dir
+        .path()
+        .join("storage/331194d0-3c87-42ed-aab0-bac0fc637063/7b798b53-5d49-404d-991f-ca92f74364e7");
+    std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+    std::fs::write(&legacy, "hello world").unwrap();
+    let rehashed = storage::blob::rehash_legacy(&pool, &*shared.blobs)
+        .await
+        .unwrap();
+    assert_eq!(rehashed, 1);
+    assert!(!legacy.exists());
+    let path = sqlx::query_scalar!(
+        "SELECT path FROM files WHERE id = '7b798b53-5d49-404d-991f-ca92f74364e7'"
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        path,
+        storage::blob::path("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+            .to_string_lossy()
+    );
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/download/7b798b53-5d49-404d-991f-ca92f74364e7",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, "hello world");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "bartholomew"))]
+async fn sharing(pool: PgPool) {
+    init_tracing();
+    let algernon = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let bartholomew = storage::auth::jwt::issue(
+        uuid!("9e0c2a4f-5b1d-4c7e-8f3a-6d2b1e0c9a87"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let upload = |token: &str, field: &str, value: &str, name: &str| {
+        let body = axum::body::Body::from(format!(
+            concat!(
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                "{}\r\n",
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "shared contents\r\n",
+                "--BOUNDARY--\r\n"
+            ),
+            field, value, name
+        ));
+        axum::http::Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", token),
+            )
+            .body(body)
+            .unwrap()
+    };
+    let request = |token: &str, method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    use axum::http::StatusCode;
+
+    let response = app
+        .clone()
+        .oneshot(upload(
+            &algernon,
+            "destination",
+            "/projects/team",
+            "plan.txt",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let file_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'plan.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let team_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'team'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let download = format!("/download/{}", file_id);
+    let permissions = format!("/files/{}/permissions", team_id);
+    let bartholomew_permission = format!("{}/9e0c2a4f-5b1d-4c7e-8f3a-6d2b1e0c9a87", permissions);
+
+    let response = app
+        .clone()
+        .oneshot(request(&bartholomew, "GET", &download, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Viewers can read everything under the shared folder, but not change it.
+    let response = app
+        .clone()
+        .oneshot(request(
+            &algernon,
+            "POST",
+            &permissions,
+            Some(serde_json::json!({"login": "bartholomew", "role": "viewer"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = app
+        .clone()
+        .oneshot(request(&bartholomew, "GET", "/shared", None))
+        .await
+        .unwrap();
+    let listing = json(response).await;
+    assert_eq!(listing.as_array().unwrap().len(), 1);
+    assert_eq!(listing[0]["id"], team_id.to_string());
+    assert_eq!(listing[0]["role"], "viewer");
+    let response = app
+        .clone()
+        .oneshot(request(&bartholomew, "GET", &download, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(request(
+            &bartholomew,
+            "GET",
+            &format!("/files/{}/ancestors", file_id),
+            None,
+        ))
+        .await
+        .unwrap();
+    let names: Vec<_> = json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(names, ["team", "plan.txt"]);
+    let response = app
+        .clone()
+        .oneshot(upload(
+            &bartholomew,
+            "parentId",
+            &team_id.to_string(),
+            "notes.txt",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .clone()
+        .oneshot(request(
+            &bartholomew,
+            "PATCH",
+            &format!("/files/{}", file_id),
+            Some(serde_json::json!({"name": "mine.txt"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .clone()
+        .oneshot(request(
+            &bartholomew,
+            "POST",
+            &permissions,
+            Some(serde_json::json!({"login": "algernon", "role": "viewer"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Editors can add to the folder, and what they add stays in the owner's drive.
+    let response = app
+        .clone()
+        .oneshot(request(
+            &algernon,
+            "PATCH",
+            &bartholomew_permission,
+            Some(serde_json::json!({"role": "editor"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["role"], "editor");
+    let response = app
+        .clone()
+        .oneshot(upload(
+            &bartholomew,
+            "parentId",
+            &team_id.to_string(),
+            "notes.txt",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let notes = sqlx::query!("SELECT id, owned_by, parent_id FROM files WHERE name = 'notes.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        notes.owned_by,
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063")
+    );
+    assert_eq!(notes.parent_id, Some(team_id));
+    let response = app
+        .clone()
+        .oneshot(request(
+            &bartholomew,
+            "PATCH",
+            &format!("/files/{}", notes.id),
+            Some(serde_json::json!({"parentId": uuid::Uuid::nil()})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .clone()
+        .oneshot(request(
+            &bartholomew,
+            "DELETE",
+            &format!("/files/{}", notes.id),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .clone()
+        .oneshot(request(&algernon, "GET", "/trash", None))
+        .await
+        .unwrap();
+    assert_eq!(json(response).await.as_array().unwrap().len(), 1);
+
+    let response = app
+        .clone()
+        .oneshot(request(&algernon, "DELETE", &bartholomew_permission, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .clone()
+        .oneshot(request(&bartholomew, "GET", &download, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .clone()
+        .oneshot(request(&bartholomew, "GET", "/shared", None))
+        .await
+        .unwrap();
+    assert!(json(response).await.as_array().unwrap().is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn share_links(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let multipart = |name: &str| {
+        axum::body::Body::from(format!(
+            concat!(
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+                "/public\r\n",
+                "--BOUNDARY\r\n",
+                "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "linked contents\r\n",
+                "--BOUNDARY--\r\n"
+            ),
+            name
+        ))
+    };
+    let request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    let anonymous = |method: &str, uri: &str, password: Option<&str>, body: axum::body::Body| {
+        let mut builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY");
+        if let Some(password) = password {
+            builder = builder.header("x-link-password", password);
+        }
+        builder.body(body).unwrap()
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    use axum::http::StatusCode;
+
+    let response = app
+        .clone()
+        .oneshot(
+            axum::http::Request::builder()
+                .method("POST")
+                .uri("/upload")
+                .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+                .header(
+                    axum::http::header::AUTHORIZATION,
+                    format!("Bearer {}", &token),
+                )
+                .body(multipart("report.txt"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let file_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'report.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let folder_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'public'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    // A password-protected link that can be downloaded from twice.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"password": "open sesame", "maxDownloads": 2})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let link = json(response).await;
+    assert_eq!(link["protected"], true);
+    assert_eq!(link["mode"], "readOnly");
+    let limited = format!("/s/{}", link["token"].as_str().unwrap());
+    assert!(link["token"].as_str().unwrap().len() >= 43);
+    let response = app
+        .clone()
+        .oneshot(anonymous("GET", &limited, None, axum::body::Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &limited,
+            Some("wrong"),
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    for _ in 0..2 {
+        let response = app
+            .clone()
+            .oneshot(anonymous(
+                "GET",
+                &limited,
+                Some("open sesame"),
+                axum::body::Body::empty(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "linked contents");
+    }
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &limited,
+            Some("open sesame"),
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::GONE);
+
+    // Expired links stop working, and no link can be made to expire in the past.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"expiresAt": "2000-01-01T00:00:00Z"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"expiresAt": chrono::Utc::now() + chrono::Duration::hours(1)})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let expiring = json(response).await;
+    sqlx::query!(
+        "UPDATE share_links SET expires_at = now() - interval '1 minute' WHERE id = $1",
+        expiring["id"]
+            .as_str()
+            .unwrap()
+            .parse::<uuid::Uuid>()
+            .unwrap()
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &format!("/s/{}", expiring["token"].as_str().unwrap()),
+            None,
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::GONE);
+
+    // Only a hash of the token is kept.
+    let stored = sqlx::query_scalar!(
+        "SELECT count(*) FROM share_links WHERE token_hash = sha256($1::TEXT::BYTEA)",
+        expiring["token"].as_str().unwrap()
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(stored, Some(1));
+
+    // Ranges past the first byte, as when resuming or seeking, are not counted as downloads
+    // unless the link has a limit, which several ranges in one request do not get around.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({})),
+        ))
+        .await
+        .unwrap();
+    let unlimited = format!("/s/{}", json(response).await["token"].as_str().unwrap());
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"maxDownloads": 1})),
+        ))
+        .await
+        .unwrap();
+    let once = format!("/s/{}", json(response).await["token"].as_str().unwrap());
+    let ranged = |link: &str, range: &str| {
+        axum::http::Request::builder()
+            .uri(link)
+            .header(axum::http::header::RANGE, range)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    for _ in 0..3 {
+        let response = app
+            .clone()
+            .oneshot(ranged(&unlimited, "bytes=7-"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "contents");
+    }
+    let downloads = sqlx::query_scalar!(
+        "SELECT downloads FROM share_links WHERE token_hash = sha256($1::TEXT::BYTEA)",
+        unlimited.strip_prefix("/s/").unwrap()
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(downloads, 0);
+    let response = app
+        .clone()
+        .oneshot(ranged(&once, "bytes=0-,0-0"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    for range in ["bytes=0-,0-0", "bytes=7-", "bytes=0-0"] {
+        let response = app.clone().oneshot(ranged(&once, range)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    // Guessing a link's password is throttled like logging in.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"password": "open sesame"})),
+        ))
+        .await
+        .unwrap();
+    let guarded = format!("/s/{}", json(response).await["token"].as_str().unwrap());
+    for guess in 0..6 {
+        let response = app
+            .clone()
+            .oneshot(anonymous(
+                "GET",
+                &guarded,
+                Some(&format!("guess {}", guess)),
+                axum::body::Body::empty(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &guarded,
+            Some("open sesame"),
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // A read-only folder link lists the folder and serves what is in it, but takes no uploads.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", folder_id),
+            Some(serde_json::json!({})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let browse = format!("/s/{}", json(response).await["token"].as_str().unwrap());
+    let response = app
+        .clone()
+        .oneshot(anonymous("GET", &browse, None, axum::body::Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await[0]["name"], "report.txt");
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &format!("{}/files/{}", browse, file_id),
+            None,
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(anonymous(
+            "GET",
+            &format!("{}/files/{}", browse, folder_id),
+            None,
+            axum::body::Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(anonymous("POST", &browse, None, multipart("intruder.txt")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Upload drops only go to folders, keep what is there and hide the contents.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", file_id),
+            Some(serde_json::json!({"mode": "uploadDrop"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/files/{}/links", folder_id),
+            Some(serde_json::json!({"mode": "uploadDrop"})),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let drop_link = json(response).await;
+    let drop = format!("/s/{}", drop_link["token"].as_str().unwrap());
+    let response = app
+        .clone()
+        .oneshot(anonymous("GET", &drop, None, axum::body::Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .clone()
+        .oneshot(anonymous("POST", &drop, None, multipart("report.txt")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let mut names = sqlx::query_scalar!("SELECT name FROM files WHERE parent_id = $1", folder_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    names.sort();
+    assert_eq!(names, ["report (1).txt", "report.txt"]);
+
+    // Listing leaves out the exhausted and expired links; revoked links are gone for good.
+    let response = app
+        .clone()
+        .oneshot(request("GET", "/links", None))
+        .await
+        .unwrap();
+    let links = json(response).await;
+    assert_eq!(links.as_array().unwrap().len(), 4);
+    assert!(links[0].get("phc").is_none());
+    assert!(links[0].get("token").is_none());
+    let response = app
+        .clone()
+        .oneshot(request(
+            "DELETE",
+            &format!("/links/{}", drop_link["id"].as_str().unwrap()),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .clone()
+        .oneshot(anonymous("POST", &drop, None, multipart("late.txt")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app
+        .clone()
+        .oneshot(request("GET", "/links", None))
+        .await
+        .unwrap();
+    assert_eq!(json(response).await.as_array().unwrap().len(), 3);
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn change_feed(pool: PgPool) {
+    init_tracing();
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                axum::http::header::AUTHORIZATION,
+                format!("Bearer {}", &token),
+            );
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(axum::body::Body::empty()).unwrap(),
+        }
+    };
+    use http_body_util::BodyExt;
+    async fn json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    use axum::http::StatusCode;
+
+    let response = app
+        .clone()
+        .oneshot(request("GET", "/changes", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let start = json(response).await;
+    assert_eq!(start["changes"].as_array().unwrap().len(), 0);
+    let cursor = start["cursor"].as_i64().unwrap();
+
+    let body = axum::body::Body::from(concat!(
+        "--BOUNDARY\r\n",
+        "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+        "/docs\r\n",
+        "--BOUNDARY\r\n",
+        "Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n",
+        "Content-Type: text/plain\r\n\r\n",
+        "hello world\r\n",
+        "--BOUNDARY--\r\n"
+    ));
+    let response = app
+        .clone()
+        .oneshot(
+            axum::http::Request::builder()
+                .method("POST")
+                .uri("/upload")
+                .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+                .header(
+                    axum::http::header::AUTHORIZATION,
+                    format!("Bearer {}", &token),
+                )
+                .body(body)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let file_id = sqlx::query_scalar!("SELECT id FROM files WHERE name = 'notes.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let file = format!("/files/{}", file_id);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PATCH",
+            &file,
+            Some(serde_json::json!({ "name": "todo.txt" })),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(request("DELETE", &file, None))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            &format!("/trash/{}/restore", file_id),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    // Creating the file and pointing it at its contents is a single change.
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/changes?cursor={}", cursor), None))
+        .await
+        .unwrap();
+    let delta = json(response).await;
+    let changes: Vec<_> = delta["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| {
+            (
+                c["kind"].as_str().unwrap().to_string(),
+                c["name"].as_str().unwrap().to_string(),
+                c["folder"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    let expected = [
+        ("create", "docs", true),
+        ("create", "notes.txt", false),
+        ("rename", "todo.txt", false),
+        ("delete", "todo.txt", false),
+        ("restore", "todo.txt", false),
+    ];
+    assert_eq!(
+        changes,
+        expected.map(|(kind, name, folder)| (kind.to_string(), name.to_string(), folder))
+    );
+    assert_eq!(delta["hasMore"], false);
+    let latest = delta["cursor"].as_i64().unwrap();
+
+    // Batches pick up where the previous one ended.
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &format!("/changes?cursor={}&limit=2", cursor),
+            None,
+        ))
+        .await
+        .unwrap();
+    let delta = json(response).await;
+    assert_eq!(delta["changes"].as_array().unwrap().len(), 2);
+    assert_eq!(delta["hasMore"], true);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &format!("/changes?cursor={}&limit=2", delta["cursor"]),
+            None,
+        ))
+        .await
+        .unwrap();
+    let delta = json(response).await;
+    assert_eq!(delta["changes"][0]["kind"], "rename");
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/changes?cursor={}", latest), None))
+        .await
+        .unwrap();
+    let delta = json(response).await;
+    assert_eq!(delta["changes"].as_array().unwrap().len(), 0);
+    assert_eq!(delta["cursor"], latest);
+    let response = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            &format!("/changes?cursor={}", latest + 1),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Once the journal is compacted, older cursors call for a full resync.
+    let dropped =
+        storage::db::change::compact(&pool, chrono::Utc::now() + chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+    assert_eq!(dropped, 5);
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/changes?cursor={}", cursor), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::GONE);
+    let response = app
+        .clone()
+        .oneshot(request("GET", "/changes", None))
+        .await
+        .unwrap();
+    assert_eq!(json(response).await["cursor"], latest);
+    let response = app
+        .clone()
+        .oneshot(request("GET", &format!("/changes?cursor={}", latest), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
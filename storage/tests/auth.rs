@@ -1,8 +1,12 @@
 use sqlx::PgPool;
 
+/// Signs the tokens of every test, as a server would with the key it signs with.
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
 #[sqlx::test]
 async fn register_success(pool: PgPool) {
-    let user_id = storage::auth::register_user(&pool, "algernon", "flowers")
+    let user_id = storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
         .await
         .unwrap();
     assert!(!user_id.is_nil())
@@ -12,10 +16,10 @@ async fn register_success(pool: PgPool) {
 async fn login_success(pool: PgPool) {
     let login = "algernon";
     let password = "flowers";
-    let user_id = storage::auth::register_user(&pool, login, password)
+    let user_id = storage::auth::register_user(&pool, &Default::default(), login, password)
         .await
         .unwrap();
-    let logged_in = storage::auth::login_user(&pool, login, password)
+    let logged_in = storage::auth::login_user(&pool, &Default::default(), login, password)
         .await
         .unwrap();
     assert_eq!(user_id, logged_in);
@@ -25,10 +29,10 @@ async fn login_success(pool: PgPool) {
 async fn login_fail(pool: PgPool) {
     let login = "algernon";
     let password = "flowers";
-    let _user_id = storage::auth::register_user(&pool, login, password)
+    let _user_id = storage::auth::register_user(&pool, &Default::default(), login, password)
         .await
         .unwrap();
-    let logged_in = storage::auth::login_user(&pool, login, "other").await;
+    let logged_in = storage::auth::login_user(&pool, &Default::default(), login, "other").await;
     assert!(logged_in.is_err());
 }
 
@@ -40,3 +44,998 @@ async fn verify_test_db(pool: PgPool) {
         .unwrap();
     println!("connected to {}", row.current_database.unwrap());
 }
+
+fn app(pool: PgPool, root: &std::path::Path) -> axum::Router {
+    storage::app(storage::api::Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: root.to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(root)),
+        settings: Default::default(),
+        events: Default::default(),
+    })
+}
+
+async fn call(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: serde_json::Value,
+) -> (axum::http::StatusCode, serde_json::Value) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        builder = builder.header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        );
+    }
+    let request = builder
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&bytes).unwrap_or_default())
+}
+
+fn tokens(body: &serde_json::Value) -> (String, String) {
+    (
+        body["accessToken"].as_str().unwrap().to_string(),
+        body["refreshToken"].as_str().unwrap().to_string(),
+    )
+}
+
+#[sqlx::test]
+async fn refresh_and_logout(pool: PgPool) {
+    use axum::http::StatusCode;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool, dir.path());
+    let credentials = serde_json::json!({ "login": "algernon", "password": "flowers" });
+
+    let (status, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["expiresIn"], 30 * 60);
+    let (access, refresh) = tokens(&body);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&access),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Refreshing rotates the refresh token.
+    let (status, body) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": refresh }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (rotated_access, rotated) = tokens(&body);
+    assert_ne!(rotated, refresh);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&rotated_access),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Presenting the old token again revokes the whole family.
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": refresh }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": rotated }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    for token in [&access, &rotated_access] {
+        let (status, _) = call(&app, "GET", "/config", Some(token), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Logging out ends one session.
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    let (first, first_refresh) = tokens(&body);
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    let (second, second_refresh) = tokens(&body);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/logout",
+        Some(&first),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&first),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": first_refresh }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&second),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Logging out everywhere ends all of them.
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials).await;
+    let (third, _) = tokens(&body);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/logout/all",
+        Some(&third),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    for token in [&second, &third] {
+        let (status, _) = call(&app, "GET", "/config", Some(token), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": second_refresh }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn sessions(pool: PgPool) {
+    use axum::http::StatusCode;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool, dir.path());
+
+    let (_, body) = call(
+        &app,
+        "POST",
+        "/auth/login",
+        None,
+        serde_json::json!({ "login": "algernon", "password": "flowers", "deviceName": "Laptop" }),
+    )
+    .await;
+    let (laptop, _) = tokens(&body);
+    let (_, body) = call(
+        &app,
+        "POST",
+        "/auth/login",
+        None,
+        serde_json::json!({ "login": "algernon", "password": "flowers" }),
+    )
+    .await;
+    let (phone, phone_refresh) = tokens(&body);
+
+    let (status, body) = call(
+        &app,
+        "GET",
+        "/auth/sessions",
+        Some(&laptop),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = body.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let current: Vec<_> = sessions.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["deviceName"], "Laptop");
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Ending a session locks out both of its tokens, and leaves the others be.
+    let uri = format!("/auth/sessions/{}", other);
+    let (status, _) = call(&app, "DELETE", &uri, Some(&laptop), serde_json::Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&phone),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/refresh",
+        None,
+        serde_json::json!({ "refreshToken": phone_refresh }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = call(
+        &app,
+        "GET",
+        "/auth/sessions",
+        Some(&laptop),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    // Sessions already ended, and ones that are not the user's, cannot be ended.
+    let (status, _) = call(&app, "DELETE", &uri, Some(&laptop), serde_json::Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let uri = format!("/auth/sessions/{}", uuid::Uuid::new_v4());
+    let (status, _) = call(&app, "DELETE", &uri, Some(&laptop), serde_json::Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn personal_access_tokens(pool: PgPool) {
+    use axum::http::StatusCode;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool, dir.path());
+    let (_, body) = call(
+        &app,
+        "POST",
+        "/auth/login",
+        None,
+        serde_json::json!({ "login": "algernon", "password": "flowers" }),
+    )
+    .await;
+    let (access, _) = tokens(&body);
+
+    let (status, body) = call(
+        &app,
+        "POST",
+        "/auth/tokens",
+        Some(&access),
+        serde_json::json!({ "name": "backup script", "scopes": ["files:read", "files:read"] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["scopes"], serde_json::json!(["files:read"]));
+    let token_id = body["id"].as_str().unwrap().to_string();
+    let token = body["token"].as_str().unwrap().to_string();
+
+    // The token works where its scope is required, and nowhere else.
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/folder/00000000-0000-0000-0000-000000000000",
+        Some(&token),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/config",
+        Some(&token),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/folder",
+        Some(&token),
+        serde_json::json!({ "name": "docs" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/auth/tokens",
+        Some(&token),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/auth/sessions",
+        Some(&token),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Tokens are listed without their secret.
+    let (status, body) = call(
+        &app,
+        "GET",
+        "/auth/tokens",
+        Some(&access),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["name"], "backup script");
+    assert!(body[0].get("token").is_none());
+
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/tokens",
+        Some(&access),
+        serde_json::json!({ "name": "late", "scopes": ["files:read"], "expiresAt": "2020-01-01T00:00:00Z" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/tokens",
+        Some(&access),
+        serde_json::json!({ "name": "odd", "scopes": ["files:delete"] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Revoked tokens stop working.
+    let uri = format!("/auth/tokens/{}", token_id);
+    let (status, _) = call(&app, "DELETE", &uri, Some(&access), serde_json::Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "GET",
+        "/folder/00000000-0000-0000-0000-000000000000",
+        Some(&token),
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn signing_key_rotation(pool: PgPool) {
+    use axum::http::StatusCode;
+    use storage::api::keys;
+
+    let user_id = storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut shared = storage::api::Shared {
+        pool: pool.clone(),
+        keys: storage::auth::jwt::Keys::new("proto-drive", "proto-drive"),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared.clone());
+    let issue =
+        |keys| storage::auth::jwt::issue(user_id, keys, chrono::Duration::minutes(30)).unwrap();
+    let kid = |token: &str| jsonwebtoken::decode_header(token).unwrap().kid.unwrap();
+
+    // The first key signs right away, and is kept until it is due.
+    assert!(keys::rotate(&shared).await.unwrap());
+    assert!(!keys::rotate(&shared).await.unwrap());
+    let old = issue(&shared.keys);
+    let (status, body) = call(
+        &app,
+        "GET",
+        "/.well-known/jwks.json",
+        None,
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["keys"].as_array().unwrap().len(), 1);
+    assert_eq!(body["keys"][0]["kid"], kid(&old));
+    assert_eq!(body["keys"][0]["alg"], "EdDSA");
+
+    // A new key is published a sweep before it signs anything.
+    shared.settings.key_rotation = chrono::Duration::zero();
+    shared.settings.jwt_algorithm = jsonwebtoken::Algorithm::RS256;
+    assert!(keys::rotate(&shared).await.unwrap());
+    let (_, body) = call(
+        &app,
+        "GET",
+        "/.well-known/jwks.json",
+        None,
+        serde_json::Value::Null,
+    )
+    .await;
+    assert_eq!(body["keys"].as_array().unwrap().len(), 2);
+    assert_eq!(kid(&issue(&shared.keys)), kid(&old));
+
+    // A day on the new key signs, and tokens of the old one work until it is retired.
+    sqlx::query!("UPDATE signing_keys SET not_before = not_before - INTERVAL '1 day';")
+        .execute(&pool)
+        .await
+        .unwrap();
+    keys::load(&shared).await.unwrap();
+    let new = issue(&shared.keys);
+    assert_ne!(kid(&new), kid(&old));
+    let config = |token| call(&app, "GET", "/config", Some(token), serde_json::Value::Null);
+    assert_eq!(config(&old).await.0, StatusCode::OK);
+    assert_eq!(config(&new).await.0, StatusCode::OK);
+    shared.settings.key_rotation = chrono::Duration::days(30);
+    assert!(!keys::rotate(&shared).await.unwrap());
+    assert_eq!(shared.keys.jwks().keys.len(), 1);
+    assert_eq!(config(&old).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(config(&new).await.0, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn two_factor(pool: PgPool) {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use storage::auth::totp;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool.clone(), dir.path());
+    let credentials = json!({ "login": "algernon", "password": "flowers" });
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    let (access, _) = tokens(&body);
+
+    // Enrolling does nothing until a code shows the authenticator was set up.
+    let (status, body) = call(&app, "POST", "/auth/2fa", Some(&access), json!(null)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        body["otpauthUri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/proto-drive:algernon?")
+    );
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    assert!(body["accessToken"].is_string());
+    let code = json!({ "code": "abcdef" });
+    let (status, _) = call(&app, "POST", "/auth/2fa/activate", Some(&access), code).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let secret = sqlx::query_scalar!("SELECT secret FROM two_factor;")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let used = totp::code(&secret, totp::now());
+    let code = json!({ "code": used });
+    let (status, body) = call(&app, "POST", "/auth/2fa/activate", Some(&access), code).await;
+    assert_eq!(status, StatusCode::OK);
+    let recovery: Vec<String> = serde_json::from_value(body["recoveryCodes"].clone()).unwrap();
+    assert_eq!(recovery.len(), 10);
+    let (status, _) = call(&app, "POST", "/auth/2fa", Some(&access), json!(null)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Logging in now takes a second step, which codes already used cannot pass.
+    let (status, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["accessToken"].is_null());
+    let pending = body["mfaToken"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, "GET", "/config", Some(&pending), json!(null)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let second = |code: &str| json!({ "mfaToken": pending, "code": code });
+    let (status, _) = call(&app, "POST", "/auth/login/2fa", None, second(&used)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = call(
+        &app,
+        "POST",
+        "/auth/login/2fa",
+        None,
+        second(&recovery[0].to_uppercase()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (access, _) = tokens(&body);
+    let (status, _) = call(&app, "POST", "/auth/login/2fa", None, second(&recovery[0])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, body) = call(&app, "GET", "/auth/2fa", Some(&access), json!(null)).await;
+    assert_eq!(body, json!({ "enabled": true, "recoveryCodesLeft": 9 }));
+
+    // Turning it off takes a fresh code, and wrong ones count as failed logins.
+    let code = json!({ "code": used });
+    let (status, _) = call(&app, "DELETE", "/auth/2fa", Some(&access), code).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let failures = sqlx::query_scalar!(
+        "SELECT failures FROM login_failures WHERE kind = 'login' AND subject = 'algernon'"
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(failures, 2);
+    let code = json!({ "code": totp::code(&secret, totp::now() + 30) });
+    let (status, _) = call(&app, "DELETE", "/auth/2fa", Some(&access), code).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials).await;
+    assert!(body["accessToken"].is_string());
+}
+
+#[sqlx::test]
+async fn lockout(pool: PgPool) {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use storage::db::login_failure::{self, Kind};
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            login_attempts: 2,
+            ip_login_attempts: 4,
+            trust_proxy: true,
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+    let login = |login: &str, password: &str, ip: &str| {
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::post("/auth/login")
+            .header("content-type", "application/json")
+            .header("x-forwarded-for", ip)
+            .body(axum::body::Body::from(
+                json!({ "login": login, "password": password }).to_string(),
+            ))
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let bytes = response.into_body().collect().await.unwrap().to_bytes();
+            (status, bytes)
+        }
+    };
+
+    // Unknown users and wrong passwords are turned away alike.
+    let unknown = login("nobody", "flowers", "192.0.2.1").await;
+    let wrong = login("algernon", "weeds", "192.0.2.1").await;
+    assert_eq!(wrong.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(unknown, wrong);
+
+    // Past the free attempts, even the right password waits out the lockout.
+    let (status, _) = login("algernon", "weeds", "192.0.2.2").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login("algernon", "weeds", "192.0.2.3").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login("algernon", "flowers", "192.0.2.4").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(
+        login_failure::clear(&pool, Kind::Login, "algernon")
+            .await
+            .unwrap()
+    );
+    let (status, _) = login("algernon", "flowers", "192.0.2.4").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Addresses are locked out by failures across names.
+    for name in ["a", "b", "c"] {
+        let (status, _) = login(name, "guess", "192.0.2.1").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _) = login("algernon", "flowers", "192.0.2.1").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = login("algernon", "flowers", "192.0.2.5").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn concurrent_guesses(pool: PgPool) {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            login_attempts: 2,
+            trust_proxy: true,
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+
+    // Guesses sent all at once are let through no further than guesses sent one by one.
+    let guesses = (0..10).map(|i| {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::post("/auth/login")
+            .header("content-type", "application/json")
+            .header("x-forwarded-for", format!("192.0.2.{}", i))
+            .body(axum::body::Body::from(
+                json!({ "login": "algernon", "password": format!("guess {}", i) }).to_string(),
+            ))
+            .unwrap();
+        tokio::spawn(app.clone().oneshot(request))
+    });
+    let mut statuses = Vec::new();
+    for guess in guesses.collect::<Vec<_>>() {
+        statuses.push(guess.await.unwrap().unwrap().status());
+    }
+    let tried = statuses
+        .iter()
+        .filter(|status| **status == StatusCode::UNAUTHORIZED)
+        .count();
+    assert_eq!(tried, 3);
+    assert!(
+        statuses.iter().all(
+            |status| [StatusCode::UNAUTHORIZED, StatusCode::TOO_MANY_REQUESTS].contains(status)
+        )
+    );
+
+    // Turned away attempts do not count, and neither do successful ones.
+    let failures = sqlx::query_scalar!(
+        "SELECT failures FROM login_failures WHERE kind = 'login' AND subject = 'algernon'"
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(failures, 3);
+    let ips = sqlx::query_scalar!("SELECT sum(failures) FROM login_failures WHERE kind = 'ip'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(ips, Some(3));
+}
+
+#[sqlx::test]
+async fn passwords(pool: PgPool) {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            breached_passwords: std::sync::Arc::new(["correct horse".to_string()].into()),
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+
+    // Registering takes a password the policy allows.
+    for password in ["", "flowers", "correct horse"] {
+        let credentials = json!({ "login": "algernon", "password": password });
+        let (status, _) = call(&app, "POST", "/auth/register", None, credentials).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    // Hashes made with cheaper parameters are made anew on login.
+    let cheap = argon2::Params::new(1024, 1, 1, None).unwrap();
+    storage::auth::register_user(&pool, &cheap, "algernon", "forget-me-nots")
+        .await
+        .unwrap();
+    let credentials = json!({ "login": "algernon", "password": "forget-me-nots" });
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    let (access, _) = tokens(&body);
+    let phc = sqlx::query_scalar!(r#"SELECT phc AS "phc!" FROM users;"#)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(storage::auth::verify_password("forget-me-nots", &phc).unwrap());
+    assert!(!storage::auth::outdated(&phc, &Default::default()));
+    let (_, body) = call(&app, "POST", "/auth/login", None, credentials.clone()).await;
+    let (_, other) = tokens(&body);
+
+    // Changing the password takes the current one, and ends every other session.
+    let change =
+        |current: &str, new: &str| json!({ "currentPassword": current, "newPassword": new });
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/password",
+        Some(&access),
+        change("flowers", "sweet williams"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/password",
+        Some(&access),
+        change("forget-me-nots", "correct horse"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/password",
+        Some(&access),
+        change("forget-me-nots", "sweet williams"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(&app, "GET", "/config", Some(&access), json!(null)).await;
+    assert_eq!(status, StatusCode::OK);
+    let refresh = json!({ "refreshToken": other });
+    let (status, _) = call(&app, "POST", "/auth/refresh", None, refresh).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&app, "POST", "/auth/login", None, credentials).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let credentials = json!({ "login": "algernon", "password": "sweet williams" });
+    let (status, _) = call(&app, "POST", "/auth/login", None, credentials).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// Sends a request the way a browser would, with cookies and without an `Authorization` header.
+async fn browse(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    cookies: &str,
+    csrf: Option<&str>,
+    body: serde_json::Value,
+) -> (axum::http::StatusCode, Vec<String>, serde_json::Value) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header(axum::http::header::COOKIE, cookies);
+    if let Some(csrf) = csrf {
+        builder = builder.header("x-csrf-token", csrf);
+    }
+    let request = builder
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let set_cookies = response
+        .headers()
+        .get_all(axum::http::header::SET_COOKIE)
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .collect();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (
+        status,
+        set_cookies,
+        serde_json::from_slice(&bytes).unwrap_or_default(),
+    )
+}
+
+/// Turns the cookies a response set into the `Cookie` header a browser would send back.
+fn jar(set_cookies: &[String]) -> String {
+    set_cookies
+        .iter()
+        .map(|cookie| cookie.split(';').next().unwrap())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[sqlx::test]
+async fn cookie_sessions(pool: PgPool) {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool, dir.path());
+    let credentials = json!({ "login": "algernon", "password": "flowers", "cookies": true });
+    let (status, set_cookies, body) =
+        browse(&app, "POST", "/auth/login", "", None, credentials).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("accessToken").is_none());
+    assert!(body.get("refreshToken").is_none());
+    let csrf = body["csrfToken"].as_str().unwrap().to_string();
+    let find = |name: &str| {
+        set_cookies
+            .iter()
+            .find(|cookie| cookie.starts_with(&format!("{}=", name)))
+            .unwrap()
+            .clone()
+    };
+    assert!(find("access_token").ends_with("; Secure; SameSite=Strict; HttpOnly"));
+    assert!(find("refresh_token").contains("; Path=/auth;"));
+    assert!(find("refresh_token").ends_with("; HttpOnly"));
+    assert_eq!(find("csrf_token"), {
+        let ttl = 30 * 24 * 60 * 60;
+        format!(
+            "csrf_token={}; Path=/; Max-Age={}; Secure; SameSite=Strict",
+            csrf, ttl
+        )
+    });
+    let cookies = jar(&set_cookies);
+
+    // Reading needs only the cookies; changing anything needs the CSRF token too.
+    let (status, _, _) = browse(&app, "GET", "/config", &cookies, None, json!(null)).await;
+    assert_eq!(status, StatusCode::OK);
+    let folder = json!({ "name": "docs" });
+    let (status, _, _) = browse(&app, "POST", "/folder", &cookies, None, folder.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let wrong = Some("not the token");
+    let (status, _, _) = browse(&app, "POST", "/folder", &cookies, wrong, folder.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, _) = browse(
+        &app,
+        "POST",
+        "/folder",
+        &cookies,
+        Some(&csrf),
+        folder.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _, _) = browse(&app, "GET", "/config", "", None, json!(null)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Refreshing takes the refresh token from its cookie, and hands out a new CSRF token.
+    let (status, _, _) = browse(&app, "POST", "/auth/refresh", &cookies, None, json!({})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, set_cookies, body) = browse(
+        &app,
+        "POST",
+        "/auth/refresh",
+        &cookies,
+        Some(&csrf),
+        json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("refreshToken").is_none());
+    let renewed = body["csrfToken"].as_str().unwrap().to_string();
+    let renewed_cookies = jar(&set_cookies);
+    assert_ne!(renewed_cookies, cookies);
+    let (status, _, _) = browse(
+        &app,
+        "PUT",
+        "/config",
+        &renewed_cookies,
+        Some(&csrf),
+        json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Logging out ends the session and has the browser drop its cookies.
+    let (status, set_cookies, _) = browse(
+        &app,
+        "POST",
+        "/auth/logout",
+        &renewed_cookies,
+        Some(&renewed),
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(set_cookies.len(), 3);
+    assert!(
+        set_cookies
+            .iter()
+            .all(|cookie| cookie.contains("Max-Age=0"))
+    );
+    let (status, _, _) = browse(&app, "GET", "/config", &renewed_cookies, None, json!(null)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn cors(pool: PgPool) {
+    use tower::ServiceExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: std::sync::Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            cors_origins: vec![String::from("https://drive.example.com")],
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+    let preflight = |origin: &str| {
+        axum::http::Request::options("/folder")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header(
+                "access-control-request-headers",
+                "content-type,x-csrf-token",
+            )
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    let response = app
+        .clone()
+        .oneshot(preflight("https://drive.example.com"))
+        .await
+        .unwrap();
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://drive.example.com"
+    );
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    assert!(
+        headers["access-control-allow-headers"]
+            .to_str()
+            .unwrap()
+            .contains("x-csrf-token")
+    );
+    let response = app
+        .oneshot(preflight("https://evil.example.com"))
+        .await
+        .unwrap();
+    assert!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none()
+    );
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use sqlx::PgPool;
+use storage::api::Shared;
+use storage::blob::BlobStore;
+use tokio::io::AsyncReadExt;
+use tower::ServiceExt;
+use uuid::uuid;
+
+/// Signs the tokens of every test, as a server would with the key it signs with.
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+async fn read(store: &dyn BlobStore, key: &str, range: Option<std::ops::Range<u64>>) -> String {
+    let mut contents = String::new();
+    store
+        .get(key, range)
+        .await
+        .unwrap()
+        .read_to_string(&mut contents)
+        .await
+        .unwrap();
+    contents
+}
+
+/// What every blob store has to do.
+async fn exercise(store: &dyn BlobStore) {
+    let key = "blobs/b9/4d/b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    assert!(!store.exists(key).await.unwrap());
+    assert_eq!(
+        store.size(key).await.unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        store.get(key, None).await.err().unwrap().kind(),
+        std::io::ErrorKind::NotFound
+    );
+
+    let len = store
+        .put(key, Box::pin(std::io::Cursor::new("hello world")))
+        .await
+        .unwrap();
+    assert_eq!(len, 11);
+    assert!(store.exists(key).await.unwrap());
+    assert_eq!(store.size(key).await.unwrap(), 11);
+    assert_eq!(read(store, key, None).await, "hello world");
+    assert_eq!(read(store, key, Some(6..11)).await, "world");
+    assert_eq!(read(store, key, Some(0..1)).await, "h");
+
+    store
+        .put(key, Box::pin(std::io::Cursor::new("replaced")))
+        .await
+        .unwrap();
+    assert_eq!(read(store, key, None).await, "replaced");
+
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("upload");
+    std::fs::write(&local, "from a file").unwrap();
+    let len = store.put_file("storage/moved", &local).await.unwrap();
+    assert_eq!(len, 11);
+    assert!(!local.exists());
+    assert_eq!(read(store, "storage/moved", None).await, "from a file");
+
+    store.delete(key).await.unwrap();
+    assert!(!store.exists(key).await.unwrap());
+    store.delete(key).await.unwrap();
+}
+
+#[tokio::test]
+async fn local_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = storage::blob::local::LocalStore::new(dir.path());
+    exercise(&store).await;
+    // Keys map onto the same layout as before there were stores.
+    assert!(dir.path().join("storage/moved").is_file());
+}
+
+#[tokio::test]
+async fn memory_store() {
+    exercise(&storage::blob::memory::MemoryStore::default()).await;
+}
+
+type Bucket = Arc<Mutex<HashMap<String, Bytes>>>;
+
+/// Just enough of the S3 API, path-style, for the client to work against.
+fn stand_in(bucket: Bucket) -> axum::Router {
+    async fn put_object(
+        State(bucket): State<Bucket>,
+        Path((_, key)): Path<(String, String)>,
+        body: Bytes,
+    ) -> Response {
+        let etag = format!("\"{}\"", body.len());
+        bucket.lock().unwrap().insert(key, body);
+        (StatusCode::OK, [(header::ETAG, etag)]).into_response()
+    }
+
+    async fn get_object(
+        State(bucket): State<Bucket>,
+        Path((_, key)): Path<(String, String)>,
+        headers: HeaderMap,
+    ) -> Response {
+        let Some(object) = bucket.lock().unwrap().get(&key).cloned() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let len = object.len();
+        let range = headers
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'))
+            .map(|(first, last)| {
+                let first: usize = first.parse().unwrap();
+                let last: usize = last.parse().unwrap_or(len - 1).min(len - 1);
+                first..last + 1
+            });
+        match range {
+            Some(range) => (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::ETAG, format!("\"{}\"", len)),
+                    (header::CONTENT_LENGTH, range.len().to_string()),
+                    (
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+                    ),
+                ],
+                object.slice(range),
+            )
+                .into_response(),
+            None => (
+                StatusCode::OK,
+                [
+                    (header::ETAG, format!("\"{}\"", len)),
+                    (header::CONTENT_LENGTH, len.to_string()),
+                ],
+                object,
+            )
+                .into_response(),
+        }
+    }
+
+    async fn delete_object(
+        State(bucket): State<Bucket>,
+        Path((_, key)): Path<(String, String)>,
+    ) -> StatusCode {
+        bucket.lock().unwrap().remove(&key);
+        StatusCode::NO_CONTENT
+    }
+
+    axum::Router::new()
+        .route(
+            "/{bucket}/{*key}",
+            axum::routing::get(get_object)
+                .put(put_object)
+                .delete(delete_object),
+        )
+        .with_state(bucket)
+}
+
+#[tokio::test]
+async fn s3_store() {
+    let bucket = Bucket::default();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::serve(listener, stand_in(bucket.clone())).into_future());
+    let store = storage::blob::s3::S3Store::new(&storage::blob::s3::Config {
+        bucket: String::from("drive"),
+        endpoint: Some(format!("http://{}", addr)),
+        region: String::from("us-east-1"),
+        access_key_id: String::from("minioadmin"),
+        secret_access_key: String::from("minioadmin"),
+    })
+    .unwrap();
+    exercise(&store).await;
+    assert!(bucket.lock().unwrap().contains_key("storage/moved"));
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn app_with_memory_store(pool: PgPool) {
+    let token = storage::auth::jwt::issue(
+        uuid!("331194d0-3c87-42ed-aab0-bac0fc637063"),
+        &KEYS,
+        chrono::Duration::minutes(30),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = Arc::new(storage::blob::memory::MemoryStore::default());
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: blobs.clone(),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let app = storage::app(shared);
+    let body = axum::body::Body::from(concat!(
+        "--BOUNDARY\r\n",
+        "Content-Disposition: form-data; name=\"destination\"\r\n\r\n",
+        "/docs\r\n",
+        "--BOUNDARY\r\n",
+        "Content-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\n",
+        "Content-Type: text/plain\r\n\r\n",
+        "hello world\r\n",
+        "--BOUNDARY--\r\n"
+    ));
+    let req = axum::http::Request::builder()
+        .method("POST")
+        .uri("/upload")
+        .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+        .header(header::AUTHORIZATION, format!("Bearer {}", &token))
+        .body(body)
+        .unwrap();
+    let response = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let file = sqlx::query!("SELECT id, path FROM files WHERE name = 'test.txt'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let path = file.path.unwrap();
+    assert!(blobs.exists(&path).await.unwrap());
+    assert!(!dir.path().join(&path).exists());
+
+    let req = axum::http::Request::builder()
+        .uri(format!("/download/{}", file.id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", &token))
+        .header(header::RANGE, "bytes=6-")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    use http_body_util::BodyExt;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "world");
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn orphans_are_swept(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = Arc::new(storage::blob::memory::MemoryStore::default());
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: blobs.clone(),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    let hash = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    let key = storage::blob::path(hash).to_string_lossy().into_owned();
+    let contents = || -> storage::blob::Reader { Box::pin(&b"hello world"[..]) };
+    blobs.put(&key, contents()).await.unwrap();
+    blobs.put("storage/legacy", contents()).await.unwrap();
+
+    // A claim that is still being committed keeps the contents.
+    let mut tx = pool.begin().await.unwrap();
+    assert!(storage::db::blob::claim(&mut *tx, hash, 11).await.unwrap());
+    let sweep = tokio::spawn({
+        let shared = shared.clone();
+        async move { storage::blob::sweep_orphans(&shared).await.unwrap() }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!sweep.is_finished());
+    tx.commit().await.unwrap();
+    assert_eq!(sweep.await.unwrap(), 0);
+    assert!(blobs.exists(&key).await.unwrap());
+
+    // Contents whose claim rolled back are swept, and anything not stored as a blob is left alone.
+    sqlx::query!("DELETE FROM blobs")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mut tx = pool.begin().await.unwrap();
+    assert!(storage::db::blob::claim(&mut *tx, hash, 11).await.unwrap());
+    tx.rollback().await.unwrap();
+    assert_eq!(storage::blob::sweep_orphans(&shared).await.unwrap(), 1);
+    assert!(!blobs.exists(&key).await.unwrap());
+    assert!(blobs.exists("storage/legacy").await.unwrap());
+    let rows = sqlx::query_scalar!("SELECT count(*) FROM blobs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, Some(0));
+
+    // A blob claimed again while being collected is taken back.
+    blobs.put(&key, contents()).await.unwrap();
+    assert!(storage::db::blob::claim(&pool, hash, 11).await.unwrap());
+    let marked = storage::db::blob::mark_unreferenced(&pool).await.unwrap();
+    assert_eq!(marked, [hash]);
+    assert!(!storage::db::blob::claim(&pool, hash, 11).await.unwrap());
+    assert!(!storage::db::blob::release(&pool, hash).await.unwrap());
+    assert!(blobs.exists(&key).await.unwrap());
+}
@@ -0,0 +1,317 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{StatusCode, header};
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use sqlx::PgPool;
+use storage::api::Shared;
+use tower::ServiceExt;
+use uuid::{Uuid, uuid};
+
+/// Signs the tokens of every test, as a server would with the key it signs with.
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+const ALGERNON: Uuid = uuid!("331194d0-3c87-42ed-aab0-bac0fc637063");
+const BARTHOLOMEW: Uuid = uuid!("9e0c2a4f-5b1d-4c7e-8f3a-6d2b1e0c9a87");
+
+fn token(user_id: Uuid) -> String {
+    storage::auth::jwt::issue(user_id, &KEYS, chrono::Duration::minutes(30)).unwrap()
+}
+
+/// Starts relaying events, returning once the relay is known to be listening.
+async fn relaying(pool: PgPool, root: &std::path::Path) -> Shared {
+    let shared = Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: root.to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(root)),
+        settings: Default::default(),
+        events: Default::default(),
+    };
+    tokio::spawn(storage::tasks::relay_events(shared.clone()));
+    let probe = Uuid::new_v4();
+    let mut heard = std::pin::pin!(shared.events.subscribe(probe));
+    let payload = serde_json::json!({
+        "userId": probe,
+        "kind": "edit",
+        "fileId": probe,
+        "name": "probe",
+        "parentId": null,
+        "folder": false,
+    });
+    loop {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(storage::api::events::CHANNEL)
+            .bind(payload.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        if tokio::time::timeout(Duration::from_millis(100), heard.next())
+            .await
+            .is_ok()
+        {
+            return shared;
+        }
+    }
+}
+
+fn create_folder(token: &str, name: &str) -> axum::http::Request<axum::body::Body> {
+    axum::http::Request::builder()
+        .method("POST")
+        .uri("/folder")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(
+            serde_json::json!({ "name": name }).to_string(),
+        ))
+        .unwrap()
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn server_sent_events(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(relaying(pool, dir.path()).await);
+    let algernon = token(ALGERNON);
+
+    let response = app
+        .clone()
+        .oneshot(
+            axum::http::Request::builder()
+                .uri("/events")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .clone()
+        .oneshot(
+            axum::http::Request::builder()
+                .uri("/events")
+                .header(header::AUTHORIZATION, format!("Bearer {}", &algernon))
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+    let mut body = response.into_body();
+
+    let response = app
+        .clone()
+        .oneshot(create_folder(&algernon, "live"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let text = String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap();
+    let data = text.trim().strip_prefix("data: ").unwrap();
+    let event: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(event["kind"], "create");
+    assert_eq!(event["name"], "live");
+    assert_eq!(event["folder"], true);
+    assert!(event["seq"].is_i64());
+    assert!(event.get("userId").is_none());
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon", "bartholomew"))]
+async fn websocket_events(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(relaying(pool, dir.path()).await);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::serve(listener, app.clone()).into_future());
+    let algernon = token(ALGERNON);
+
+    let rejected = tokio_tungstenite::connect_async(format!("ws://{}/events", addr)).await;
+    assert!(rejected.is_err());
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!(
+        "ws://{}/events?accessToken={}",
+        addr,
+        token(BARTHOLOMEW)
+    ))
+    .await
+    .unwrap();
+
+    // Changes to someone else's drive are not heard, sharing with the user is.
+    let response = app
+        .clone()
+        .oneshot(create_folder(&algernon, "private"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = app
+        .clone()
+        .oneshot(create_folder(&algernon, "team"))
+        .await
+        .unwrap();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let team: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let response = app
+        .clone()
+        .oneshot(
+            axum::http::Request::builder()
+                .method("POST")
+                .uri(format!(
+                    "/files/{}/permissions",
+                    team["id"].as_str().unwrap()
+                ))
+                .header(header::AUTHORIZATION, format!("Bearer {}", &algernon))
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(
+                    serde_json::json!({ "login": "bartholomew", "role": "editor" }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let event: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!(event["kind"], "share");
+    assert_eq!(event["name"], "team");
+    assert_eq!(event["role"], "editor");
+    socket.close(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn lagging_sessions_resync() {
+    let hub = storage::api::events::Hub::default();
+    let mut algernon = std::pin::pin!(hub.subscribe(ALGERNON));
+    let mut bartholomew = std::pin::pin!(hub.subscribe(BARTHOLOMEW));
+    let event = |user_id: Uuid, name: &str| {
+        serde_json::from_value::<storage::api::events::Event>(serde_json::json!({
+            "userId": user_id,
+            "kind": "create",
+            "fileId": Uuid::new_v4(),
+            "name": name,
+            "parentId": null,
+            "folder": false,
+        }))
+        .unwrap()
+    };
+    for i in 0..2000 {
+        hub.publish(event(ALGERNON, &i.to_string()));
+    }
+    hub.publish(event(BARTHOLOMEW, "calm"));
+
+    // The events of someone else do not crowd out those of a user.
+    let notice = serde_json::to_value(bartholomew.next().await.unwrap()).unwrap();
+    assert_eq!(notice["name"], "calm");
+    let notice = serde_json::to_value(algernon.next().await.unwrap()).unwrap();
+    assert_eq!(notice, serde_json::json!({ "kind": "resync" }));
+    let notice = serde_json::to_value(algernon.next().await.unwrap()).unwrap();
+    assert_eq!(notice["kind"], "create");
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn streams_end_with_their_token(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            events_recheck: Duration::from_millis(100),
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+    let open = |token: String| {
+        app.clone().oneshot(
+            axum::http::Request::builder()
+                .uri("/events")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+    };
+    let ended = |response: axum::response::Response| async move {
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(frame) = body.frame().await {
+                frame.unwrap();
+            }
+        })
+        .await
+        .is_ok()
+    };
+
+    let expiring =
+        storage::auth::jwt::issue(ALGERNON, &KEYS, chrono::Duration::seconds(2)).unwrap();
+    assert!(ended(open(expiring).await.unwrap()).await);
+
+    let revoked = token(ALGERNON);
+    let response = open(revoked.clone()).await.unwrap();
+    let claims = storage::auth::jwt::validate(&revoked, &KEYS).unwrap();
+    storage::db::token::revoke_access(&pool, &claims.jti, claims.expires_at())
+        .await
+        .unwrap();
+    assert!(ended(response).await);
+}
+
+#[sqlx::test(migrations = "./migrations", fixtures("algernon"))]
+async fn cookie_websockets_check_origin(pool: PgPool) {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: storage::api::Settings {
+            cors_origins: vec![String::from("https://drive.example.com")],
+            ..Default::default()
+        },
+        events: Default::default(),
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::serve(listener, app).into_future());
+    let cookie = format!("access_token={}", token(ALGERNON));
+    let connect = |origin: String| {
+        let mut request = format!("ws://{}/events", addr)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert(header::COOKIE, cookie.parse().unwrap());
+        request
+            .headers_mut()
+            .insert(header::ORIGIN, origin.parse().unwrap());
+        tokio_tungstenite::connect_async(request)
+    };
+
+    // Pages of other sites cannot ride on the cookies of the browser they run in.
+    let Err(tokio_tungstenite::tungstenite::Error::Http(rejected)) =
+        connect(String::from("https://evil.example.com")).await
+    else {
+        panic!("The upgrade was not refused");
+    };
+    assert_eq!(rejected.status(), StatusCode::FORBIDDEN);
+    let (mut socket, _) = connect(String::from("https://drive.example.com"))
+        .await
+        .unwrap();
+    socket.close(None).await.unwrap();
+    let (mut socket, _) = connect(format!("http://{}", addr)).await.unwrap();
+    socket.close(None).await.unwrap();
+}
@@ -0,0 +1,4 @@
+INSERT INTO users (id, login, phc)
+VALUES ('9e0c2a4f-5b1d-4c7e-8f3a-6d2b1e0c9a87'::UUID, 'bartholomew', '$argon2id$v=19$m=19456,t=2,p=1$m3V1wT80/Q9Mk2RI83XbqA$Z7gQc/BXfb5OcVXwDws3GDSSSvgMHeZA7xk5P6r4g0k');
+INSERT INTO configs (user_id)
+VALUES ('9e0c2a4f-5b1d-4c7e-8f3a-6d2b1e0c9a87'::UUID);
\ No newline at end of file
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use futures_util::{SinkExt, StreamExt};
+use ldap3_proto::LdapCodec;
+use ldap3_proto::proto::LdapFilter;
+use ldap3_proto::simple::*;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use storage::db::user::Role;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+const SERVICE_DN: &str = "cn=drive,dc=example,dc=com";
+const SERVICE_PASSWORD: &str = "service secret";
+const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+/// An entry of the stand-in directory, with its attributes and the password binding to it takes.
+struct Entry {
+    password: String,
+    attrs: HashMap<String, Vec<String>>,
+}
+
+type Entries = Arc<Mutex<HashMap<String, Entry>>>;
+
+fn person(uid: &str, password: &str, groups: &[&str]) -> (String, Entry) {
+    let attrs = HashMap::from([
+        (String::from("objectClass"), vec![String::from("person")]),
+        (String::from("uid"), vec![uid.to_string()]),
+        (
+            String::from("memberOf"),
+            groups.iter().map(|group| group.to_string()).collect(),
+        ),
+    ]);
+    let dn = format!("uid={},ou=people,dc=example,dc=com", uid);
+    let password = password.to_string();
+    (dn, Entry { password, attrs })
+}
+
+fn matches(filter: &LdapFilter, entry: &Entry) -> bool {
+    let values = |attr: &str| {
+        entry
+            .attrs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(attr))
+            .map(|(_, values)| values.as_slice())
+            .unwrap_or_default()
+    };
+    match filter {
+        LdapFilter::And(filters) => filters.iter().all(|filter| matches(filter, entry)),
+        LdapFilter::Or(filters) => filters.iter().any(|filter| matches(filter, entry)),
+        LdapFilter::Not(filter) => !matches(filter, entry),
+        LdapFilter::Equality(attr, value) => {
+            values(attr).iter().any(|v| v.eq_ignore_ascii_case(value))
+        }
+        LdapFilter::Present(attr) => !values(attr).is_empty(),
+        _ => false,
+    }
+}
+
+/// Answers binds and searches from `entries`, just well enough for logging in.
+async fn serve_client(socket: tokio::net::TcpStream, entries: Entries) {
+    let (r, w) = tokio::io::split(socket);
+    let mut requests = FramedRead::new(r, LdapCodec::default());
+    let mut responses = FramedWrite::new(w, LdapCodec::default());
+    while let Some(Ok(msg)) = requests.next().await {
+        let replies = match ServerOps::try_from(msg) {
+            Ok(ServerOps::SimpleBind(bind)) => {
+                let entries = entries.lock().unwrap();
+                let ok = (bind.dn == SERVICE_DN && bind.pw == SERVICE_PASSWORD)
+                    || entries
+                        .get(&bind.dn)
+                        .is_some_and(|entry| entry.password == bind.pw);
+                vec![if ok {
+                    bind.gen_success()
+                } else {
+                    bind.gen_invalid_cred()
+                }]
+            }
+            Ok(ServerOps::Search(search)) => {
+                let entries = entries.lock().unwrap();
+                let mut replies: Vec<LdapMsg> = entries
+                    .iter()
+                    .filter(|(dn, entry)| {
+                        dn.ends_with(&search.base) && matches(&search.filter, entry)
+                    })
+                    .map(|(dn, entry)| {
+                        search.gen_result_entry(LdapSearchResultEntry {
+                            dn: dn.clone(),
+                            attributes: entry
+                                .attrs
+                                .iter()
+                                .map(|(atype, vals)| LdapPartialAttribute {
+                                    atype: atype.clone(),
+                                    vals: vals.iter().map(|v| v.as_bytes().to_vec()).collect(),
+                                })
+                                .collect(),
+                        })
+                    })
+                    .collect();
+                replies.push(search.gen_success());
+                replies
+            }
+            _ => return,
+        };
+        for reply in replies {
+            if responses.send(reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Serves a stand-in directory on a free port, returning its entries and its URL.
+async fn directory() -> (Entries, String) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ldap://{}", listener.local_addr().unwrap());
+    let entries: Entries = Arc::new(Mutex::new(HashMap::from([
+        person("algernon", "flowers", &[ADMINS]),
+        person("bob", "builder", &[]),
+        person("carol", "from the directory", &[]),
+    ])));
+    let served = entries.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve_client(socket, served.clone()));
+        }
+    });
+    (entries, url)
+}
+
+fn settings(url: &str) -> storage::api::Settings {
+    let directory = storage::auth::ldap::Directory::new(storage::auth::ldap::Config {
+        url: url.to_string(),
+        bind_dn: String::from(SERVICE_DN),
+        bind_password: String::from(SERVICE_PASSWORD),
+        base_dn: String::from("ou=people,dc=example,dc=com"),
+        login_attribute: String::from("uid"),
+        user_filter: String::from("(objectClass=person)"),
+        group_attribute: String::from("memberOf"),
+        admin_groups: vec![String::from(ADMINS)],
+        sync_interval: std::time::Duration::from_secs(60),
+    });
+    storage::api::Settings {
+        ldap: Some(directory),
+        ..Default::default()
+    }
+}
+
+async fn call(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        builder = builder.header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        );
+    }
+    let request = builder
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&bytes).unwrap_or_default())
+}
+
+async fn login(app: &axum::Router, login: &str, password: &str) -> (StatusCode, Value) {
+    let credentials = json!({ "login": login, "password": password });
+    call(app, "POST", "/auth/login", None, credentials).await
+}
+
+async fn role(pool: &PgPool, login: &str) -> Role {
+    storage::db::user::find_by_login(pool, login)
+        .await
+        .unwrap()
+        .unwrap()
+        .role
+}
+
+#[sqlx::test]
+async fn directory_logins(pool: PgPool) {
+    let (entries, url) = directory().await;
+    let settings = settings(&url);
+    let directory = settings.ldap.clone().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings,
+        events: Default::default(),
+    });
+    storage::auth::register_user(&pool, &Default::default(), "dave", "hunter22")
+        .await
+        .unwrap();
+    storage::auth::register_user(&pool, &Default::default(), "carol", "local password")
+        .await
+        .unwrap();
+
+    // Users of the directory are made on their first login, with the role of their groups.
+    let (status, _) = login(&app, "algernon", "flowers").await;
+    assert_eq!(status, StatusCode::OK);
+    let user = storage::db::user::find_by_login(&pool, "algernon")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(user.phc.is_none());
+    assert_eq!(user.role, Role::Admin);
+    let (status, body) = login(&app, "bob", "builder").await;
+    assert_eq!(status, StatusCode::OK);
+    let bob = body["accessToken"].as_str().unwrap().to_string();
+    assert_eq!(role(&pool, "bob").await, Role::User);
+    let (status, _) = login(&app, "algernon", "flowers").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Wrong and empty passwords are turned away, and so is anyone unknown to both.
+    let (status, _) = login(&app, "algernon", "weeds").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "algernon", "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "mallory", "flowers").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Users with their own password keep logging in with it, and the directory cannot take them over.
+    let (status, _) = login(&app, "dave", "hunter22").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "carol", "local password").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "carol", "from the directory").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = call(
+        &app,
+        "POST",
+        "/auth/tokens",
+        Some(&bob),
+        json!({ "name": "backup script", "scopes": ["files:read"] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let token = body["token"].as_str().unwrap().to_string();
+
+    // Syncing disables users whose accounts are gone and logs them out, and follows group changes.
+    entries
+        .lock()
+        .unwrap()
+        .remove("uid=bob,ou=people,dc=example,dc=com");
+    entries
+        .lock()
+        .unwrap()
+        .get_mut("uid=algernon,ou=people,dc=example,dc=com")
+        .unwrap()
+        .attrs
+        .insert(String::from("memberOf"), vec![]);
+    assert_eq!(directory.sync(&pool).await.unwrap(), (1, 0));
+    assert_eq!(directory.sync(&pool).await.unwrap(), (0, 0));
+    assert_eq!(role(&pool, "algernon").await, Role::User);
+    let folder = "/folder/00000000-0000-0000-0000-000000000000";
+    let (status, _) = call(&app, "GET", folder, Some(&bob), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&app, "GET", folder, Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = login(&app, "bob", "builder").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Accounts back in the directory have their users enabled again.
+    let (dn, entry) = person("bob", "builder", &[]);
+    entries.lock().unwrap().insert(dn, entry);
+    assert_eq!(directory.sync(&pool).await.unwrap(), (0, 1));
+    let (status, _) = call(&app, "GET", folder, Some(&token), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(&app, "bob", "builder").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Users an administrator disabled stay so, even when their account leaves and comes back.
+    let algernon = storage::db::user::find_by_login(&pool, "algernon")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        storage::db::user::disable(&pool, &algernon.id)
+            .await
+            .unwrap()
+    );
+    let (dn, entry) = entries
+        .lock()
+        .unwrap()
+        .remove_entry("uid=algernon,ou=people,dc=example,dc=com")
+        .unwrap();
+    assert_eq!(directory.sync(&pool).await.unwrap(), (1, 0));
+    entries.lock().unwrap().insert(dn, entry);
+    assert_eq!(directory.sync(&pool).await.unwrap(), (0, 0));
+    let (status, _) = login(&app, "algernon", "flowers").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let algernon = storage::db::user::find_by_login(&pool, "algernon")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(algernon.disabled_at.is_some());
+}
+
+#[sqlx::test]
+async fn disabled_users_cannot_log_in(pool: PgPool) {
+    let user_id = storage::auth::register_user(&pool, &Default::default(), "dave", "hunter22")
+        .await
+        .unwrap();
+    assert!(storage::db::user::disable(&pool, &user_id).await.unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let app = storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings: Default::default(),
+        events: Default::default(),
+    });
+    let (status, _) = login(&app, "dave", "hunter22").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(storage::db::user::enable(&pool, &user_id).await.unwrap());
+    let (status, _) = login(&app, "dave", "hunter22").await;
+    assert_eq!(status, StatusCode::OK);
+}
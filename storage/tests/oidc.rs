@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Form, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+const CLIENT_ID: &str = "proto-drive";
+
+/// Who the mock issuer says is logging in, and who it says the ID token is for.
+#[derive(Clone)]
+struct Profile {
+    subject: String,
+    username: String,
+    audience: String,
+}
+
+/// A code handed out by the mock issuer, waiting to be redeemed.
+struct Grant {
+    profile: Profile,
+    nonce: String,
+    challenge: String,
+}
+
+/// An identity provider that logs in whoever its profile says without asking.
+#[derive(Clone)]
+struct Mock {
+    issuer: String,
+    pem: Arc<String>,
+    keys: storage::auth::jwt::Keys,
+    profile: Arc<Mutex<Profile>>,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+async fn discovery(State(mock): State<Mock>) -> Response {
+    axum::Json(json!({
+        "issuer": mock.issuer,
+        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+        "token_endpoint": format!("{}/token", mock.issuer),
+        "jwks_uri": format!("{}/jwks", mock.issuer),
+    }))
+    .into_response()
+}
+
+async fn jwks(State(mock): State<Mock>) -> Response {
+    axum::Json(mock.keys.jwks()).into_response()
+}
+
+async fn authorize(
+    State(mock): State<Mock>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    assert_eq!(params["response_type"], "code");
+    assert_eq!(params["client_id"], CLIENT_ID);
+    assert_eq!(params["code_challenge_method"], "S256");
+    let code = storage::auth::token::generate();
+    let grant = Grant {
+        profile: mock.profile.lock().unwrap().clone(),
+        nonce: params["nonce"].clone(),
+        challenge: params["code_challenge"].clone(),
+    };
+    mock.grants.lock().unwrap().insert(code.clone(), grant);
+    let location = reqwest::Url::parse_with_params(
+        &params["redirect_uri"],
+        &[("code", &code), ("state", &params["state"])],
+    )
+    .unwrap();
+    Redirect::to(location.as_str()).into_response()
+}
+
+async fn token(State(mock): State<Mock>, Form(form): Form<HashMap<String, String>>) -> Response {
+    let invalid = (
+        StatusCode::BAD_REQUEST,
+        axum::Json(json!({ "error": "invalid_grant" })),
+    );
+    let Some(grant) = mock.grants.lock().unwrap().remove(&form["code"]) else {
+        return invalid.into_response();
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form["code_verifier"].as_bytes()));
+    if challenge != grant.challenge {
+        return invalid.into_response();
+    }
+    let now = chrono::Utc::now().timestamp();
+    let claims = json!({
+        "iss": mock.issuer,
+        "aud": grant.profile.audience,
+        "sub": grant.profile.subject,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": grant.nonce,
+        "preferred_username": grant.profile.username,
+        "email": format!("{}@example.com", grant.profile.username),
+    });
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(String::from("mock"));
+    let key = EncodingKey::from_ed_pem(mock.pem.as_bytes()).unwrap();
+    let id_token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+    axum::Json(json!({ "access_token": "unused", "token_type": "Bearer", "id_token": id_token }))
+        .into_response()
+}
+
+/// Serves a mock issuer on a free port.
+async fn mock_issuer() -> Mock {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let pem = storage::auth::jwt::generate(Algorithm::EdDSA).unwrap();
+    let keys = storage::auth::jwt::Keys::new(&issuer, CLIENT_ID);
+    keys.replace(&[("mock", Algorithm::EdDSA, &pem)], Some("mock"))
+        .unwrap();
+    let mock = Mock {
+        issuer,
+        pem: Arc::new(pem),
+        keys,
+        profile: Arc::new(Mutex::new(Profile {
+            subject: String::from("248289761001"),
+            username: String::from("algernon"),
+            audience: String::from(CLIENT_ID),
+        })),
+        grants: Default::default(),
+    };
+    let router = axum::Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .with_state(mock.clone());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    mock
+}
+
+fn app(pool: PgPool, root: &std::path::Path, issuer: &str, provision: bool) -> axum::Router {
+    let provider = storage::auth::oidc::Provider::new(storage::auth::oidc::Config {
+        issuer: issuer.to_string(),
+        client_id: String::from(CLIENT_ID),
+        client_secret: Some(String::from("hunter2")),
+        redirect_uri: String::from("https://drive.example.com/auth/oidc/callback"),
+        scopes: String::from("openid profile email"),
+        provision,
+    });
+    storage::app(storage::api::Shared {
+        pool,
+        keys: KEYS.clone(),
+        root: root.to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(root)),
+        settings: storage::api::Settings {
+            oidc: Some(provider),
+            ..Default::default()
+        },
+        events: Default::default(),
+    })
+}
+
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    cookie: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value, axum::http::HeaderMap) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        builder = builder.header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        );
+    }
+    if let Some(cookie) = cookie {
+        builder = builder.header(axum::http::header::COOKIE, cookie);
+    }
+    let request = builder
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (
+        status,
+        serde_json::from_slice(&bytes).unwrap_or_default(),
+        headers,
+    )
+}
+
+async fn call(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value, Option<String>) {
+    let (status, body, headers) = send(app, method, uri, token, None, body).await;
+    let location = headers
+        .get(axum::http::header::LOCATION)
+        .map(|v| v.to_str().unwrap().to_string());
+    (status, body, location)
+}
+
+/// The cookies a response sets, as a browser would send them back.
+fn cookies(headers: &axum::http::HeaderMap) -> String {
+    headers
+        .get_all(axum::http::header::SET_COOKIE)
+        .iter()
+        .map(|v| v.to_str().unwrap().split(';').next().unwrap())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Follows a user to the provider, returning the callback it sends them back to.
+async fn authorize_at(authorization_url: &str) -> String {
+    let http = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let response = http.get(authorization_url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = response.headers()[reqwest::header::LOCATION]
+        .to_str()
+        .unwrap();
+    let callback = reqwest::Url::parse(location).unwrap();
+    assert_eq!(callback.path(), "/auth/oidc/callback");
+    format!("/auth/oidc/callback?{}", callback.query().unwrap())
+}
+
+/// A user sent back from the provider: the callback and the cookies of their browser.
+struct Return {
+    callback: String,
+    cookie: String,
+}
+
+/// Starts a login or link, following the user to the provider and back.
+async fn start(app: &axum::Router, method: &str, uri: &str, token: Option<&str>) -> Return {
+    let (_, body, headers) = send(app, method, uri, token, None, json!({})).await;
+    let authorization_url = match headers.get(axum::http::header::LOCATION) {
+        Some(location) => location.to_str().unwrap().to_string(),
+        None => body["authorizationUrl"].as_str().unwrap().to_string(),
+    };
+    Return {
+        callback: authorize_at(&authorization_url).await,
+        cookie: cookies(&headers),
+    }
+}
+
+async fn finish(app: &axum::Router, back: &Return) -> (StatusCode, Value, axum::http::HeaderMap) {
+    send(
+        app,
+        "GET",
+        &back.callback,
+        None,
+        Some(&back.cookie),
+        json!({}),
+    )
+    .await
+}
+
+/// Logs in through the provider from the start, returning the callback's answer and the return.
+async fn sso(app: &axum::Router) -> (StatusCode, Value, Return) {
+    let back = start(app, "GET", "/auth/oidc/login?deviceName=laptop", None).await;
+    let (status, body, _) = finish(app, &back).await;
+    (status, body, back)
+}
+
+#[sqlx::test]
+async fn provisioning(pool: PgPool) {
+    let mock = mock_issuer().await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool.clone(), dir.path(), &mock.issuer, true);
+
+    // The first login makes a user named after the account, without a password.
+    let (status, body, back) = sso(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    let access = body["accessToken"].as_str().unwrap().to_string();
+    assert!(body["refreshToken"].is_string());
+    let user = storage::db::user::find_by_login(&pool, "algernon")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(user.phc.is_none());
+    let (status, body, _) = call(&app, "GET", "/auth/sessions", Some(&access), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["deviceName"], "laptop");
+
+    // A state is only good once, and only in the browser it was handed to.
+    let (status, _, _) = finish(&app, &back).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let back = start(&app, "GET", "/auth/oidc/login", None).await;
+    let (status, _, _) = call(&app, "GET", &back.callback, None, json!({})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let elsewhere = start(&app, "GET", "/auth/oidc/login", None).await;
+    let (status, _, _) = send(
+        &app,
+        "GET",
+        &back.callback,
+        None,
+        Some(&elsewhere.cookie),
+        json!({}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, _) = finish(&app, &back).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Browsers may ask for the session in cookies.
+    let back = start(&app, "GET", "/auth/oidc/login?cookies=true", None).await;
+    let (status, body, headers) = finish(&app, &back).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["csrfToken"].is_string());
+    assert!(body.get("accessToken").is_none());
+    assert!(cookies(&headers).contains("access_token="));
+
+    // Later logins find the same user.
+    let (status, _, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    let count = sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM users;"#)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+
+    // Without a password, there is nothing to log in or change it with.
+    let credentials = json!({ "login": "algernon", "password": "" });
+    let (status, _, _) = call(&app, "POST", "/auth/login", None, credentials).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let change = json!({ "currentPassword": "", "newPassword": "forget-me-nots" });
+    let (status, _, _) = call(&app, "POST", "/auth/password", Some(&access), change).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Another account named like an existing user is turned away rather than merged into it.
+    mock.profile.lock().unwrap().subject = String::from("impostor");
+    let (status, _, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // So are ID tokens meant for another client.
+    *mock.profile.lock().unwrap() = Profile {
+        subject: String::from("248289761001"),
+        username: String::from("algernon"),
+        audience: String::from("someone-else"),
+    };
+    let (status, _, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn linking(pool: PgPool) {
+    let mock = mock_issuer().await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(pool.clone(), dir.path(), &mock.issuer, false);
+    let user_id = storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+    let access = storage::auth::jwt::issue(user_id, &KEYS, chrono::Duration::minutes(5)).unwrap();
+
+    // Without provisioning, accounts not linked to anyone are turned away.
+    let (status, _, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, _) = call(&app, "POST", "/auth/oidc/link", None, json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let back = start(&app, "POST", "/auth/oidc/link", Some(&access)).await;
+    let (status, _, _) = finish(&app, &back).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // The account logs in as the user now, who keeps their password.
+    let (status, body, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    let other = body["accessToken"].as_str().unwrap();
+    let claims = storage::auth::jwt::validate(other, &KEYS).unwrap();
+    assert_eq!(claims.sub, user_id);
+    storage::auth::login_user(&pool, &Default::default(), "algernon", "flowers")
+        .await
+        .unwrap();
+
+    // A second factor is asked for as after a password.
+    let secret = storage::auth::totp::generate_secret();
+    storage::db::two_factor::enroll(&pool, &user_id, &secret)
+        .await
+        .unwrap();
+    storage::db::two_factor::enable(&pool, &user_id)
+        .await
+        .unwrap();
+    let (status, body, _) = sso(&app).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["accessToken"].is_null());
+    let second = json!({
+        "mfaToken": body["mfaToken"],
+        "code": storage::auth::totp::code(&secret, storage::auth::totp::now()),
+    });
+    let (status, body, _) = call(&app, "POST", "/auth/login/2fa", None, second).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["accessToken"].is_string());
+
+    // An account can only be linked to one user.
+    let bob = storage::auth::register_user(&pool, &Default::default(), "bob", "flowers")
+        .await
+        .unwrap();
+    let access = storage::auth::jwt::issue(bob, &KEYS, chrono::Duration::minutes(5)).unwrap();
+    let back = start(&app, "POST", "/auth/oidc/link", Some(&access)).await;
+    let (status, _, _) = finish(&app, &back).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
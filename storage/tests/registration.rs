@@ -0,0 +1,351 @@
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use storage::api::registration::Mode;
+use storage::db::user::Role;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+static KEYS: std::sync::LazyLock<storage::auth::jwt::Keys> =
+    std::sync::LazyLock::new(storage::auth::jwt::Keys::ephemeral);
+
+type Inbox = Arc<Mutex<Vec<String>>>;
+
+/// Takes in mail over SMTP without sending it anywhere, keeping what it was given.
+async fn sink() -> (Inbox, String) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("smtp://{}", listener.local_addr().unwrap());
+    let inbox: Inbox = Default::default();
+    let delivered = inbox.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let inbox = delivered.clone();
+            tokio::spawn(async move {
+                let (r, mut w) = socket.into_split();
+                let mut lines = BufReader::new(r).lines();
+                w.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let command = line.to_ascii_uppercase();
+                    let reply: &[u8] = if command.starts_with("DATA") {
+                        w.write_all(b"354 Go ahead\r\n").await.unwrap();
+                        let mut message = String::new();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if line == "." {
+                                break;
+                            }
+                            message.push_str(&line);
+                            message.push('\n');
+                        }
+                        inbox.lock().unwrap().push(message);
+                        b"250 Queued\r\n"
+                    } else if command.starts_with("QUIT") {
+                        let _ = w.write_all(b"221 Bye\r\n").await;
+                        return;
+                    } else {
+                        b"250 OK\r\n"
+                    };
+                    if w.write_all(reply).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (inbox, url)
+}
+
+fn app(pool: &PgPool, dir: &tempfile::TempDir, settings: storage::api::Settings) -> axum::Router {
+    storage::app(storage::api::Shared {
+        pool: pool.clone(),
+        keys: KEYS.clone(),
+        root: dir.path().to_path_buf(),
+        blobs: Arc::new(storage::blob::local::LocalStore::new(dir.path())),
+        settings,
+        events: Default::default(),
+    })
+}
+
+async fn call(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value) {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mut builder = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        builder = builder.header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token),
+        );
+    }
+    let request = builder
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&bytes).unwrap_or_default())
+}
+
+async fn register(app: &axum::Router, body: Value) -> StatusCode {
+    call(app, "POST", "/auth/register", None, body).await.0
+}
+
+async fn login(app: &axum::Router, login: &str, password: &str) -> (StatusCode, Value) {
+    let credentials = json!({ "login": login, "password": password });
+    call(app, "POST", "/auth/login", None, credentials).await
+}
+
+/// Finds the verification token in a mail, undoing the quoted-printable encoding it may be in.
+fn verification_token(mail: &str) -> String {
+    let decoded = mail.replace("=\n", "").replace("=3D", "=");
+    let start = decoded.find("token=").unwrap() + "token=".len();
+    decoded[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+#[sqlx::test]
+async fn invite_only(pool: PgPool) {
+    let dir = tempfile::tempdir().unwrap();
+    let settings = storage::api::Settings {
+        registration: Mode::Invite,
+        ..Default::default()
+    };
+    let app = app(&pool, &dir, settings);
+    let admin_id =
+        storage::auth::register_user(&pool, &Default::default(), "algernon", "flowers for")
+            .await
+            .unwrap();
+    storage::db::user::set_role(&pool, &admin_id, Role::Admin)
+        .await
+        .unwrap();
+    let (_, body) = login(&app, "algernon", "flowers for").await;
+    let admin = body["accessToken"].as_str().unwrap().to_string();
+
+    let (status, body) = call(&app, "POST", "/admin/invites", Some(&admin), json!({})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let code = body["code"].as_str().unwrap().to_string();
+    let bob = json!({ "login": "bob", "password": "the builder" });
+    assert_eq!(register(&app, bob.clone()).await, StatusCode::FORBIDDEN);
+    let wrong = json!({ "login": "bob", "password": "the builder", "inviteCode": "guessed" });
+    assert_eq!(register(&app, wrong).await, StatusCode::FORBIDDEN);
+    let invited = json!({ "login": "bob", "password": "the builder", "inviteCode": code });
+    assert_eq!(register(&app, invited).await, StatusCode::CREATED);
+    let (status, _) = login(&app, "bob", "the builder").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Codes are only good once, and nobody is made with a used one.
+    let again = json!({ "login": "mallory", "password": "sneaking in", "inviteCode": code });
+    assert_eq!(register(&app, again).await, StatusCode::FORBIDDEN);
+    assert!(
+        !storage::db::user::login_exists(&pool, "mallory")
+            .await
+            .unwrap()
+    );
+    let (_, invites) = call(&app, "GET", "/admin/invites", Some(&admin), Value::Null).await;
+    assert_eq!(invites.as_array().unwrap().len(), 1);
+    assert!(invites[0]["usedBy"].is_string());
+    assert!(invites[0].get("code").is_none());
+
+    // Invites for an address only work with it.
+    let (_, body) = call(
+        &app,
+        "POST",
+        "/admin/invites",
+        Some(&admin),
+        json!({ "email": "carol@example.com" }),
+    )
+    .await;
+    let code = body["code"].as_str().unwrap().to_string();
+    let elsewhere = json!({
+        "login": "carol",
+        "password": "singing carols",
+        "email": "carol@elsewhere.org",
+        "inviteCode": code,
+    });
+    assert_eq!(register(&app, elsewhere).await, StatusCode::FORBIDDEN);
+    let carol = json!({
+        "login": "carol",
+        "password": "singing carols",
+        "email": "Carol@Example.com",
+        "inviteCode": code,
+    });
+    assert_eq!(register(&app, carol).await, StatusCode::CREATED);
+
+    // Withdrawn invites are gone, and only administrators make them.
+    let (_, body) = call(&app, "POST", "/admin/invites", Some(&admin), json!({})).await;
+    let code = body["code"].as_str().unwrap().to_string();
+    let withdraw = format!("/admin/invites/{}", body["id"].as_str().unwrap());
+    let (status, _) = call(&app, "DELETE", &withdraw, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(&app, "DELETE", &withdraw, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let dave = json!({ "login": "dave", "password": "hunter22", "inviteCode": code });
+    assert_eq!(register(&app, dave).await, StatusCode::FORBIDDEN);
+    let (_, body) = login(&app, "bob", "the builder").await;
+    let bob = body["accessToken"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, "POST", "/admin/invites", Some(&bob), json!({})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let closed = storage::api::Settings {
+        registration: Mode::Disabled,
+        ..Default::default()
+    };
+    let app = self::app(&pool, &dir, closed);
+    let erin = json!({ "login": "erin", "password": "eavesdrop" });
+    assert_eq!(register(&app, erin).await, StatusCode::FORBIDDEN);
+
+    // Without verification, an address is taken as soon as someone gives it.
+    let open = storage::api::Settings {
+        registration: Mode::Open,
+        ..Default::default()
+    };
+    let app = self::app(&pool, &dir, open);
+    let robert =
+        json!({ "login": "robert", "password": "the builder", "email": "CAROL@example.com" });
+    assert_eq!(register(&app, robert).await, StatusCode::CONFLICT);
+}
+
+#[sqlx::test]
+async fn allowed_domains(pool: PgPool) {
+    let (_inbox, url) = sink().await;
+    let mailer = storage::mail::smtp::Smtp::new(&storage::mail::smtp::Config {
+        url,
+        from: String::from("drive@example.com"),
+    })
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let settings = storage::api::Settings {
+        registration_domains: vec![String::from("example.com")],
+        email_verification: true,
+        mailer: Some(Arc::new(mailer)),
+        ..Default::default()
+    };
+    let app = app(&pool, &dir, settings);
+    let bare = json!({ "login": "bob", "password": "the builder" });
+    assert_eq!(register(&app, bare).await, StatusCode::BAD_REQUEST);
+    let malformed = json!({ "login": "bob", "password": "the builder", "email": "bob" });
+    assert_eq!(register(&app, malformed).await, StatusCode::BAD_REQUEST);
+    let outside = json!({ "login": "bob", "password": "the builder", "email": "bob@example.org" });
+    assert_eq!(register(&app, outside).await, StatusCode::FORBIDDEN);
+    let sub = json!({ "login": "bob", "password": "the builder", "email": "bob@evil.example.com" });
+    assert_eq!(register(&app, sub).await, StatusCode::FORBIDDEN);
+    let inside = json!({ "login": "bob", "password": "the builder", "email": "bob@EXAMPLE.com" });
+    assert_eq!(register(&app, inside).await, StatusCode::CREATED);
+}
+
+#[sqlx::test]
+async fn email_verification(pool: PgPool) {
+    let (inbox, url) = sink().await;
+    let mailer = storage::mail::smtp::Smtp::new(&storage::mail::smtp::Config {
+        url,
+        from: String::from("Drive <drive@example.com>"),
+    })
+    .unwrap();
+    let settings = storage::api::Settings {
+        email_verification: true,
+        email_verification_url: Some(String::from("https://drive.example.com/verify")),
+        mailer: Some(Arc::new(mailer)),
+        ..Default::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let app = app(&pool, &dir, settings.clone());
+
+    let bare = json!({ "login": "carol", "password": "singing carols" });
+    assert_eq!(register(&app, bare).await, StatusCode::BAD_REQUEST);
+    let carol =
+        json!({ "login": "carol", "password": "singing carols", "email": "carol@example.com" });
+    assert_eq!(register(&app, carol).await, StatusCode::CREATED);
+    let mail = inbox.lock().unwrap().pop().unwrap();
+    assert!(mail.contains("To: carol@example.com"));
+    assert!(mail.contains("From: Drive <drive@example.com>"));
+    let token = verification_token(&mail);
+
+    // Someone else giving the address before it is verified does not keep carol out,
+    // and only whoever verifies it first gets it.
+    let mallory =
+        json!({ "login": "mallory", "password": "malicious", "email": "Carol@example.com" });
+    assert_eq!(register(&app, mallory).await, StatusCode::CREATED);
+    let stolen = verification_token(&inbox.lock().unwrap().pop().unwrap());
+
+    // Unverified users cannot log in, and asking again within a minute mails nothing.
+    let (status, _) = login(&app, "carol", "singing carols").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let resend = json!({ "email": "carol@example.com" });
+    let (status, _) = call(&app, "POST", "/auth/verify/resend", None, resend).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let unknown = json!({ "email": "nobody@example.com" });
+    let (status, _) = call(&app, "POST", "/auth/verify/resend", None, unknown).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(inbox.lock().unwrap().is_empty());
+
+    let wrong = json!({ "token": "guessed" });
+    let (status, _) = call(&app, "POST", "/auth/verify", None, wrong).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/verify",
+        None,
+        json!({ "token": token }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/verify",
+        None,
+        json!({ "token": token }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = login(&app, "carol", "singing carols").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/auth/verify",
+        None,
+        json!({ "token": stolen }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let erin = json!({ "login": "erin", "password": "eavesdrop", "email": "carol@EXAMPLE.com" });
+    assert_eq!(register(&app, erin).await, StatusCode::CONFLICT);
+
+    // Nobody is registered when their mail cannot be sent.
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let unreachable = format!("smtp://{}", closed.local_addr().unwrap());
+    drop(closed);
+    let mailer = storage::mail::smtp::Smtp::new(&storage::mail::smtp::Config {
+        url: unreachable,
+        from: String::from("drive@example.com"),
+    })
+    .unwrap();
+    let app = self::app(
+        &pool,
+        &dir,
+        storage::api::Settings {
+            mailer: Some(Arc::new(mailer)),
+            ..settings
+        },
+    );
+    let dave = json!({ "login": "dave", "password": "hunter22", "email": "dave@example.com" });
+    assert_eq!(register(&app, dave).await, StatusCode::BAD_GATEWAY);
+    assert!(
+        !storage::db::user::login_exists(&pool, "dave")
+            .await
+            .unwrap()
+    );
+}